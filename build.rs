@@ -1,4 +1,46 @@
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use flate2::{write::GzEncoder, Compression};
+
 fn main() {
     // Embed HTML templates at compile-time so runtime rendering stays self-contained.
     minijinja_embed::embed_templates!("templates", &[".html"]);
+
+    // Bundled scripts are served precompressed, never compressed per request.
+    precompress("static/js/mermaid.min.js");
+    precompress("static/js/katex.min.js");
+}
+
+/// Write gzip (`<name>.gz`) and brotli (`<name>.br`) copies of the file at
+/// `path` to `OUT_DIR`.
+fn precompress(path: &str) {
+    println!("cargo:rerun-if-changed={path}");
+    let contents = fs::read(path).unwrap_or_else(|e| panic!("failed to read {path}: {e}"));
+    let name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .expect("asset paths end in a file name");
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("cargo sets OUT_DIR"));
+
+    let mut gzip = GzEncoder::new(Vec::new(), Compression::best());
+    gzip.write_all(&contents).expect("writing to memory");
+    fs::write(
+        out_dir.join(format!("{name}.gz")),
+        gzip.finish().expect("writing to memory"),
+    )
+    .expect("failed to write gzip copy");
+
+    let mut brotli = Vec::new();
+    let params = brotli::enc::BrotliEncoderParams {
+        quality: 11,
+        lgwin: 22,
+        ..Default::default()
+    };
+    brotli::BrotliCompress(&mut contents.as_slice(), &mut brotli, &params)
+        .expect("writing to memory");
+    fs::write(out_dir.join(format!("{name}.br")), brotli).expect("failed to write brotli copy");
 }
@@ -0,0 +1,102 @@
+//! GitHub alerts: blockquotes starting with `[!NOTE]`, `[!TIP]`,
+//! `[!IMPORTANT]`, `[!WARNING]` or `[!CAUTION]` on a line of their own are
+//! shown as callouts, the way GitHub renders them in READMEs.
+
+/// Alert types and the titles shown for them.
+const KINDS: [(&str, &str); 5] = [
+    ("note", "Note"),
+    ("tip", "Tip"),
+    ("important", "Important"),
+    ("warning", "Warning"),
+    ("caution", "Caution"),
+];
+
+const OPEN: &str = "<blockquote>\n<p>[!";
+const CLOSE: &str = "</blockquote>";
+
+/// Turn alert blockquotes of parser output into
+/// `<div class="markdown-alert markdown-alert-{kind}">` with a title
+/// paragraph. Other blockquotes are left alone.
+pub(crate) fn render_alerts(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    // Closing tags still to rewrite, innermost last: whether each open
+    // blockquote became an alert
+    let mut open: Vec<bool> = Vec::new();
+
+    loop {
+        let next_open = rest.find("<blockquote>");
+        let next_close = rest.find(CLOSE);
+        match (next_open, next_close) {
+            (Some(start), close) if close.is_none_or(|close| start < close) => {
+                out.push_str(&rest[..start]);
+                rest = &rest[start..];
+                match alert_kind(rest) {
+                    Some((kind, title, marker_len)) => {
+                        out.push_str(&format!(
+                            "<div class=\"markdown-alert markdown-alert-{kind}\">\n\
+                             <p class=\"markdown-alert-title\">{title}</p>\n<p>"
+                        ));
+                        rest = &rest[marker_len..];
+                        open.push(true);
+                    }
+                    None => {
+                        out.push_str("<blockquote>");
+                        rest = &rest["<blockquote>".len()..];
+                        open.push(false);
+                    }
+                }
+            }
+            (_, Some(close)) => {
+                out.push_str(&rest[..close]);
+                out.push_str(if open.pop() == Some(true) {
+                    "</div>"
+                } else {
+                    CLOSE
+                });
+                rest = &rest[close + CLOSE.len()..];
+            }
+            _ => break,
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Kind, title and marker length (up to the start of the alert's text) of
+/// the alert blockquote `html` starts with, if it is one. The marker must
+/// be alone on its line and followed by content.
+fn alert_kind(html: &str) -> Option<(&'static str, &'static str, usize)> {
+    let after = html.strip_prefix(OPEN)?;
+    let end = after.find(']')?;
+    let marker = &after[..end];
+    let (kind, title) = KINDS
+        .iter()
+        .find(|(kind, _)| kind.eq_ignore_ascii_case(marker))?;
+    let text = after[end + 1..].strip_prefix('\n')?;
+    (!text.starts_with("</p>")).then_some((kind, title, html.len() - text.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alert_blockquotes_become_callouts() {
+        let html = "<blockquote>\n<p>[!note]\nUseful <em>info</em>.</p>\n\
+                    <blockquote>\n<p>nested</p>\n</blockquote>\n</blockquote>\n\
+                    <blockquote>\n<p>[!TIP] same line</p>\n</blockquote>\n\
+                    <blockquote>\n<p>[!CAUTION]</p>\n</blockquote>\n\
+                    <blockquote>\n<p>[!OTHER]\nx</p>\n</blockquote>";
+
+        assert_eq!(
+            render_alerts(html),
+            "<div class=\"markdown-alert markdown-alert-note\">\n\
+             <p class=\"markdown-alert-title\">Note</p>\n<p>Useful <em>info</em>.</p>\n\
+             <blockquote>\n<p>nested</p>\n</blockquote>\n</div>\n\
+             <blockquote>\n<p>[!TIP] same line</p>\n</blockquote>\n\
+             <blockquote>\n<p>[!CAUTION]</p>\n</blockquote>\n\
+             <blockquote>\n<p>[!OTHER]\nx</p>\n</blockquote>"
+        );
+    }
+}
@@ -1,55 +1,155 @@
 use anyhow::{Context, Result};
 use axum::{
-    extract::{
-        ws::{Message, WebSocket},
-        Path as AxumPath, State, WebSocketUpgrade,
-    },
-    http::{header, HeaderMap, StatusCode},
-    response::{Html, IntoResponse},
-    routing::get,
+    body::Body,
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{get, post, put},
     Router,
 };
-use futures_util::{SinkExt, StreamExt};
-use minijinja::{context, value::Value, Environment};
-use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use futures_util::future::BoxFuture;
+use notify::Event;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{
+        hash_map::{DefaultHasher, Entry},
+        BTreeMap, BTreeSet, HashMap, HashSet,
+    },
     fs,
-    net::{Ipv4Addr, Ipv6Addr},
-    path::{Path, PathBuf},
-    sync::{Arc, OnceLock},
-    time::SystemTime,
+    hash::{Hash, Hasher},
+    io::Read,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 use tokio::{
+    io::AsyncReadExt,
     net::TcpListener,
-    sync::{broadcast, mpsc, Mutex},
+    sync::{broadcast, mpsc, watch, Mutex, RwLock},
+    task::JoinHandle,
+};
+use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, NotForContentType, Predicate},
+        CompressionLayer,
+    },
+    cors::CorsLayer,
+    trace::TraceLayer,
 };
-use tower_http::cors::CorsLayer;
 
-const TEMPLATE_NAME: &str = "main.html";
-static TEMPLATE_ENV: OnceLock<Environment<'static>> = OnceLock::new();
-const MERMAID_JS: &str = include_str!("../static/js/mermaid.min.js");
-const MERMAID_ETAG: &str = concat!("\"", env!("CARGO_PKG_VERSION"), "\"");
-const MAX_PORT_ATTEMPTS: u16 = 10;
+use crate::{
+    auth::{self, AccessControl},
+    encoding, export,
+    frontmatter::Metadata,
+    gitignore::{IgnoreRules, EXCLUDED_DIRS},
+    headings::{document_title, Heading},
+    instance::InstanceInfo,
+    mdns, paths,
+    render::{Diagnostic, MathMode, RenderOptions, RenderPipeline, RenderedDocument, MATH_FEATURE},
+    server::{self, ConnectionLimits},
+    share, signal,
+    template::{FileTemplate, PageTemplate},
+    terminal,
+    tls::{self, TlsCertificate},
+    tunnel::Tunnel,
+    watch::{
+        file_events, is_degraded_event, is_rescan_event, ChangeSource, FileEvent, NotifySource,
+    },
+    wikilinks::render_wikilinks,
+};
 
-type SharedMarkdownState = Arc<Mutex<MarkdownState>>;
+mod builder;
+mod handlers;
 
-fn template_env() -> &'static Environment<'static> {
-    TEMPLATE_ENV.get_or_init(|| {
-        let mut env = Environment::new();
-        minijinja_embed::load_templates!(&mut env);
-        env
-    })
+pub use builder::{ExportSummary, Mdserve, MdserveBuilder};
+use handlers::{
+    create_share_link, delete_draft, events_handler, get_draft, instance_info, put_draft,
+    render_preview, render_tracked, save_file, serve_download, serve_file, serve_html_root,
+    serve_mermaid_js, serve_raw, serve_shared, serve_versioned_katex_js,
+    serve_versioned_mermaid_js, sync_scroll, upload_file, websocket_handler,
+};
+
+const MERMAID_JS: &str = include_str!("../static/js/mermaid.min.js");
+/// Version of the bundled `mermaid.min.js`; update together with the file.
+const MERMAID_VERSION: &str = "11.12.0";
+const KATEX_JS: &str = include_str!("../static/js/katex.min.js");
+/// Version of the bundled `katex.min.js`; update together with the file.
+const KATEX_VERSION: &str = "0.16.4";
+pub(crate) const MAX_PORT_ATTEMPTS: u16 = 10;
+/// How much of a document is read looking for its title.
+const TITLE_SCAN_BYTES: u64 = 64 * 1024;
+/// Watcher events arriving this close together are handled as one burst.
+const COALESCE_WINDOW: Duration = Duration::from_millis(50);
+/// Extensions never accepted as uploads, even when served: they could run
+/// scripts on mdserve's origin when opened.
+const ACTIVE_CONTENT_EXTENSIONS: &[&str] = &[
+    "svg", "svgz", "html", "htm", "xhtml", "xht", "xml", "js", "mjs",
+];
+
+/// Requests share read locks; only file changes, cache fills and other
+/// edits of the tracked set take the write lock.
+type SharedMarkdownState = Arc<RwLock<MarkdownState>>;
+
+/// Message pushed to connected previews over the `/ws` socket.
+///
+/// Serialized as JSON tagged by `type`. The page reloads on `Reload`, shows
+/// a notice while `WatchStatus` reports `watching: false`, and re-dispatches
+/// `Custom` messages as a `mdserve:<kind>` DOM event on `window` with the
+/// payload as `detail`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[non_exhaustive]
+pub enum ServerMessage {
+    /// Documents changed. `file` names the one document whose content
+    /// changed; only clients viewing it reload. `None` reloads every page,
+    /// e.g. when the file list changed.
+    Reload {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        file: Option<String>,
+    },
+    /// File watching stopped (`false`) or resumed (`true`). Pages do not
+    /// live reload while it is stopped.
+    WatchStatus { watching: bool },
+    /// Something pages should point out, shown as a toast: a file added to
+    /// the sidebar or renamed, or a document that failed to render.
+    Notice { level: NoticeLevel, message: String },
+    /// The server is stopping; the connection closes after this message.
+    Shutdown,
+    /// An editor's cursor is on `line` of `file`: pages showing it scroll
+    /// to the block made from that line.
+    ScrollTo { file: String, line: usize },
+    Custom {
+        kind: String,
+        payload: serde_json::Value,
+    },
 }
 
+/// Message a preview sends over the `/ws` socket.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(tag = "type")]
-enum ServerMessage {
-    Reload,
+#[non_exhaustive]
+pub enum ClientMessage {
+    /// The tracked document the page shows. Reloads for other documents
+    /// are not sent to it.
+    Viewing { file: String },
+    /// Scroll the pages showing `file` to `line`, as `POST /api/sync` does.
+    /// Ignored unless the server syncs scrolling.
+    ScrollTo { file: String, line: usize },
+}
+
+/// How a [`ServerMessage::Notice`] is styled.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NoticeLevel {
+    Info,
+    Error,
 }
 
-pub(crate) fn scan_markdown_files(dir: &Path) -> Result<Vec<PathBuf>> {
+/// List the markdown files directly inside `dir`, sorted by path.
+pub fn scan_markdown_files(dir: &Path) -> Result<Vec<PathBuf>> {
     let mut md_files = Vec::new();
 
     for entry in fs::read_dir(dir)? {
@@ -66,1037 +166,5065 @@ pub(crate) fn scan_markdown_files(dir: &Path) -> Result<Vec<PathBuf>> {
     Ok(md_files)
 }
 
-fn is_markdown_file(path: &Path) -> bool {
-    path.extension()
-        .and_then(|ext| ext.to_str())
-        .map(|ext| ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("markdown"))
-        .unwrap_or(false)
-}
+/// Entries between progress log lines while scanning recursively, so
+/// walking a very large tree doesn't look like a hang.
+const SCAN_PROGRESS_INTERVAL: usize = 10_000;
 
-struct TrackedFile {
-    path: PathBuf,
-    last_modified: SystemTime,
-    html: String,
+/// List the markdown files in `dir` and all of its subdirectories, sorted
+/// by path. Hidden files and folders (such as `.git`), `node_modules`,
+/// `target` and whatever the tree's `.gitignore` files exclude are skipped
+/// without being entered, and symlinked folders are not followed. Folders
+/// below `dir` that can't be read are skipped with a warning.
+///
+/// The tree is walked a level at a time, with each level's folders read on
+/// all available cores, which is what matters in monorepos with tens of
+/// thousands of entries.
+pub fn scan_markdown_files_recursive(dir: &Path) -> Result<Vec<PathBuf>> {
+    let workers = std::thread::available_parallelism().map_or(1, usize::from);
+    let root = list_dir(dir, &IgnoreRules::default())?;
+    let mut md_files = root.files;
+    let mut level = root.dirs;
+    let mut entries = root.entries;
+    let mut next_report = SCAN_PROGRESS_INTERVAL;
+
+    while !level.is_empty() {
+        let chunk_size = level.len().div_ceil(workers);
+        let listings: Vec<(&Path, std::io::Result<DirListing>)> = std::thread::scope(|scope| {
+            let readers: Vec<_> = level
+                .chunks(chunk_size)
+                .map(|dirs| {
+                    scope.spawn(move || {
+                        dirs.iter()
+                            .map(|(dir, rules)| (dir.as_path(), list_dir(dir, rules)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            readers
+                .into_iter()
+                .flat_map(|reader| {
+                    reader
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect()
+        });
+
+        let mut next_level = Vec::new();
+        for (dir, listing) in listings {
+            let listing = match listing {
+                Ok(listing) => listing,
+                Err(e) => {
+                    tracing::warn!("skipping {}: {e}", dir.display());
+                    continue;
+                }
+            };
+            entries += listing.entries;
+            md_files.extend(listing.files);
+            next_level.extend(listing.dirs);
+        }
+        level = next_level;
+        if entries >= next_report {
+            tracing::info!(entries, files = md_files.len(), "scanning");
+            next_report = entries + SCAN_PROGRESS_INTERVAL;
+        }
+    }
+
+    md_files.sort();
+
+    Ok(md_files)
 }
 
-struct MarkdownState {
-    base_dir: PathBuf,
-    tracked_files: HashMap<String, TrackedFile>,
-    is_directory_mode: bool,
-    change_tx: broadcast::Sender<ServerMessage>,
+/// What one folder holds, for [`scan_markdown_files_recursive`]: its
+/// markdown files and the folders to enter next, with the ignore rules in
+/// effect in them.
+struct DirListing {
+    files: Vec<PathBuf>,
+    dirs: Vec<(PathBuf, IgnoreRules)>,
+    entries: usize,
 }
 
-impl MarkdownState {
-    fn new(base_dir: PathBuf, file_paths: Vec<PathBuf>, is_directory_mode: bool) -> Result<Self> {
-        let (change_tx, _) = broadcast::channel::<ServerMessage>(16);
-
-        let mut tracked_files = HashMap::new();
-        for file_path in file_paths {
-            let metadata = fs::metadata(&file_path)?;
-            let last_modified = metadata.modified()?;
-            let content = fs::read_to_string(&file_path)?;
-            let html = Self::markdown_to_html(&content)?;
-
-            let filename = file_path.file_name().unwrap().to_string_lossy().to_string();
-
-            tracked_files.insert(
-                filename,
-                TrackedFile {
-                    path: file_path,
-                    last_modified,
-                    html,
-                },
-            );
+/// List `dir`, leaving out what `rules` or its own `.gitignore` exclude.
+fn list_dir(dir: &Path, rules: &IgnoreRules) -> std::io::Result<DirListing> {
+    let rules = rules.enter(dir);
+    let mut listing = DirListing {
+        files: Vec::new(),
+        dirs: Vec::new(),
+        entries: 0,
+    };
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        listing.entries += 1;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            if !EXCLUDED_DIRS.contains(&name.as_ref()) && !rules.is_ignored(&path, true) {
+                listing.dirs.push((path, rules.clone()));
+            }
+        } else if path.is_file() && is_markdown_file(&path) && !rules.is_ignored(&path, false) {
+            listing.files.push(path);
         }
-
-        Ok(MarkdownState {
-            base_dir,
-            tracked_files,
-            is_directory_mode,
-            change_tx,
-        })
     }
+    Ok(listing)
+}
 
-    fn show_navigation(&self) -> bool {
-        self.is_directory_mode
-    }
+fn is_markdown_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("markdown"))
+        .unwrap_or(false)
+}
 
-    fn get_sorted_filenames(&self) -> Vec<String> {
-        let mut filenames: Vec<_> = self.tracked_files.keys().cloned().collect();
-        filenames.sort();
-        filenames
-    }
+/// Policy for serving non-markdown files from the base directory.
+///
+/// Images are always servable, and so is any other file a document links to
+/// or embeds. Everything else must be opted into, either by extension or (in
+/// directory mode) wholesale. Denied extensions are never served.
+#[derive(Clone, Debug, Default)]
+pub struct StaticFileOptions {
+    /// Allow symlinks inside the base directory to resolve outside of it.
+    pub follow_symlinks: bool,
+    /// Extra extensions (without the dot) to serve, matched case-insensitively.
+    pub extensions: Vec<String>,
+    /// Serve every non-hidden file under the base directory.
+    pub serve_all: bool,
+    /// Extensions (without the dot) never served, matched
+    /// case-insensitively. Takes precedence over everything else.
+    pub deny_extensions: Vec<String>,
+}
 
-    fn refresh_file(&mut self, filename: &str) -> Result<()> {
-        if let Some(tracked) = self.tracked_files.get_mut(filename) {
-            let content = fs::read_to_string(&tracked.path)?;
-            tracked.html = Self::markdown_to_html(&content)?;
-            tracked.last_modified = fs::metadata(&tracked.path)?.modified()?;
+impl StaticFileOptions {
+    fn allows(&self, file_path: &str) -> bool {
+        if self.denies(file_path) {
+            return false;
         }
-        Ok(())
-    }
 
-    fn add_tracked_file(&mut self, file_path: PathBuf) -> Result<()> {
-        let filename = file_path.file_name().unwrap().to_string_lossy().to_string();
+        if is_image_file(file_path) {
+            return true;
+        }
 
-        if self.tracked_files.contains_key(&filename) {
-            return Ok(());
+        if self.serve_all {
+            return !is_hidden_path(Path::new(file_path));
         }
 
-        let metadata = fs::metadata(&file_path)?;
-        let content = fs::read_to_string(&file_path)?;
+        Path::new(file_path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| {
+                self.extensions
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(ext))
+            })
+    }
 
-        self.tracked_files.insert(
-            filename,
-            TrackedFile {
-                path: file_path,
-                last_modified: metadata.modified()?,
-                html: Self::markdown_to_html(&content)?,
-            },
-        );
+    fn denies(&self, file_path: &str) -> bool {
+        Path::new(file_path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| {
+                self.deny_extensions
+                    .iter()
+                    .any(|denied| denied.eq_ignore_ascii_case(ext))
+            })
+    }
 
-        Ok(())
+    /// Whether a file a document refers to may be served even though
+    /// [`allows`](Self::allows) doesn't cover it. Files that could run
+    /// scripts on mdserve's origin never are.
+    fn allows_referenced(&self, file_path: &str) -> bool {
+        let extension = Path::new(file_path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        !self.denies(file_path)
+            && !ACTIVE_CONTENT_EXTENSIONS.contains(&extension.as_str())
+            && !is_hidden_path(Path::new(file_path))
     }
+}
 
-    fn markdown_to_html(content: &str) -> Result<String> {
-        let mut options = markdown::Options::gfm();
-        options.compile.allow_dangerous_html = true;
-        options.parse.constructs.frontmatter = true;
+/// A directory outside the base directory whose static files are served
+/// under `/<mount>/`.
+#[derive(Clone, Debug)]
+struct AssetMount {
+    mount: String,
+    dir: PathBuf,
+}
 
-        let html_body = markdown::to_html_with_options(content, &options)
-            .unwrap_or_else(|_| "Error parsing markdown".to_string());
+/// Markdown files or a directory of them served next to the base directory,
+/// tracked as `<mount>/<name>` and watched on their own.
+#[derive(Clone, Debug)]
+struct DocumentMount {
+    mount: String,
+    dir: PathBuf,
+    /// New markdown files in `dir` are tracked, as in directory mode.
+    directory: bool,
+}
 
-        Ok(html_body)
+impl DocumentMount {
+    /// Whether `path` is in this mount's directory (or below it, in
+    /// recursive mode).
+    fn contains(&self, path: &Path, recursive: bool) -> bool {
+        if recursive {
+            path.starts_with(&self.dir)
+        } else {
+            path.parent() == Some(self.dir.as_path())
+        }
     }
 }
 
-/// Handles a markdown file that may have been created or modified.
-/// Refreshes tracked files or adds new files in directory mode, sending reload notifications.
-async fn handle_markdown_file_change(path: &Path, state: &SharedMarkdownState) {
-    if !is_markdown_file(path) {
-        return;
+/// Where directories allowed by
+/// [`MdserveBuilder::allow_absolute_images`] are mounted, followed by their
+/// index.
+const ABSOLUTE_IMAGES_MOUNT: &str = "_abs-images";
+
+/// Point `<img src>` attributes holding an absolute path under one of
+/// `prefixes` (a directory and the URL it is served at) to that URL.
+fn rewrite_absolute_images(html: &str, prefixes: &[(PathBuf, String)]) -> String {
+    const SRC: &str = " src=\"";
+
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("<img") {
+        let Some(len) = rest[start..].find('>') else {
+            break;
+        };
+        let tag = &rest[start..start + len];
+        out.push_str(&rest[..start]);
+        let value = tag.find(SRC).and_then(|at| {
+            let from = at + SRC.len();
+            Some(from..from + tag[from..].find('"')?)
+        });
+        match value.and_then(|range| {
+            absolute_image_url(&tag[range.clone()], prefixes).map(|url| (range, url))
+        }) {
+            Some((range, url)) => {
+                out.push_str(&tag[..range.start]);
+                out.push_str(&url);
+                out.push_str(&tag[range.end..]);
+            }
+            None => out.push_str(tag),
+        }
+        rest = &rest[start + len..];
     }
+    out.push_str(rest);
+    out
+}
 
-    let filename = path.file_name().and_then(|n| n.to_str()).map(String::from);
-    let Some(filename) = filename else {
-        return;
+/// The URL serving `src`, if it is an absolute path (or `file://` URL)
+/// inside one of `prefixes`.
+fn absolute_image_url(src: &str, prefixes: &[(PathBuf, String)]) -> Option<String> {
+    let src = src.replace("&amp;", "&");
+    let src = src.strip_prefix("file://").unwrap_or(&src);
+    let decoded = percent_decode(src)?;
+    // `/C:/Users/...`, as Windows paths appear in file URLs
+    let decoded = match decoded.as_bytes() {
+        [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => &decoded[1..],
+        _ => &decoded,
     };
+    let path = Path::new(decoded);
+    if !path.is_absolute() {
+        return None;
+    }
 
-    let mut state_guard = state.lock().await;
-
-    // If file is already tracked, refresh its content
-    if state_guard.tracked_files.contains_key(&filename) {
-        if state_guard.refresh_file(&filename).is_ok() {
-            let _ = state_guard.change_tx.send(ServerMessage::Reload);
-        }
-    } else if state_guard.is_directory_mode {
-        // New file in directory mode - add and reload
-        if state_guard.add_tracked_file(path.to_path_buf()).is_ok() {
-            let _ = state_guard.change_tx.send(ServerMessage::Reload);
+    prefixes.iter().find_map(|(dir, url)| {
+        let relative = path.strip_prefix(dir).ok()?;
+        if relative.as_os_str().is_empty() || !is_contained_relative_path(relative) {
+            return None;
         }
-    }
+        let segments: Vec<_> = relative
+            .components()
+            .map(|c| percent_encode(&c.as_os_str().to_string_lossy()))
+            .collect();
+        Some(format!("{url}/{}", segments.join("/")))
+    })
 }
 
-async fn handle_file_event(event: Event, state: &SharedMarkdownState) {
-    match event.kind {
-        notify::EventKind::Modify(notify::event::ModifyKind::Name(rename_mode)) => {
-            use notify::event::RenameMode;
-            match rename_mode {
-                RenameMode::Both => {
-                    // Linux/Windows: Both old and new paths provided in single event
-                    if event.paths.len() == 2 {
-                        let new_path = &event.paths[1];
-                        handle_markdown_file_change(new_path, state).await;
-                    }
-                }
-                RenameMode::From => {
-                    // File being renamed away - ignore
-                }
-                RenameMode::To => {
-                    // File renamed to this location
-                    if let Some(path) = event.paths.first() {
-                        handle_markdown_file_change(path, state).await;
-                    }
-                }
-                RenameMode::Any => {
-                    // macOS: Sends separate events for old and new paths
-                    // Use file existence to distinguish old (doesn't exist) from new (exists)
-                    if let Some(path) = event.paths.first() {
-                        if path.exists() {
-                            handle_markdown_file_change(path, state).await;
-                        }
-                    }
-                }
-                _ => {}
+/// Decode `%XX` escapes. `None` if the result isn't UTF-8.
+pub(crate) fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
             }
-        }
-        _ => {
-            for path in &event.paths {
-                if is_markdown_file(path) {
-                    match event.kind {
-                        notify::EventKind::Create(_)
-                        | notify::EventKind::Modify(notify::event::ModifyKind::Data(_)) => {
-                            handle_markdown_file_change(path, state).await;
-                        }
-                        notify::EventKind::Remove(_) => {
-                            // Don't remove files from tracking. Editors like neovim save by
-                            // renaming the file to a backup, then creating a new one. If we
-                            // removed the file here, HTTP requests during that window would
-                            // see empty tracked_files and return 404.
-                        }
-                        _ => {}
-                    }
-                } else if path.is_file() && is_image_file(path.to_str().unwrap_or("")) {
-                    match event.kind {
-                        notify::EventKind::Modify(_)
-                        | notify::EventKind::Create(_)
-                        | notify::EventKind::Remove(_) => {
-                            let state_guard = state.lock().await;
-                            let _ = state_guard.change_tx.send(ServerMessage::Reload);
-                        }
-                        _ => {}
-                    }
-                }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
             }
         }
     }
+    String::from_utf8(out).ok()
 }
 
-fn new_router(
-    base_dir: PathBuf,
-    tracked_files: Vec<PathBuf>,
-    is_directory_mode: bool,
-) -> Result<Router> {
-    let base_dir = base_dir.canonicalize()?;
-
-    let state = Arc::new(Mutex::new(MarkdownState::new(
-        base_dir.clone(),
-        tracked_files,
-        is_directory_mode,
-    )?));
-
-    let watcher_state = state.clone();
-    let (tx, mut rx) = mpsc::channel(100);
-
-    let mut watcher = RecommendedWatcher::new(
-        move |res: std::result::Result<Event, notify::Error>| {
-            if let Ok(event) = res {
-                let _ = tx.blocking_send(event);
-            }
-        },
-        Config::default(),
-    )?;
-
-    watcher.watch(&base_dir, RecursiveMode::NonRecursive)?;
+/// Where and how files dropped or pasted into the editor are saved.
+///
+/// Accepted types are the ones [`StaticFileOptions`] serves, so every
+/// upload can be linked from the document, except markdown and formats
+/// that could run scripts (SVG, HTML, XML, JavaScript).
+#[derive(Clone, Debug)]
+pub struct UploadOptions {
+    /// Folder uploads are saved in, relative to the document. Created on
+    /// first upload. Empty saves next to the document.
+    pub dir: String,
+    /// Largest accepted upload in bytes.
+    pub max_size: usize,
+}
 
-    tokio::spawn(async move {
-        let _watcher = watcher;
-        while let Some(event) = rx.recv().await {
-            handle_file_event(event, &watcher_state).await;
+impl Default for UploadOptions {
+    fn default() -> Self {
+        Self {
+            dir: "assets".to_string(),
+            max_size: 16 * 1024 * 1024,
         }
-    });
-
-    let router = Router::new()
-        .route("/", get(serve_html_root))
-        .route("/ws", get(websocket_handler))
-        .route("/mermaid.min.js", get(serve_mermaid_js))
-        .route("/*filename", get(serve_file))
-        .layer(CorsLayer::permissive())
-        .with_state(state);
-
-    Ok(router)
+    }
 }
 
-async fn bind_with_retry(hostname: &str, port: u16) -> Result<(TcpListener, u16)> {
-    let mut last_err = None;
-    for offset in 0..MAX_PORT_ATTEMPTS {
-        let try_port = match port.checked_add(offset) {
-            Some(p) => p,
-            None => break,
-        };
-        match TcpListener::bind((hostname, try_port)).await {
-            Ok(listener) => return Ok((listener, try_port)),
-            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => last_err = Some(e),
-            Err(e) => return Err(e.into()),
-        }
+impl UploadOptions {
+    /// Whether a file with this extension may be uploaded.
+    fn accepts(&self, extension: &str, static_options: &StaticFileOptions) -> bool {
+        let extension = extension.to_ascii_lowercase();
+        !ACTIVE_CONTENT_EXTENSIONS.contains(&extension.as_str())
+            && !is_markdown_file(Path::new(&format!("upload.{extension}")))
+            && static_options.allows(&format!("upload.{extension}"))
     }
-    Err(last_err
-        .map(|e| anyhow::anyhow!(e))
-        .unwrap_or_else(|| anyhow::anyhow!("no valid port in range"))
-        .context(format!(
-            "could not bind to ports {}--{}",
-            port,
-            port.saturating_add(MAX_PORT_ATTEMPTS - 1)
-        )))
 }
 
-pub(crate) async fn serve_markdown(
-    base_dir: PathBuf,
-    tracked_files: Vec<PathBuf>,
-    is_directory_mode: bool,
-    hostname: impl AsRef<str>,
-    port: u16,
-    open: bool,
-) -> Result<()> {
-    let hostname = hostname.as_ref();
+/// Embedder callback applied to each rendered document, keyed by file name.
+type HtmlMapper = Arc<dyn Fn(&str, String) -> String + Send + Sync>;
 
-    let first_file = tracked_files.first().cloned();
-    let router = new_router(base_dir.clone(), tracked_files, is_directory_mode)?;
+/// Embedder callback run for each file event, next to the built-in handling.
+type FileEventHook = Arc<dyn Fn(FileEvent) -> BoxFuture<'static, ()> + Send + Sync>;
 
-    let (listener, actual_port) = bind_with_retry(hostname, port).await?;
+struct TrackedFile {
+    path: PathBuf,
+    /// Last render, made on first request. It outlives changes to the file
+    /// so it can still be served while an editor briefly removes the file
+    /// during a save.
+    document: Option<CachedDocument>,
+    /// Bumped whenever the file changes on disk.
+    generation: u64,
+    /// Generation `document` was rendered from.
+    rendered_generation: u64,
+    /// Hash of `document`'s HTML, for spotting saves that render the same.
+    html_hash: u64,
+    /// Cache clock value of the last request, for LRU eviction. Atomic so
+    /// cache hits can record it under a read lock.
+    last_used: AtomicU64,
+    /// Shown in the sidebar. Files only reached by following a link from
+    /// another document are not.
+    listed: bool,
+    /// Sidebar label from the document's title. Read outside the state
+    /// lock whenever the file is tracked or changes, and only when the
+    /// sidebar uses titles.
+    title: Option<String>,
+}
 
-    if actual_port != port {
-        println!("⚠ Port {port} in use, using {actual_port} instead");
+impl TrackedFile {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            document: None,
+            generation: 0,
+            rendered_generation: 0,
+            html_hash: 0,
+            last_used: AtomicU64::new(0),
+            listed: true,
+            title: None,
+        }
     }
 
-    let listen_addr = format_host(hostname, actual_port);
-
-    if is_directory_mode {
-        println!("📁 Serving markdown files from: {}", base_dir.display());
-    } else if let Some(file_path) = first_file {
-        println!("📄 Serving markdown file: {}", file_path.display());
+    fn linked(path: PathBuf) -> Self {
+        Self {
+            listed: false,
+            ..Self::new(path)
+        }
     }
 
-    println!("🌐 Server running at: http://{listen_addr}");
-    println!("⚡ Live reload enabled");
-    println!("\nPress Ctrl+C to stop the server");
-
-    if open {
-        let browse_addr = format_host(&browsable_host(hostname), actual_port);
-        open_browser(&format!("http://{browse_addr}"))?;
+    fn fresh_document(&self) -> Option<&CachedDocument> {
+        self.document
+            .as_ref()
+            .filter(|_| self.rendered_generation == self.generation)
     }
 
-    axum::serve(listener, router).await?;
-
-    Ok(())
-}
+    fn with_title(self, title: Option<String>) -> Self {
+        Self { title, ..self }
+    }
 
-/// Format the host address (hostname + port) for printing.
-fn format_host(hostname: &str, port: u16) -> String {
-    if hostname.parse::<Ipv6Addr>().is_ok() {
-        format!("[{hostname}]:{port}")
-    } else {
-        format!("{hostname}:{port}")
+    fn invalidate(&mut self) {
+        self.generation += 1;
     }
-}
 
-/// Map wildcard bind addresses to loopback so the browser gets a
-/// reachable URL.
-fn browsable_host(hostname: &str) -> String {
-    if hostname
-        .parse::<Ipv4Addr>()
-        .ok()
-        .is_some_and(|ip| ip.is_unspecified())
-    {
-        "127.0.0.1".into()
-    } else if hostname
-        .parse::<Ipv6Addr>()
-        .ok()
-        .is_some_and(|ip| ip.is_unspecified())
-    {
-        "::1".into()
-    } else {
-        hostname.into()
+    /// Cache a render of `generation`. Returns `false` if it produced the
+    /// same HTML as the render it replaces.
+    fn store(&mut self, document: CachedDocument, generation: u64) -> bool {
+        let mut hasher = DefaultHasher::new();
+        document.html.hash(&mut hasher);
+        let html_hash = hasher.finish();
+
+        let changed = self.document.is_none() || html_hash != self.html_hash;
+        self.document = Some(document);
+        self.rendered_generation = generation;
+        self.html_hash = html_hash;
+        changed
     }
 }
 
-/// Open a URL in the default browser using platform commands.
-///
-/// Fails immediately if the command cannot be spawned (e.g. not
-/// installed). Exit status is monitored in a background thread
-/// since opener commands may block until their handler process
-/// returns.
-fn open_browser(url: &str) -> Result<()> {
-    let program = if cfg!(target_os = "macos") {
-        "open"
-    } else if cfg!(target_os = "linux") {
-        "xdg-open"
-    } else {
-        anyhow::bail!("--open is not supported on this platform");
-    };
-
-    let mut child = std::process::Command::new(program)
-        .arg(url)
-        .stdin(std::process::Stdio::null())
-        .stdout(std::process::Stdio::null())
-        .stderr(std::process::Stdio::null())
-        .spawn()
-        .with_context(|| format!("failed to run {program}"))?;
-
-    std::thread::spawn(move || match child.wait() {
-        Ok(status) if !status.success() => {
-            eprintln!("{program} exited with {status}");
-        }
-        Err(e) => eprintln!("Failed waiting on {program}: {e}"),
-        _ => {}
-    });
-
-    Ok(())
+/// Bounds on the rendered-document cache. Unset limits are unbounded.
+#[derive(Clone, Copy, Debug, Default)]
+struct CacheLimits {
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
 }
 
-async fn serve_html_root(State(state): State<SharedMarkdownState>) -> impl IntoResponse {
-    let state = state.lock().await;
+/// Router configuration that never changes after it is built. Handlers
+/// read it without taking the state lock, so serving assets and share
+/// links never waits on a render.
+struct RouterSettings {
+    base_dir: PathBuf,
+    is_directory_mode: bool,
+    /// Pages show the sidebar listing every tracked file.
+    navigation: bool,
+    /// Files in subdirectories are tracked too, under their relative path.
+    recursive: bool,
+    /// Markdown paths served under a prefix next to the base directory.
+    document_mounts: Vec<DocumentMount>,
+    static_options: StaticFileOptions,
+    /// Extra directories static files are served from, by mount.
+    asset_mounts: Vec<AssetMount>,
+    share_secret: Vec<u8>,
+    template: Arc<dyn PageTemplate>,
+    /// Stylesheet for highlighted code, empty when highlighting is off.
+    highlight_css: String,
+    /// Mermaid options for every page, before frontmatter overrides.
+    mermaid_config: Metadata,
+    /// Pages show an outline of the document's headings.
+    toc: bool,
+    /// The sidebar labels documents by title rather than file name.
+    sidebar_titles: bool,
+    /// `[[Page Name]]` in text links to the matching document.
+    wikilinks: bool,
+    /// Editors can scroll pages to a source line.
+    scroll_sync: bool,
+    renderer: Arc<Renderer>,
+    base_path: String,
+    /// Pages show an editor next to the preview.
+    edit: bool,
+    /// Markdown files may be written through `PUT /api/files/<name>`.
+    allow_write: bool,
+    upload_options: UploadOptions,
+    /// Document served at `/` while it is tracked.
+    index: Option<String>,
+    /// `/` redirects to the index document instead of rendering it.
+    redirect_index: bool,
+    /// Answer to `GET /api/instance`, identifying what this server serves.
+    instance: Arc<InstanceInfo>,
+}
 
-    let filename = match state.get_sorted_filenames().into_iter().next() {
-        Some(name) => name,
-        None => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Html("No files available to serve".to_string()),
-            );
+impl RouterSettings {
+    /// Sidebar titles of `files`, by name, read on the blocking pool. Empty
+    /// unless the sidebar uses titles.
+    async fn read_titles(&self, files: Vec<(String, PathBuf)>) -> HashMap<String, String> {
+        if !self.sidebar_titles {
+            return HashMap::new();
         }
-    };
-
-    render_markdown(&state, &filename).await
-}
+        tokio::task::spawn_blocking(move || {
+            files
+                .into_iter()
+                .filter_map(|(name, path)| Some((name, read_title(&path)?)))
+                .collect()
+        })
+        .await
+        .unwrap_or_default()
+    }
 
-async fn serve_file(
-    AxumPath(filename): AxumPath<String>,
-    State(state): State<SharedMarkdownState>,
-) -> axum::response::Response {
-    if filename.ends_with(".md") || filename.ends_with(".markdown") {
-        let state = state.lock().await;
+    /// Whether `document`'s page loads KaTeX: with client-side math, when
+    /// it has math or may gain some in the editor.
+    fn katex_enabled(&self, document: &CachedDocument, edit: bool) -> bool {
+        self.renderer.options.math == MathMode::Client
+            && (document.features.contains(MATH_FEATURE) || edit)
+    }
 
-        if !state.tracked_files.contains_key(&filename) {
-            return (StatusCode::NOT_FOUND, Html("File not found".to_string())).into_response();
+    /// Headings to outline on `document`'s page.
+    fn toc<'a>(&self, document: &'a CachedDocument) -> &'a [Heading] {
+        if self.toc {
+            &document.headings
+        } else {
+            &[]
         }
+    }
 
-        let (status, html) = render_markdown(&state, &filename).await;
-        (status, html).into_response()
-    } else if is_image_file(&filename) {
-        serve_static_file_inner(filename, state).await
-    } else {
-        (StatusCode::NOT_FOUND, Html("File not found".to_string())).into_response()
+    /// Key `path` is tracked and served under.
+    fn tracked_name(&self, path: &Path) -> Option<String> {
+        document_name(&self.base_dir, self.recursive, &self.document_mounts, path)
     }
-}
 
-async fn render_markdown(state: &MarkdownState, current_file: &str) -> (StatusCode, Html<String>) {
-    let env = template_env();
-    let template = match env.get_template(TEMPLATE_NAME) {
-        Ok(t) => t,
-        Err(e) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Html(format!("Template error: {e}")),
-            );
+    /// Whether a new markdown file at `path` gets tracked: in directory
+    /// mode, or in a mounted directory.
+    fn tracks_new_files(&self, path: &Path) -> bool {
+        match self
+            .document_mounts
+            .iter()
+            .find(|document_mount| document_mount.contains(path, self.recursive))
+        {
+            Some(document_mount) => document_mount.directory,
+            None => self.is_directory_mode,
         }
-    };
+    }
 
-    let (content, has_mermaid) = if let Some(tracked) = state.tracked_files.get(current_file) {
-        let html = &tracked.html;
-        let mermaid = html.contains(r#"class="language-mermaid""#);
-        (Value::from_safe_string(html.clone()), mermaid)
-    } else {
-        return (StatusCode::NOT_FOUND, Html("File not found".to_string()));
-    };
+    /// Path from the site root that the static file at `path` is served
+    /// under: below a mounted asset or document directory containing it,
+    /// otherwise below the base directory.
+    fn site_path(&self, path: &Path) -> Option<String> {
+        let (dir, mount) = self
+            .asset_mounts
+            .iter()
+            .map(|asset_mount| (&asset_mount.dir, asset_mount.mount.as_str()))
+            .chain(
+                self.document_mounts
+                    .iter()
+                    .map(|document_mount| (&document_mount.dir, document_mount.mount.as_str())),
+            )
+            .find(|(dir, _)| path.starts_with(dir))
+            .unwrap_or((&self.base_dir, ""));
+        let relative = nested_name(dir, path)?;
+        Some(if mount.is_empty() {
+            relative
+        } else {
+            format!("{mount}/{relative}")
+        })
+    }
 
-    // Derive page title from filename (stem without extension)
-    let page_title = std::path::Path::new(current_file)
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or(current_file);
+    /// The directory a static request path is served from and the path
+    /// within it: a mounted asset directory when the path starts with its
+    /// mount, otherwise the document directory for it.
+    fn static_root<'a>(&'a self, filename: &'a str) -> (&'a Path, &'a str) {
+        self.asset_mounts
+            .iter()
+            .find_map(|asset_mount| {
+                let relative = filename
+                    .strip_prefix(asset_mount.mount.as_str())?
+                    .strip_prefix('/')?;
+                Some((asset_mount.dir.as_path(), relative))
+            })
+            .unwrap_or_else(|| self.document_root(filename))
+    }
 
-    let rendered = if state.show_navigation() {
-        let filenames = state.get_sorted_filenames();
-        let files: Vec<Value> = filenames
+    /// The directory documents named `filename` are read from and their
+    /// path within it: a mounted path's directory when the name starts
+    /// with its mount, otherwise the base directory.
+    fn document_root<'a>(&'a self, filename: &'a str) -> (&'a Path, &'a str) {
+        self.document_mounts
             .iter()
-            .map(|name| {
-                Value::from_object({
-                    let mut map = std::collections::HashMap::new();
-                    map.insert("name".to_string(), Value::from(name.clone()));
-                    map
-                })
+            .find_map(|document_mount| {
+                let relative = filename
+                    .strip_prefix(document_mount.mount.as_str())?
+                    .strip_prefix('/')?;
+                Some((document_mount.dir.as_path(), relative))
             })
-            .collect();
+            .unwrap_or((&self.base_dir, filename))
+    }
+}
 
-        match template.render(context! {
-            content => content,
-            mermaid_enabled => has_mermaid,
-            show_navigation => true,
-            files => files,
-            current_file => current_file,
-            page_title => page_title,
-        }) {
-            Ok(r) => r,
-            Err(e) => {
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Html(format!("Rendering error: {e}")),
-                );
+/// State shared by the router's handlers.
+#[derive(Clone)]
+struct AppState {
+    settings: Arc<RouterSettings>,
+    markdown: SharedMarkdownState,
+    change_tx: broadcast::Sender<ServerMessage>,
+    /// Editor drafts by tracked file name.
+    drafts: Arc<Mutex<HashMap<String, Draft>>>,
+}
+
+/// Unsaved editor contents, autosaved so edits survive a refresh or a
+/// crashed tab. Held in memory until the file is saved or the draft is
+/// discarded.
+#[derive(Clone, Debug, Serialize)]
+struct Draft {
+    source: String,
+    /// Seconds since the Unix epoch.
+    saved_at: u64,
+}
+
+struct MarkdownState {
+    settings: Arc<RouterSettings>,
+    tracked_files: HashMap<String, TrackedFile>,
+    cache_limits: CacheLimits,
+    cache_clock: AtomicU64,
+    change_tx: broadcast::Sender<ServerMessage>,
+}
+
+/// Everything needed to turn a markdown file into a cached document. Kept
+/// behind an `Arc` so files can be read and rendered without holding the
+/// state lock.
+struct Renderer {
+    options: RenderOptions,
+    pipeline: RenderPipeline,
+    html_mappers: Vec<HtmlMapper>,
+    /// Files larger than this are cut short instead of read whole.
+    max_file_size: usize,
+}
+
+impl Renderer {
+    fn render(&self, filename: &str, content: &str) -> RenderedDocument {
+        let mut document = self.pipeline.render(content, &self.options);
+        for map_html in &self.html_mappers {
+            document.html = map_html(filename, document.html);
+        }
+        document
+    }
+
+    /// [`load`](Self::load) for a file that just changed. Editors that save
+    /// by renaming or truncating leave a moment where the file is missing
+    /// or empty, so those reads are retried with backoff before giving up.
+    async fn load_settled(&self, filename: &str, path: &Path) -> Result<CachedDocument> {
+        for delay in SETTLE_DELAYS {
+            let empty = match tokio::fs::metadata(path).await {
+                Ok(metadata) => metadata.len() == 0,
+                Err(_) => true,
+            };
+            if !empty {
+                break;
             }
+            tokio::time::sleep(delay).await;
         }
-    } else {
-        match template.render(context! {
-            content => content,
-            mermaid_enabled => has_mermaid,
-            show_navigation => false,
-            page_title => page_title,
-        }) {
-            Ok(r) => r,
-            Err(e) => {
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Html(format!("Rendering error: {e}")),
-                );
+
+        let mut result = self.load(filename, path).await;
+        for delay in SETTLE_DELAYS {
+            if result.is_ok() {
+                break;
             }
+            tokio::time::sleep(delay).await;
+            result = self.load(filename, path).await;
         }
-    };
+        result
+    }
+
+    async fn load(&self, filename: &str, path: &Path) -> Result<CachedDocument> {
+        let size = tokio::fs::metadata(path).await?.len();
+        let truncated = size > self.max_file_size as u64;
+        let bytes = if truncated {
+            // Only read what will be rendered, so a huge file can't exhaust memory
+            let mut bytes = Vec::with_capacity(self.max_file_size);
+            tokio::fs::File::open(path)
+                .await?
+                .take(self.max_file_size as u64)
+                .read_to_end(&mut bytes)
+                .await?;
+            bytes
+        } else {
+            tokio::fs::read(path).await?
+        };
+
+        let decoded = if truncated {
+            // Don't leave half a character at the end
+            encoding::decode(encoding::complete_prefix(&bytes))
+        } else {
+            encoding::decode(&bytes)
+        };
+        let content = if truncated {
+            truncate_at_line(&decoded.text)
+        } else {
+            &decoded.text
+        };
+
+        let mut document = self.render(filename, content);
+        if truncated {
+            document.html.insert_str(
+                0,
+                &format!(
+                    "<blockquote class=\"mdserve-truncated\"><p>This file is {}, so only the \
+                     first {} are shown.</p></blockquote>\n",
+                    format_megabytes(size),
+                    format_megabytes(self.max_file_size as u64)
+                ),
+            );
+        }
+        if let Some(encoding) = decoded.encoding {
+            document.html.insert_str(
+                0,
+                &format!(
+                    "<blockquote class=\"mdserve-encoding\"><p>This file is not UTF-8, so it \
+                     was read as {encoding}. Saving it from mdserve writes UTF-8.</p></blockquote>\n"
+                ),
+            );
+        }
+        Ok(CachedDocument::new(filename, document))
+    }
+}
 
-    (StatusCode::OK, Html(rendered))
+/// Waits between reads of a file caught mid-save, about 0.4 s in total.
+const SETTLE_DELAYS: [Duration; 5] = [
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(200),
+];
+
+/// A rendered document as cached. The HTML is shared with every page
+/// response instead of being copied per request.
+#[derive(Clone, Debug)]
+struct CachedDocument {
+    html: Arc<str>,
+    features: BTreeSet<String>,
+    headings: Arc<[Heading]>,
+    metadata: Arc<Metadata>,
+    diagnostics: Arc<[Diagnostic]>,
+    /// Static files the HTML refers to, as paths from the site root. Asset
+    /// requests check it instead of scanning every cached page.
+    assets: Arc<HashSet<String>>,
 }
 
-async fn serve_mermaid_js(headers: HeaderMap) -> impl IntoResponse {
-    if is_etag_match(&headers) {
-        return mermaid_response(StatusCode::NOT_MODIFIED, None);
+impl CachedDocument {
+    /// Cache `document`, the render of `name`.
+    fn new(name: &str, document: RenderedDocument) -> Self {
+        let assets = export::local_references(&document.html, split_folder(name).0);
+        Self {
+            html: document.html.into(),
+            features: document.features,
+            headings: document.headings.into(),
+            metadata: Arc::new(document.metadata),
+            diagnostics: document.diagnostics.into(),
+            assets: Arc::new(assets.into_iter().collect()),
+        }
     }
+}
+
+/// The title of the markdown file at `path` (see [`document_title`]), if it
+/// has one. Blocking; only the start of the file is read.
+fn read_title(path: &Path) -> Option<String> {
+    let mut head = Vec::new();
+    fs::File::open(path)
+        .and_then(|file| file.take(TITLE_SCAN_BYTES).read_to_end(&mut head))
+        .ok()?;
+    document_title(truncate_at_line(&String::from_utf8_lossy(&head)))
+}
 
-    mermaid_response(StatusCode::OK, Some(MERMAID_JS))
+/// The longest prefix of `text` ending at a line break, so truncation
+/// doesn't split a line.
+fn truncate_at_line(text: &str) -> &str {
+    text.rfind('\n').map_or(text, |end| &text[..=end])
 }
 
-fn is_etag_match(headers: &HeaderMap) -> bool {
-    headers
-        .get(header::IF_NONE_MATCH)
-        .and_then(|v| v.to_str().ok())
-        .is_some_and(|etags| etags.split(',').any(|tag| tag.trim() == MERMAID_ETAG))
+fn format_megabytes(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
 }
 
-fn mermaid_response(status: StatusCode, body: Option<&'static str>) -> impl IntoResponse {
-    // Use no-cache to force revalidation on each request. This ensures clients
-    // get updated content when mdserve is rebuilt with a new Mermaid version,
-    // while still benefiting from 304 responses via ETag matching.
-    let headers = [
-        (header::CONTENT_TYPE, "application/javascript"),
-        (header::ETAG, MERMAID_ETAG),
-        (header::CACHE_CONTROL, "public, no-cache"),
-    ];
+/// Key a file is tracked and served under.
+fn tracked_name(path: &Path) -> Option<String> {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+}
+
+/// Folder part of a tracked name and the name within it: `guides/setup.md`
+/// is `("guides", "setup.md")`, `README.md` is `("", "README.md")`.
+pub(crate) fn split_folder(name: &str) -> (&str, &str) {
+    name.rsplit_once('/').unwrap_or(("", name))
+}
 
-    match body {
-        Some(content) => (status, headers, content).into_response(),
-        None => (status, headers).into_response(),
+/// Key `path` is tracked under: its file name, or in recursive mode its
+/// path below `base_dir`.
+fn tracked_name_in(base_dir: &Path, recursive: bool, path: &Path) -> Option<String> {
+    if recursive {
+        nested_name(base_dir, path)
+    } else {
+        tracked_name(path)
     }
 }
 
-async fn serve_static_file_inner(
-    filename: String,
-    state: SharedMarkdownState,
-) -> axum::response::Response {
-    let state = state.lock().await;
-
-    let full_path = state.base_dir.join(&filename);
-
-    match full_path.canonicalize() {
-        Ok(canonical_path) => {
-            if !canonical_path.starts_with(&state.base_dir) {
-                return (
-                    StatusCode::FORBIDDEN,
-                    [(header::CONTENT_TYPE, "text/plain")],
-                    "Access denied".to_string(),
-                )
-                    .into_response();
-            }
+/// Key `path` is tracked under: `<mount>/` and its name in a mounted
+/// directory containing it, otherwise its name in `base_dir`.
+fn document_name(
+    base_dir: &Path,
+    recursive: bool,
+    document_mounts: &[DocumentMount],
+    path: &Path,
+) -> Option<String> {
+    match document_mounts
+        .iter()
+        .find(|document_mount| document_mount.contains(path, recursive))
+    {
+        Some(document_mount) => tracked_name_in(&document_mount.dir, recursive, path)
+            .map(|name| format!("{}/{name}", document_mount.mount)),
+        None => tracked_name_in(base_dir, recursive, path),
+    }
+}
 
-            match fs::read(&canonical_path) {
-                Ok(contents) => {
-                    let content_type = guess_image_content_type(&filename);
-                    (
-                        StatusCode::OK,
-                        [(header::CONTENT_TYPE, content_type.as_str())],
-                        contents,
-                    )
-                        .into_response()
+/// Mount named after `dir`, with a `-2`, `-3`, … suffix while the name is
+/// in `taken` or would hide another folder of the base directory. Added to
+/// `taken`.
+fn unique_mount(dir: &Path, base_dir: &Path, taken: &mut HashSet<String>) -> String {
+    let name = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| normalize_mount(name.trim_start_matches('.')).ok())
+        .unwrap_or_else(|| "docs".to_string());
+    let mount = (1..)
+        .map(|i| match i {
+            1 => name.clone(),
+            i => format!("{name}-{i}"),
+        })
+        .find(|mount| {
+            let folder = base_dir.join(mount);
+            !taken.contains(mount) && (!folder.exists() || folder == dir)
+        })
+        .expect("some suffix is free");
+    taken.insert(mount.clone());
+    mount
+}
+
+/// Sort tracked names in sidebar order: files directly in the base
+/// directory first, then one group per folder.
+fn sort_for_sidebar(names: &mut [String]) {
+    names.sort_by(|a, b| (split_folder(a), a).cmp(&(split_folder(b), b)));
+}
+
+/// Names preferred for the document at `/`, in order.
+const DEFAULT_INDEX_NAMES: [&str; 3] = ["README.md", "readme.md", "index.md"];
+
+/// Document served at `/` when none is configured: a top-level README or
+/// index.md if there is one, otherwise the first file in sidebar order.
+fn default_index(sorted_names: &[String]) -> Option<&String> {
+    DEFAULT_INDEX_NAMES
+        .iter()
+        .find_map(|preferred| sorted_names.iter().find(|name| name == preferred))
+        .or_else(|| sorted_names.first())
+}
+
+/// Key a file is tracked under in recursive mode: its `/`-separated path
+/// below `base_dir`, e.g. `guides/setup.md`.
+fn nested_name(base_dir: &Path, path: &Path) -> Option<String> {
+    let segments = path
+        .strip_prefix(base_dir)
+        .ok()?
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (!segments.is_empty()).then(|| segments.join("/"))
+}
+
+impl MarkdownState {
+    fn new(config: RouterConfig) -> Result<Self> {
+        let (change_tx, _) = broadcast::channel::<ServerMessage>(config.message_buffer.max(1));
+
+        let settings = RouterSettings {
+            base_dir: config.base_dir,
+            is_directory_mode: config.is_directory_mode,
+            navigation: config.navigation,
+            recursive: config.recursive,
+            document_mounts: config.document_mounts,
+            static_options: config.static_options,
+            asset_mounts: config.asset_mounts,
+            share_secret: config.share_secret,
+            template: config.template,
+            highlight_css: config.highlight_css,
+            mermaid_config: config.mermaid_config,
+            toc: config.toc,
+            sidebar_titles: config.sidebar_titles,
+            wikilinks: config.wikilinks,
+            scroll_sync: config.scroll_sync,
+            renderer: Arc::new(Renderer {
+                options: config.render_options,
+                pipeline: config.pipeline,
+                html_mappers: config.html_mappers,
+                max_file_size: config.max_file_size,
+            }),
+            base_path: config.base_path,
+            edit: config.edit,
+            allow_write: config.allow_write,
+            upload_options: config.upload_options,
+            index: config.index,
+            redirect_index: config.redirect_index,
+            instance: Arc::new(InstanceInfo::new(&config.instance_roots)),
+        };
+        let mut state = MarkdownState {
+            settings: Arc::new(settings),
+            tracked_files: HashMap::new(),
+            cache_limits: config.cache_limits,
+            cache_clock: AtomicU64::new(0),
+            change_tx,
+        };
+
+        for file_path in config.tracked_files {
+            let Some(filename) = state.settings.tracked_name(&file_path) else {
+                continue;
+            };
+            if let Entry::Vacant(entry) = state.tracked_files.entry(filename) {
+                // Only check the file is there; rendering waits for the first request
+                if !fs::metadata(&file_path)?.is_file() {
+                    anyhow::bail!("{} is not a file", file_path.display());
                 }
-                Err(_) => (
-                    StatusCode::NOT_FOUND,
-                    [(header::CONTENT_TYPE, "text/plain")],
-                    "File not found".to_string(),
-                )
-                    .into_response(),
+                let title = state
+                    .settings
+                    .sidebar_titles
+                    .then(|| read_title(&file_path))
+                    .flatten();
+                entry.insert(TrackedFile::new(file_path).with_title(title));
             }
         }
-        Err(_) => (
-            StatusCode::NOT_FOUND,
-            [(header::CONTENT_TYPE, "text/plain")],
-            "File not found".to_string(),
-        )
-            .into_response(),
+
+        Ok(state)
     }
-}
 
-fn is_image_file(file_path: &str) -> bool {
-    guess_image_content_type(file_path).starts_with("image/")
-}
+    /// Record a request for `filename` in the LRU order.
+    fn touch(&self, filename: &str) {
+        let now = self.cache_clock.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(tracked) = self.tracked_files.get(filename) {
+            tracked.last_used.fetch_max(now, Ordering::Relaxed);
+        }
+    }
 
-fn guess_image_content_type(file_path: &str) -> String {
-    let extension = std::path::Path::new(file_path)
-        .extension()
-        .and_then(|ext| ext.to_str())
-        .unwrap_or("");
+    /// Drop least recently used renders until the cache fits its limits.
+    /// `keep` is never evicted, so the document being served stays cached.
+    fn evict_to_limits(&mut self, keep: &str) {
+        let CacheLimits {
+            max_entries,
+            max_bytes,
+        } = self.cache_limits;
+        if max_entries.is_none() && max_bytes.is_none() {
+            return;
+        }
+
+        let mut cached: Vec<(u64, usize, String)> = self
+            .tracked_files
+            .iter()
+            .filter_map(|(name, tracked)| {
+                let document = tracked.document.as_ref()?;
+                Some((
+                    tracked.last_used.load(Ordering::Relaxed),
+                    document.html.len(),
+                    name.clone(),
+                ))
+            })
+            .collect();
+        cached.sort_unstable();
+
+        let mut entries = cached.len();
+        let mut bytes: usize = cached.iter().map(|(_, size, _)| size).sum();
+        for (_, size, name) in cached {
+            let over_entries = max_entries.is_some_and(|max| entries > max);
+            let over_bytes = max_bytes.is_some_and(|max| bytes > max);
+            if !over_entries && !over_bytes {
+                break;
+            }
+            if name == keep {
+                continue;
+            }
+            if let Some(tracked) = self.tracked_files.get_mut(&name) {
+                tracked.document = None;
+            }
+            entries -= 1;
+            bytes -= size;
+        }
+    }
+
+    /// Whether the cached render of `document`, or of any document when
+    /// `None`, refers to the static file `filename`.
+    fn references(&self, document: Option<&str>, filename: &str) -> bool {
+        let refers = |tracked: &TrackedFile| {
+            tracked
+                .document
+                .as_ref()
+                .is_some_and(|cached| cached.assets.contains(filename))
+        };
+        match document {
+            Some(document) => self.tracked_files.get(document).is_some_and(refers),
+            None => self.tracked_files.values().any(refers),
+        }
+    }
 
-    match extension.to_lowercase().as_str() {
-        "png" => "image/png",
-        "jpg" | "jpeg" => "image/jpeg",
-        "gif" => "image/gif",
-        "svg" => "image/svg+xml",
-        "webp" => "image/webp",
-        "bmp" => "image/bmp",
-        "ico" => "image/x-icon",
-        _ => "application/octet-stream",
+    /// Whether a cached document links to the markdown file `filename`.
+    fn links_to(&self, filename: &str) -> bool {
+        self.tracked_files.iter().any(|(name, tracked)| {
+            tracked.document.as_ref().is_some_and(|document| {
+                export::markdown_references(&document.html, split_folder(name).0)
+                    .iter()
+                    .any(|reference| reference == filename)
+            })
+        })
+    }
+
+    fn show_navigation(&self) -> bool {
+        self.settings.navigation
+    }
+
+    /// The HTML of `name`'s document as shown on its page: with wiki links
+    /// resolved against the listed files, when they are on.
+    fn page_html(&self, name: &str, html: &Arc<str>) -> Arc<str> {
+        if !self.settings.wikilinks {
+            return html.clone();
+        }
+        render_wikilinks(html, name, &self.get_sorted_filenames()).into()
+    }
+
+    /// Sidebar labels of the listed documents that have a title, by name.
+    /// Empty unless the sidebar uses titles.
+    fn sidebar_titles(&self) -> BTreeMap<String, String> {
+        if !self.settings.sidebar_titles {
+            return BTreeMap::new();
+        }
+        self.tracked_files
+            .iter()
+            .filter(|(_, tracked)| tracked.listed)
+            .filter_map(|(name, tracked)| Some((name.clone(), tracked.title.clone()?)))
+            .collect()
     }
-    .to_string()
-}
 
-async fn websocket_handler(
-    ws: WebSocketUpgrade,
-    State(state): State<SharedMarkdownState>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_websocket(socket, state))
+    /// Name and path of every tracked file listed in the sidebar.
+    fn listed_files(&self) -> Vec<(String, PathBuf)> {
+        self.tracked_files
+            .iter()
+            .filter(|(_, tracked)| tracked.listed)
+            .map(|(name, tracked)| (name.clone(), tracked.path.clone()))
+            .collect()
+    }
+
+    fn get_sorted_filenames(&self) -> Vec<String> {
+        let mut filenames: Vec<_> = self
+            .tracked_files
+            .iter()
+            .filter(|(_, tracked)| tracked.listed)
+            .map(|(name, _)| name.clone())
+            .collect();
+        sort_for_sidebar(&mut filenames);
+        filenames
+    }
 }
 
-async fn handle_websocket(socket: WebSocket, state: SharedMarkdownState) {
-    let (mut sender, mut receiver) = socket.split();
+/// Handles a markdown file that may have been created or modified.
+/// Refreshes tracked files or adds new files in directory mode, sending reload notifications.
+/// `renamed_from` is the path it was renamed from, if any, for the notice
+/// about a newly tracked file.
+async fn handle_markdown_file_change(
+    path: &Path,
+    state: &SharedMarkdownState,
+    renamed_from: Option<&Path>,
+) {
+    if !is_markdown_file(path) {
+        return;
+    }
 
-    let mut change_rx = {
-        let state = state.lock().await;
-        state.change_tx.subscribe()
+    let settings = state.read().await.settings.clone();
+    let Some(filename) = settings.tracked_name(path) else {
+        return;
+    };
+    let mut titles = settings
+        .read_titles(vec![(filename.clone(), path.to_path_buf())])
+        .await;
+    let title = titles.remove(&filename);
+
+    let mut state_guard = state.write().await;
+    let added = !state_guard.tracked_files.contains_key(&filename);
+    let prerender = if let Some(tracked) = state_guard.tracked_files.get_mut(&filename) {
+        tracked.invalidate();
+        tracked.title = title;
+        // Documents already in the cache are being viewed, so render them
+        // now; the others stay lazy.
+        tracked
+            .document
+            .is_some()
+            .then(|| (tracked.path.clone(), tracked.generation))
+    } else if state_guard.settings.tracks_new_files(path) {
+        // New file in directory mode - track it and reload
+        state_guard.tracked_files.insert(
+            filename.clone(),
+            TrackedFile::new(path.to_path_buf()).with_title(title),
+        );
+        let old_name = renamed_from.and_then(|old| state_guard.settings.tracked_name(old));
+        let message = match old_name {
+            Some(old) => format!("Renamed {old} to {filename}"),
+            None => format!("Added {filename}"),
+        };
+        tracing::info!("{message}");
+        let _ = state_guard.change_tx.send(ServerMessage::Notice {
+            level: NoticeLevel::Info,
+            message,
+        });
+        None
+    } else {
+        return;
     };
 
-    let recv_task = tokio::spawn(async move {
-        while let Some(msg) = receiver.next().await {
-            match msg {
-                Ok(Message::Text(_)) => {}
-                Ok(Message::Close(_)) => break,
-                _ => {}
+    if let Some((file_path, generation)) = prerender {
+        let renderer = state_guard.settings.renderer.clone();
+        drop(state_guard);
+
+        // Render without the lock so requests keep being served meanwhile
+        let loaded = renderer.load_settled(&filename, &file_path).await;
+
+        state_guard = state.write().await;
+        let loaded = loaded
+            .map_err(|e| {
+                tracing::warn!(file = %filename, "could not render: {e}");
+                let _ = state_guard.change_tx.send(ServerMessage::Notice {
+                    level: NoticeLevel::Error,
+                    message: format!("Could not render {filename}: {e}"),
+                });
+            })
+            .ok();
+        if let (Some(document), Some(tracked)) =
+            (loaded, state_guard.tracked_files.get_mut(&filename))
+        {
+            // A newer event owns the cache if the file changed again
+            if tracked.generation == generation && !tracked.store(document, generation) {
+                // Touched or rewritten with identical bytes; skip the
+                // reload flash
+                tracing::debug!(file = %filename, "unchanged, not reloading");
+                return;
             }
         }
-    });
+    }
+
+    // Broadcast only once fresh HTML is cached, so the reload is a cache hit.
+    // New files change every page's sidebar; edits only their own page.
+    if !added {
+        tracing::info!(file = %filename, "refreshed");
+    }
+    let file = (renamed_from.is_none() && !added).then_some(filename);
+    let _ = state_guard.change_tx.send(ServerMessage::Reload { file });
+}
+
+/// Net effect of a burst of watcher events. A single save often produces
+/// several events for the same file; each file is handled once per burst.
+#[derive(Default)]
+struct PendingChanges {
+    /// Markdown files to refresh, in the order they were first seen.
+    markdown: Vec<PathBuf>,
+    /// Markdown files renamed to another markdown name, new path to old.
+    renamed: HashMap<PathBuf, PathBuf>,
+    /// Other files that changed; pages reload if any of them is servable.
+    assets: Vec<PathBuf>,
+    /// Whether the source last reported watching as stopped or resumed.
+    watching: Option<bool>,
+    /// Changes may have been missed, so every file is re-read.
+    rescan: bool,
+}
 
-    let send_task = tokio::spawn(async move {
-        while let Ok(reload_msg) = change_rx.recv().await {
-            if let Ok(json) = serde_json::to_string(&reload_msg) {
-                if sender.send(Message::Text(json)).await.is_err() {
-                    break;
+impl PendingChanges {
+    fn add(&mut self, event: &Event) {
+        if is_degraded_event(event) {
+            self.watching = Some(false);
+            return;
+        }
+        if is_rescan_event(event) {
+            self.watching = Some(true);
+            self.rescan = true;
+            return;
+        }
+
+        match event.kind {
+            notify::EventKind::Modify(notify::event::ModifyKind::Name(rename_mode)) => {
+                use notify::event::RenameMode;
+                match rename_mode {
+                    RenameMode::Both if event.paths.len() == 2 => {
+                        // Linux/Windows: Both old and new paths provided in single event
+                        let (from, to) = (&event.paths[0], &event.paths[1]);
+                        if is_markdown_file(from) && is_markdown_file(to) {
+                            self.renamed.insert(to.clone(), from.clone());
+                        } else if !is_markdown_file(from) {
+                            // Pages showing the old asset need to reload
+                            self.asset_changed(from);
+                        }
+                        self.file_changed(to);
+                    }
+                    RenameMode::From => {
+                        // Markdown renamed away stays tracked; an asset is gone
+                        if let Some(path) = event.paths.first() {
+                            if !is_markdown_file(path) {
+                                self.asset_changed(path);
+                            }
+                        }
+                    }
+                    RenameMode::To => {
+                        // File renamed to this location
+                        if let Some(path) = event.paths.first() {
+                            self.file_changed(path);
+                        }
+                    }
+                    RenameMode::Any => {
+                        // macOS: Sends separate events for old and new paths
+                        // Use file existence to distinguish old (doesn't exist) from new (exists)
+                        if let Some(path) = event.paths.first() {
+                            if path.exists() {
+                                self.file_changed(path);
+                            } else if !is_markdown_file(path) {
+                                self.asset_changed(path);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {
+                for path in &event.paths {
+                    if is_markdown_file(path) {
+                        match event.kind {
+                            notify::EventKind::Create(_)
+                            | notify::EventKind::Modify(notify::event::ModifyKind::Data(_)) => {
+                                self.markdown_changed(path);
+                            }
+                            notify::EventKind::Remove(_) => {
+                                // Don't remove files from tracking. Editors like neovim save by
+                                // renaming the file to a backup, then creating a new one. If we
+                                // removed the file here, HTTP requests during that window would
+                                // see empty tracked_files and return 404.
+                            }
+                            _ => {}
+                        }
+                    } else {
+                        match event.kind {
+                            notify::EventKind::Modify(_) | notify::EventKind::Create(_)
+                                if path.is_file() =>
+                            {
+                                self.asset_changed(path);
+                            }
+                            // Gone, so there's nothing left to check
+                            notify::EventKind::Remove(_) => {
+                                self.asset_changed(path);
+                            }
+                            _ => {}
+                        }
+                    }
                 }
             }
         }
-    });
+    }
+
+    /// Record a markdown change. Non-markdown paths are handled (and
+    /// ignored) by `handle_markdown_file_change` itself.
+    fn markdown_changed(&mut self, path: &Path) {
+        if !self.markdown.iter().any(|seen| seen == path) {
+            self.markdown.push(path.to_path_buf());
+        }
+    }
+
+    /// Record a change to a file that exists at `path`, markdown or not.
+    fn file_changed(&mut self, path: &Path) {
+        if is_markdown_file(path) {
+            self.markdown_changed(path);
+        } else if path.is_file() {
+            self.asset_changed(path);
+        }
+    }
+
+    fn asset_changed(&mut self, path: &Path) {
+        if !self.assets.iter().any(|seen| seen == path) {
+            self.assets.push(path.to_path_buf());
+        }
+    }
+
+    async fn apply(self, state: &SharedMarkdownState) {
+        if let Some(watching) = self.watching {
+            if watching {
+                tracing::info!("file watching resumed, rescanning");
+            } else {
+                tracing::warn!("file watching stopped; pages won't reload until it resumes");
+            }
+            let state_guard = state.read().await;
+            let _ = state_guard
+                .change_tx
+                .send(ServerMessage::WatchStatus { watching });
+        }
+        if self.rescan {
+            rescan(state).await;
+            return;
+        }
+
+        for path in &self.markdown {
+            let renamed_from = self.renamed.get(path).map(PathBuf::as_path);
+            handle_markdown_file_change(path, state, renamed_from).await;
+        }
 
-    tokio::select! {
-        _ = recv_task => {},
-        _ = send_task => {},
+        if self.assets.is_empty() {
+            return;
+        }
+        let state_guard = state.read().await;
+        let settings = &state_guard.settings;
+        // The same check as `servable`: allowed outright, or a file a page
+        // refers to
+        if self.assets.iter().any(|path| {
+            settings.static_options.allows(path.to_str().unwrap_or(""))
+                || settings.site_path(path).is_some_and(|site_path| {
+                    settings.static_options.allows_referenced(&site_path)
+                        && state_guard.references(None, &site_path)
+                })
+        }) {
+            let _ = state_guard
+                .change_tx
+                .send(ServerMessage::Reload { file: None });
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use tempfile::tempdir;
+/// Re-read everything after the watcher may have missed changes: cached
+/// renders are dropped and, in directory mode and mounted directories, new
+/// files are picked up.
+async fn rescan(state: &SharedMarkdownState) {
+    let (settings, mut files) = {
+        let state_guard = state.read().await;
+        (state_guard.settings.clone(), state_guard.listed_files())
+    };
+    let dirs: Vec<PathBuf> = settings
+        .is_directory_mode
+        .then(|| settings.base_dir.clone())
+        .into_iter()
+        .chain(
+            settings
+                .document_mounts
+                .iter()
+                .filter(|document_mount| document_mount.directory)
+                .map(|document_mount| document_mount.dir.clone()),
+        )
+        .collect();
+    let recursive = settings.recursive;
+    // Walk without the lock; big trees take a while
+    let scanned = tokio::task::spawn_blocking(move || {
+        dirs.iter()
+            .filter_map(|dir| {
+                let scanned = if recursive {
+                    scan_markdown_files_recursive(dir)
+                } else {
+                    scan_markdown_files(dir)
+                };
+                scanned
+                    .map_err(|e| tracing::warn!("could not rescan {}: {e}", dir.display()))
+                    .ok()
+            })
+            .flatten()
+            .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap_or_default();
+    files.extend(
+        scanned
+            .into_iter()
+            .filter_map(|path| Some((settings.tracked_name(&path)?, path))),
+    );
+    let mut titles = settings.read_titles(files.clone()).await;
+
+    let mut state_guard = state.write().await;
+    for (name, tracked) in state_guard.tracked_files.iter_mut() {
+        tracked.invalidate();
+        tracked.title = titles.remove(name);
+    }
+    for (filename, path) in files {
+        let title = titles.remove(&filename);
+        state_guard
+            .tracked_files
+            .entry(filename)
+            .or_insert_with(|| TrackedFile::new(path).with_title(title));
+    }
+    let _ = state_guard
+        .change_tx
+        .send(ServerMessage::Reload { file: None });
+}
 
-    #[test]
-    fn test_is_markdown_file() {
-        assert!(is_markdown_file(Path::new("test.md")));
-        assert!(is_markdown_file(Path::new("/path/to/file.md")));
+/// Runtime handle to a router's tracked files.
+///
+/// Obtained from [`MdserveBuilder::build`]. Cloning is cheap; every clone
+/// refers to the same router. Methods that change what is served notify
+/// connected browsers to reload.
+#[derive(Clone)]
+pub struct StateHandle {
+    state: SharedMarkdownState,
+    change_tx: broadcast::Sender<ServerMessage>,
+}
 
-        assert!(is_markdown_file(Path::new("test.markdown")));
-        assert!(is_markdown_file(Path::new("/path/to/file.markdown")));
+impl StateHandle {
+    /// Start tracking a markdown file. Files already tracked under the same
+    /// name are left alone.
+    pub async fn add_file(&self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        if !is_markdown_file(&path) {
+            anyhow::bail!("{} is not a markdown file", path.display());
+        }
+        let path = paths::canonicalize_async(&path).await?;
+        if !tokio::fs::metadata(&path).await?.is_file() {
+            anyhow::bail!("{} is not a file", path.display());
+        }
 
-        assert!(is_markdown_file(Path::new("test.MD")));
-        assert!(is_markdown_file(Path::new("test.Md")));
-        assert!(is_markdown_file(Path::new("test.MARKDOWN")));
-        assert!(is_markdown_file(Path::new("test.MarkDown")));
+        let settings = self.state.read().await.settings.clone();
+        let filename = settings
+            .tracked_name(&path)
+            .context("path is outside the served directory")?;
+        let mut titles = settings
+            .read_titles(vec![(filename.clone(), path.clone())])
+            .await;
+        let title = titles.remove(&filename);
+
+        let mut state = self.state.write().await;
+        state
+            .tracked_files
+            .entry(filename)
+            .or_insert_with(|| TrackedFile::new(path).with_title(title));
+        let _ = state.change_tx.send(ServerMessage::Reload { file: None });
+        Ok(())
+    }
 
-        assert!(!is_markdown_file(Path::new("test.txt")));
-        assert!(!is_markdown_file(Path::new("test.rs")));
-        assert!(!is_markdown_file(Path::new("test.html")));
-        assert!(!is_markdown_file(Path::new("test")));
-        assert!(!is_markdown_file(Path::new("README")));
+    /// Stop tracking `filename`. Returns whether it was tracked.
+    pub async fn remove_file(&self, filename: &str) -> bool {
+        let mut state = self.state.write().await;
+        let removed = state.tracked_files.remove(filename).is_some();
+        if removed {
+            let _ = state.change_tx.send(ServerMessage::Reload { file: None });
+        }
+        removed
     }
 
-    #[test]
-    fn test_is_image_file() {
-        assert!(is_image_file("test.png"));
-        assert!(is_image_file("test.jpg"));
-        assert!(is_image_file("test.jpeg"));
-        assert!(is_image_file("test.gif"));
-        assert!(is_image_file("test.svg"));
-        assert!(is_image_file("test.webp"));
-        assert!(is_image_file("test.bmp"));
-        assert!(is_image_file("test.ico"));
+    /// Mark every cached render stale so each file is re-read on its next
+    /// request.
+    pub async fn refresh_all(&self) {
+        let (settings, files) = {
+            let state = self.state.read().await;
+            (state.settings.clone(), state.listed_files())
+        };
+        let mut titles = settings.read_titles(files).await;
 
-        assert!(is_image_file("test.PNG"));
-        assert!(is_image_file("test.JPG"));
-        assert!(is_image_file("test.JPEG"));
+        let mut state = self.state.write().await;
+        for (name, tracked) in state.tracked_files.iter_mut() {
+            tracked.invalidate();
+            tracked.title = titles.remove(name);
+        }
+        let _ = state.change_tx.send(ServerMessage::Reload { file: None });
+    }
 
-        assert!(is_image_file("/path/to/image.png"));
-        assert!(is_image_file("./images/photo.jpg"));
+    /// Ask connected browsers to reload without changing anything.
+    pub async fn broadcast_reload(&self) {
+        self.send(ServerMessage::Reload { file: None });
+    }
 
-        assert!(!is_image_file("test.txt"));
-        assert!(!is_image_file("test.md"));
-        assert!(!is_image_file("test.rs"));
-        assert!(!is_image_file("test"));
+    /// Push a message to every connected preview. Returns how many
+    /// connections it was delivered to.
+    pub fn send(&self, message: ServerMessage) -> usize {
+        self.change_tx.send(message).unwrap_or(0)
     }
 
-    #[test]
-    fn test_guess_image_content_type() {
-        assert_eq!(guess_image_content_type("test.png"), "image/png");
-        assert_eq!(guess_image_content_type("test.jpg"), "image/jpeg");
-        assert_eq!(guess_image_content_type("test.jpeg"), "image/jpeg");
-        assert_eq!(guess_image_content_type("test.gif"), "image/gif");
-        assert_eq!(guess_image_content_type("test.svg"), "image/svg+xml");
-        assert_eq!(guess_image_content_type("test.webp"), "image/webp");
-        assert_eq!(guess_image_content_type("test.bmp"), "image/bmp");
-        assert_eq!(guess_image_content_type("test.ico"), "image/x-icon");
-
-        assert_eq!(guess_image_content_type("test.PNG"), "image/png");
-        assert_eq!(guess_image_content_type("test.JPG"), "image/jpeg");
+    /// Push an application-defined event to every connected preview.
+    pub fn send_custom(&self, kind: impl Into<String>, payload: impl Serialize) -> Result<usize> {
+        Ok(self.send(ServerMessage::Custom {
+            kind: kind.into(),
+            payload: serde_json::to_value(payload)?,
+        }))
+    }
+
+    /// Wait until file changes reported so far have been applied: the
+    /// current burst is over and no change holds the state.
+    async fn settle(&self) {
+        tokio::time::sleep(COALESCE_WINDOW).await;
+        drop(self.state.write().await);
+    }
+
+    /// Names of the tracked files, sorted.
+    pub async fn tracked_files(&self) -> Vec<String> {
+        self.state.read().await.get_sorted_filenames()
+    }
+}
+
+struct RouterConfig {
+    base_dir: PathBuf,
+    tracked_files: Vec<PathBuf>,
+    is_directory_mode: bool,
+    navigation: bool,
+    recursive: bool,
+    document_mounts: Vec<DocumentMount>,
+    template: Arc<dyn PageTemplate>,
+    /// Set when `template` is read from disk, to reload it on edits.
+    file_template: Option<Arc<FileTemplate>>,
+    highlight_css: String,
+    mermaid_config: Metadata,
+    toc: bool,
+    sidebar_titles: bool,
+    wikilinks: bool,
+    scroll_sync: bool,
+    render_options: RenderOptions,
+    pipeline: RenderPipeline,
+    html_mappers: Vec<HtmlMapper>,
+    static_options: StaticFileOptions,
+    asset_mounts: Vec<AssetMount>,
+    /// What this server serves: the file in single-file mode, otherwise the
+    /// base directory, then any extra paths. Identifies it to other
+    /// invocations.
+    instance_roots: Vec<PathBuf>,
+    /// Secret share links are signed with, already scoped to the first of
+    /// `instance_roots`.
+    share_secret: Vec<u8>,
+    access: AccessControl,
+    change_source: Option<Box<dyn ChangeSource>>,
+    file_event_hooks: Vec<FileEventHook>,
+    base_path: String,
+    cache_limits: CacheLimits,
+    message_buffer: usize,
+    max_file_size: usize,
+    connection_limits: ConnectionLimits,
+    compression: bool,
+    tls: Option<TlsCertificate>,
+    port_fallbacks: u16,
+    edit: bool,
+    allow_write: bool,
+    upload_options: UploadOptions,
+    index: Option<String>,
+    redirect_index: bool,
+}
+
+impl RouterConfig {
+    /// Key `path` is tracked and served under.
+    fn tracked_name(&self, path: &Path) -> Option<String> {
+        document_name(&self.base_dir, self.recursive, &self.document_mounts, path)
+    }
+}
+
+/// Asset mount without surrounding slashes. It must be a relative URL path
+/// that doesn't shadow mdserve's own routes.
+fn normalize_mount(mount: &str) -> Result<String> {
+    const RESERVED: [&str; 6] = ["api", "download", "events", "raw", "share", "ws"];
+
+    let trimmed = mount.trim_matches('/');
+    let first = trimmed.split('/').next().unwrap_or_default();
+    if trimmed.is_empty()
+        || !is_contained_relative_path(Path::new(trimmed))
+        || RESERVED.contains(&first)
+    {
+        anyhow::bail!("invalid mount: {mount}");
+    }
+    Ok(trimmed.to_string())
+}
+
+fn new_router(mut config: RouterConfig) -> Result<(Router, StateHandle)> {
+    let base_dir = paths::canonicalize(&config.base_dir)?;
+    let change_source = config.change_source.take();
+    let file_event_hooks = std::mem::take(&mut config.file_event_hooks);
+    let file_template = config.file_template.take();
+    let access = std::mem::take(&mut config.access);
+    let compression = config.compression;
+    for asset_mount in &mut config.asset_mounts {
+        asset_mount.dir = paths::canonicalize(&asset_mount.dir)
+            .ok()
+            .filter(|dir| dir.is_dir())
+            .with_context(|| format!("not a directory: {}", asset_mount.dir.display()))?;
+    }
+
+    let state = MarkdownState::new(RouterConfig {
+        base_dir: base_dir.clone(),
+        ..config
+    })?;
+    let change_tx = state.change_tx.clone();
+    let settings = state.settings.clone();
+    let state = Arc::new(RwLock::new(state));
+
+    if let Some(source) = change_source {
+        for document_mount in &settings.document_mounts {
+            let source = if settings.recursive {
+                NotifySource::recursive()
+            } else {
+                NotifySource::default()
+            };
+            spawn_watcher(
+                Box::new(source),
+                &document_mount.dir,
+                state.clone(),
+                file_event_hooks.clone(),
+            )?;
+        }
+        spawn_watcher(source, &base_dir, state.clone(), file_event_hooks)?;
+        for asset_mount in &settings.asset_mounts {
+            spawn_asset_watcher(&asset_mount.dir, state.clone())?;
+        }
+        if let Some(template) = file_template {
+            spawn_template_watcher(template, state.clone())?;
+        }
+    }
+
+    let app_state = AppState {
+        settings,
+        markdown: state.clone(),
+        change_tx: change_tx.clone(),
+        drafts: Arc::default(),
+    };
+
+    let max_body = app_state.settings.renderer.max_file_size;
+    let RouterSettings {
+        edit,
+        allow_write,
+        scroll_sync,
+        ..
+    } = *app_state.settings;
+    let upload_limit = app_state.settings.upload_options.max_size;
+    let mut router = Router::new()
+        .route("/", get(serve_html_root))
+        .route("/mermaid.min.js", get(serve_mermaid_js))
+        .route(
+            &format!("/assets/mermaid-{MERMAID_VERSION}.min.js"),
+            get(serve_versioned_mermaid_js),
+        )
+        .route(
+            &format!("/assets/katex-{KATEX_VERSION}.min.js"),
+            get(serve_versioned_katex_js),
+        )
+        .route("/download/*filename", get(serve_download))
+        .route("/raw/*filename", get(serve_raw))
+        .route("/share/:token/*path", get(serve_shared))
+        .route("/api/render/*filename", get(render_tracked))
+        .route(
+            "/api/render",
+            post(render_preview).layer(DefaultBodyLimit::max(max_body)),
+        )
+        .route("/api/instance", get(instance_info))
+        .route("/*filename", get(serve_file));
+    if compression {
+        router = router.layer(CompressionLayer::new().compress_when(compressible()));
+    }
+    // Added after compression, so live-reload messages are never held back
+    // in an encoder's buffer
+    router = router
+        .route("/ws", get(websocket_handler))
+        .route("/events", get(events_handler))
+        .layer(CorsLayer::permissive());
+    // Added after the CORS layer, so other origins can't read what these
+    // return, and turning away cross-site requests, which browsers send
+    // without asking CORS first
+    let body_limit = DefaultBodyLimit::max(max_body);
+    let same_origin = middleware::from_fn(auth::require_same_origin);
+    // A share link opens its file to anyone holding it
+    router = router.route(
+        "/api/share",
+        post(create_share_link).layer(same_origin.clone()),
+    );
+    if edit {
+        router = router.route(
+            "/api/drafts/*filename",
+            get(get_draft)
+                .put(put_draft)
+                .delete(delete_draft)
+                .layer(body_limit)
+                .layer(same_origin.clone()),
+        );
+    }
+    if scroll_sync {
+        router = router.route("/api/sync", post(sync_scroll).layer(same_origin.clone()));
+    }
+    if allow_write {
+        router = router
+            .route(
+                "/api/files/*filename",
+                put(save_file).layer(body_limit).layer(same_origin.clone()),
+            )
+            .route(
+                "/api/upload",
+                post(upload_file)
+                    .layer(DefaultBodyLimit::max(upload_limit))
+                    .layer(same_origin),
+            );
+    }
+    if access.is_enabled() {
+        let access = AccessControl {
+            share_secret: app_state.settings.share_secret.clone(),
+            asset_paths: vec![
+                format!("/assets/mermaid-{MERMAID_VERSION}.min.js"),
+                format!("/assets/katex-{KATEX_VERSION}.min.js"),
+            ],
+            ..access
+        };
+        // Outermost, so no route (or CORS preflight) runs without credentials
+        router = router.layer(middleware::from_fn_with_state(
+            Arc::new(access),
+            auth::require_access,
+        ));
+    }
+    // Outermost, so requests turned away without credentials are logged too
+    let router = router
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &axum::http::Request<Body>| {
+                    // The path only: a query may carry the access token
+                    tracing::info_span!(
+                        "request",
+                        method = %request.method(),
+                        path = request.uri().path()
+                    )
+                })
+                .on_request(())
+                .on_response(
+                    |response: &axum::http::Response<Body>,
+                     latency: Duration,
+                     _span: &tracing::Span| {
+                        tracing::info!(
+                            status = response.status().as_u16(),
+                            latency_ms = latency.as_millis(),
+                            "served"
+                        );
+                    },
+                ),
+        )
+        .with_state(app_state);
+
+    Ok((router, StateHandle { state, change_tx }))
+}
+
+/// Responses worth compressing: not images, media or archives, which are
+/// compressed already, nor event streams (see [`DefaultPredicate`]).
+fn compressible() -> impl Predicate {
+    DefaultPredicate::new()
+        .and(NotForContentType::const_new("video/"))
+        .and(NotForContentType::const_new("audio/"))
+        .and(NotForContentType::const_new("application/zip"))
+        .and(NotForContentType::const_new("application/gzip"))
+}
+
+fn spawn_watcher(
+    source: Box<dyn ChangeSource>,
+    base_dir: &Path,
+    state: SharedMarkdownState,
+    hooks: Vec<FileEventHook>,
+) -> Result<()> {
+    let mut rx = source.start(base_dir)?;
+
+    tokio::spawn(async move {
+        while let Some(burst) = next_burst(&mut rx).await {
+            let mut changes = PendingChanges::default();
+            let mut events: Vec<FileEvent> = Vec::new();
+            for event in &burst {
+                tracing::debug!(kind = ?event.kind, paths = ?event.paths, "watcher event");
+                changes.add(event);
+                if !hooks.is_empty() {
+                    for file_event in file_events(event) {
+                        if !events.contains(&file_event) {
+                            events.push(file_event);
+                        }
+                    }
+                }
+            }
+
+            changes.apply(&state).await;
+
+            for file_event in events {
+                for hook in &hooks {
+                    tokio::spawn(hook(file_event.clone()));
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Reload pages when a servable file in a mounted asset directory changes.
+/// Markdown files there are not tracked.
+fn spawn_asset_watcher(dir: &Path, state: SharedMarkdownState) -> Result<()> {
+    let mut rx = Box::new(NotifySource::default()).start(dir)?;
+
+    tokio::spawn(async move {
+        while let Some(burst) = next_burst(&mut rx).await {
+            let mut changes = PendingChanges::default();
+            for event in &burst {
+                changes.add(event);
+            }
+            PendingChanges {
+                assets: changes.assets,
+                ..Default::default()
+            }
+            .apply(&state)
+            .await;
+        }
+    });
+
+    Ok(())
+}
+
+/// Recompile a template read from disk when it changes, and reload open
+/// pages with it. A template that fails to compile is reported to the
+/// pages, which keep the previous one.
+fn spawn_template_watcher(template: Arc<FileTemplate>, state: SharedMarkdownState) -> Result<()> {
+    let (dir, source) = if template.path().is_dir() {
+        (template.path(), NotifySource::recursive())
+    } else {
+        let parent = template
+            .path()
+            .parent()
+            .context("template has no parent directory")?;
+        (parent, NotifySource::default())
+    };
+    let mut rx = Box::new(source).start(dir)?;
+
+    tokio::spawn(async move {
+        while let Some(burst) = next_burst(&mut rx).await {
+            let touches_template = burst
+                .iter()
+                .flat_map(|event| &event.paths)
+                .any(|path| path.starts_with(template.path()));
+            if !touches_template {
+                continue;
+            }
+            let message = match template.reload() {
+                Ok(()) => ServerMessage::Reload { file: None },
+                Err(e) => ServerMessage::Notice {
+                    level: NoticeLevel::Error,
+                    message: format!("Could not load template: {e:#}"),
+                },
+            };
+            let _ = state.read().await.change_tx.send(message);
+        }
+    });
+
+    Ok(())
+}
+
+/// Wait for an event and gather the rest of its burst, so no file is
+/// touched before the burst is over.
+async fn next_burst(rx: &mut mpsc::Receiver<Event>) -> Option<Vec<Event>> {
+    let mut burst = vec![rx.recv().await?];
+    let deadline = tokio::time::Instant::now() + COALESCE_WINDOW;
+    while let Ok(Some(event)) = tokio::time::timeout_at(deadline, rx.recv()).await {
+        burst.push(event);
+    }
+    Some(burst)
+}
+
+/// Bind `hostname:port`, or the first free one of the `fallbacks` ports
+/// after it. Port 0 leaves the choice to the OS.
+async fn bind_with_retry(hostname: &str, port: u16, fallbacks: u16) -> Result<TcpListener> {
+    let fallbacks = if port == 0 { 0 } else { fallbacks };
+    let mut last_err = None;
+    for offset in 0..=fallbacks {
+        let try_port = match port.checked_add(offset) {
+            Some(p) => p,
+            None => break,
+        };
+        match TcpListener::bind((hostname, try_port)).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => last_err = Some(e),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(last_err
+        .map(|e| anyhow::anyhow!(e))
+        .unwrap_or_else(|| anyhow::anyhow!("no valid port in range"))
+        .context(if fallbacks == 0 {
+            format!("could not bind to port {port}")
+        } else {
+            format!(
+                "could not bind to ports {}--{}",
+                port,
+                port.saturating_add(fallbacks)
+            )
+        }))
+}
+
+/// Build the router described by `builder`, bind it and serve until the
+/// process exits, printing the startup banner the CLI shows.
+pub async fn serve_markdown(
+    mut builder: MdserveBuilder,
+    hostname: impl AsRef<str>,
+    port: u16,
+    open: bool,
+) -> Result<()> {
+    let hostname = hostname.as_ref();
+
+    if builder.share_secret.is_none() {
+        let share_secret = share::load_or_create_secret().unwrap_or_else(|e| {
+            eprintln!("⚠ {e:#}; share links will only work until the server stops");
+            share::random_secret()
+        });
+        builder = builder.share_secret(share_secret);
+    }
+    builder = builder.guard_public_tunnel();
+
+    if builder.tls.is_some() {
+        // Both expect the server to speak plain HTTP
+        if builder.public_tunnel.is_some() {
+            anyhow::bail!("a public tunnel already serves HTTPS; it can't be combined with TLS");
+        }
+        if builder.mdns {
+            anyhow::bail!("mDNS advertises plain HTTP; it can't be combined with TLS");
+        }
+    }
+
+    let public_tunnel = builder.public_tunnel;
+    let copy_url = builder.copy_url;
+    let on_listening = builder.on_listening.take();
+    let lan_address = builder
+        .mdns
+        .then(|| mdns::lan_address(hostname))
+        .transpose()?;
+    let config = builder.into_config()?;
+    let base_dir = config.base_dir.clone();
+    let is_directory_mode = config.is_directory_mode;
+    let first_file = config.tracked_files.first().cloned();
+    let document_mounts = config.document_mounts.clone();
+    let share_secret = config.share_secret.clone();
+    let access = config.access.clone();
+    let scheme = if config.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let index = config.index.clone().or_else(|| {
+        let mut names: Vec<_> = config
+            .tracked_files
+            .iter()
+            .filter_map(|file| config.tracked_name(file))
+            .collect();
+        sort_for_sidebar(&mut names);
+        default_index(&names).cloned()
+    });
+    let server = spawn_server(config, hostname, port).await?;
+    let actual_port = server.local_addr().port();
+
+    if port != 0 && actual_port != port {
+        println!("⚠ Port {port} in use, using {actual_port} instead");
+    }
+    if let Some(hook) = on_listening {
+        hook(server.local_addr());
+    }
+
+    let listen_addr = format_host(hostname, actual_port);
+    let mut browse_url = format!(
+        "{scheme}://{}",
+        format_host(&browsable_host(hostname), actual_port)
+    );
+    if let Some(token) = &access.token {
+        // Opened or copied, the URL lets its holder in
+        browse_url.push_str(&format!("/?token={}", percent_encode(token)));
+    }
+
+    if is_directory_mode {
+        println!("📁 Serving markdown files from: {}", base_dir.display());
+    } else if let Some(file_path) = first_file {
+        println!("📄 Serving markdown file: {}", file_path.display());
+    }
+    for document_mount in &document_mounts {
+        println!(
+            "📁 Serving {} at /{}/",
+            document_mount.dir.display(),
+            document_mount.mount
+        );
+    }
+
+    println!(
+        "🌐 Server running at: {}",
+        terminal::hyperlink(&browse_url, &format!("{scheme}://{listen_addr}"))
+    );
+    println!("⚡ Live reload enabled");
+    if access.is_enabled() {
+        println!("🔒 Access requires credentials (share links don't)");
+    }
+
+    let advertisement = if let Some(ip) = lan_address {
+        let advertisement = mdns::advertise(ip, actual_port)?;
+        println!(
+            "📡 Advertised on the local network as \"{}\" ({})",
+            advertisement.instance(),
+            advertisement.url()
+        );
+        Some(advertisement)
+    } else {
+        None
+    };
+
+    // Kept alive until the server exits; dropping it closes the tunnel
+    let mut share_link = None;
+    let tunnel = match public_tunnel {
+        Some(provider) => {
+            let tunnel = Tunnel::start(provider, &browsable_host(hostname), actual_port).await?;
+            let mut public_url = tunnel.url.clone();
+            if let Some(token) = &access.token {
+                public_url.push_str(&format!("/?token={}", percent_encode(token)));
+            }
+            println!(
+                "🌍 Public URL: {}",
+                terminal::hyperlink(&public_url, &public_url)
+            );
+            if let Some(index) = &index {
+                let token = share::mint_token(&share_secret, index, share::DEFAULT_SHARE_TTL);
+                let link = format!("{}{}", tunnel.url, share::share_path(&token, index));
+                println!(
+                    "🔗 Share link (24h, only {index}): {}",
+                    terminal::hyperlink(&link, &link)
+                );
+                share_link = Some(link);
+            }
+            Some(tunnel)
+        }
+        None => None,
+    };
+
+    if copy_url {
+        // With a tunnel, the link meant for someone else
+        let url = share_link.as_deref().unwrap_or(&browse_url);
+        terminal::copy_url(url);
+    }
+
+    println!("\nPress Ctrl+C to stop the server");
+
+    if open {
+        open_browser(&browse_url)?;
+    }
+
+    signal::shutdown_requested().await;
+    println!("\n👋 Stopping mdserve…");
+    server.state().settle().await;
+    server.shutdown();
+    let result = server.join().await;
+    drop(tunnel);
+    drop(advertisement);
+    result
+}
+
+async fn spawn_server(config: RouterConfig, hostname: &str, port: u16) -> Result<ServerHandle> {
+    let limits = config.connection_limits;
+    let fallbacks = config.port_fallbacks;
+    let tls = config
+        .tls
+        .as_ref()
+        .map(|certificate| tls::acceptor(certificate, hostname))
+        .transpose()?;
+    let (router, state) = new_router(config)?;
+    let listener = bind_with_retry(hostname, port, fallbacks).await?;
+    let local_addr = listener.local_addr()?;
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let task = tokio::spawn(server::serve(listener, router, limits, tls, shutdown_rx));
+
+    Ok(ServerHandle {
+        local_addr,
+        state,
+        shutdown_tx,
+        task,
+    })
+}
+
+/// A running server started with [`MdserveBuilder::serve`].
+///
+/// Dropping the handle leaves the server running; call
+/// [`shutdown`](Self::shutdown) and [`join`](Self::join) to stop it.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    state: StateHandle,
+    shutdown_tx: watch::Sender<bool>,
+    task: JoinHandle<std::io::Result<()>>,
+}
+
+impl ServerHandle {
+    /// Address the server actually bound, after port fallback or with port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Handle for changing what the server tracks.
+    pub fn state(&self) -> &StateHandle {
+        &self.state
+    }
+
+    /// Tell open pages the server is stopping, stop accepting connections
+    /// and let in-flight requests finish. Returns immediately; await
+    /// [`join`](Self::join) to wait for exit.
+    pub fn shutdown(&self) {
+        self.state.send(ServerMessage::Shutdown);
+        let _ = self.shutdown_tx.send(true);
+    }
+
+    /// Wait for the server to exit.
+    pub async fn join(self) -> Result<()> {
+        self.task.await.context("server task panicked")??;
+        Ok(())
+    }
+}
+
+/// Format the host address (hostname + port) for printing.
+pub(crate) fn format_host(hostname: &str, port: u16) -> String {
+    if hostname.parse::<Ipv6Addr>().is_ok() {
+        format!("[{hostname}]:{port}")
+    } else {
+        format!("{hostname}:{port}")
+    }
+}
+
+/// Map wildcard bind addresses to loopback so the browser gets a
+/// reachable URL.
+pub(crate) fn browsable_host(hostname: &str) -> String {
+    if hostname
+        .parse::<Ipv4Addr>()
+        .ok()
+        .is_some_and(|ip| ip.is_unspecified())
+    {
+        "127.0.0.1".into()
+    } else if hostname
+        .parse::<Ipv6Addr>()
+        .ok()
+        .is_some_and(|ip| ip.is_unspecified())
+    {
+        "::1".into()
+    } else {
+        hostname.into()
+    }
+}
+
+/// Open a URL in the default browser using platform commands.
+///
+/// Fails immediately if the command cannot be spawned (e.g. not
+/// installed). Exit status is monitored in a background thread
+/// since opener commands may block until their handler process
+/// returns.
+pub(crate) fn open_browser(url: &str) -> Result<()> {
+    let program = if cfg!(target_os = "macos") {
+        "open"
+    } else if cfg!(target_os = "linux") {
+        "xdg-open"
+    } else {
+        anyhow::bail!("--open is not supported on this platform");
+    };
+
+    let mut child = std::process::Command::new(program)
+        .arg(url)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .with_context(|| format!("failed to run {program}"))?;
+
+    std::thread::spawn(move || match child.wait() {
+        Ok(status) if !status.success() => {
+            eprintln!("{program} exited with {status}");
+        }
+        Err(e) => eprintln!("Failed waiting on {program}: {e}"),
+        _ => {}
+    });
+
+    Ok(())
+}
+
+/// Check that a request path stays inside the directory it is joined onto,
+/// i.e. it has no `..`, root or prefix components.
+fn is_contained_relative_path(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Check whether any component of a relative path is a dotfile.
+fn is_hidden_path(path: &Path) -> bool {
+    path.components().any(|c| match c {
+        Component::Normal(name) => name.to_str().is_some_and(|n| n.starts_with('.')),
+        _ => false,
+    })
+}
+
+fn is_image_file(file_path: &str) -> bool {
+    mime_guess::from_path(file_path)
+        .first()
+        .is_some_and(|mime| mime.type_() == mime_guess::mime::IMAGE)
+}
+
+/// Percent-encode everything but unreserved URL characters.
+pub(crate) fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{render::RenderStage, template::PageContext};
+    use axum::{
+        body::Bytes,
+        http::{header, HeaderValue, StatusCode},
+    };
+    use futures_util::StreamExt;
+    use handlers::{CONTENT_PLACEHOLDER, STREAM_THRESHOLD};
+    use std::fs;
+    use tempfile::tempdir;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_events_stream_sends_messages_for_the_viewed_file() {
+        use tower::ServiceExt;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("a.md"), "# A").expect("Failed to write");
+        let (router, handle) = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .build()
+            .expect("Failed to create router");
+
+        let response = router
+            .oneshot(
+                axum::http::Request::get("/events?file=a.md")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+
+        handle.send(ServerMessage::Reload {
+            file: Some("b.md".to_string()),
+        });
+        handle.send(ServerMessage::Reload {
+            file: Some("a.md".to_string()),
+        });
+        let mut body = response.into_body().into_data_stream();
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.next())
+            .await
+            .expect("No event sent")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&chunk),
+            "data: {\"type\":\"Reload\",\"file\":\"a.md\"}\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_scrolls_pages_showing_the_file() {
+        use tower::ServiceExt;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("a.md"), "# A\n\ntext\n").expect("Failed to write");
+        fs::write(temp_dir.path().join("b.md"), "# B").expect("Failed to write");
+        let (router, _handle) = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .scroll_sync(true)
+            .build()
+            .expect("Failed to create router");
+
+        let page = router
+            .clone()
+            .oneshot(
+                axum::http::Request::get("/a.md")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let page = axum::body::to_bytes(page.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&page).contains(r#"<p data-sourcepos="3:1-3:5">text</p>"#));
+
+        let events = router
+            .clone()
+            .oneshot(
+                axum::http::Request::get("/events?file=a.md")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        for (file, status) in [
+            ("missing.md", StatusCode::NOT_FOUND),
+            ("b.md", StatusCode::NO_CONTENT),
+            ("a.md", StatusCode::NO_CONTENT),
+        ] {
+            let response = router
+                .clone()
+                .oneshot(
+                    axum::http::Request::post("/api/sync")
+                        .header(header::CONTENT_TYPE, "application/json")
+                        .body(Body::from(format!(r#"{{"file":"{file}","line":3}}"#)))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status);
+        }
+
+        let mut body = events.into_body().into_data_stream();
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.next())
+            .await
+            .expect("No event sent")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&chunk),
+            "data: {\"type\":\"ScrollTo\",\"file\":\"a.md\",\"line\":3}\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_websocket_refuses_other_sites() {
+        let (server, _temp_dir) = create_directory_server_with_http().await;
+
+        let response = server
+            .get_websocket("/ws")
+            .add_header("sec-fetch-site", HeaderValue::from_static("cross-site"))
+            .add_header(
+                header::ORIGIN,
+                HeaderValue::from_static("http://evil.example"),
+            )
+            .await;
+        assert_eq!(response.status_code(), StatusCode::FORBIDDEN);
+
+        let response = server
+            .get_websocket("/ws")
+            .add_header("sec-fetch-site", HeaderValue::from_static("same-origin"))
+            .await;
+        assert_eq!(response.status_code(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_is_announced_and_ends_event_streams() {
+        use tower::ServiceExt;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("a.md"), "# A").expect("Failed to write");
+        let (router, handle) = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .build()
+            .expect("Failed to create router");
+        let response = router
+            .oneshot(
+                axum::http::Request::get("/events")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        handle.send(ServerMessage::Shutdown);
+        let body = tokio::time::timeout(
+            Duration::from_secs(5),
+            axum::body::to_bytes(response.into_body(), usize::MAX),
+        )
+        .await
+        .expect("Stream did not end")
+        .unwrap();
+        assert_eq!(body, "data: {\"type\":\"Shutdown\"}\n\n");
+    }
+
+    #[test]
+    fn test_is_markdown_file() {
+        assert!(is_markdown_file(Path::new("test.md")));
+        assert!(is_markdown_file(Path::new("/path/to/file.md")));
+
+        assert!(is_markdown_file(Path::new("test.markdown")));
+        assert!(is_markdown_file(Path::new("/path/to/file.markdown")));
+
+        assert!(is_markdown_file(Path::new("test.MD")));
+        assert!(is_markdown_file(Path::new("test.Md")));
+        assert!(is_markdown_file(Path::new("test.MARKDOWN")));
+        assert!(is_markdown_file(Path::new("test.MarkDown")));
+
+        assert!(!is_markdown_file(Path::new("test.txt")));
+        assert!(!is_markdown_file(Path::new("test.rs")));
+        assert!(!is_markdown_file(Path::new("test.html")));
+        assert!(!is_markdown_file(Path::new("test")));
+        assert!(!is_markdown_file(Path::new("README")));
+    }
+
+    #[test]
+    fn test_is_image_file() {
+        assert!(is_image_file("test.png"));
+        assert!(is_image_file("test.jpg"));
+        assert!(is_image_file("test.jpeg"));
+        assert!(is_image_file("test.gif"));
+        assert!(is_image_file("test.svg"));
+        assert!(is_image_file("test.webp"));
+        assert!(is_image_file("test.bmp"));
+        assert!(is_image_file("test.ico"));
+
+        assert!(is_image_file("test.PNG"));
+        assert!(is_image_file("test.JPG"));
+        assert!(is_image_file("test.JPEG"));
+
+        assert!(is_image_file("/path/to/image.png"));
+        assert!(is_image_file("./images/photo.jpg"));
+
+        assert!(!is_image_file("test.txt"));
+        assert!(!is_image_file("test.md"));
+        assert!(!is_image_file("test.rs"));
+        assert!(!is_image_file("test"));
+    }
+
+    #[test]
+    fn test_scan_markdown_files_empty_directory() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+
+        let result = scan_markdown_files(temp_dir.path()).expect("Failed to scan");
+        assert_eq!(result.len(), 0);
+    }
+
+    #[test]
+    fn test_scan_markdown_files_with_markdown_files() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+
+        fs::write(temp_dir.path().join("test1.md"), "# Test 1").expect("Failed to write");
+        fs::write(temp_dir.path().join("test2.markdown"), "# Test 2").expect("Failed to write");
+        fs::write(temp_dir.path().join("test3.md"), "# Test 3").expect("Failed to write");
+
+        fs::write(temp_dir.path().join("test.txt"), "text").expect("Failed to write");
+        fs::write(temp_dir.path().join("README"), "readme").expect("Failed to write");
+
+        let result = scan_markdown_files(temp_dir.path()).expect("Failed to scan");
+
+        assert_eq!(result.len(), 3);
+
+        let filenames: Vec<_> = result
+            .iter()
+            .map(|p| p.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(filenames, vec!["test1.md", "test2.markdown", "test3.md"]);
+    }
+
+    #[test]
+    fn test_scan_markdown_files_ignores_subdirectories() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+
+        fs::write(temp_dir.path().join("root.md"), "# Root").expect("Failed to write");
+
+        let sub_dir = temp_dir.path().join("subdir");
+        fs::create_dir(&sub_dir).expect("Failed to create subdir");
+        fs::write(sub_dir.join("nested.md"), "# Nested").expect("Failed to write");
+
+        let result = scan_markdown_files(temp_dir.path()).expect("Failed to scan");
+
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].file_name().unwrap().to_str().unwrap(), "root.md");
+    }
+
+    #[test]
+    fn test_scan_markdown_files_recursive_skips_hidden_and_ignored_folders() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+
+        fs::write(temp_dir.path().join("root.md"), "# Root").expect("Failed to write");
+        let guides = temp_dir.path().join("guides");
+        fs::create_dir_all(guides.join("deep")).expect("Failed to create subdirs");
+        fs::write(guides.join("setup.md"), "# Setup").expect("Failed to write");
+        fs::write(guides.join("deep").join("notes.md"), "# Notes").expect("Failed to write");
+        let hidden = temp_dir.path().join(".git");
+        fs::create_dir(&hidden).expect("Failed to create hidden dir");
+        fs::write(hidden.join("hidden.md"), "# Hidden").expect("Failed to write");
+        for excluded in ["node_modules/pkg", "target/doc", "dist", "guides/drafts"] {
+            let dir = temp_dir.path().join(excluded);
+            fs::create_dir_all(&dir).expect("Failed to create subdirs");
+            fs::write(dir.join("README.md"), "# Excluded").expect("Failed to write");
+        }
+        fs::write(
+            temp_dir.path().join(".gitignore"),
+            "dist/
+",
+        )
+        .expect("Failed to write");
+        fs::write(
+            guides.join(".gitignore"),
+            "drafts
+*.tmp.md
+",
+        )
+        .expect("Failed to write");
+        fs::write(guides.join("scratch.tmp.md"), "# Scratch").expect("Failed to write");
+
+        let result = scan_markdown_files_recursive(temp_dir.path()).expect("Failed to scan");
+
+        let names: Vec<_> = result
+            .iter()
+            .map(|p| nested_name(temp_dir.path(), p).unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["guides/deep/notes.md", "guides/setup.md", "root.md"]
+        );
+    }
+
+    #[test]
+    fn test_scan_markdown_files_recursive_reads_wide_trees_completely() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let mut expected = Vec::new();
+        for folder in 0..40 {
+            let dir = temp_dir.path().join(format!("f{folder:02}")).join("sub");
+            fs::create_dir_all(&dir).expect("Failed to create subdirs");
+            fs::write(dir.join("doc.md"), "# Doc").expect("Failed to write");
+            fs::write(dir.join("data.txt"), "data").expect("Failed to write");
+            expected.push(format!("f{folder:02}/sub/doc.md"));
+        }
+
+        let result = scan_markdown_files_recursive(temp_dir.path()).expect("Failed to scan");
+
+        let names: Vec<_> = result
+            .iter()
+            .map(|p| nested_name(temp_dir.path(), p).unwrap())
+            .collect();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn test_scan_markdown_files_case_insensitive() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+
+        fs::write(temp_dir.path().join("test1.md"), "# Test 1").expect("Failed to write");
+        fs::write(temp_dir.path().join("test2.MD"), "# Test 2").expect("Failed to write");
+        fs::write(temp_dir.path().join("test3.Md"), "# Test 3").expect("Failed to write");
+        fs::write(temp_dir.path().join("test4.MARKDOWN"), "# Test 4").expect("Failed to write");
+
+        let result = scan_markdown_files(temp_dir.path()).expect("Failed to scan");
+
+        assert_eq!(result.len(), 4);
+    }
+
+    #[test]
+    fn test_format_host() {
+        assert_eq!(format_host("127.0.0.1", 3000), "127.0.0.1:3000");
+        assert_eq!(format_host("192.168.1.1", 8080), "192.168.1.1:8080");
+
+        assert_eq!(format_host("localhost", 3000), "localhost:3000");
+        assert_eq!(format_host("example.com", 80), "example.com:80");
+
+        assert_eq!(format_host("::1", 3000), "[::1]:3000");
+        assert_eq!(format_host("2001:db8::1", 8080), "[2001:db8::1]:8080");
+    }
+
+    #[test]
+    fn test_browsable_host() {
+        assert_eq!(browsable_host("0.0.0.0"), "127.0.0.1");
+        assert_eq!(browsable_host("::"), "::1");
+        assert_eq!(browsable_host("127.0.0.1"), "127.0.0.1");
+        assert_eq!(browsable_host("::1"), "::1");
+        assert_eq!(browsable_host("192.168.1.1"), "192.168.1.1");
+        assert_eq!(browsable_host("localhost"), "localhost");
+        assert_eq!(browsable_host("example.com"), "example.com");
+    }
+
+    #[tokio::test]
+    async fn test_bind_retries_on_addr_in_use() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let blocked_port = listener.local_addr().unwrap().port();
+
+        let retry_listener = bind_with_retry("127.0.0.1", blocked_port, MAX_PORT_ATTEMPTS - 1)
+            .await
+            .unwrap();
+        let actual_port = retry_listener.local_addr().unwrap().port();
+
+        assert!(
+            actual_port > blocked_port,
+            "Should bind to a higher port when requested port is in use"
+        );
+        assert!(bind_with_retry("127.0.0.1", blocked_port, 0).await.is_err());
+
+        drop(retry_listener);
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn test_port_zero_reports_the_bound_port() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("a.md"), "# A").expect("Failed to write");
+
+        let server = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .auto_port(0)
+            .serve("127.0.0.1", 0)
+            .await
+            .expect("Failed to serve");
+        let port = server.local_addr().port();
+        assert_ne!(port, 0);
+        assert!(TcpListener::bind(("127.0.0.1", port)).await.is_err());
+
+        server.shutdown();
+        server.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serves_https_with_certificate_files() {
+        use hyper_util::rt::TokioIo;
+        use tokio_rustls::{
+            rustls::{crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore},
+            TlsConnector,
+        };
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("a.md"), "# Over TLS").expect("Failed to write");
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = temp_dir.path().join("cert.pem");
+        let key_path = temp_dir.path().join("key.pem");
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+
+        let server = Mdserve::builder()
+            .file(temp_dir.path().join("a.md"))
+            .tls(TlsCertificate::Files {
+                cert: cert_path,
+                key: key_path,
+            })
+            .serve("127.0.0.1", 0)
+            .await
+            .expect("Failed to serve");
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+        let mut client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connect = |client: ClientConfig| async {
+            let tcp = tokio::net::TcpStream::connect(server.local_addr())
+                .await
+                .unwrap();
+            TlsConnector::from(Arc::new(client))
+                .connect(ServerName::try_from("localhost").unwrap(), tcp)
+                .await
+                .expect("TLS handshake failed")
+        };
+        let stream = connect(client.clone()).await;
+
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        let request = axum::http::Request::get("/")
+            .header(header::HOST, "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(Body::new(response.into_body()), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("Over TLS"));
+
+        // Browsers get HTTP/2 through ALPN
+        client.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let stream = connect(client).await;
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+        let (mut sender, conn) = hyper::client::conn::http2::handshake(
+            hyper_util::rt::TokioExecutor::new(),
+            TokioIo::new(stream),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(conn);
+        let request = axum::http::Request::get("https://localhost/")
+            .body(Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.version(), axum::http::Version::HTTP_2);
+
+        // Plain HTTP isn't answered on the TLS port
+        let mut plain = tokio::net::TcpStream::connect(server.local_addr())
+            .await
+            .unwrap();
+        plain
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(5), plain.read_to_end(&mut reply)).await;
+        assert!(!reply.starts_with(b"HTTP/"));
+
+        server.shutdown();
+        server.join().await.unwrap();
+    }
+
+    use axum_test::TestServer;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use std::time::Duration;
+    use tempfile::{Builder, NamedTempFile, TempDir};
+
+    const FILE_WATCH_DELAY_MS: u64 = 100;
+    const WEBSOCKET_TIMEOUT_SECS: u64 = 5;
+
+    const TEST_FILE_1_CONTENT: &str = "# Test 1\n\nContent of test1";
+    const TEST_FILE_2_CONTENT: &str = "# Test 2\n\nContent of test2";
+    const TEST_FILE_3_CONTENT: &str = "# Test 3\n\nContent of test3";
+    const YAML_FRONTMATTER_CONTENT: &str =
+        "---\ntitle: Test Post\nauthor: Name\n---\n\n# Test Post\n";
+    const TOML_FRONTMATTER_CONTENT: &str = "+++\ntitle = \"Test Post\"\n+++\n\n# Test Post\n";
+
+    fn create_test_server_impl(content: &str, use_http: bool) -> (TestServer, NamedTempFile) {
+        let temp_file = Builder::new()
+            .suffix(".md")
+            .tempfile()
+            .expect("Failed to create temp file");
+        fs::write(&temp_file, content).expect("Failed to write temp file");
+
+        let canonical_path = temp_file
+            .path()
+            .canonicalize()
+            .unwrap_or_else(|_| temp_file.path().to_path_buf());
+
+        let router = Mdserve::builder()
+            .file(canonical_path)
+            .build_router()
+            .expect("Failed to create router");
+
+        let server = if use_http {
+            TestServer::builder()
+                .http_transport()
+                .build(router)
+                .expect("Failed to create test server")
+        } else {
+            TestServer::new(router).expect("Failed to create test server")
+        };
+
+        (server, temp_file)
+    }
+
+    async fn create_test_server(content: &str) -> (TestServer, NamedTempFile) {
+        create_test_server_impl(content, false)
+    }
+
+    async fn create_test_server_with_http(content: &str) -> (TestServer, NamedTempFile) {
+        create_test_server_impl(content, true)
+    }
+
+    fn create_directory_server_impl(use_http: bool) -> (TestServer, TempDir) {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+
+        fs::write(temp_dir.path().join("test1.md"), TEST_FILE_1_CONTENT)
+            .expect("Failed to write test1.md");
+        fs::write(temp_dir.path().join("test2.markdown"), TEST_FILE_2_CONTENT)
+            .expect("Failed to write test2.markdown");
+        fs::write(temp_dir.path().join("test3.md"), TEST_FILE_3_CONTENT)
+            .expect("Failed to write test3.md");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .build_router()
+            .expect("Failed to create router");
+
+        let server = if use_http {
+            TestServer::builder()
+                .http_transport()
+                .build(router)
+                .expect("Failed to create test server")
+        } else {
+            TestServer::new(router).expect("Failed to create test server")
+        };
+
+        (server, temp_dir)
+    }
+
+    async fn create_directory_server() -> (TestServer, TempDir) {
+        create_directory_server_impl(false)
+    }
+
+    async fn create_directory_server_with_http() -> (TestServer, TempDir) {
+        create_directory_server_impl(true)
+    }
+
+    #[test]
+    fn test_builder_requires_base_dir_or_files() {
+        assert!(Mdserve::builder().build_router().is_err());
+        assert!(Mdserve::builder()
+            .files(Vec::<PathBuf>::new())
+            .build_router()
+            .is_err());
+    }
+
+    #[test]
+    fn test_builder_without_watch_needs_no_runtime() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("test.md"), "# Test").expect("Failed to write");
+
+        let result = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .watch(false)
+            .build_router();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_builder_index_chooses_root_document() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("api.md"), "# API").expect("Failed to write");
+        fs::write(temp_dir.path().join("my guide.md"), "# Guide").expect("Failed to write");
+
+        let builder = || {
+            Mdserve::builder()
+                .base_dir(temp_dir.path())
+                .index("my guide.md")
+                .watch(false)
+        };
+
+        let server = TestServer::new(builder().build_router().unwrap()).unwrap();
+        assert!(server.get("/").await.text().contains("Guide</h1>"));
+
+        let server =
+            TestServer::new(builder().redirect_index(true).build_router().unwrap()).unwrap();
+        let response = server.get("/").await;
+        response.assert_status(StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(response.header("location"), "/my%20guide.md");
+
+        assert!(Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .index("missing.md")
+            .build_router()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_root_prefers_readme_then_index() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("api.md"), "# API").expect("Failed to write");
+        fs::write(temp_dir.path().join("index.md"), "# Index").expect("Failed to write");
+
+        let root = || async {
+            let router = Mdserve::builder()
+                .base_dir(temp_dir.path())
+                .watch(false)
+                .build_router()
+                .unwrap();
+            TestServer::new(router).unwrap().get("/").await.text()
+        };
+
+        assert!(root().await.contains("Index</h1>"));
+
+        fs::write(temp_dir.path().join("README.md"), "# Readme").expect("Failed to write");
+        assert!(root().await.contains("Readme</h1>"));
+    }
+
+    #[tokio::test]
+    async fn test_export_writes_static_site() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let docs = temp_dir.path().join("docs");
+        fs::create_dir_all(docs.join("guides").join("img")).expect("Failed to create dirs");
+        fs::write(
+            docs.join("README.md"),
+            "# Home\n\n[Setup](guides/setup.md#install)",
+        )
+        .expect("Failed to write");
+        fs::write(
+            docs.join("guides").join("setup.md"),
+            "# Setup\n\n![shot](img/shot.png)\n\n[Home](../README.md)",
+        )
+        .expect("Failed to write");
+        fs::write(docs.join("guides").join("img").join("shot.png"), "png")
+            .expect("Failed to write");
+
+        let out = temp_dir.path().join("site");
+        let summary = Mdserve::builder()
+            .base_dir(&docs)
+            .recursive(true)
+            .export(&out)
+            .await
+            .expect("Failed to export");
+        assert_eq!((summary.pages, summary.files), (2, 1));
+
+        let home = fs::read_to_string(out.join("README.html")).unwrap();
+        assert!(home.contains(r#"href="guides/setup.html#install""#));
+        assert!(home.contains(r#"href="./guides/setup.html""#));
+        assert!(!home.contains("setupLiveReload();"));
+        assert_eq!(fs::read_to_string(out.join("index.html")).unwrap(), home);
+
+        let setup = fs::read_to_string(out.join("guides").join("setup.html")).unwrap();
+        assert!(setup.contains(r#"href="../README.html""#));
+
+        // A single file takes the documents it links to along
+        let out = temp_dir.path().join("single");
+        let summary = Mdserve::builder()
+            .file(docs.join("README.md"))
+            .export(&out)
+            .await
+            .expect("Failed to export");
+        assert_eq!(summary.pages, 2);
+        assert!(out.join("guides").join("setup.html").is_file());
+        assert!(out.join("guides").join("img").join("shot.png").is_file());
+        assert!(out
+            .join(format!("assets/mermaid-{MERMAID_VERSION}.min.js"))
+            .is_file());
+        assert!(summary.diagnostics.is_empty());
+
+        // Lint findings are collected per document
+        fs::write(docs.join("guides").join("skip.md"), "# Skip\n\n### Deep\n")
+            .expect("Failed to write");
+        let summary = Mdserve::builder()
+            .base_dir(&docs)
+            .recursive(true)
+            .lint(true)
+            .export(temp_dir.path().join("linted"))
+            .await
+            .expect("Failed to export");
+        let flagged: Vec<_> = summary
+            .diagnostics
+            .iter()
+            .map(|(name, diagnostic)| (name.as_str(), diagnostic.line))
+            .collect();
+        assert_eq!(flagged, [("guides/skip.md", 3)]);
+    }
+
+    #[tokio::test]
+    async fn test_linked_documents_are_served_from_a_single_file() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::create_dir(temp_dir.path().join("docs")).expect("Failed to create dir");
+        let main = temp_dir.path().join("main.md");
+        fs::write(&main, "# Main\n\n[Setup](docs/setup.md#install)").expect("Failed to write");
+        fs::write(
+            temp_dir.path().join("docs").join("setup.md"),
+            "# Setup\n\n[Back](../main.md) [Notes](notes.md)",
+        )
+        .expect("Failed to write");
+        fs::write(temp_dir.path().join("docs").join("notes.md"), "# Notes")
+            .expect("Failed to write");
+        fs::write(temp_dir.path().join("private.md"), "# Private").expect("Failed to write");
+
+        let (router, handle) = Mdserve::builder()
+            .file(&main)
+            .build()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        // Nothing links to it until the linking document has rendered
+        assert_eq!(
+            server.get("/docs/setup.md").await.status_code(),
+            StatusCode::NOT_FOUND
+        );
+        server.get("/main.md").await.assert_status_ok();
+        assert!(server
+            .get("/docs/setup.md")
+            .await
+            .text()
+            .contains("Setup</h1>"));
+        assert!(server
+            .get("/docs/notes.md")
+            .await
+            .text()
+            .contains("Notes</h1>"));
+        assert_eq!(
+            server.get("/private.md").await.status_code(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(handle.tracked_files().await, vec!["main.md"]);
+    }
+
+    #[tokio::test]
+    async fn test_builder_custom_template() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("test.md"), "# Custom").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .template("<main data-title=\"{{ page_title }}\">{{ content }}</main>")
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        let body = server.get("/").await.text();
+        assert_eq!(
+            body,
+            "<main data-title=\"test\"><h1 id=\"custom\">Custom</h1></main>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_frontmatter_metadata_reaches_templates() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(
+            temp_dir.path().join("cv.md"),
+            "---\nauthor: Ada Lovelace\ntags: [math, engines]\n---\n# Résumé",
+        )
+        .expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .template("{{ metadata.author }}|{{ metadata.tags|join(',') }}|{{ metadata.missing }}")
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        assert_eq!(
+            server.get("/cv.md").await.text(),
+            "Ada Lovelace|math,engines|"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mermaid_config_from_flag_and_frontmatter() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("plain.md"), "# Plain").expect("Failed to write");
+        fs::write(
+            temp_dir.path().join("themed.md"),
+            "---\nmermaid:\n  theme: neutral\n  flowchart:\n    curve: basis\n---\n# Themed",
+        )
+        .expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .mermaid_theme("forest")
+            .template("{{ mermaid_config|tojson }}")
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        assert_eq!(
+            server.get("/plain.md").await.text(),
+            r#"{"theme":"forest"}"#
+        );
+        assert_eq!(
+            server.get("/themed.md").await.text(),
+            r#"{"flowchart":{"curve":"basis"},"theme":"neutral"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_builder_render_stage() {
+        struct Callout;
+
+        impl RenderStage for Callout {
+            fn post_html(&self, html: String, ctx: &mut crate::RenderContext) -> String {
+                ctx.enable_feature("callout");
+                html.replace("<blockquote>", "<blockquote class=\"callout\">")
+            }
+        }
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("test.md"), "> Note").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .template("{{ features | join(',') }}|{{ content }}")
+            .render_stage(Callout)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        let body = server.get("/").await.text();
+        assert!(body.starts_with("callout|<blockquote class=\"callout\">"));
+    }
+
+    #[tokio::test]
+    async fn test_builder_map_html() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("a.md"), "# A").expect("Failed to write");
+        fs::write(temp_dir.path().join("b.md"), "# B").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .template("{{ content }}")
+            .map_html(|file, html| format!("<aside>{file}</aside>{html}"))
+            .map_html(|_, html| html.replace("<h1 ", "<h1 class=\"title\" "))
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        let body = server.get("/b.md").await.text();
+        assert_eq!(
+            body,
+            "<aside>b.md</aside><h1 class=\"title\" id=\"b\">B</h1>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_render_api_serves_tracked_documents() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("a.md"), "# Alpha\n\nFirst.").expect("Failed to write");
+        fs::write(temp_dir.path().join("notes.txt"), "text").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        let response = server.get("/api/render/a.md").await;
+        response.assert_status_ok();
+        let body: serde_json::Value = response.json();
+        assert_eq!(body["html"], "<h1 id=\"alpha\">Alpha</h1>\n<p>First.</p>");
+
+        for missing in ["/api/render/b.md", "/api/render/notes.txt"] {
+            server
+                .get(missing)
+                .await
+                .assert_status(StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_builder_prose_lint_rechecks_on_change() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().join("test.md");
+        fs::write(&file, "# Title\n\nSee the the chart.\n").expect("Failed to write");
+
+        let (changes, source) = crate::watch::channel();
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .template("{{ content }}")
+            .change_source(source)
+            .prose_lint(true)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        let body = server.get("/").await.text();
+        assert!(body.contains("<summary>1 suggestion</summary>"));
+        assert!(body.contains("<div class=\"lint-marker\" id=\"lint-line-3\""));
+
+        fs::write(&file, "# Title\n\nSee the chart.\n").expect("Failed to write");
+        assert!(changes.modified(&file).await);
+        let body = tokio::time::timeout(Duration::from_secs(WEBSOCKET_TIMEOUT_SECS), async {
+            loop {
+                let body = server.get("/").await.text();
+                if !body.contains("lint-panel") {
+                    break body;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("Timeout waiting for lint to re-run");
+        assert_eq!(body, "<h1 id=\"title\">Title</h1>\n<p>See the chart.</p>\n");
+    }
+
+    #[tokio::test]
+    async fn test_channel_change_source_drives_reload() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().join("test.md");
+        fs::write(&file, "# Before").expect("Failed to write");
+
+        let (changes, source) = crate::watch::channel();
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .change_source(source)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+
+        // Once rendered, an edit without an event goes unnoticed
+        assert!(server.get("/").await.text().contains("Before"));
+        fs::write(&file, "# After").expect("Failed to write");
+        assert!(server.get("/").await.text().contains("Before"));
+
+        assert!(changes.modified(&file).await);
+        let message = tokio::time::timeout(
+            Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
+            websocket.receive_json::<ServerMessage>(),
+        )
+        .await
+        .expect("Timeout waiting for reload");
+
+        assert_eq!(
+            message,
+            ServerMessage::Reload {
+                file: Some("test.md".to_string())
+            }
+        );
+        assert!(server.get("/").await.text().contains("After"));
+    }
+
+    #[tokio::test]
+    async fn test_reads_during_a_save_are_retried() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().join("test.md");
+        fs::write(&file, "# Before").expect("Failed to write");
+
+        let (changes, source) = crate::watch::channel();
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .change_source(source)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+        let mut socket =
+            crate::testing::PreviewSocket(server.get_websocket("/ws").await.into_websocket().await);
+        assert!(server.get("/").await.text().contains("Before"));
+
+        // The event arrives while the file is briefly gone, then empty
+        fs::remove_file(&file).expect("Failed to remove");
+        assert!(changes.modified(&file).await);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        fs::write(&file, "").expect("Failed to write");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        fs::write(&file, "# After").expect("Failed to write");
+
+        assert_eq!(
+            socket.next_message().await.unwrap(),
+            ServerMessage::Reload {
+                file: Some("test.md".to_string())
+            }
+        );
+        assert!(server.get("/").await.text().contains("After"));
+    }
+
+    #[tokio::test]
+    async fn test_renames_are_announced() {
+        use notify::event::{ModifyKind, RenameMode};
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        // Named by their path below the root, which tells folders apart
+        let old = temp_dir.path().join("notes").join("draft.md");
+        let new = temp_dir.path().join("final.md");
+        fs::create_dir(temp_dir.path().join("notes")).expect("Failed to create dir");
+        fs::write(&old, "# Draft").expect("Failed to write");
+
+        let (changes, source) = crate::watch::channel();
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .recursive(true)
+            .change_source(source)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+        let mut socket =
+            crate::testing::PreviewSocket(server.get_websocket("/ws").await.into_websocket().await);
+
+        fs::rename(&old, &new).expect("Failed to rename");
+        changes
+            .send(
+                Event::new(notify::EventKind::Modify(ModifyKind::Name(
+                    RenameMode::Both,
+                )))
+                .add_path(old)
+                .add_path(new),
+            )
+            .await;
+
+        assert_eq!(
+            socket.next_message().await.unwrap(),
+            ServerMessage::Notice {
+                level: NoticeLevel::Info,
+                message: "Renamed notes/draft.md to final.md".to_string(),
+            }
+        );
+        socket.expect_reload().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_removed_and_renamed_assets_reload() {
+        use notify::event::{ModifyKind, RemoveKind, RenameMode};
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let image = temp_dir.path().join("chart.png");
+        fs::write(temp_dir.path().join("a.md"), "![chart](chart.png)").expect("Failed to write");
+        fs::write(&image, "png").expect("Failed to write");
+
+        let (changes, source) = crate::watch::channel();
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .change_source(source)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+        let mut socket =
+            crate::testing::PreviewSocket(server.get_websocket("/ws").await.into_websocket().await);
+
+        // Renamed away, as editors and `mv` report it on some platforms
+        let moved = temp_dir.path().join("old-chart.png");
+        fs::rename(&image, &moved).expect("Failed to rename");
+        changes
+            .send(
+                Event::new(notify::EventKind::Modify(ModifyKind::Name(
+                    RenameMode::From,
+                )))
+                .add_path(image.clone()),
+            )
+            .await;
+        socket.expect_reload().await.unwrap();
+
+        fs::remove_file(&moved).expect("Failed to remove");
+        changes
+            .send(Event::new(notify::EventKind::Remove(RemoveKind::File)).add_path(moved))
+            .await;
+        socket.expect_reload().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_referenced_non_image_assets_reload() {
+        use notify::event::{DataChange, ModifyKind};
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::create_dir(temp_dir.path().join("css")).expect("Failed to create dir");
+        let stylesheet = temp_dir.path().join("css").join("print.css");
+        let unreferenced = temp_dir.path().join("css").join("other.css");
+        fs::write(
+            temp_dir.path().join("a.md"),
+            "<link rel=\"stylesheet\" href=\"css/print.css\">\n\n# A",
+        )
+        .expect("Failed to write");
+        fs::write(&stylesheet, "h1 {}").expect("Failed to write");
+        fs::write(&unreferenced, "h1 {}").expect("Failed to write");
+
+        let (changes, source) = crate::watch::channel();
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .change_source(source)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+        server.get("/a.md").await.assert_status_ok();
+        let mut socket =
+            crate::testing::PreviewSocket(server.get_websocket("/ws").await.into_websocket().await);
+
+        let modified = |path: &Path| {
+            Event::new(notify::EventKind::Modify(ModifyKind::Data(
+                DataChange::Content,
+            )))
+            .add_path(path.to_path_buf())
+        };
+        // Nothing refers to it, and css isn't served otherwise
+        changes.send(modified(&unreferenced)).await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), socket.next_message())
+                .await
+                .is_err()
+        );
+
+        fs::write(&stylesheet, "h1 { color: red }").expect("Failed to write");
+        changes.send(modified(&stylesheet)).await;
+        socket.expect_reload().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_state_handle_manages_tracked_files() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("a.md"), "# A").expect("Failed to write");
+        fs::write(temp_dir.path().join("b.md"), "# B").expect("Failed to write");
+
+        let (router, handle) = Mdserve::builder()
+            .file(temp_dir.path().join("a.md"))
+            .watch(false)
+            .build()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        handle
+            .add_file(temp_dir.path().join("b.md"))
+            .await
+            .expect("Failed to add file");
+        assert_eq!(handle.tracked_files().await, vec!["a.md", "b.md"]);
+        assert!(server
+            .get("/b.md")
+            .await
+            .text()
+            .contains("<h1 id=\"b\">B</h1>"));
+
+        fs::write(temp_dir.path().join("b.md"), "# B2").expect("Failed to write");
+        handle.refresh_all().await;
+        assert!(server
+            .get("/b.md")
+            .await
+            .text()
+            .contains("<h1 id=\"b2\">B2</h1>"));
+
+        assert!(handle.remove_file("b.md").await);
+        assert!(!handle.remove_file("b.md").await);
+        server.get("/b.md").await.assert_status_not_found();
+
+        assert!(handle
+            .add_file(temp_dir.path().join("notes.txt"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_state_handle_sends_custom_messages() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("a.md"), "# A").expect("Failed to write");
+
+        let (router, handle) = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .watch(false)
+            .build()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+
+        assert_eq!(handle.send(ServerMessage::Reload { file: None }), 0);
+
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        handle
+            .send_custom("build", serde_json::json!({ "ok": true }))
+            .expect("Failed to serialize payload");
+
+        let message = tokio::time::timeout(
+            Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
+            websocket.receive_json::<ServerMessage>(),
+        )
+        .await
+        .expect("Timeout waiting for custom message");
+
+        assert_eq!(
+            message,
+            ServerMessage::Custom {
+                kind: "build".to_string(),
+                payload: serde_json::json!({ "ok": true }),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_event_hooks_see_changes() {
+        use crate::watch::FileEventKind;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().join("test.md");
+        fs::write(&file, "# Test").expect("Failed to write");
+
+        let (changes, source) = crate::watch::channel();
+        let (seen_tx, mut seen_rx) = tokio::sync::mpsc::unbounded_channel();
+        let _router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .change_source(source)
+            .on_file_event(move |event| {
+                let seen_tx = seen_tx.clone();
+                async move {
+                    let _ = seen_tx.send(event);
+                }
+            })
+            .build_router()
+            .expect("Failed to create router");
+
+        assert!(changes.modified(&file).await);
+        let event = tokio::time::timeout(Duration::from_secs(1), seen_rx.recv())
+            .await
+            .expect("Timeout waiting for hook")
+            .expect("Hook channel closed");
+
+        assert_eq!(event.path, file);
+        assert_eq!(event.kind, FileEventKind::Modified);
+    }
+
+    #[tokio::test]
+    async fn test_router_nests_under_base_path() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(
+            temp_dir.path().join("a.md"),
+            "# A\n\n```mermaid\ngraph TD\n```",
+        )
+        .expect("Failed to write");
+        fs::write(temp_dir.path().join("b.md"), "# B").expect("Failed to write");
+
+        let preview = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .base_path("/preview")
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let app = Router::new().nest("/preview", preview);
+        let server = TestServer::new(app).expect("Failed to create test server");
+
+        let body = server.get("/preview/a.md").await.text();
+        assert!(body.contains(r#"<a href="/preview/b.md""#));
+        let mermaid_src = format!("/preview/assets/mermaid-{MERMAID_VERSION}.min.js");
+        assert!(body.contains(&format!(r#"src="{mermaid_src}""#)));
+        assert!(body.contains("const BASE_PATH = '/preview';"));
+
+        server.get(&mermaid_src).await.assert_status_ok();
+
+        let share = server
+            .post("/preview/api/share")
+            .json(&serde_json::json!({ "file": "b.md" }))
+            .await;
+        let path = share.json::<serde_json::Value>()["path"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(path.starts_with("/preview/share/"));
+        assert!(server
+            .get(&path)
+            .await
+            .text()
+            .contains("<h1 id=\"b\">B</h1>"));
+    }
+
+    #[tokio::test]
+    async fn test_serve_reports_address_and_shuts_down() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("a.md"), "# A").expect("Failed to write");
+
+        let server = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .watch(false)
+            .serve("127.0.0.1", 0)
+            .await
+            .expect("Failed to start server");
+        let addr = server.local_addr();
+        assert_ne!(addr.port(), 0);
+
+        let mut stream = tokio::net::TcpStream::connect(addr)
+            .await
+            .expect("Failed to connect");
+        stream
+            .write_all(b"GET /a.md HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .expect("Failed to write request");
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .await
+            .expect("Failed to read response");
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("<h1 id=\"a\">A</h1>"));
+
+        server.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server.join())
+            .await
+            .expect("Timeout waiting for shutdown")
+            .expect("Server failed");
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_builder_page_template() {
+        struct Branded;
+
+        impl PageTemplate for Branded {
+            fn render(&self, page: &PageContext<'_>) -> Result<String> {
+                Ok(format!(
+                    "<title>Acme | {}</title>{}<nav>{}</nav>",
+                    page.page_title,
+                    page.content,
+                    page.files.join(" ")
+                ))
+            }
+        }
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("a.md"), "# A").expect("Failed to write");
+        fs::write(temp_dir.path().join("b.md"), "# B").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .page_template(Branded)
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        let body = server.get("/b.md").await.text();
+        assert_eq!(
+            body,
+            "<title>Acme | b</title><h1 id=\"b\">B</h1><nav>a.md b.md</nav>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watcher_prerenders_viewed_documents() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().join("a.md");
+        fs::write(&file, "# v1").expect("Failed to write");
+
+        let (changes, source) = crate::watch::channel();
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .change_source(source)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+
+        assert!(server.get("/a.md").await.text().contains("v1"));
+
+        fs::write(&file, "# v2").expect("Failed to write");
+        assert!(changes.modified(&file).await);
+        tokio::time::timeout(
+            Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
+            websocket.receive_json::<ServerMessage>(),
+        )
+        .await
+        .expect("Timeout waiting for reload");
+
+        // The reload request is served from HTML rendered before the broadcast
+        fs::write(&file, "# v3").expect("Failed to write");
+        assert!(server.get("/a.md").await.text().contains("v2"));
+    }
+
+    #[tokio::test]
+    async fn test_event_bursts_are_coalesced() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().canonicalize().unwrap().join("a.md");
+        fs::write(&file, "# A").expect("Failed to write");
+
+        let (changes, source) = crate::watch::channel();
+        let (seen_tx, mut seen_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (router, state) = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .change_source(source)
+            .on_file_event(move |event| {
+                let seen_tx = seen_tx.clone();
+                async move {
+                    let _ = seen_tx.send(event);
+                }
+            })
+            .build()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+
+        // What a single editor save can look like
+        assert!(changes.created(&file).await);
+        assert!(changes.modified(&file).await);
+        assert!(changes.modified(&file).await);
+        tokio::time::timeout(Duration::from_secs(1), seen_rx.recv())
+            .await
+            .expect("Timeout waiting for hook");
+
+        state
+            .send_custom("marker", serde_json::Value::Null)
+            .unwrap();
+        let mut messages = Vec::new();
+        loop {
+            let message = tokio::time::timeout(
+                Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
+                websocket.receive_json::<ServerMessage>(),
+            )
+            .await
+            .expect("Timeout waiting for message");
+            if matches!(message, ServerMessage::Custom { .. }) {
+                break;
+            }
+            messages.push(message);
+        }
+        assert_eq!(
+            messages,
+            vec![ServerMessage::Reload {
+                file: Some("a.md".to_string())
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_recovery_rescans_files() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().join("a.md");
+        fs::write(&file, "# v1").expect("Failed to write");
+
+        let (changes, source) = crate::watch::channel();
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .change_source(source)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+        let mut socket =
+            crate::testing::PreviewSocket(server.get_websocket("/ws").await.into_websocket().await);
+        assert!(server.get("/a.md").await.text().contains("v1"));
+
+        assert!(changes.degraded().await);
+        assert_eq!(
+            socket.next_message().await.unwrap(),
+            ServerMessage::WatchStatus { watching: false }
+        );
+
+        // Changes made while nothing was watching
+        fs::write(&file, "# v2").expect("Failed to write");
+        fs::write(temp_dir.path().join("b.md"), "# B").expect("Failed to write");
+
+        assert!(changes.rescan().await);
+        assert_eq!(
+            socket.next_message().await.unwrap(),
+            ServerMessage::WatchStatus { watching: true }
+        );
+        assert_eq!(
+            socket.next_message().await.unwrap(),
+            ServerMessage::Reload { file: None }
+        );
+
+        assert!(server.get("/a.md").await.text().contains("v2"));
+        assert!(server
+            .get("/b.md")
+            .await
+            .text()
+            .contains("<h1 id=\"b\">B</h1>"));
+    }
+
+    #[tokio::test]
+    async fn test_identical_rerender_skips_reload() {
+        let preview = crate::testing::TestPreview::new(&[("a.md", "# Same")]).unwrap();
+        assert!(preview.server.get("/a.md").await.text().contains("Same"));
+        let mut socket = preview.websocket().await;
+
+        // Rewriting the same bytes renders the same HTML, so nothing is sent
+        preview.write("a.md", "# Same").await.unwrap();
+        preview
+            .state
+            .send_custom("marker", serde_json::Value::Null)
+            .unwrap();
+        match socket.next_message().await.unwrap() {
+            ServerMessage::Custom { kind, .. } => assert_eq!(kind, "marker"),
+            other => panic!("expected marker, got {other:?}"),
+        }
+
+        preview.write("a.md", "# Changed").await.unwrap();
+        socket.expect_reload().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_documents_render_on_first_request() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().join("a.md");
+        fs::write(&file, "# Startup").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        // Nothing was rendered at startup, so the first request sees the edit
+        fs::write(&file, "# First request").expect("Failed to write");
+        assert!(server.get("/a.md").await.text().contains("First request"));
+
+        // Later requests are served from the cache
+        fs::write(&file, "# Uncached").expect("Failed to write");
+        assert!(server.get("/a.md").await.text().contains("First request"));
+    }
+
+    #[test]
+    fn test_truncate_at_line() {
+        assert_eq!(truncate_at_line("one\ntwo\nthr"), "one\ntwo\n");
+        assert_eq!(truncate_at_line("no newline"), "no newline");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_files_are_truncated_with_notice() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let content: String = (1..=20).map(|i| format!("Line {i}\n\n")).collect();
+        fs::write(temp_dir.path().join("dump.md"), &content).expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .watch(false)
+            .max_file_size(50)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        let body = server.get("/dump.md").await.text();
+        assert!(body.contains(r#"class="mdserve-truncated""#));
+        assert!(body.contains("only the first 0.0 MB are shown"));
+        assert!(body.contains("<p>Line 1</p>"));
+        assert!(!body.contains("Line 20"));
+    }
+
+    #[tokio::test]
+    async fn test_truncation_keeps_utf8_files_utf8() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        // The limit falls inside the second byte of `é`, with no line break
+        // to cut at before it
+        fs::write(temp_dir.path().join("a.md"), "Caf\u{e9} au lait\n").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .watch(false)
+            .max_file_size(4)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        let body = server.get("/a.md").await.text();
+        assert!(body.contains(r#"class="mdserve-truncated""#));
+        assert!(!body.contains("mdserve-encoding"));
+        assert!(body.contains("<p>Caf</p>"));
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_files_are_decoded_with_notice() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("latin1.md"), b"# Caf\xe9\n").expect("Failed to write");
+        fs::write(temp_dir.path().join("bom.md"), b"\xEF\xBB\xBF# Title\n")
+            .expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        let body = server.get("/latin1.md").await.text();
+        assert!(body.contains("was read as Windows-1252"));
+        assert!(body.contains(">Café</h1>"));
+
+        let body = server.get("/bom.md").await.text();
+        assert!(!body.contains("mdserve-encoding"));
+        assert!(body.contains("<h1 id=\"title\">Title</h1>"));
+    }
+
+    #[tokio::test]
+    async fn test_large_documents_are_streamed_whole() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("report.md"), "# Report").expect("Failed to write");
+
+        // Pad the HTML directly; parsing megabytes of markdown is slow in
+        // debug builds
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .watch(false)
+            .map_html(|_, html| {
+                let padding = "<p>A line of the generated report.</p>\n".repeat(50_000);
+                format!("{html}{padding}<p>The end.</p>")
+            })
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+        let response = server.get("/report.md").await;
+
+        assert_eq!(response.status_code(), 200);
+        assert!(response
+            .header(header::CONTENT_TYPE)
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        assert!(response.maybe_header(header::CONTENT_LENGTH).is_none());
+
+        let body = response.text();
+        assert!(body.len() > STREAM_THRESHOLD);
+        assert!(body.starts_with("<!DOCTYPE html>"));
+        assert!(body.contains("<h1 id=\"report\">Report</h1>"));
+        assert!(body.contains("<p>The end.</p>"));
+        assert!(body.trim_end().ends_with("</html>"));
+        assert!(!body.contains(CONTENT_PLACEHOLDER));
+    }
+
+    #[tokio::test]
+    async fn test_cache_evicts_least_recently_used() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        for name in ["a.md", "b.md", "c.md"] {
+            fs::write(temp_dir.path().join(name), format!("# {name} v1")).expect("Failed to write");
+        }
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .max_cached_documents(2)
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        server.get("/a.md").await.assert_status_ok();
+        server.get("/b.md").await.assert_status_ok();
+        server.get("/a.md").await.assert_status_ok();
+        // Caching c.md evicts b.md, the least recently used
+        server.get("/c.md").await.assert_status_ok();
+
+        for name in ["a.md", "b.md", "c.md"] {
+            fs::write(temp_dir.path().join(name), format!("# {name} v2")).expect("Failed to write");
+        }
+
+        assert!(server.get("/a.md").await.text().contains("a.md v1"));
+        assert!(server.get("/b.md").await.text().contains("b.md v2"));
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_template() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("test.md"), "# Test").expect("Failed to write");
+
+        let result = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .template("{% if %}")
+            .watch(false)
+            .build_router();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_starts_and_serves_basic_markdown() {
+        let (server, _temp_file) =
+            create_test_server("# Hello World\n\nThis is **bold** text.").await;
+
+        let response = server.get("/").await;
+
+        assert_eq!(response.status_code(), 200);
+        let body = response.text();
+
+        assert!(body.contains("<h1 id=\"hello-world\">Hello World</h1>"));
+        assert!(body.contains("<strong>bold</strong>"));
+        assert!(body.contains("theme-toggle"));
+        assert!(body.contains("openThemeModal"));
+        assert!(body.contains("--bg-color"));
+        assert!(body.contains("data-theme=\"dark\""));
+        // Reloads patch changed blocks in place before falling back
+        assert!(body.contains("async function patchContent()"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_connection() {
+        let (server, _temp_file) = create_test_server_with_http("# WebSocket Test").await;
+
+        let response = server.get_websocket("/ws").await;
+        response.assert_status_switching_protocols();
+    }
+
+    #[tokio::test]
+    async fn test_file_modification_updates_via_websocket() {
+        let (server, temp_file) = create_test_server_with_http("# Original Content").await;
+
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+
+        fs::write(&temp_file, "# Modified Content").expect("Failed to modify file");
+
+        tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+
+        let update_result = tokio::time::timeout(
+            Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
+            websocket.receive_json::<ServerMessage>(),
+        )
+        .await;
+
+        update_result.expect("Timeout waiting for WebSocket update after file modification");
+    }
+
+    #[tokio::test]
+    async fn test_server_handles_gfm_features() {
+        let markdown_content = r#"# GFM Test
+
+## Table
+| Name | Age |
+|------|-----|
+| John | 30  |
+| Jane | 25  |
+
+## Strikethrough
+~~deleted text~~
+
+## Code block
+```rust
+fn main() {
+    println!("Hello!");
+}
+```
+"#;
+
+        let (server, _temp_file) = create_test_server(markdown_content).await;
+
+        let response = server.get("/").await;
+
+        assert_eq!(response.status_code(), 200);
+        let body = response.text();
+
+        assert!(body.contains("<table>"));
+        assert!(body.contains("<th>Name</th>"));
+        assert!(body.contains("<td>John</td>"));
+        assert!(body.contains("<del>deleted text</del>"));
+        assert!(body.contains("<pre>"));
+        assert!(body.contains(r#"<span class="hl-keyword">fn</span> main()"#));
+        assert!(body.contains(r#"<span class="hl-string">&quot;Hello!&quot;</span>"#));
+        assert!(body.contains(".hl-keyword {"));
+    }
+
+    #[tokio::test]
+    async fn test_404_for_unknown_routes() {
+        let (server, _temp_file) = create_test_server("# 404 Test").await;
+
+        let response = server.get("/unknown-route").await;
+
+        assert_eq!(response.status_code(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_image_serving() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+
+        let md_content =
+            "# Test with Image\n\n![Test Image](test.png)\n\nThis markdown references an image.";
+        let md_path = temp_dir.path().join("test.md");
+        fs::write(&md_path, md_content).expect("Failed to write markdown file");
+
+        let png_data = vec![
+            0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48,
+            0x44, 0x52, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x00, 0x00,
+            0x00, 0x90, 0x77, 0x53, 0xDE, 0x00, 0x00, 0x00, 0x0C, 0x49, 0x44, 0x41, 0x54, 0x08,
+            0xD7, 0x63, 0xF8, 0x0F, 0x00, 0x00, 0x01, 0x00, 0x01, 0x5C, 0xDD, 0x8D, 0xB4, 0x00,
+            0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82,
+        ];
+        let img_path = temp_dir.path().join("test.png");
+        fs::write(&img_path, png_data).expect("Failed to write image file");
+
+        let router = Mdserve::builder()
+            .file(md_path)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        let response = server.get("/").await;
+        assert_eq!(response.status_code(), 200);
+        let body = response.text();
+        assert!(body.contains("<img src=\"test.png\" alt=\"Test Image\""));
+
+        let img_response = server.get("/test.png").await;
+        assert_eq!(img_response.status_code(), 200);
+        assert_eq!(img_response.header("content-type"), "image/png");
+        assert!(!img_response.as_bytes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_pages_and_images_are_not_resent() {
+        let preview =
+            crate::testing::TestPreview::new(&[("a.md", "# A"), ("img.png", "png")]).unwrap();
+        let server = &preview.server;
+
+        let page = server.get("/a.md").await;
+        let etag = page.header("etag");
+        assert_eq!(page.header("cache-control"), "no-cache");
+        let cached = server
+            .get("/a.md")
+            .add_header(header::IF_NONE_MATCH, etag.clone())
+            .await;
+        assert_eq!(cached.status_code(), 304);
+        assert_eq!(cached.header("etag"), etag);
+        assert!(cached.as_bytes().is_empty());
+
+        preview.write("a.md", "# Changed").await.unwrap();
+        let changed = server
+            .get("/a.md")
+            .add_header(header::IF_NONE_MATCH, etag.clone())
+            .await;
+        assert_eq!(changed.status_code(), 200);
+        assert_ne!(changed.header("etag"), etag);
+
+        let image = server.get("/img.png").await;
+        assert_eq!(image.header("cache-control"), "no-cache");
+        let last_modified = image.header("last-modified");
+        for (name, value) in [
+            (header::IF_NONE_MATCH, image.header("etag")),
+            (header::IF_MODIFIED_SINCE, last_modified),
+        ] {
+            let cached = server.get("/img.png").add_header(name, value).await;
+            assert_eq!(cached.status_code(), 304);
+            assert!(cached.as_bytes().is_empty());
+        }
+        let stale = server
+            .get("/img.png")
+            .add_header(header::IF_NONE_MATCH, HeaderValue::from_static("\"old\""))
+            .add_header(
+                header::IF_MODIFIED_SINCE,
+                HeaderValue::from_static("Thu, 01 Jan 1970 00:00:00 GMT"),
+            )
+            .await;
+        assert_eq!(stale.status_code(), 200);
+        assert_eq!(stale.text(), "png");
+    }
+
+    #[tokio::test]
+    async fn test_large_static_files_are_streamed_intact() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("test.md"), "# Image").expect("Failed to write");
+        let image: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(temp_dir.path().join("large.png"), &image).expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
 
+        let response = server.get("/large.png").await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.header("content-type"), "image/png");
         assert_eq!(
-            guess_image_content_type("test.xyz"),
-            "application/octet-stream"
+            response.header("content-length"),
+            image.len().to_string().as_str()
         );
-        assert_eq!(guess_image_content_type("test"), "application/octet-stream");
+        assert_eq!(response.as_bytes().as_ref(), image.as_slice());
     }
 
-    #[test]
-    fn test_scan_markdown_files_empty_directory() {
+    #[tokio::test]
+    async fn test_edit_mode_previews_and_saves() {
         let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().join("doc.md");
+        fs::write(&file, "# Before").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .file(&file)
+            .edit(true)
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
 
-        let result = scan_markdown_files(temp_dir.path()).expect("Failed to scan");
-        assert_eq!(result.len(), 0);
+        let page = server.get("/doc.md").await.text();
+        assert!(page.contains(r#"<textarea id="editor" data-file="doc.md""#));
+
+        let preview = server
+            .post("/api/render")
+            .add_query_param("file", "doc.md")
+            .text("# Draft\n\n```mermaid\ngraph TD\n```")
+            .await;
+        assert_eq!(preview.status_code(), 200);
+        let preview: serde_json::Value = preview.json();
+        assert!(preview["html"]
+            .as_str()
+            .unwrap()
+            .contains("<h1 id=\"draft\">Draft</h1>"));
+        assert_eq!(preview["features"], serde_json::json!(["mermaid"]));
+        // Previews are not saved
+        assert_eq!(fs::read_to_string(&file).unwrap(), "# Before");
+
+        let saved = server.put("/api/files/doc.md").text("# After").await;
+        assert_eq!(saved.status_code(), 204);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "# After");
+
+        let untracked = server.put("/api/files/other.md").text("# New").await;
+        assert_eq!(untracked.status_code(), 404);
+        assert!(!temp_dir.path().join("other.md").exists());
     }
 
-    #[test]
-    fn test_scan_markdown_files_with_markdown_files() {
-        let temp_dir = tempdir().expect("Failed to create temp dir");
+    #[tokio::test]
+    async fn test_markdown_to_html_matches_served_pages() {
+        let content = "# Guide\n\n## Setup\n\n:::details More\nHidden *text*\n:::\n";
+        let (server, _temp_dir) = create_test_server(content).await;
+
+        let html = crate::markdown_to_html(content, &RenderOptions::default());
+        assert!(html.contains("<h2 id=\"setup\">Setup</h2>"));
+        assert!(html.contains("<details"));
+        assert!(server.get("/").await.text().contains(&html));
+    }
 
-        fs::write(temp_dir.path().join("test1.md"), "# Test 1").expect("Failed to write");
-        fs::write(temp_dir.path().join("test2.markdown"), "# Test 2").expect("Failed to write");
-        fs::write(temp_dir.path().join("test3.md"), "# Test 3").expect("Failed to write");
+    #[tokio::test]
+    async fn test_render_api_renders_posted_markdown() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().join("doc.md");
+        fs::write(&file, "# Doc").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .file(&file)
+            .render_options(RenderOptions {
+                math: crate::render::MathMode::Server,
+                ..Default::default()
+            })
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
 
-        fs::write(temp_dir.path().join("test.txt"), "text").expect("Failed to write");
-        fs::write(temp_dir.path().join("README"), "readme").expect("Failed to write");
+        let response = server
+            .post("/api/render")
+            .text("# Notes\n\nArea is $x^2$.\n\n```mermaid\ngraph TD\n```")
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let rendered: serde_json::Value = response.json();
+        let html = rendered["html"].as_str().unwrap();
+        assert!(html.contains("<h1 id=\"notes\">Notes</h1>"));
+        assert!(html.contains("<msup><mi>x</mi><mn>2</mn></msup>"));
+        assert_eq!(rendered["features"], serde_json::json!(["math", "mermaid"]));
+
+        let plain: serde_json::Value = server.post("/api/render").text("Plain").await.json();
+        assert_eq!(plain["html"], "<p>Plain</p>");
+        assert_eq!(plain["features"], serde_json::json!([]));
+    }
 
-        let result = scan_markdown_files(temp_dir.path()).expect("Failed to scan");
+    #[tokio::test]
+    async fn test_write_endpoint_saves_atomically_and_reloads() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("a.md"), "# A").expect("Failed to write");
 
-        assert_eq!(result.len(), 3);
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .allow_write(true)
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+        let mut socket =
+            crate::testing::PreviewSocket(server.get_websocket("/ws").await.into_websocket().await);
+        assert!(server
+            .get("/a.md")
+            .await
+            .text()
+            .contains("<h1 id=\"a\">A</h1>"));
+
+        // Reloads without a watcher, and the next request sees the new HTML
+        let saved = server.put("/api/files/a.md").text("# A2").await;
+        assert_eq!(saved.status_code(), 204);
+        socket.expect_reload().await.unwrap();
+        assert!(server
+            .get("/a.md")
+            .await
+            .text()
+            .contains("<h1 id=\"a2\">A2</h1>"));
+
+        // New files are created in directory mode
+        let created = server.put("/api/files/b.md").text("# B").await;
+        assert_eq!(created.status_code(), 201);
+        assert_eq!(
+            socket.next_message().await.unwrap(),
+            ServerMessage::Notice {
+                level: NoticeLevel::Info,
+                message: "Added b.md".to_string(),
+            }
+        );
+        socket.expect_reload().await.unwrap();
+        assert!(server
+            .get("/b.md")
+            .await
+            .text()
+            .contains("<h1 id=\"b\">B</h1>"));
+
+        for name in ["..%2Fescape.md", "sub%2Fc.md", "notes.txt"] {
+            let rejected = server.put(&format!("/api/files/{name}")).text("x").await;
+            assert_eq!(rejected.status_code(), 403, "{name}");
+        }
+        assert!(!temp_dir.path().join("../escape.md").exists());
+        assert!(!temp_dir.path().join("notes.txt").exists());
 
-        let filenames: Vec<_> = result
-            .iter()
-            .map(|p| p.file_name().unwrap().to_str().unwrap())
+        // No temporary files are left behind
+        let mut names: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
             .collect();
-        assert_eq!(filenames, vec!["test1.md", "test2.markdown", "test3.md"]);
+        names.sort();
+        assert_eq!(names, ["a.md", "b.md"]);
     }
 
-    #[test]
-    fn test_scan_markdown_files_ignores_subdirectories() {
+    #[tokio::test]
+    async fn test_pasted_images_saved_under_assets() {
         let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("doc.md"), "# Doc").expect("Failed to write");
 
-        fs::write(temp_dir.path().join("root.md"), "# Root").expect("Failed to write");
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .edit(true)
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
 
-        let sub_dir = temp_dir.path().join("subdir");
-        fs::create_dir(&sub_dir).expect("Failed to create subdir");
-        fs::write(sub_dir.join("nested.md"), "# Nested").expect("Failed to write");
+        let upload = || {
+            server
+                .post("/api/upload")
+                .add_query_param("file", "doc.md")
+                .content_type("image/png")
+                .bytes(Bytes::from_static(b"\x89PNG"))
+        };
+        let first: serde_json::Value = upload().await.json();
+        let second: serde_json::Value = upload().await.json();
+        let first = first["path"].as_str().unwrap().to_string();
+        let second = second["path"].as_str().unwrap().to_string();
+        assert!(first.starts_with("assets/image-") && first.ends_with(".png"));
+        assert_ne!(first, second);
+
+        // Served back at the path relative to the document
+        let image = server.get(&format!("/{first}")).await;
+        assert_eq!(image.status_code(), 200);
+        assert_eq!(image.as_bytes().as_ref(), b"\x89PNG");
+
+        let svg = server
+            .post("/api/upload")
+            .add_query_param("file", "doc.md")
+            .content_type("image/svg+xml")
+            .text("<svg/>")
+            .await;
+        assert_eq!(svg.status_code(), 415);
 
-        let result = scan_markdown_files(temp_dir.path()).expect("Failed to scan");
+        let untracked = server
+            .post("/api/upload")
+            .add_query_param("file", "missing.md")
+            .content_type("image/png")
+            .bytes(Bytes::from_static(b"\x89PNG"))
+            .await;
+        assert_eq!(untracked.status_code(), 404);
 
-        assert_eq!(result.len(), 1);
-        assert_eq!(result[0].file_name().unwrap().to_str().unwrap(), "root.md");
+        // Another site's page can't write files, even with a simple request
+        let foreign = server
+            .post("/api/upload")
+            .add_query_param("file", "doc.md")
+            .add_query_param("name", "evil.png")
+            .add_header(
+                header::ORIGIN,
+                HeaderValue::from_static("http://evil.example"),
+            )
+            .content_type("text/plain")
+            .text("x")
+            .await;
+        assert_eq!(foreign.status_code(), 403);
+        assert!(!temp_dir.path().join("assets/evil.png").exists());
+        let foreign = server
+            .put("/api/drafts/doc.md")
+            .add_header("sec-fetch-site", HeaderValue::from_static("cross-site"))
+            .text("draft")
+            .await;
+        assert_eq!(foreign.status_code(), 403);
+        let own = server
+            .put("/api/drafts/doc.md")
+            .add_header("sec-fetch-site", HeaderValue::from_static("same-origin"))
+            .text("draft")
+            .await;
+        assert!(own.status_code().is_success());
     }
 
-    #[test]
-    fn test_scan_markdown_files_case_insensitive() {
+    #[tokio::test]
+    async fn test_dropped_files_saved_with_unique_names() {
         let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("doc.md"), "# Doc").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .allow_write(true)
+            .static_files(StaticFileOptions {
+                extensions: vec!["pdf".to_string(), "html".to_string()],
+                ..Default::default()
+            })
+            .uploads(UploadOptions {
+                dir: "/files/".to_string(),
+                max_size: 1024,
+            })
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
 
-        fs::write(temp_dir.path().join("test1.md"), "# Test 1").expect("Failed to write");
-        fs::write(temp_dir.path().join("test2.MD"), "# Test 2").expect("Failed to write");
-        fs::write(temp_dir.path().join("test3.Md"), "# Test 3").expect("Failed to write");
-        fs::write(temp_dir.path().join("test4.MARKDOWN"), "# Test 4").expect("Failed to write");
+        let upload = |name: &str, size: usize| {
+            server
+                .post("/api/upload")
+                .add_query_param("file", "doc.md")
+                .add_query_param("name", name)
+                .bytes(Bytes::from(vec![b'x'; size]))
+        };
 
-        let result = scan_markdown_files(temp_dir.path()).expect("Failed to scan");
+        let paths: Vec<String> = vec![
+            upload("My Report.pdf", 10)
+                .await
+                .json::<serde_json::Value>(),
+            upload("My Report.pdf", 10)
+                .await
+                .json::<serde_json::Value>(),
+            server
+                .post("/api/upload")
+                .add_query_param("file", "doc.md")
+                .add_query_param("name", "../../up.png")
+                .bytes(Bytes::from_static(b"\x89PNG\r\n\x1a\n"))
+                .await
+                .json::<serde_json::Value>(),
+        ]
+        .into_iter()
+        .map(|response| response["path"].as_str().unwrap().to_string())
+        .collect();
+        assert_eq!(
+            paths,
+            [
+                "files/My-Report.pdf",
+                "files/My-Report-1.pdf",
+                "files/up.png"
+            ]
+        );
+        assert!(temp_dir.path().join("files/up.png").exists());
 
-        assert_eq!(result.len(), 4);
+        // Not servable, able to run scripts, markdown, or not what it claims
+        for name in ["data.csv", "page.html", "notes.md", "noext", "fake.png"] {
+            assert!(!upload(name, 10).await.status_code().is_success(), "{name}");
+        }
+        assert_eq!(upload("big.pdf", 2048).await.status_code(), 413);
     }
 
-    #[test]
-    fn test_format_host() {
-        assert_eq!(format_host("127.0.0.1", 3000), "127.0.0.1:3000");
-        assert_eq!(format_host("192.168.1.1", 8080), "192.168.1.1:8080");
+    #[tokio::test]
+    async fn test_drafts_kept_until_saved_or_discarded() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().join("doc.md");
+        fs::write(&file, "# Saved").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .file(&file)
+            .edit(true)
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
 
-        assert_eq!(format_host("localhost", 3000), "localhost:3000");
-        assert_eq!(format_host("example.com", 80), "example.com:80");
+        assert_eq!(server.get("/api/drafts/doc.md").await.status_code(), 404);
 
-        assert_eq!(format_host("::1", 3000), "[::1]:3000");
-        assert_eq!(format_host("2001:db8::1", 8080), "[2001:db8::1]:8080");
+        let put = server.put("/api/drafts/doc.md").text("# Draft").await;
+        assert_eq!(put.status_code(), 204);
+        let draft: serde_json::Value = server.get("/api/drafts/doc.md").await.json();
+        assert_eq!(draft["source"], "# Draft");
+        assert!(draft["saved_at"].as_u64().unwrap() > 0);
+        // Drafts never touch the file
+        assert_eq!(fs::read_to_string(&file).unwrap(), "# Saved");
+
+        // Saving the file drops its draft
+        server.put("/api/files/doc.md").text("# Draft").await;
+        assert_eq!(server.get("/api/drafts/doc.md").await.status_code(), 404);
+
+        server.put("/api/drafts/doc.md").text("# Again").await;
+        let deleted = server.delete("/api/drafts/doc.md").await;
+        assert_eq!(deleted.status_code(), 204);
+        assert_eq!(server.get("/api/drafts/doc.md").await.status_code(), 404);
+
+        let untracked = server.put("/api/drafts/other.md").text("# Other").await;
+        assert_eq!(untracked.status_code(), 404);
     }
 
-    #[test]
-    fn test_browsable_host() {
-        assert_eq!(browsable_host("0.0.0.0"), "127.0.0.1");
-        assert_eq!(browsable_host("::"), "::1");
-        assert_eq!(browsable_host("127.0.0.1"), "127.0.0.1");
-        assert_eq!(browsable_host("::1"), "::1");
-        assert_eq!(browsable_host("192.168.1.1"), "192.168.1.1");
-        assert_eq!(browsable_host("localhost"), "localhost");
-        assert_eq!(browsable_host("example.com"), "example.com");
+    #[tokio::test]
+    async fn test_editing_disabled_by_default() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().join("doc.md");
+        fs::write(&file, "# Before").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .file(&file)
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        assert!(!server.get("/doc.md").await.text().contains("id=\"editor\""));
+        let saved = server.put("/api/files/doc.md").text("# After").await;
+        assert!(!saved.status_code().is_success());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "# Before");
+        let draft = server.put("/api/drafts/doc.md").text("# Draft").await;
+        assert!(!draft.status_code().is_success());
     }
 
     #[tokio::test]
-    async fn test_bind_retries_on_addr_in_use() {
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let blocked_port = listener.local_addr().unwrap().port();
+    async fn test_static_files_served_while_state_locked() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("test.md"), "# Image").expect("Failed to write");
+        fs::write(temp_dir.path().join("image.png"), b"png").expect("Failed to write");
 
-        let (retry_listener, actual_port) =
-            bind_with_retry("127.0.0.1", blocked_port).await.unwrap();
+        let (router, handle) = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .build()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
 
-        assert!(
-            actual_port > blocked_port,
-            "Should bind to a higher port when requested port is in use"
-        );
+        // As if a slow render held the lock
+        let _guard = handle.state.write().await;
+        let response = tokio::time::timeout(Duration::from_secs(5), server.get("/image.png"))
+            .await
+            .expect("static request waited on the state lock");
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.as_bytes().as_ref(), b"png");
+    }
 
-        drop(retry_listener);
-        drop(listener);
+    #[tokio::test]
+    async fn test_cached_pages_served_alongside_other_readers() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("test.md"), "# Cached").expect("Failed to write");
+
+        let (router, handle) = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .build()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+        server.get("/test.md").await.assert_status_ok();
+
+        // Another request reading the state doesn't hold up cache hits
+        let _guard = handle.state.read().await;
+        let response = tokio::time::timeout(Duration::from_secs(5), server.get("/test.md"))
+            .await
+            .expect("cached page waited on another reader");
+        assert!(response.text().contains("Cached"));
     }
 
-    use axum_test::TestServer;
-    use std::time::Duration;
-    use tempfile::{Builder, NamedTempFile, TempDir};
+    #[tokio::test]
+    async fn test_non_image_files_not_served() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
 
-    const FILE_WATCH_DELAY_MS: u64 = 100;
-    const WEBSOCKET_TIMEOUT_SECS: u64 = 5;
+        let md_content = "# Test";
+        let md_path = temp_dir.path().join("test.md");
+        fs::write(&md_path, md_content).expect("Failed to write markdown file");
 
-    const TEST_FILE_1_CONTENT: &str = "# Test 1\n\nContent of test1";
-    const TEST_FILE_2_CONTENT: &str = "# Test 2\n\nContent of test2";
-    const TEST_FILE_3_CONTENT: &str = "# Test 3\n\nContent of test3";
-    const YAML_FRONTMATTER_CONTENT: &str =
-        "---\ntitle: Test Post\nauthor: Name\n---\n\n# Test Post\n";
-    const TOML_FRONTMATTER_CONTENT: &str = "+++\ntitle = \"Test Post\"\n+++\n\n# Test Post\n";
+        let txt_path = temp_dir.path().join("secret.txt");
+        fs::write(&txt_path, "secret content").expect("Failed to write txt file");
 
-    fn create_test_server_impl(content: &str, use_http: bool) -> (TestServer, NamedTempFile) {
-        let temp_file = Builder::new()
-            .suffix(".md")
-            .tempfile()
-            .expect("Failed to create temp file");
-        fs::write(&temp_file, content).expect("Failed to write temp file");
+        let router = Mdserve::builder()
+            .file(md_path)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
 
-        let canonical_path = temp_file
-            .path()
-            .canonicalize()
-            .unwrap_or_else(|_| temp_file.path().to_path_buf());
+        let response = server.get("/secret.txt").await;
+        assert_eq!(response.status_code(), 404);
+    }
 
-        let base_dir = canonical_path
-            .parent()
-            .unwrap_or_else(|| std::path::Path::new("."))
-            .to_path_buf();
-        let tracked_files = vec![canonical_path];
-        let is_directory_mode = false;
+    #[test]
+    fn test_is_contained_relative_path() {
+        assert!(is_contained_relative_path(Path::new("image.png")));
+        assert!(is_contained_relative_path(Path::new("images/photo.jpg")));
+        assert!(is_contained_relative_path(Path::new("./images/photo.jpg")));
+
+        assert!(!is_contained_relative_path(Path::new("../secret.png")));
+        assert!(!is_contained_relative_path(Path::new(
+            "images/../../secret.png"
+        )));
+        assert!(!is_contained_relative_path(Path::new("/etc/secret.png")));
+    }
 
-        let router = new_router(base_dir, tracked_files, is_directory_mode)
-            .expect("Failed to create router");
+    #[cfg(unix)]
+    fn create_symlinked_image_server(follow_symlinks: bool) -> (TestServer, TempDir, TempDir) {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let shared_dir = tempdir().expect("Failed to create shared dir");
 
-        let server = if use_http {
-            TestServer::builder()
-                .http_transport()
-                .build(router)
-                .expect("Failed to create test server")
-        } else {
-            TestServer::new(router).expect("Failed to create test server")
-        };
+        let md_path = temp_dir.path().join("test.md");
+        fs::write(&md_path, "![Shared](images/shared.png)").expect("Failed to write markdown");
+        fs::write(shared_dir.path().join("shared.png"), b"png").expect("Failed to write image");
+        std::os::unix::fs::symlink(shared_dir.path(), temp_dir.path().join("images"))
+            .expect("Failed to create symlink");
+
+        let router = Mdserve::builder()
+            .file(md_path)
+            .static_files(StaticFileOptions {
+                follow_symlinks,
+                ..Default::default()
+            })
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
 
-        (server, temp_file)
+        (server, temp_dir, shared_dir)
     }
 
-    async fn create_test_server(content: &str) -> (TestServer, NamedTempFile) {
-        create_test_server_impl(content, false)
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlinked_images_rejected_by_default() {
+        let (server, _temp_dir, _shared_dir) = create_symlinked_image_server(false);
+
+        let response = server.get("/images/shared.png").await;
+        assert_eq!(response.status_code(), 403);
     }
 
-    async fn create_test_server_with_http(content: &str) -> (TestServer, NamedTempFile) {
-        create_test_server_impl(content, true)
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlinked_images_served_with_follow_symlinks() {
+        let (server, _temp_dir, _shared_dir) = create_symlinked_image_server(true);
+
+        let response = server.get("/images/shared.png").await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.header("content-type"), "image/png");
     }
 
-    fn create_directory_server_impl(use_http: bool) -> (TestServer, TempDir) {
+    fn create_static_server(static_options: StaticFileOptions) -> (TestServer, TempDir) {
         let temp_dir = tempdir().expect("Failed to create temp dir");
 
-        fs::write(temp_dir.path().join("test1.md"), TEST_FILE_1_CONTENT)
-            .expect("Failed to write test1.md");
-        fs::write(temp_dir.path().join("test2.markdown"), TEST_FILE_2_CONTENT)
-            .expect("Failed to write test2.markdown");
-        fs::write(temp_dir.path().join("test3.md"), TEST_FILE_3_CONTENT)
-            .expect("Failed to write test3.md");
-
-        let base_dir = temp_dir.path().to_path_buf();
-        let tracked_files = scan_markdown_files(&base_dir).expect("Failed to scan markdown files");
-        let is_directory_mode = true;
+        fs::write(temp_dir.path().join("test.md"), "# Test")
+            .expect("Failed to write markdown file");
+        fs::write(temp_dir.path().join("data.json"), "{}").expect("Failed to write json");
+        fs::write(temp_dir.path().join("notes.txt"), "notes").expect("Failed to write txt");
+        fs::write(temp_dir.path().join(".env"), "SECRET=1").expect("Failed to write dotfile");
 
-        let router = new_router(base_dir, tracked_files, is_directory_mode)
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .static_files(static_options)
+            .build_router()
             .expect("Failed to create router");
-
-        let server = if use_http {
-            TestServer::builder()
-                .http_transport()
-                .build(router)
-                .expect("Failed to create test server")
-        } else {
-            TestServer::new(router).expect("Failed to create test server")
-        };
+        let server = TestServer::new(router).expect("Failed to create test server");
 
         (server, temp_dir)
     }
 
-    async fn create_directory_server() -> (TestServer, TempDir) {
-        create_directory_server_impl(false)
-    }
+    #[tokio::test]
+    async fn test_static_extensions_allowlist() {
+        let (server, _temp_dir) = create_static_server(StaticFileOptions {
+            extensions: vec!["json".to_string()],
+            ..Default::default()
+        });
 
-    async fn create_directory_server_with_http() -> (TestServer, TempDir) {
-        create_directory_server_impl(true)
+        let response = server.get("/data.json").await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.header("content-type"), "application/json");
+
+        let response = server.get("/notes.txt").await;
+        assert_eq!(response.status_code(), 404);
     }
 
     #[tokio::test]
-    async fn test_server_starts_and_serves_basic_markdown() {
-        let (server, _temp_file) =
-            create_test_server("# Hello World\n\nThis is **bold** text.").await;
+    async fn test_files_documents_reference_are_served() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(
+            temp_dir.path().join("test.md"),
+            "[Notes](notes.txt) [Page](page.html) ![Logo](logo.png)\n\n<video src=\"clip.mp4\"></video>",
+        )
+        .expect("Failed to write markdown file");
+        for name in [
+            "notes.txt",
+            "page.html",
+            "clip.mp4",
+            "logo.png",
+            "secret.txt",
+        ] {
+            fs::write(temp_dir.path().join(name), name).expect("Failed to write file");
+        }
 
-        let response = server.get("/").await;
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .static_files(StaticFileOptions {
+                deny_extensions: vec!["png".to_string()],
+                ..Default::default()
+            })
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
 
+        server.get("/test.md").await.assert_status_ok();
+        let response = server.get("/notes.txt").await;
         assert_eq!(response.status_code(), 200);
-        let body = response.text();
+        assert_eq!(response.header("content-type"), "text/plain; charset=utf-8");
+        let response = server.get("/clip.mp4").await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.header("content-type"), "video/mp4");
 
-        assert!(body.contains("<h1>Hello World</h1>"));
-        assert!(body.contains("<strong>bold</strong>"));
-        assert!(body.contains("theme-toggle"));
-        assert!(body.contains("openThemeModal"));
-        assert!(body.contains("--bg-color"));
-        assert!(body.contains("data-theme=\"dark\""));
+        // Unreferenced, active content or denied
+        for path in ["/secret.txt", "/page.html", "/logo.png"] {
+            assert_eq!(server.get(path).await.status_code(), 404, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_all_static_skips_hidden_files() {
+        let (server, _temp_dir) = create_static_server(StaticFileOptions {
+            serve_all: true,
+            ..Default::default()
+        });
+
+        let response = server.get("/notes.txt").await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.text(), "notes");
+
+        let response = server.get("/.env").await;
+        assert_eq!(response.status_code(), 404);
+    }
+
+    fn create_asset_dir_server(watch: bool) -> (TestServer, TempDir, TempDir) {
+        let docs = tempdir().expect("Failed to create temp dir");
+        let shots = tempdir().expect("Failed to create temp dir");
+        fs::write(docs.path().join("test.md"), "![](shots/a.png)").unwrap();
+        fs::write(shots.path().join("a.png"), "png").unwrap();
+        fs::write(shots.path().join("notes.txt"), "notes").unwrap();
+        fs::create_dir(shots.path().join("sub")).unwrap();
+        fs::write(shots.path().join("sub").join("b.png"), "png").unwrap();
+
+        let router = Mdserve::builder()
+            .base_dir(docs.path())
+            .asset_dir(shots.path(), "/shots/")
+            .watch(watch)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+        (server, docs, shots)
+    }
+
+    #[tokio::test]
+    async fn test_asset_dirs_served_under_their_mount() {
+        let (server, _docs, _shots) = create_asset_dir_server(false);
+
+        for path in ["/shots/a.png", "/shots/sub/b.png", "/download/shots/a.png"] {
+            let response = server.get(path).await;
+            assert_eq!(response.status_code(), 200, "{path}");
+            assert_eq!(response.text(), "png");
+        }
+        assert_eq!(server.get("/shots/notes.txt").await.status_code(), 404);
+        assert_eq!(server.get("/shots/missing.png").await.status_code(), 404);
+        assert_eq!(server.get("/a.png").await.status_code(), 404);
+        assert_eq!(
+            server
+                .get("/shots/sub%2F..%2F..%2Fa.png")
+                .await
+                .status_code(),
+            403
+        );
+    }
+
+    #[tokio::test]
+    async fn test_asset_dir_changes_reload() {
+        let (server, _docs, shots) = create_asset_dir_server(true);
+        let mut socket =
+            crate::testing::PreviewSocket(server.get_websocket("/ws").await.into_websocket().await);
+        tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+
+        fs::write(shots.path().join("a.png"), "png2").unwrap();
+        socket.expect_reload().await.unwrap();
+        assert_eq!(server.get("/shots/a.png").await.text(), "png2");
     }
 
-    #[tokio::test]
-    async fn test_websocket_connection() {
-        let (server, _temp_file) = create_test_server_with_http("# WebSocket Test").await;
+    #[test]
+    fn test_rewrite_absolute_images() {
+        let prefixes = vec![(
+            PathBuf::from("/Users/me/Pictures"),
+            "/docs/_abs-images/0".to_string(),
+        )];
+        let rewrite = |html: &str| rewrite_absolute_images(html, &prefixes);
 
-        let response = server.get_websocket("/ws").await;
-        response.assert_status_switching_protocols();
+        assert_eq!(
+            rewrite(r#"<p><img src="/Users/me/Pictures/My%20Diagram.png" alt="d" /></p>"#),
+            r#"<p><img src="/docs/_abs-images/0/My%20Diagram.png" alt="d" /></p>"#
+        );
+        assert_eq!(
+            rewrite(r#"<img alt="x" src="file:///Users/me/Pictures/a/b.png">"#),
+            r#"<img alt="x" src="/docs/_abs-images/0/a/b.png">"#
+        );
+        for unchanged in [
+            r#"<img src="/Users/me/Pictures2/a.png" />"#,
+            r#"<img src="/Users/me/Pictures/../secret.png" />"#,
+            r#"<img src="images/a.png" />"#,
+            r#"<a href="/Users/me/Pictures/a.png">a</a>"#,
+        ] {
+            assert_eq!(rewrite(unchanged), unchanged);
+        }
     }
 
     #[tokio::test]
-    async fn test_file_modification_updates_via_websocket() {
-        let (server, temp_file) = create_test_server_with_http("# Original Content").await;
+    async fn test_absolute_image_references_are_served_when_allowed() {
+        let docs = tempdir().unwrap();
+        let pictures = tempdir().unwrap();
+        let other = tempdir().unwrap();
+        fs::write(pictures.path().join("diagram.png"), "png").unwrap();
+        fs::write(other.path().join("secret.png"), "png").unwrap();
+        fs::write(
+            docs.path().join("test.md"),
+            format!(
+                "![d]({})\n\n![s]({})",
+                pictures.path().join("diagram.png").display(),
+                other.path().join("secret.png").display()
+            ),
+        )
+        .unwrap();
+
+        let router = Mdserve::builder()
+            .base_dir(docs.path())
+            .allow_absolute_images(pictures.path())
+            .watch(false)
+            .build_router()
+            .unwrap();
+        let server = TestServer::new(router).unwrap();
+
+        let body = server.get("/test.md").await.text();
+        assert!(body.contains(r#"<img src="/_abs-images/0/diagram.png""#));
+        assert!(body.contains(&format!(
+            r#"<img src="{}""#,
+            other.path().join("secret.png").display()
+        )));
+        assert_eq!(server.get("/_abs-images/0/diagram.png").await.text(), "png");
+    }
 
-        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+    #[test]
+    fn test_invalid_asset_mounts_are_rejected() {
+        let docs = tempdir().unwrap();
+        fs::write(docs.path().join("test.md"), "# Test").unwrap();
+        let build = |mounts: &[&str]| {
+            mounts
+                .iter()
+                .fold(
+                    Mdserve::builder().base_dir(docs.path()),
+                    |builder, mount| builder.asset_dir(docs.path(), *mount),
+                )
+                .watch(false)
+                .into_config()
+        };
 
-        fs::write(&temp_file, "# Modified Content").expect("Failed to modify file");
+        assert!(build(&["img", "img/icons"]).is_ok());
+        for mounts in [&["api"][..], &["/"], &["../up"], &["img", "img/"]] {
+            assert!(build(mounts).is_err(), "{mounts:?}");
+        }
+        let missing = Mdserve::builder()
+            .base_dir(docs.path())
+            .asset_dir(docs.path().join("missing"), "img")
+            .build_router();
+        assert!(missing.is_err());
+    }
 
-        tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+    #[tokio::test]
+    async fn test_download_markdown_source() {
+        let (server, _temp_file) = create_test_server("# Download Me").await;
+
+        let body = server.get("/").await.text();
+        let current_file = body
+            .split("href=\"/download/")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .expect("download link should be rendered")
+            .to_string();
+
+        let response = server.get(&format!("/download/{current_file}")).await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(
+            response.header("content-type"),
+            "text/markdown; charset=utf-8"
+        );
+        assert!(response
+            .header("content-disposition")
+            .to_str()
+            .unwrap()
+            .starts_with("attachment;"));
+        assert_eq!(response.text(), "# Download Me");
+    }
 
-        let update_result = tokio::time::timeout(
-            Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
-            websocket.receive_json::<ServerMessage>(),
-        )
-        .await;
+    #[tokio::test]
+    async fn test_raw_markdown_source() {
+        let (server, _temp_dir) = create_static_server(StaticFileOptions::default());
 
-        update_result.expect("Timeout waiting for WebSocket update after file modification");
+        let response = server.get("/raw/test.md").await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(
+            response.header("content-type"),
+            "text/markdown; charset=utf-8"
+        );
+        assert!(response.maybe_header("content-disposition").is_none());
+        assert_eq!(response.text(), "# Test");
+
+        // Only tracked documents, not static files
+        assert_eq!(server.get("/raw/data.json").await.status_code(), 404);
+        assert_eq!(server.get("/raw/missing.md").await.status_code(), 404);
     }
 
     #[tokio::test]
-    async fn test_server_handles_gfm_features() {
-        let markdown_content = r#"# GFM Test
+    async fn test_download_respects_static_policy() {
+        let (server, _temp_dir) = create_static_server(StaticFileOptions {
+            extensions: vec!["json".to_string()],
+            ..Default::default()
+        });
 
-## Table
-| Name | Age |
-|------|-----|
-| John | 30  |
-| Jane | 25  |
+        let response = server.get("/download/data.json").await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(
+            response.header("content-disposition"),
+            "attachment; filename=\"data.json\"; filename*=UTF-8''data.json"
+        );
 
-## Strikethrough
-~~deleted text~~
+        let response = server.get("/download/notes.txt").await;
+        assert_eq!(response.status_code(), 404);
 
-## Code block
-```rust
-fn main() {
-    println!("Hello!");
-}
-```
-"#;
+        let response = server.get("/download/.env").await;
+        assert_eq!(response.status_code(), 404);
+    }
 
-        let (server, _temp_file) = create_test_server(markdown_content).await;
+    #[tokio::test]
+    async fn test_share_link_grants_single_document() {
+        let (server, _temp_dir) = create_directory_server().await;
 
-        let response = server.get("/").await;
+        let response = server
+            .post("/api/share")
+            .json(&serde_json::json!({ "file": "test1.md" }))
+            .await;
+        assert_eq!(response.status_code(), 200);
+        let share: serde_json::Value = response.json();
+        let path = share["path"].as_str().unwrap();
+        assert!(path.starts_with("/share/"));
+        assert!(path.ends_with("/test1.md"));
 
+        let response = server.get(path).await;
         assert_eq!(response.status_code(), 200);
         let body = response.text();
+        assert!(body.contains("Content of test1"));
+        assert!(!body.contains(r#"<nav class="sidebar">"#));
 
-        assert!(body.contains("<table>"));
-        assert!(body.contains("<th>Name</th>"));
-        assert!(body.contains("<td>John</td>"));
-        assert!(body.contains("<del>deleted text</del>"));
-        assert!(body.contains("<pre>"));
-        assert!(body.contains("fn main()"));
+        let other = path.replace("/test1.md", "/test2.markdown");
+        let response = server.get(&other).await;
+        assert_eq!(response.status_code(), 404);
+
+        // Other sites can neither mint links nor read them back
+        let response = server
+            .post("/api/share")
+            .add_header(
+                header::ORIGIN,
+                HeaderValue::from_static("http://evil.example"),
+            )
+            .json(&serde_json::json!({ "file": "test1.md" }))
+            .await;
+        assert_eq!(response.status_code(), 403);
+        assert!(response
+            .maybe_header(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
     }
 
     #[tokio::test]
-    async fn test_404_for_unknown_routes() {
-        let (server, _temp_file) = create_test_server("# 404 Test").await;
-
-        let response = server.get("/unknown-route").await;
+    async fn test_share_button_keeps_file_name_out_of_script() {
+        let name = "x');alert(1);('.md";
+        let preview = crate::testing::TestPreview::new(&[(name, "# X")]).unwrap();
 
-        assert_eq!(response.status_code(), 404);
+        let body = preview.server.get(&format!("/{name}")).await.text();
+        assert!(body.contains(r#"id="shareButton" data-file="x&#x27;);alert(1);(&#x27;.md""#));
+        assert!(!body.contains("onclick=\"copyShareLink"));
     }
 
     #[tokio::test]
-    async fn test_image_serving() {
-        let temp_dir = tempdir().expect("Failed to create temp dir");
+    async fn test_share_link_serves_only_referenced_assets() {
+        let preview = crate::testing::TestPreview::with_builder(
+            &[
+                ("a.md", "# A\n\n![used](used.png)"),
+                ("used.png", "png"),
+                ("other.png", "png"),
+            ],
+            |builder| builder.access_token("s3cret"),
+        )
+        .unwrap();
+        let server = &preview.server;
 
-        let md_content =
-            "# Test with Image\n\n![Test Image](test.png)\n\nThis markdown references an image.";
-        let md_path = temp_dir.path().join("test.md");
-        fs::write(&md_path, md_content).expect("Failed to write markdown file");
+        let share: serde_json::Value = server
+            .post("/api/share")
+            .add_header(
+                header::AUTHORIZATION,
+                HeaderValue::from_static("Bearer s3cret"),
+            )
+            .json(&serde_json::json!({ "file": "a.md" }))
+            .await
+            .json();
+        let page = share["path"].as_str().unwrap();
+        assert_eq!(server.get(page).await.status_code(), 200);
+
+        let asset = |name: &str| page.replace("/a.md", &format!("/{name}"));
+        assert_eq!(server.get(&asset("used.png")).await.text(), "png");
+        // Servable on its own, but not part of the shared document
+        assert_eq!(server.get(&asset("other.png")).await.status_code(), 404);
+    }
 
-        let png_data = vec![
-            0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48,
-            0x44, 0x52, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x00, 0x00,
-            0x00, 0x90, 0x77, 0x53, 0xDE, 0x00, 0x00, 0x00, 0x0C, 0x49, 0x44, 0x41, 0x54, 0x08,
-            0xD7, 0x63, 0xF8, 0x0F, 0x00, 0x00, 0x01, 0x00, 0x01, 0x5C, 0xDD, 0x8D, 0xB4, 0x00,
-            0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82,
-        ];
-        let img_path = temp_dir.path().join("test.png");
-        fs::write(&img_path, png_data).expect("Failed to write image file");
+    #[tokio::test]
+    async fn test_share_link_only_opens_on_its_own_root() {
+        let files = [("a.md", "# A")];
+        let ours =
+            crate::testing::TestPreview::with_builder(&files, |b| b.share_secret("k")).unwrap();
+        let theirs =
+            crate::testing::TestPreview::with_builder(&files, |b| b.share_secret("k")).unwrap();
+
+        let share: serde_json::Value = ours
+            .server
+            .post("/api/share")
+            .json(&serde_json::json!({ "file": "a.md" }))
+            .await
+            .json();
+        let page = share["path"].as_str().unwrap();
+        assert_eq!(ours.server.get(page).await.status_code(), 200);
+        // Same user secret, same file name, another served tree
+        assert_eq!(theirs.server.get(page).await.status_code(), 403);
+    }
 
-        let base_dir = temp_dir.path().to_path_buf();
-        let tracked_files = vec![md_path];
-        let is_directory_mode = false;
-        let router = new_router(base_dir, tracked_files, is_directory_mode)
-            .expect("Failed to create router");
-        let server = TestServer::new(router).expect("Failed to create test server");
+    #[tokio::test]
+    async fn test_share_link_rejects_invalid_token() {
+        let (server, _temp_dir) = create_directory_server().await;
 
-        let response = server.get("/").await;
-        assert_eq!(response.status_code(), 200);
-        let body = response.text();
-        assert!(body.contains("<img src=\"test.png\" alt=\"Test Image\""));
+        let response = server
+            .get("/share/dGVzdDEubWQ.9999999999.bad/test1.md")
+            .await;
+        assert_eq!(response.status_code(), 403);
 
-        let img_response = server.get("/test.png").await;
-        assert_eq!(img_response.status_code(), 200);
-        assert_eq!(img_response.header("content-type"), "image/png");
-        assert!(!img_response.as_bytes().is_empty());
+        let response = server
+            .post("/api/share")
+            .json(&serde_json::json!({ "file": "missing.md" }))
+            .await;
+        assert_eq!(response.status_code(), 404);
     }
 
     #[tokio::test]
-    async fn test_non_image_files_not_served() {
+    async fn test_credentials_are_required_except_for_share_links() {
         let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("test.md"), "# Private").expect("Failed to write");
+        fs::create_dir(temp_dir.path().join("assets")).expect("Failed to create dir");
+        fs::write(
+            temp_dir.path().join("assets").join("mermaid-notes.md"),
+            "# Private",
+        )
+        .expect("Failed to write");
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .recursive(true)
+            .basic_auth("ada", "pa:ss")
+            .access_token("s3cret")
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
 
-        let md_content = "# Test";
-        let md_path = temp_dir.path().join("test.md");
-        fs::write(&md_path, md_content).expect("Failed to write markdown file");
+        let response = server.get("/test.md").await;
+        assert_eq!(response.status_code(), 401);
+        assert!(response
+            .header(header::WWW_AUTHENTICATE)
+            .to_str()
+            .unwrap()
+            .starts_with("Basic"));
+        assert_eq!(server.get("/ws").await.status_code(), 401);
+        assert_eq!(
+            server.get("/raw/test.md?token=wrong").await.status_code(),
+            401
+        );
+        // Only the exact public routes are open, not files that look like them
+        for path in [
+            "/assets/mermaid-notes.md",
+            "/share/forged/test.md",
+            "/api/instance",
+        ] {
+            assert_eq!(server.get(path).await.status_code(), 401, "{path}");
+        }
+        for path in [
+            "/mermaid.min.js".to_string(),
+            format!("/assets/mermaid-{MERMAID_VERSION}.min.js"),
+        ] {
+            assert_eq!(server.get(&path).await.status_code(), 200, "{path}");
+        }
 
-        let txt_path = temp_dir.path().join("secret.txt");
-        fs::write(&txt_path, "secret content").expect("Failed to write txt file");
+        let basic = |credentials: &str| {
+            HeaderValue::from_str(&format!("Basic {}", STANDARD.encode(credentials))).unwrap()
+        };
+        let response = server
+            .get("/test.md")
+            .add_header(header::AUTHORIZATION, basic("ada:pa:ss"))
+            .await;
+        assert!(response.text().contains("Private</h1>"));
+        let response = server
+            .get("/test.md")
+            .add_header(header::AUTHORIZATION, basic("ada:wrong"))
+            .await;
+        assert_eq!(response.status_code(), 401);
 
-        let base_dir = temp_dir.path().to_path_buf();
-        let tracked_files = vec![md_path];
-        let is_directory_mode = false;
-        let router = new_router(base_dir, tracked_files, is_directory_mode)
-            .expect("Failed to create router");
-        let server = TestServer::new(router).expect("Failed to create test server");
+        let response = server
+            .post("/api/share")
+            .add_header(
+                header::AUTHORIZATION,
+                HeaderValue::from_static("Bearer s3cret"),
+            )
+            .json(&serde_json::json!({ "file": "test.md" }))
+            .await;
+        let share: serde_json::Value = response.json();
+        let response = server.get(share["path"].as_str().unwrap()).await;
+        assert!(response.text().contains("Private</h1>"));
 
-        let response = server.get("/secret.txt").await;
-        assert_eq!(response.status_code(), 404);
+        // A token in the URL is remembered for the page's other requests
+        let response = server.get("/test.md?token=s3cret").await;
+        assert_eq!(response.status_code(), 200);
+        let cookie = response.header(header::SET_COOKIE);
+        let cookie = cookie.to_str().unwrap().split(';').next().unwrap();
+        let response = server
+            .get("/raw/test.md")
+            .add_header(header::COOKIE, HeaderValue::from_str(cookie).unwrap())
+            .await;
+        assert_eq!(response.text(), "# Private");
     }
 
     #[tokio::test]
@@ -1166,7 +5294,9 @@ console.log("Hello World");
             "Expected mermaid content not found in body"
         );
 
-        assert!(body.contains(r#"<script src="/mermaid.min.js"></script>"#));
+        assert!(body.contains(&format!(
+            r#"<script src="/assets/mermaid-{MERMAID_VERSION}.min.js"></script>"#
+        )));
         assert!(body.contains("function initMermaid()"));
         assert!(body.contains("function transformMermaidCodeBlocks()"));
         assert!(body.contains("function getMermaidTheme()"));
@@ -1247,7 +5377,9 @@ classDiagram
         assert!(body.contains("Animal &lt;|-- Duck") || body.contains("Animal <|-- Duck"));
 
         let script_occurrences = body
-            .matches(r#"<script src="/mermaid.min.js"></script>"#)
+            .matches(&format!(
+                r#"<script src="/assets/mermaid-{MERMAID_VERSION}.min.js"></script>"#
+            ))
             .count();
         assert_eq!(script_occurrences, 1);
     }
@@ -1296,6 +5428,167 @@ classDiagram
         assert!(!response_200.as_bytes().is_empty());
     }
 
+    #[tokio::test]
+    async fn test_mermaid_js_served_precompressed() {
+        use std::io::Read;
+
+        let (server, _temp_file) = create_test_server("# Test").await;
+        for path in [
+            "/mermaid.min.js".to_string(),
+            format!("/assets/mermaid-{MERMAID_VERSION}.min.js"),
+        ] {
+            let response = server
+                .get(&path)
+                .add_header(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"))
+                .await;
+            assert_eq!(response.status_code(), 200);
+            assert_eq!(response.header("content-encoding"), "gzip");
+            assert_eq!(response.header("vary"), "accept-encoding");
+            let mut decoded = String::new();
+            flate2::read::GzDecoder::new(response.as_bytes().as_ref())
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, MERMAID_JS);
+
+            let response = server
+                .get(&path)
+                .add_header(
+                    header::ACCEPT_ENCODING,
+                    HeaderValue::from_static("gzip, deflate, br"),
+                )
+                .await;
+            assert_eq!(response.header("content-encoding"), "br");
+            let mut decoded = String::new();
+            brotli::Decompressor::new(response.as_bytes().as_ref(), 4096)
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, MERMAID_JS);
+
+            let plain = server.get(&path).await;
+            assert!(plain.maybe_header("content-encoding").is_none());
+            assert_eq!(plain.as_bytes().len(), MERMAID_JS.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_math_loads_katex_only_with_math() {
+        use std::io::Read;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("math.md"), "# Math\n\nArea is $x^2$.").unwrap();
+        fs::write(temp_dir.path().join("plain.md"), "# Plain").unwrap();
+        let server = |math| {
+            let router = Mdserve::builder()
+                .base_dir(temp_dir.path())
+                .render_options(RenderOptions {
+                    math,
+                    ..Default::default()
+                })
+                .access_token("s3cret")
+                .watch(false)
+                .build_router()
+                .expect("Failed to create router");
+            TestServer::new(router).expect("Failed to create test server")
+        };
+        // Guards against upgrading the bundle without bumping its URL
+        assert!(KATEX_JS.contains(&format!(r#"version:"{KATEX_VERSION}""#)));
+        let katex_src = format!("/assets/katex-{KATEX_VERSION}.min.js");
+        let script = format!(r#"<script src="{katex_src}"></script>"#);
+
+        let client = server(MathMode::Client);
+        let page = client.get("/math.md?token=s3cret").await.text();
+        assert!(page.contains(&script));
+        assert!(page.contains(r#"<code class="language-math math-inline">x^2</code>"#));
+        let page = client.get("/plain.md?token=s3cret").await.text();
+        assert!(!page.contains(&script));
+
+        // Served without credentials, like Mermaid, and precompressed
+        let response = client
+            .get(&katex_src)
+            .add_header(header::ACCEPT_ENCODING, HeaderValue::from_static("br"))
+            .await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.header("content-encoding"), "br");
+        assert!(response
+            .header("cache-control")
+            .to_str()
+            .unwrap()
+            .contains("immutable"));
+        let mut decoded = String::new();
+        brotli::Decompressor::new(response.as_bytes().as_ref(), 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, KATEX_JS);
+
+        // Math rendered on the server needs no script
+        let page = server(MathMode::Server)
+            .get("/math.md?token=s3cret")
+            .await
+            .text();
+        assert!(page.contains("<msup><mi>x</mi><mn>2</mn></msup>"));
+        assert!(!page.contains(&script));
+    }
+
+    #[tokio::test]
+    async fn test_pages_are_compressed_unless_disabled() {
+        use std::io::Read;
+
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("test.md"), "# Compressed\n\nHello").unwrap();
+        fs::write(temp_dir.path().join("chart.png"), "png".repeat(100)).unwrap();
+        let get = |compression: bool, path: &'static str| {
+            let router = Mdserve::builder()
+                .base_dir(temp_dir.path())
+                .compression(compression)
+                .watch(false)
+                .build_router()
+                .expect("Failed to create router");
+            async move {
+                TestServer::new(router)
+                    .expect("Failed to create test server")
+                    .get(path)
+                    .add_header(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"))
+                    .await
+            }
+        };
+
+        let response = get(true, "/test.md").await;
+        assert_eq!(response.header("content-encoding"), "gzip");
+        let mut page = String::new();
+        flate2::read::GzDecoder::new(response.as_bytes().as_ref())
+            .read_to_string(&mut page)
+            .unwrap();
+        assert!(page.contains("Compressed</h1>"));
+
+        // Images are compressed already
+        let response = get(true, "/chart.png").await;
+        assert!(response.maybe_header("content-encoding").is_none());
+
+        let response = get(false, "/test.md").await;
+        assert!(response.maybe_header("content-encoding").is_none());
+        assert!(response.text().contains("Compressed</h1>"));
+    }
+
+    #[tokio::test]
+    async fn test_versioned_mermaid_js_is_immutable() {
+        // Guards against upgrading the bundle without bumping its URL
+        assert!(MERMAID_JS.contains(&format!(r#"version:"{MERMAID_VERSION}""#)));
+
+        let (server, _temp_file) = create_test_server("# Test").await;
+        let response = server
+            .get(&format!("/assets/mermaid-{MERMAID_VERSION}.min.js"))
+            .await;
+
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.header("content-type"), "application/javascript");
+        assert!(response
+            .header("cache-control")
+            .to_str()
+            .unwrap()
+            .contains("immutable"));
+        assert_eq!(response.as_bytes().len(), MERMAID_JS.len());
+    }
+
     #[tokio::test]
     async fn test_directory_mode_serves_multiple_files() {
         let (server, _temp_dir) = create_directory_server().await;
@@ -1303,19 +5596,19 @@ classDiagram
         let response1 = server.get("/test1.md").await;
         assert_eq!(response1.status_code(), 200);
         let body1 = response1.text();
-        assert!(body1.contains("<h1>Test 1</h1>"));
+        assert!(body1.contains("<h1 id=\"test-1\">Test 1</h1>"));
         assert!(body1.contains("Content of test1"));
 
         let response2 = server.get("/test2.markdown").await;
         assert_eq!(response2.status_code(), 200);
         let body2 = response2.text();
-        assert!(body2.contains("<h1>Test 2</h1>"));
+        assert!(body2.contains("<h1 id=\"test-2\">Test 2</h1>"));
         assert!(body2.contains("Content of test2"));
 
         let response3 = server.get("/test3.md").await;
         assert_eq!(response3.status_code(), 200);
         let body3 = response3.text();
-        assert!(body3.contains("<h1>Test 3</h1>"));
+        assert!(body3.contains("<h1 id=\"test-3\">Test 3</h1>"));
         assert!(body3.contains("Content of test3"));
     }
 
@@ -1342,6 +5635,89 @@ classDiagram
         assert!(body.contains("test3.md"));
     }
 
+    #[tokio::test]
+    async fn test_sidebar_titles_label_documents() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(
+            temp_dir.path().join("a.md"),
+            "---\ntitle: Getting Started\n---\n\n# Intro\n",
+        )
+        .expect("Failed to write");
+        fs::write(
+            temp_dir.path().join("b.md"),
+            "Some *notes*\n\n# Release `v2` Notes\n",
+        )
+        .expect("Failed to write");
+        fs::write(temp_dir.path().join("c.md"), "no heading here\n").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .sidebar_titles(true)
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::new(router).unwrap();
+
+        let body = server.get("/c.md").await.text();
+        assert!(body.contains("Getting Started"));
+        assert!(body.contains("Release v2 Notes"));
+        assert!(body.contains(
+            r#"class="active">
+                    c.md"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sidebar_titles_follow_changes() {
+        let preview = crate::testing::TestPreview::with_builder(
+            &[("a.md", "# Zebra Plan\n"), ("b.md", "# Other\n")],
+            |builder| builder.sidebar_titles(true),
+        )
+        .unwrap();
+
+        assert!(preview
+            .server
+            .get("/b.md")
+            .await
+            .text()
+            .contains("Zebra Plan"));
+        preview.write("a.md", "# Yak Plan\n").await.unwrap();
+        preview.write("c.md", "# Xerus Plan\n").await.unwrap();
+
+        let body = preview.server.get("/b.md").await.text();
+        assert!(body.contains("Yak Plan"));
+        assert!(!body.contains("Zebra Plan"));
+        assert!(body.contains("Xerus Plan"));
+    }
+
+    #[tokio::test]
+    async fn test_wikilinks_follow_the_file_list() {
+        let preview = crate::testing::TestPreview::with_builder(
+            &[("index.md", "See [[Release Notes|the notes]].\n")],
+            |builder| builder.wikilinks(true),
+        )
+        .unwrap();
+
+        let body = preview.server.get("/index.md").await.text();
+        assert!(body.contains(r#"<span class="wikilink wikilink-missing""#));
+
+        preview
+            .write("release-notes.md", "# Notes\n")
+            .await
+            .unwrap();
+        let body = preview.server.get("/index.md").await.text();
+        assert!(body.contains(r#"<a href="release-notes.md" class="wikilink">the notes</a>"#));
+    }
+
+    #[tokio::test]
+    async fn test_headings_have_copyable_anchors() {
+        let (server, _temp_file) = create_test_server("# Design\n\n## API Surface\n").await;
+
+        let body = server.get("/").await.text();
+        assert!(body.contains(r#"<h2 id="api-surface">API Surface</h2>"#));
+        assert!(body.contains("function addHeadingAnchors()"));
+    }
+
     #[tokio::test]
     async fn test_single_file_mode_no_navigation_sidebar() {
         let (server, _temp_file) = create_test_server("# Single File Test").await;
@@ -1355,6 +5731,27 @@ classDiagram
         assert!(!body.contains(r#"<ul class="file-list">"#));
     }
 
+    #[tokio::test]
+    async fn test_toc_outlines_current_document() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().join("guide.md");
+        fs::write(&file, "# Guide\n\n## Install & Setup\n\ntext\n").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .file(&file)
+            .toc(true)
+            .watch(false)
+            .build_router()
+            .expect("Failed to create router");
+        let body = TestServer::new(router).unwrap().get("/").await.text();
+
+        assert!(body.contains(r#"<nav class="sidebar">"#));
+        assert!(!body.contains(r#"<ul class="file-list">"#));
+        assert!(body.contains(
+            r##"<li class="toc-level-2"><a href="#install--setup">Install &amp; Setup</a></li>"##
+        ));
+    }
+
     #[tokio::test]
     async fn test_directory_mode_active_file_highlighting() {
         let (server, _temp_dir) = create_directory_server().await;
@@ -1405,6 +5802,142 @@ classDiagram
         );
     }
 
+    fn create_recursive_server() -> (TestServer, TempDir) {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("README.md"), "# Root").expect("Failed to write");
+        let guides = temp_dir.path().join("guides");
+        fs::create_dir(&guides).expect("Failed to create subdir");
+        fs::write(guides.join("setup.md"), "# Setup Guide").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .recursive(true)
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+
+        (server, temp_dir)
+    }
+
+    #[tokio::test]
+    async fn test_recursive_mode_serves_nested_files_grouped_by_folder() {
+        let (server, _temp_dir) = create_recursive_server();
+
+        let response = server.get("/guides/setup.md").await;
+        assert_eq!(response.status_code(), 200);
+        let body = response.text();
+        assert!(body.contains("Setup Guide</h1>"));
+        assert!(body.contains(r#"<details class="file-folder" data-folder="guides" open>"#));
+        assert!(body.contains("<summary>guides/</summary>"));
+
+        let root_pos = body.find("README.md").expect("README.md not found");
+        let folder_pos = body.find("file-folder\"").expect("folder not found");
+        assert!(
+            root_pos < folder_pos,
+            "top-level files should come before folders"
+        );
+
+        assert_eq!(server.get("/setup.md").await.status_code(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_recursive_mode_new_nested_file_triggers_reload() {
+        let (server, temp_dir) = create_recursive_server();
+
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+
+        let reference = temp_dir.path().join("guides").join("reference");
+        fs::create_dir(&reference).expect("Failed to create subdir");
+        fs::write(reference.join("api.md"), "# API").expect("Failed to write");
+
+        tokio::time::timeout(
+            Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
+            websocket.receive_json::<ServerMessage>(),
+        )
+        .await
+        .expect("Timeout waiting for WebSocket update after new nested file");
+
+        let response = server.get("/guides/reference/api.md").await;
+        assert_eq!(response.status_code(), 200);
+        assert!(response.text().contains("API</h1>"));
+    }
+
+    #[tokio::test]
+    async fn test_extra_paths_are_mounted_and_watched() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let root = temp_dir.path().canonicalize().unwrap();
+        for dir in ["docs", "notes", "other/docs"] {
+            fs::create_dir_all(root.join(dir)).expect("Failed to create dir");
+        }
+        fs::write(root.join("a.md"), "# A").expect("Failed to write");
+        fs::write(root.join("b.md"), "# B").expect("Failed to write");
+        fs::write(root.join("docs/guide.md"), "# Guide\n\n![](shot.png)").unwrap();
+        fs::write(root.join("docs/shot.png"), b"png").expect("Failed to write");
+        fs::write(root.join("notes/todo.md"), "# Todo").expect("Failed to write");
+        fs::write(root.join("notes/private.md"), "# Private").expect("Failed to write");
+        fs::write(root.join("other/docs/guide.md"), "# Other").expect("Failed to write");
+
+        let router = Mdserve::builder()
+            .file(root.join("a.md"))
+            .extra_paths([
+                root.join("b.md"),
+                root.join("docs"),
+                root.join("notes/todo.md"),
+                root.join("other/docs"),
+            ])
+            .build_router()
+            .expect("Failed to create router");
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .expect("Failed to create test server");
+
+        let body = server.get("/a.md").await.text();
+        assert!(body.contains("b.md"));
+        for folder in ["docs", "notes", "docs-2"] {
+            let header = format!("<summary>{folder}/</summary>");
+            assert!(body.contains(&header), "sidebar is missing {folder}");
+        }
+        assert!(server
+            .get("/docs/guide.md")
+            .await
+            .text()
+            .contains("Guide</h1>"));
+        assert!(server
+            .get("/docs-2/guide.md")
+            .await
+            .text()
+            .contains("Other</h1>"));
+        assert_eq!(
+            server.get("/docs/shot.png").await.as_bytes().as_ref(),
+            b"png"
+        );
+        // Only the given file of a directory is served
+        assert_eq!(server.get("/notes/private.md").await.status_code(), 404);
+
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        tokio::time::sleep(Duration::from_millis(FILE_WATCH_DELAY_MS)).await;
+        fs::write(root.join("docs/new.md"), "# New").expect("Failed to write");
+        tokio::time::timeout(
+            Duration::from_secs(WEBSOCKET_TIMEOUT_SECS),
+            websocket.receive_json::<ServerMessage>(),
+        )
+        .await
+        .expect("Timeout waiting for WebSocket update after new mounted file");
+        assert!(server.get("/docs/new.md").await.text().contains("New</h1>"));
+
+        let clash = Mdserve::builder()
+            .file(root.join("a.md"))
+            .mount(root.join("docs"), "n")
+            .mount(root.join("notes"), "n/")
+            .build_router();
+        assert!(clash.is_err());
+    }
+
     #[tokio::test]
     async fn test_directory_mode_websocket_file_modification() {
         let (server, temp_dir) = create_directory_server_with_http().await;
@@ -1457,7 +5990,7 @@ classDiagram
         let new_file_response = server.get("/test4.md").await;
         assert_eq!(new_file_response.status_code(), 200);
         let new_file_body = new_file_response.text();
-        assert!(new_file_body.contains("<h1>Test 4</h1>"));
+        assert!(new_file_body.contains("<h1 id=\"test-4\">Test 4</h1>"));
         assert!(new_file_body.contains("This is a new file"));
     }
 
@@ -1592,7 +6125,7 @@ classDiagram
 
         assert!(!body.contains("title: Test Post"));
         assert!(!body.contains("author: Name"));
-        assert!(body.contains("<h1>Test Post</h1>"));
+        assert!(body.contains("<h1 id=\"test-post\">Test Post</h1>"));
     }
 
     #[tokio::test]
@@ -1605,7 +6138,7 @@ classDiagram
         let body = response.text();
 
         assert!(!body.contains("title = \"Test Post\""));
-        assert!(body.contains("<h1>Test Post</h1>"));
+        assert!(body.contains("<h1 id=\"test-post\">Test Post</h1>"));
     }
 
     #[tokio::test]
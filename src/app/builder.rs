@@ -0,0 +1,1048 @@
+//! [`Mdserve`] and the builder embedders describe what to serve with, then
+//! turn into a router, a running server or a static export.
+
+use anyhow::{Context, Result};
+use axum::Router;
+use std::{
+    collections::{HashSet, VecDeque},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    auth::{self, AccessControl},
+    export,
+    frontmatter::Metadata,
+    highlight::HighlightTheme,
+    lint::{LintRules, LintStage},
+    paths,
+    render::{Diagnostic, MathMode, RenderOptions, RenderPipeline, RenderStage, MERMAID_FEATURE},
+    server::ConnectionLimits,
+    share,
+    template::{FileTemplate, MiniJinjaTemplate, PageContext, PageTemplate},
+    tls::TlsCertificate,
+    tunnel::TunnelProvider,
+    watch::{ChangeSource, FileEvent, NotifySource},
+};
+
+use super::{
+    default_index, document_name,
+    handlers::{linked_document_path, mermaid_config, page_title, resolve_static_path},
+    is_contained_relative_path, is_markdown_file, new_router, normalize_mount,
+    rewrite_absolute_images, scan_markdown_files, scan_markdown_files_recursive, spawn_server,
+    split_folder, unique_mount, AssetMount, CacheLimits, DocumentMount, FileEventHook, HtmlMapper,
+    MarkdownState, RouterConfig, ServerHandle, StateHandle, StaticFileOptions, UploadOptions,
+    ABSOLUTE_IMAGES_MOUNT, KATEX_JS, KATEX_VERSION, MAX_PORT_ATTEMPTS, MERMAID_JS, MERMAID_VERSION,
+};
+
+/// Listening callback registered with [`MdserveBuilder::on_listening`].
+type ListeningHook = Box<dyn FnOnce(SocketAddr) + Send>;
+
+const DEFAULT_MESSAGE_BUFFER: usize = 16;
+const DEFAULT_MAX_FILE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Entry point for embedding mdserve in another application.
+///
+/// Use [`Mdserve::builder`] to describe what to serve, then build an axum
+/// [`Router`] to mount or serve yourself.
+pub struct Mdserve;
+
+impl Mdserve {
+    pub fn builder() -> MdserveBuilder {
+        MdserveBuilder::default()
+    }
+}
+
+/// Builder for the mdserve router.
+///
+/// Set either [`base_dir`](Self::base_dir) to serve every markdown file in a
+/// directory (with navigation), or [`file`](Self::file)/[`files`](Self::files)
+/// to serve specific files. Everything else has defaults matching the CLI.
+pub struct MdserveBuilder {
+    base_dir: Option<PathBuf>,
+    files: Option<Vec<PathBuf>>,
+    directory_mode: Option<bool>,
+    recursive: bool,
+    template: Option<TemplateChoice>,
+    render_options: RenderOptions,
+    highlight_theme: HighlightTheme,
+    mermaid_config: Metadata,
+    toc: bool,
+    sidebar_titles: bool,
+    wikilinks: bool,
+    scroll_sync: bool,
+    pipeline: RenderPipeline,
+    html_mappers: Vec<HtmlMapper>,
+    static_options: StaticFileOptions,
+    asset_mounts: Vec<AssetMount>,
+    /// Further markdown paths, with their mount if one was chosen.
+    extra_paths: Vec<(PathBuf, Option<String>)>,
+    absolute_image_dirs: Vec<PathBuf>,
+    pub(super) share_secret: Option<Vec<u8>>,
+    access: AccessControl,
+    pub(super) public_tunnel: Option<TunnelProvider>,
+    pub(super) mdns: bool,
+    pub(super) copy_url: bool,
+    watch: bool,
+    change_source: Option<Box<dyn ChangeSource>>,
+    file_event_hooks: Vec<FileEventHook>,
+    base_path: Option<String>,
+    cache_limits: CacheLimits,
+    message_buffer: usize,
+    max_file_size: usize,
+    connection_limits: ConnectionLimits,
+    compression: bool,
+    pub(super) tls: Option<TlsCertificate>,
+    port_fallbacks: u16,
+    pub(super) on_listening: Option<ListeningHook>,
+    edit: bool,
+    allow_write: bool,
+    upload_options: UploadOptions,
+    lint_rules: LintRules,
+    index: Option<String>,
+    redirect_index: bool,
+}
+
+impl Default for MdserveBuilder {
+    fn default() -> Self {
+        Self {
+            base_dir: None,
+            files: None,
+            directory_mode: None,
+            recursive: false,
+            template: None,
+            render_options: RenderOptions::default(),
+            highlight_theme: HighlightTheme::default(),
+            mermaid_config: Metadata::new(),
+            toc: false,
+            sidebar_titles: false,
+            wikilinks: false,
+            scroll_sync: false,
+            pipeline: RenderPipeline::default(),
+            html_mappers: Vec::new(),
+            static_options: StaticFileOptions::default(),
+            asset_mounts: Vec::new(),
+            extra_paths: Vec::new(),
+            absolute_image_dirs: Vec::new(),
+            share_secret: None,
+            access: AccessControl::default(),
+            public_tunnel: None,
+            mdns: false,
+            copy_url: false,
+            watch: true,
+            change_source: None,
+            file_event_hooks: Vec::new(),
+            base_path: None,
+            cache_limits: CacheLimits::default(),
+            message_buffer: DEFAULT_MESSAGE_BUFFER,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            connection_limits: ConnectionLimits::default(),
+            compression: true,
+            tls: None,
+            port_fallbacks: MAX_PORT_ATTEMPTS - 1,
+            on_listening: None,
+            edit: false,
+            allow_write: false,
+            upload_options: UploadOptions::default(),
+            lint_rules: LintRules::default(),
+            index: None,
+            redirect_index: false,
+        }
+    }
+}
+
+impl MdserveBuilder {
+    /// Directory that files and assets are served from. Without explicit
+    /// files, every markdown file in it is tracked.
+    pub fn base_dir(mut self, base_dir: impl Into<PathBuf>) -> Self {
+        self.base_dir = Some(base_dir.into());
+        self
+    }
+
+    /// Serve a single markdown file.
+    pub fn file(self, file: impl Into<PathBuf>) -> Self {
+        self.files([file.into()])
+    }
+
+    /// Serve exactly these markdown files. The base directory defaults to
+    /// the parent of the first file.
+    pub fn files(mut self, files: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.files = Some(files.into_iter().collect());
+        self
+    }
+
+    /// Show the navigation sidebar and track new files. Defaults to `true`
+    /// when only a base directory is given, `false` otherwise.
+    pub fn directory_mode(mut self, directory_mode: bool) -> Self {
+        self.directory_mode = Some(directory_mode);
+        self
+    }
+
+    /// Also track markdown files in subdirectories of the base directory,
+    /// under their relative path (e.g. `guides/setup.md`). Hidden folders
+    /// are skipped. Defaults to `false`.
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Also serve the markdown file or directory at `path` under
+    /// `/<mount>/`, e.g. `notes/todo.md` for `todo.md` in a directory
+    /// mounted at `notes`. Directories are tracked like the base directory
+    /// in directory mode, files one by one; each is watched on its own.
+    /// Files from one directory may share a mount. Mounted documents are
+    /// listed in the sidebar.
+    pub fn mount(mut self, path: impl Into<PathBuf>, mount: impl Into<String>) -> Self {
+        self.extra_paths.push((path.into(), Some(mount.into())));
+        self
+    }
+
+    /// Also serve these markdown files or directories, each mounted under
+    /// its directory's name like [`mount`](Self::mount). Names already
+    /// taken get a `-2`, `-3`, … suffix. Files in the same directory as
+    /// the base directory's files are served alongside them instead.
+    pub fn extra_paths(mut self, paths: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.extra_paths
+            .extend(paths.into_iter().map(|path| (path, None)));
+        self
+    }
+
+    /// Replace the built-in page template with MiniJinja source. It receives
+    /// the same context variables as `templates/main.html`.
+    pub fn template(mut self, source: impl Into<String>) -> Self {
+        self.template = Some(TemplateChoice::Source(source.into()));
+        self
+    }
+
+    /// Load the page template from disk: a MiniJinja file, or a directory
+    /// whose `main.html` renders pages and can include or extend the other
+    /// templates in it. Edits are picked up while serving, and open pages
+    /// reload.
+    pub fn template_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.template = Some(TemplateChoice::File(path.into()));
+        self
+    }
+
+    /// Render pages with `template` instead of MiniJinja, e.g. to apply an
+    /// application's own branding without shipping template files.
+    pub fn page_template(mut self, template: impl PageTemplate + 'static) -> Self {
+        self.template = Some(TemplateChoice::Custom(Arc::new(template)));
+        self
+    }
+
+    /// How markdown is rendered to HTML.
+    pub fn render_options(mut self, options: RenderOptions) -> Self {
+        self.render_options = options;
+        self
+    }
+
+    /// Show an outline of the current document's headings in the
+    /// sidebar, linking to each. Defaults to `false`.
+    pub fn toc(mut self, toc: bool) -> Self {
+        self.toc = toc;
+        self
+    }
+
+    /// Label documents in the sidebar with their frontmatter `title` or
+    /// first `# ` heading instead of their file name, for files that have
+    /// one. URLs stay the same. Defaults to `false`.
+    pub fn sidebar_titles(mut self, enabled: bool) -> Self {
+        self.sidebar_titles = enabled;
+        self
+    }
+
+    /// Link `[[Page Name]]`, `[[Page Name|label]]` and `[[Page
+    /// Name#Heading]]` in documents to the served document whose name
+    /// matches, ignoring case, extension and spaces versus `-` or `_`.
+    /// Unmatched links get the `wikilink-missing` class. Defaults to
+    /// `false`.
+    pub fn wikilinks(mut self, enabled: bool) -> Self {
+        self.wikilinks = enabled;
+        self
+    }
+
+    /// Let editors scroll previews to the line under their cursor: top-level
+    /// blocks get a `data-sourcepos` attribute, and `POST /api/sync` with
+    /// `{"file": "notes.md", "line": 42}` (or a `ScrollTo` message on
+    /// `/ws`) scrolls the pages showing that document there. Defaults to
+    /// `false`.
+    pub fn scroll_sync(mut self, enabled: bool) -> Self {
+        self.scroll_sync = enabled;
+        self
+    }
+
+    /// Mermaid theme for diagrams (`default`, `neutral`, `dark`, `forest`
+    /// or `base`) instead of one following the page theme. A document's
+    /// `mermaid` frontmatter mapping, e.g. `mermaid: {theme: forest}`,
+    /// overrides it and any other Mermaid option for that page.
+    pub fn mermaid_theme(mut self, theme: impl Into<String>) -> Self {
+        self.mermaid_config
+            .insert("theme".to_string(), serde_json::Value::String(theme.into()));
+        self
+    }
+
+    /// Colours for highlighted code blocks. Defaults to
+    /// [`HighlightTheme::Auto`], which follows the page theme.
+    pub fn highlight_theme(mut self, theme: HighlightTheme) -> Self {
+        self.highlight_theme = theme;
+        self
+    }
+
+    /// Register a transform that runs on every document, after the
+    /// built-in stages.
+    pub fn render_stage(mut self, stage: impl RenderStage + 'static) -> Self {
+        self.pipeline.push(stage);
+        self
+    }
+
+    /// Replace the whole render pipeline, including the built-in stages.
+    pub fn render_pipeline(mut self, pipeline: RenderPipeline) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+
+    /// Transform each document's HTML after rendering and before it is
+    /// cached, e.g. to inject a banner or strip content. The callback gets
+    /// the file name and the rendered HTML. Repeated calls chain in order.
+    pub fn map_html<F>(mut self, map_html: F) -> Self
+    where
+        F: Fn(&str, String) -> String + Send + Sync + 'static,
+    {
+        self.html_mappers.push(Arc::new(map_html));
+        self
+    }
+
+    /// Policy for serving non-markdown files.
+    pub fn static_files(mut self, options: StaticFileOptions) -> Self {
+        self.static_options = options;
+        self
+    }
+
+    /// Also serve static files from `dir` under `/<mount>/`, e.g. a shared
+    /// screenshots folder. The same file types, traversal checks and
+    /// symlink policy apply as in the base directory, and when watching,
+    /// changes in it reload open pages. May be called repeatedly; a mount
+    /// takes precedence over a base directory folder of the same name.
+    pub fn asset_dir(mut self, dir: impl Into<PathBuf>, mount: impl Into<String>) -> Self {
+        self.asset_mounts.push(AssetMount {
+            mount: mount.into(),
+            dir: dir.into(),
+        });
+        self
+    }
+
+    /// Resolve images referenced by an absolute path under `dir`, such as
+    /// `/Users/me/Pictures/diagram.png` in a document exported from another
+    /// tool. Their `src` is rewritten to a route serving `dir` like an
+    /// [`asset_dir`](Self::asset_dir); absolute paths anywhere else stay
+    /// unresolved. May be called repeatedly.
+    pub fn allow_absolute_images(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.absolute_image_dirs.push(dir.into());
+        self
+    }
+
+    /// Secret used to sign share links. Defaults to a random secret, so links
+    /// stop working when the router is dropped.
+    pub fn share_secret(mut self, secret: impl Into<Vec<u8>>) -> Self {
+        self.share_secret = Some(secret.into());
+        self
+    }
+
+    /// Require HTTP Basic credentials for every route except share links.
+    /// Browsers ask for them once and send them with each request,
+    /// WebSocket upgrades included.
+    pub fn basic_auth(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.access.basic = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Require `token` for every route except share links, given as a
+    /// `?token=` query parameter (remembered in a cookie for the rest of
+    /// the session) or an `Authorization: Bearer` header. With
+    /// [`basic_auth`](Self::basic_auth) too, either is enough.
+    pub fn access_token(mut self, token: impl Into<String>) -> Self {
+        self.access.token = Some(token.into());
+        self
+    }
+
+    /// Expose the server through `provider` when it is started with
+    /// [`serve_markdown`], which prints the public HTTPS URL and a share link
+    /// for the index document. The bare URL reaches every served file, so
+    /// hand out the share link. Without credentials set, a random access
+    /// token is required. Ignored by [`serve`](Self::serve).
+    pub fn public_tunnel(mut self, provider: TunnelProvider) -> Self {
+        self.public_tunnel = Some(provider);
+        self
+    }
+
+    /// With a public tunnel but neither [`basic_auth`](Self::basic_auth)
+    /// nor [`access_token`](Self::access_token), require a random access
+    /// token, so the tunnel never opens every served file to the internet.
+    pub(super) fn guard_public_tunnel(mut self) -> Self {
+        if self.public_tunnel.is_some() && !self.access.is_enabled() {
+            self.access.token = Some(auth::random_token());
+        }
+        self
+    }
+
+    /// Advertise the server on the local network over mDNS when it is
+    /// started with [`serve_markdown`], as "markdown preview on <machine>".
+    /// The hostname must be reachable from other devices. Defaults to
+    /// `false`; ignored by [`serve`](Self::serve).
+    pub fn mdns(mut self, enabled: bool) -> Self {
+        self.mdns = enabled;
+        self
+    }
+
+    /// Copy the server's URL (or with a [`public_tunnel`](Self::public_tunnel),
+    /// the share link) to the clipboard when it is started with
+    /// [`serve_markdown`]. Defaults to `false`; ignored by
+    /// [`serve`](Self::serve).
+    pub fn copy_url(mut self, enabled: bool) -> Self {
+        self.copy_url = enabled;
+        self
+    }
+
+    /// Path the router is mounted at with [`Router::nest`], e.g. `/preview`.
+    /// Links, the WebSocket URL and asset URLs in served pages are
+    /// generated under it. Defaults to the root.
+    pub fn base_path(mut self, base_path: impl Into<String>) -> Self {
+        self.base_path = Some(base_path.into());
+        self
+    }
+
+    /// Keep at most `max` rendered documents in memory, evicting the least
+    /// recently viewed. Evicted documents are re-rendered on demand.
+    pub fn max_cached_documents(mut self, max: usize) -> Self {
+        self.cache_limits.max_entries = Some(max);
+        self
+    }
+
+    /// Cap the total size of cached HTML in bytes, evicting the least
+    /// recently viewed documents. The document being served is always kept.
+    pub fn max_cache_bytes(mut self, max: usize) -> Self {
+        self.cache_limits.max_bytes = Some(max);
+        self
+    }
+
+    /// Render at most `max` bytes of each markdown file. Larger files are cut
+    /// at the last full line with a notice at the top, instead of being
+    /// read into memory whole. Defaults to 16 MiB.
+    pub fn max_file_size(mut self, max: usize) -> Self {
+        self.max_file_size = max;
+        self
+    }
+
+    /// How many WebSocket messages are buffered for each connected browser.
+    /// A browser that falls further behind (e.g. a suspended tab) skips the
+    /// backlog and gets a single reload instead. Defaults to 16.
+    pub fn message_buffer(mut self, capacity: usize) -> Self {
+        self.message_buffer = capacity;
+        self
+    }
+
+    /// How many ports after the requested one [`serve`](Self::serve) and
+    /// [`serve_markdown`] try when it is taken. Defaults to 9; with 0 a busy
+    /// port is an error. Port 0 always gets a free port from the OS.
+    pub fn auto_port(mut self, fallbacks: u16) -> Self {
+        self.port_fallbacks = fallbacks;
+        self
+    }
+
+    /// Call `hook` with the address [`serve_markdown`] bound, once it
+    /// listens: the way to learn the port after fallback or with port 0.
+    /// [`serve`](Self::serve) returns it in [`ServerHandle::local_addr`]
+    /// instead.
+    pub fn on_listening(mut self, hook: impl FnOnce(SocketAddr) + Send + 'static) -> Self {
+        self.on_listening = Some(Box::new(hook));
+        self
+    }
+
+    /// Serve at most `max` connections at once when started with
+    /// [`serve`](Self::serve). Further clients wait until a connection closes.
+    /// Unlimited by default.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.connection_limits.max_connections = Some(max);
+        self
+    }
+
+    /// Close connections that sit idle between requests for longer than
+    /// `timeout` when started with [`serve`](Self::serve). `None` keeps them
+    /// open indefinitely. Defaults to 60 seconds.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connection_limits.idle_timeout = timeout;
+        self
+    }
+
+    /// Compress responses with gzip or brotli when the client accepts it,
+    /// except images, media, archives and the live-reload connections.
+    /// Defaults to `true`.
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// Serve HTTPS instead of plain HTTP when started with
+    /// [`serve`](Self::serve), with the given certificate. Routers mounted
+    /// into another application are unaffected.
+    pub fn tls(mut self, certificate: TlsCertificate) -> Self {
+        self.tls = Some(certificate);
+        self
+    }
+
+    /// Serve pages with a source editor next to the preview. Edits are
+    /// previewed through `POST /api/render` and saved through the write
+    /// endpoint, which this enables as well. Defaults to `false`.
+    pub fn edit(mut self, edit: bool) -> Self {
+        self.edit = edit;
+        self
+    }
+
+    /// Accept `PUT /api/files/<name>` to write a markdown file: a tracked
+    /// one, or in directory mode a new one in the base directory. Writes are
+    /// atomic and reload connected pages. Defaults to `false`.
+    pub fn allow_write(mut self, allow_write: bool) -> Self {
+        self.allow_write = allow_write;
+        self
+    }
+
+    /// Where files uploaded through `POST /api/upload` (enabled with
+    /// writing) are saved, and how large they may be.
+    pub fn uploads(mut self, options: UploadOptions) -> Self {
+        self.upload_options = options;
+        self
+    }
+
+    /// Check documents for long sentences, repeated words and images
+    /// without alt text. Findings are listed in a collapsible panel above
+    /// the content and marked next to the blocks they occur in. Defaults to
+    /// `false`.
+    pub fn prose_lint(mut self, enabled: bool) -> Self {
+        self.lint_rules.prose = enabled;
+        self
+    }
+
+    /// Check documents against common markdown style rules: heading levels
+    /// that skip a step, trailing spaces, bare URLs and duplicate headings.
+    /// Findings are shown like [`prose_lint`](Self::prose_lint)'s. Defaults
+    /// to `false`.
+    pub fn lint(mut self, enabled: bool) -> Self {
+        self.lint_rules.markdown = enabled;
+        self
+    }
+
+    /// File name of the document served at `/`. Defaults to `README.md`,
+    /// `readme.md` or `index.md` if one is tracked, otherwise the
+    /// alphabetically first tracked file; the default is also used if this
+    /// one is removed while serving.
+    pub fn index(mut self, file: impl Into<String>) -> Self {
+        self.index = Some(file.into());
+        self
+    }
+
+    /// Redirect `/` to the index document's own URL instead of rendering
+    /// it in place, so the address bar names the file. Defaults to `false`.
+    pub fn redirect_index(mut self, redirect: bool) -> Self {
+        self.redirect_index = redirect;
+        self
+    }
+
+    /// Watch the base directory and live reload on changes. Defaults to `true`.
+    pub fn watch(mut self, watch: bool) -> Self {
+        self.watch = watch;
+        self
+    }
+
+    /// Where change events come from. Defaults to a native filesystem
+    /// watcher on the base directory; see [`crate::watch::channel`] for a
+    /// manually driven source.
+    pub fn change_source(mut self, source: impl ChangeSource + 'static) -> Self {
+        self.change_source = Some(Box::new(source));
+        self
+    }
+
+    /// Run `hook` for every create, modify, rename or remove event the change
+    /// source reports, after mdserve has handled it. Hooks are spawned, so a
+    /// slow hook does not delay live reload.
+    pub fn on_file_event<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(FileEvent) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.file_event_hooks
+            .push(Arc::new(move |event| Box::pin(hook(event))));
+        self
+    }
+
+    /// Build the router. Must be called within a Tokio runtime when watching
+    /// is enabled.
+    pub fn build_router(self) -> Result<Router> {
+        self.build().map(|(router, _)| router)
+    }
+
+    /// Build the router, bind it on `hostname:port` (trying the next few ports
+    /// if taken, see [`auto_port`](Self::auto_port)) and serve it in the
+    /// background. No banner is printed.
+    pub async fn serve(self, hostname: impl AsRef<str>, port: u16) -> Result<ServerHandle> {
+        spawn_server(self.into_config()?, hostname.as_ref(), port).await
+    }
+
+    /// Build the router together with a [`StateHandle`] for changing what it
+    /// serves at runtime.
+    pub fn build(self) -> Result<(Router, StateHandle)> {
+        new_router(self.into_config()?)
+    }
+
+    /// Write every tracked file through the page template to a static site
+    /// in `out_dir`: one `.html` page per document with `.md` links pointing
+    /// at the pages, the images and other static files the documents use,
+    /// the bundled Mermaid, and `index.html` for the index document.
+    pub async fn export(self, out_dir: impl AsRef<Path>) -> Result<ExportSummary> {
+        let out_dir = out_dir.as_ref();
+        let state = MarkdownState::new(self.watch(false).into_config()?)?;
+        let settings = &state.settings;
+        let names = state.get_sorted_filenames();
+        let file_titles = state.sidebar_titles();
+        let index = match &settings.index {
+            Some(index) if state.tracked_files.contains_key(index) => Some(index),
+            _ => default_index(&names),
+        };
+        let mut summary = ExportSummary::default();
+        let mut copied = HashSet::new();
+        // Linked documents outside the tracked set are exported too, so
+        // every rewritten link has a page
+        let mut pages: VecDeque<(String, PathBuf)> = names
+            .iter()
+            .map(|name| (name.clone(), state.tracked_files[name].path.clone()))
+            .collect();
+        let mut queued: HashSet<String> = names.iter().cloned().collect();
+
+        while let Some((name, path)) = pages.pop_front() {
+            let name = &name;
+            let document = settings
+                .renderer
+                .load(name, &path)
+                .await
+                .with_context(|| format!("failed to render {}", path.display()))?;
+            let base_path = export::root_prefix(name);
+            let content = state.page_html(name, &document.html);
+            let page = PageContext {
+                content: &content,
+                page_title: page_title(name),
+                current_file: name,
+                files: if state.show_navigation() { &names } else { &[] },
+                file_titles: &file_titles,
+                show_navigation: state.show_navigation(),
+                mermaid_enabled: document.features.contains(MERMAID_FEATURE),
+                mermaid_version: MERMAID_VERSION,
+                katex_enabled: settings.katex_enabled(&document, false),
+                katex_version: KATEX_VERSION,
+                features: &document.features,
+                shared: false,
+                exported: true,
+                toc: settings.toc(&document),
+                base_path: &base_path,
+                edit: false,
+                highlight_css: &settings.highlight_css,
+                metadata: &document.metadata,
+                mermaid_config: &mermaid_config(&settings.mermaid_config, &document.metadata),
+            };
+            let html = export::rewrite_markdown_links(&settings.template.render(&page)?);
+            write_creating_dirs(&out_dir.join(export::html_name(name)), html.as_bytes()).await?;
+            summary.pages += 1;
+            summary.diagnostics.extend(
+                document
+                    .diagnostics
+                    .iter()
+                    .map(|diagnostic| (name.clone(), diagnostic.clone())),
+            );
+
+            if index == Some(name) {
+                // Relative URLs only work unchanged from the top level
+                let index_html = if split_folder(name).0.is_empty() {
+                    html
+                } else {
+                    let url = export::html_name(name);
+                    format!(
+                        "<!DOCTYPE html>\n<meta http-equiv=\"refresh\" content=\"0; url={url}\">\n\
+                         <a href=\"{url}\">{url}</a>\n"
+                    )
+                };
+                tokio::fs::write(out_dir.join("index.html"), index_html).await?;
+            }
+
+            for linked in export::markdown_references(&document.html, split_folder(name).0) {
+                if queued.contains(&linked) {
+                    continue;
+                }
+                let (root, relative) = settings.document_root(&linked);
+                if let Some(path) = linked_document_path(root, relative).await {
+                    queued.insert(linked.clone());
+                    pages.push_back((linked, path));
+                }
+            }
+
+            for reference in export::local_references(&document.html, split_folder(name).0) {
+                if !copied.insert(reference.clone()) {
+                    continue;
+                }
+                let (root, relative) = settings.static_root(&reference);
+                let source =
+                    match resolve_static_path(root, &settings.static_options, relative).await {
+                        Ok(source) if source.is_file() => source,
+                        _ => continue,
+                    };
+                let options = &settings.static_options;
+                if options.allows(&reference) || options.allows_referenced(&reference) {
+                    write_creating_dirs(&out_dir.join(&reference), &tokio::fs::read(source).await?)
+                        .await?;
+                    summary.files += 1;
+                }
+            }
+        }
+
+        write_creating_dirs(
+            &out_dir.join(format!("assets/mermaid-{MERMAID_VERSION}.min.js")),
+            MERMAID_JS.as_bytes(),
+        )
+        .await?;
+        if settings.renderer.options.math == MathMode::Client {
+            write_creating_dirs(
+                &out_dir.join(format!("assets/katex-{KATEX_VERSION}.min.js")),
+                KATEX_JS.as_bytes(),
+            )
+            .await?;
+        }
+
+        Ok(summary)
+    }
+
+    pub(super) fn into_config(mut self) -> Result<RouterConfig> {
+        let (base_dir, mut tracked_files, is_directory_mode) = match (self.base_dir, self.files) {
+            (_, Some(files)) if files.is_empty() => anyhow::bail!("No markdown files to serve"),
+            (Some(base_dir), Some(files)) => {
+                (base_dir, files, self.directory_mode.unwrap_or(false))
+            }
+            (None, Some(files)) => {
+                let base_dir = files[0]
+                    .parent()
+                    .unwrap_or_else(|| Path::new("."))
+                    .to_path_buf();
+                (base_dir, files, self.directory_mode.unwrap_or(false))
+            }
+            (Some(base_dir), None) if self.recursive => {
+                // Nested names are relative to the canonical directory the
+                // watcher reports paths under
+                let base_dir = paths::canonicalize(&base_dir)?;
+                let files = scan_markdown_files_recursive(&base_dir)?;
+                if files.is_empty() {
+                    anyhow::bail!("No markdown files found in directory");
+                }
+                (base_dir, files, self.directory_mode.unwrap_or(true))
+            }
+            (Some(base_dir), None) => {
+                let files = scan_markdown_files(&base_dir)?;
+                if files.is_empty() {
+                    anyhow::bail!("No markdown files found in directory");
+                }
+                (base_dir, files, self.directory_mode.unwrap_or(true))
+            }
+            (None, None) => anyhow::bail!("Either a base directory or files must be set"),
+        };
+
+        if self.access.token.as_deref() == Some("") {
+            anyhow::bail!("access token must not be empty");
+        }
+
+        let base_path = normalize_base_path(self.base_path.as_deref().unwrap_or(""))?;
+        let mut asset_mounts = self.asset_mounts;
+        let mut html_mappers = self.html_mappers;
+        if !self.absolute_image_dirs.is_empty() {
+            let mut prefixes = Vec::new();
+            for (i, dir) in self.absolute_image_dirs.into_iter().enumerate() {
+                if !dir.is_absolute() {
+                    anyhow::bail!("not an absolute path: {}", dir.display());
+                }
+                let mount = format!("{ABSOLUTE_IMAGES_MOUNT}/{i}");
+                prefixes.push((dir.clone(), format!("{base_path}/{mount}")));
+                asset_mounts.push(AssetMount { mount, dir });
+            }
+            // First, so user mappers see the final URLs
+            html_mappers.insert(
+                0,
+                Arc::new(move |_: &str, html: String| rewrite_absolute_images(&html, &prefixes)),
+            );
+        }
+
+        let mut mounts = HashSet::new();
+        for asset_mount in &mut asset_mounts {
+            asset_mount.mount = normalize_mount(&asset_mount.mount)?;
+            if !mounts.insert(asset_mount.mount.clone()) {
+                anyhow::bail!("two asset directories mounted at /{}", asset_mount.mount);
+            }
+        }
+
+        let navigation = is_directory_mode || !self.extra_paths.is_empty();
+        let canonical_base = paths::canonicalize(&base_dir).unwrap_or_else(|_| base_dir.clone());
+        let mut document_mounts: Vec<DocumentMount> = Vec::new();
+        let mut extra_roots = Vec::new();
+        for (path, mount) in self.extra_paths {
+            let path = paths::canonicalize(&path)
+                .with_context(|| format!("not found: {}", path.display()))?;
+            extra_roots.push(path.clone());
+            let directory = path.is_dir();
+            if !directory && !is_markdown_file(&path) {
+                anyhow::bail!("not a markdown file or directory: {}", path.display());
+            }
+            let (dir, mut files) = if directory {
+                let files = if self.recursive {
+                    scan_markdown_files_recursive(&path)?
+                } else {
+                    scan_markdown_files(&path)?
+                };
+                (path, files)
+            } else {
+                let dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+                (dir, vec![path])
+            };
+            let mount = mount.as_deref().map(normalize_mount).transpose()?;
+
+            if dir == canonical_base {
+                if directory || mount.is_some() {
+                    anyhow::bail!("{} is already served at /", dir.display());
+                }
+                tracked_files.append(&mut files);
+                continue;
+            }
+            let existing = document_mounts.iter_mut().find(|document_mount| {
+                document_mount.dir == dir
+                    && mount
+                        .as_ref()
+                        .is_none_or(|mount| *mount == document_mount.mount)
+            });
+            if let Some(existing) = existing {
+                existing.directory |= directory;
+                tracked_files.append(&mut files);
+                continue;
+            }
+            let mount = match mount {
+                Some(mount) if !mounts.insert(mount.clone()) => {
+                    anyhow::bail!("two directories mounted at /{mount}")
+                }
+                Some(mount) => mount,
+                None => unique_mount(&dir, &canonical_base, &mut mounts),
+            };
+            document_mounts.push(DocumentMount {
+                mount,
+                dir,
+                directory,
+            });
+            tracked_files.append(&mut files);
+        }
+
+        if let Some(index) = &self.index {
+            if !tracked_files.iter().any(|file| {
+                document_name(&base_dir, self.recursive, &document_mounts, file).as_ref()
+                    == Some(index)
+            }) {
+                anyhow::bail!("index file is not one of the served markdown files: {index}");
+            }
+        }
+
+        let mut file_template = None;
+        let template: Arc<dyn PageTemplate> = match self.template {
+            None => Arc::new(MiniJinjaTemplate::builtin()),
+            Some(TemplateChoice::Source(source)) => {
+                Arc::new(MiniJinjaTemplate::from_source(source)?)
+            }
+            Some(TemplateChoice::File(path)) => {
+                let template = Arc::new(FileTemplate::load(path)?);
+                file_template = Some(template.clone());
+                template
+            }
+            Some(TemplateChoice::Custom(template)) => template,
+        };
+
+        if self.scroll_sync {
+            self.render_options.source_positions = true;
+        }
+        let mut pipeline = self.pipeline;
+        if self.lint_rules.any() {
+            pipeline.prepend(LintStage::new(self.lint_rules, self.render_options.clone()));
+        }
+
+        let source = if self.recursive {
+            NotifySource::recursive()
+        } else {
+            NotifySource::default()
+        };
+
+        let instance_root = match tracked_files.first() {
+            Some(file) if !is_directory_mode => {
+                paths::canonicalize(file).unwrap_or_else(|_| file.clone())
+            }
+            _ => base_dir.clone(),
+        };
+        // A link minted for one served tree never opens another's files
+        let share_secret = share::root_secret(
+            &self.share_secret.unwrap_or_else(share::random_secret),
+            &instance_root,
+        );
+        let mut instance_roots = vec![instance_root];
+        instance_roots.append(&mut extra_roots);
+
+        Ok(RouterConfig {
+            base_dir,
+            tracked_files,
+            is_directory_mode,
+            navigation,
+            recursive: self.recursive,
+            document_mounts,
+            template,
+            file_template,
+            highlight_css: if self.render_options.highlight {
+                self.highlight_theme.css()
+            } else {
+                String::new()
+            },
+            mermaid_config: self.mermaid_config,
+            toc: self.toc,
+            sidebar_titles: self.sidebar_titles,
+            wikilinks: self.wikilinks,
+            scroll_sync: self.scroll_sync,
+            render_options: self.render_options,
+            pipeline,
+            html_mappers,
+            static_options: self.static_options,
+            asset_mounts,
+            instance_roots,
+            share_secret,
+            access: self.access,
+            change_source: self
+                .watch
+                .then(|| self.change_source.unwrap_or_else(|| Box::new(source))),
+            file_event_hooks: self.file_event_hooks,
+            base_path,
+            cache_limits: self.cache_limits,
+            message_buffer: self.message_buffer,
+            max_file_size: self.max_file_size,
+            connection_limits: self.connection_limits,
+            compression: self.compression,
+            tls: self.tls,
+            port_fallbacks: self.port_fallbacks,
+            edit: self.edit,
+            allow_write: self.allow_write || self.edit,
+            upload_options: UploadOptions {
+                dir: normalize_upload_dir(&self.upload_options.dir)?,
+                ..self.upload_options
+            },
+            index: self.index,
+            redirect_index: self.redirect_index,
+        })
+    }
+}
+
+/// What [`MdserveBuilder::export`] wrote.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ExportSummary {
+    /// HTML pages, one per tracked document (not counting `index.html`).
+    pub pages: usize,
+    /// Images and other static files copied alongside them.
+    pub files: usize,
+    /// What linting found, by document name, when
+    /// [`lint`](MdserveBuilder::lint) or
+    /// [`prose_lint`](MdserveBuilder::prose_lint) is on.
+    pub diagnostics: Vec<(String, Diagnostic)>,
+}
+
+async fn write_creating_dirs(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(path, contents).await
+}
+
+/// Upload folder without surrounding slashes, which must stay inside the
+/// document's directory.
+fn normalize_upload_dir(dir: &str) -> Result<String> {
+    let trimmed = dir.trim_matches('/');
+    if !is_contained_relative_path(Path::new(trimmed)) {
+        anyhow::bail!("upload directory must be relative and inside the served directory: {dir}");
+    }
+    Ok(trimmed.to_string())
+}
+
+/// Canonical form of a mount path: empty for the root, otherwise a leading
+/// slash and no trailing one. Restricted to unreserved URL characters since
+/// it is spliced into HTML and JavaScript.
+fn normalize_base_path(base_path: &str) -> Result<String> {
+    let trimmed = base_path.trim_matches('/');
+    if trimmed.is_empty() {
+        return Ok(String::new());
+    }
+
+    let valid = trimmed
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~' | '/'));
+    if !valid || trimmed.split('/').any(|segment| segment.is_empty()) {
+        anyhow::bail!("invalid base path: {base_path}");
+    }
+
+    Ok(format!("/{trimmed}"))
+}
+
+enum TemplateChoice {
+    Source(String),
+    File(PathBuf),
+    Custom(Arc<dyn PageTemplate>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum_test::TestServer;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_public_tunnel_requires_a_token_without_credentials() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        fs::write(temp_dir.path().join("test.md"), "# Private").expect("Failed to write");
+
+        let builder = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .public_tunnel(TunnelProvider::Cloudflared)
+            .guard_public_tunnel();
+        let token = builder.access.token.clone().expect("no token generated");
+        let router = builder.build_router().expect("Failed to create router");
+        let server = TestServer::new(router).expect("Failed to create test server");
+
+        assert_eq!(server.get("/test.md").await.status_code(), 401);
+        let response = server.get(&format!("/test.md?token={token}")).await;
+        assert!(response.text().contains("Private</h1>"));
+
+        // Credentials the user chose are kept as they are
+        let builder = Mdserve::builder()
+            .base_dir(temp_dir.path())
+            .public_tunnel(TunnelProvider::Cloudflared)
+            .basic_auth("ada", "pa:ss")
+            .guard_public_tunnel();
+        assert_eq!(builder.access.token, None);
+    }
+
+    #[test]
+    fn test_normalize_base_path() {
+        assert_eq!(normalize_base_path("").unwrap(), "");
+        assert_eq!(normalize_base_path("/").unwrap(), "");
+        assert_eq!(normalize_base_path("preview").unwrap(), "/preview");
+        assert_eq!(
+            normalize_base_path("/docs/preview/").unwrap(),
+            "/docs/preview"
+        );
+        assert!(normalize_base_path("/a//b").is_err());
+        assert!(normalize_base_path("/x'y").is_err());
+    }
+}
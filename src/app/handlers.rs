@@ -0,0 +1,1698 @@
+//! The route handlers `new_router` mounts: pages, raw files and downloads,
+//! the bundled scripts, the editing and upload API, share links and the
+//! live reload sockets.
+
+use anyhow::Result;
+use axum::{
+    body::{Body, Bytes},
+    extract::{
+        ws::{Message, WebSocket},
+        Path as AxumPath, Query, State, WebSocketUpgrade,
+    },
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        Html, IntoResponse,
+    },
+    Json,
+};
+use futures_util::{stream, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet},
+    hash::Hasher,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{broadcast, watch},
+};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    auth,
+    frontmatter::Metadata,
+    instance::InstanceInfo,
+    paths,
+    render::MERMAID_FEATURE,
+    share,
+    template::{PageContext, PageTemplate},
+};
+
+use super::{
+    default_index, handle_markdown_file_change, is_contained_relative_path, is_hidden_path,
+    is_markdown_file, percent_encode, AppState, CachedDocument, ClientMessage, Draft,
+    MarkdownState, RouterSettings, ServerMessage, SharedMarkdownState, StaticFileOptions,
+    TrackedFile, KATEX_JS, KATEX_VERSION, MERMAID_JS, MERMAID_VERSION,
+};
+
+/// `MERMAID_JS` compressed by the build script, so the ~2.7 MB script is
+/// never compressed per request.
+const MERMAID_JS_GZIP: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/mermaid.min.js.gz"));
+const MERMAID_JS_BROTLI: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/mermaid.min.js.br"));
+const MERMAID_ETAG: &str = concat!("\"", env!("CARGO_PKG_VERSION"), "\"");
+const MERMAID_GZIP_ETAG: &str = concat!("\"", env!("CARGO_PKG_VERSION"), "-gzip\"");
+const MERMAID_BROTLI_ETAG: &str = concat!("\"", env!("CARGO_PKG_VERSION"), "-br\"");
+const KATEX_JS_GZIP: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/katex.min.js.gz"));
+const KATEX_JS_BROTLI: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/katex.min.js.br"));
+/// Documents with at least this much HTML are streamed instead of buffered.
+pub(super) const STREAM_THRESHOLD: usize = 1024 * 1024;
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+/// Stands in for the document while rendering the page shell of a streamed
+/// response. NUL bytes never occur in rendered markdown.
+pub(super) const CONTENT_PLACEHOLDER: &str = "\0mdserve-content\0";
+
+pub(super) async fn serve_html_root(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let index_file = {
+        let markdown = state.markdown.read().await;
+        match &state.settings.index {
+            Some(index) if markdown.tracked_files.contains_key(index) => Some(index.clone()),
+            _ => default_index(&markdown.get_sorted_filenames()).cloned(),
+        }
+    };
+
+    let filename = match index_file {
+        Some(name) => name,
+        None => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                PageBody::Whole("No files available to serve".to_string()),
+            )
+                .into_response();
+        }
+    };
+
+    if state.settings.redirect_index {
+        let location = format!("{}/{}", state.settings.base_path, percent_encode(&filename));
+        return axum::response::Redirect::temporary(&location).into_response();
+    }
+    page_response(
+        &headers,
+        render_markdown(&state.markdown, &filename, false).await,
+    )
+}
+
+pub(super) async fn serve_file(
+    AxumPath(filename): AxumPath<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    if filename.ends_with(".md") || filename.ends_with(".markdown") {
+        track_linked_document(&state, &filename).await;
+        page_response(
+            &headers,
+            render_markdown(&state.markdown, &filename, false).await,
+        )
+    } else if servable(&state, None, &filename).await {
+        serve_static_file_inner(filename, &state.settings, &headers).await
+    } else {
+        tracing::debug!(
+            file = %filename,
+            "not served: its extension isn't allowed and no document references it"
+        );
+        (StatusCode::NOT_FOUND, Html("File not found".to_string())).into_response()
+    }
+}
+
+/// Whether the static file `filename` may be served: the static file
+/// options allow it, or the cached render of `document` (any document when
+/// `None`) refers to it.
+async fn servable(state: &AppState, document: Option<&str>, filename: &str) -> bool {
+    let options = &state.settings.static_options;
+    options.allows(filename)
+        || (options.allows_referenced(filename)
+            && state.markdown.read().await.references(document, filename))
+}
+
+/// Start tracking `filename` when a tracked document links to it, so links
+/// between documents also work for files outside the tracked set: others
+/// next to a single file, or in subfolders of a non-recursive directory.
+/// Files nothing links to stay unreachable.
+async fn track_linked_document(state: &AppState, filename: &str) {
+    {
+        let markdown = state.markdown.read().await;
+        if markdown.tracked_files.contains_key(filename) || !markdown.links_to(filename) {
+            return;
+        }
+    }
+    let (root, relative) = state.settings.document_root(filename);
+    let Some(path) = linked_document_path(root, relative).await else {
+        return;
+    };
+    state
+        .markdown
+        .write()
+        .await
+        .tracked_files
+        .entry(filename.to_string())
+        .or_insert_with(|| TrackedFile::linked(path));
+}
+
+/// The markdown file at `name` under `base_dir`, if there is one inside it.
+/// Hidden files are never linked.
+pub(super) async fn linked_document_path(base_dir: &Path, name: &str) -> Option<PathBuf> {
+    let relative = Path::new(name);
+    if !is_markdown_file(relative)
+        || !is_contained_relative_path(relative)
+        || is_hidden_path(relative)
+    {
+        return None;
+    }
+    let base_dir = paths::canonicalize_async(base_dir).await.ok()?;
+    let path = paths::canonicalize_async(&base_dir.join(relative))
+        .await
+        .ok()?;
+    let is_file = tokio::fs::metadata(&path).await.ok()?.is_file();
+    (is_file && path.starts_with(&base_dir)).then_some(path)
+}
+
+/// Render a tracked file through the page template. Shared views never show
+/// the sidebar, so a share link exposes only the one document.
+async fn render_markdown(
+    state: &SharedMarkdownState,
+    current_file: &str,
+    shared: bool,
+) -> (StatusCode, PageBody) {
+    with_document(state, current_file, |state, document| {
+        render_page(state, current_file, document, shared)
+    })
+    .await
+    .unwrap_or_else(|| {
+        tracing::debug!(file = current_file, "not served: not a tracked document");
+        (
+            StatusCode::NOT_FOUND,
+            PageBody::Whole("File not found".to_string()),
+        )
+    })
+}
+
+/// Run `view` on the rendered document for a tracked file, rendering it
+/// outside the lock on a cache miss. Cache hits only take a read lock.
+/// A tracked file that is missing and was never rendered is shown empty.
+/// `None` if the file is not tracked or could never be read.
+async fn with_document<T>(
+    state: &SharedMarkdownState,
+    current_file: &str,
+    view: impl FnOnce(&MarkdownState, &CachedDocument) -> T,
+) -> Option<T> {
+    let (renderer, path, generation) = {
+        let state = state.read().await;
+        let tracked = state.tracked_files.get(current_file)?;
+        let pending = (
+            state.settings.renderer.clone(),
+            tracked.path.clone(),
+            tracked.generation,
+        );
+        state.touch(current_file);
+        if let Some(document) = state.tracked_files[current_file].fresh_document() {
+            return Some(view(&state, document));
+        }
+        pending
+    };
+
+    let mut loaded = renderer.load(current_file, &path).await;
+    if loaded.is_err() && !path.exists() {
+        // Editors that save by renaming the file away leave it missing for
+        // a moment; give it a chance to come back
+        loaded = renderer.load_settled(current_file, &path).await;
+    }
+    let missing = loaded.is_err() && !path.exists();
+
+    let mut state = state.write().await;
+    let tracked = state.tracked_files.get_mut(current_file)?;
+
+    match loaded {
+        // Only cache if the file didn't change again while we were reading
+        Ok(document) if tracked.generation == generation => {
+            tracked.store(document, generation);
+            state.evict_to_limits(current_file);
+        }
+        Ok(document) => return Some(view(&state, &document)),
+        // The file is missing or unreadable; fall back to the last render
+        Err(_) if tracked.document.is_some() => {}
+        // Still tracked but never rendered: show it empty, uncached, until
+        // the watcher sees it again and the page reloads
+        Err(_) if missing => {
+            let document = CachedDocument::new(current_file, renderer.render(current_file, ""));
+            return Some(view(&state, &document));
+        }
+        Err(_) => return None,
+    }
+
+    let document = state.tracked_files[current_file].document.as_ref()?;
+    Some(view(&state, document))
+}
+
+/// Page title derived from the file name (stem without extension).
+pub(super) fn page_title(file: &str) -> &str {
+    Path::new(file)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(file)
+}
+
+fn render_page(
+    state: &MarkdownState,
+    current_file: &str,
+    document: &CachedDocument,
+    shared: bool,
+) -> (StatusCode, PageBody) {
+    let page_title = page_title(current_file);
+
+    let show_navigation = state.show_navigation() && !shared;
+    let (files, file_titles) = if show_navigation {
+        (state.get_sorted_filenames(), state.sidebar_titles())
+    } else {
+        Default::default()
+    };
+
+    let edit = state.settings.edit && !shared;
+    let mermaid_config = mermaid_config(&state.settings.mermaid_config, &document.metadata);
+    let content = state.page_html(current_file, &document.html);
+    let page = PageContext {
+        content: &content,
+        page_title,
+        current_file,
+        files: &files,
+        file_titles: &file_titles,
+        show_navigation,
+        mermaid_enabled: document.features.contains(MERMAID_FEATURE),
+        mermaid_version: MERMAID_VERSION,
+        katex_enabled: state.settings.katex_enabled(document, edit),
+        katex_version: KATEX_VERSION,
+        features: &document.features,
+        shared,
+        exported: false,
+        toc: state.settings.toc(document),
+        base_path: &state.settings.base_path,
+        edit,
+        highlight_css: &state.settings.highlight_css,
+        metadata: &document.metadata,
+        mermaid_config: &mermaid_config,
+    };
+
+    if document.html.len() >= STREAM_THRESHOLD {
+        if let Some(body) = streamed_page(state.settings.template.as_ref(), &page) {
+            return (StatusCode::OK, body);
+        }
+    }
+
+    match state.settings.template.render(&page) {
+        Ok(rendered) => (StatusCode::OK, PageBody::Whole(rendered)),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            PageBody::Whole(format!("{e:#}")),
+        ),
+    }
+}
+
+/// Mermaid options for a page: the router's, with the document's `mermaid`
+/// frontmatter mapping merged on top.
+pub(super) fn mermaid_config(base: &Metadata, metadata: &Metadata) -> Metadata {
+    let mut config = base.clone();
+    if let Some(serde_json::Value::Object(overrides)) = metadata.get("mermaid") {
+        merge_objects(&mut config, overrides);
+    }
+    config
+}
+
+/// Copy `from` into `into`, merging nested objects key by key.
+fn merge_objects(into: &mut Metadata, from: &Metadata) {
+    for (key, value) in from {
+        match (into.get_mut(key), value) {
+            (Some(serde_json::Value::Object(into)), serde_json::Value::Object(from)) => {
+                merge_objects(into, from)
+            }
+            _ => {
+                into.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// An HTML page, either buffered or streamed around the document body.
+enum PageBody {
+    Whole(String),
+    Streamed {
+        head: String,
+        content: Bytes,
+        tail: String,
+    },
+}
+
+impl PageBody {
+    /// A hash of the page, fed its parts in order so a streamed page isn't
+    /// joined to compute it.
+    fn etag(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        match self {
+            PageBody::Whole(html) => hasher.write(html.as_bytes()),
+            PageBody::Streamed {
+                head,
+                content,
+                tail,
+            } => {
+                hasher.write(head.as_bytes());
+                hasher.write(content);
+                hasher.write(tail.as_bytes());
+            }
+        }
+        format!("\"{:016x}\"", hasher.finish())
+    }
+}
+
+/// Respond with a rendered page, or `304 Not Modified` when the browser
+/// already has it. Pages change with the sidebar and other documents, not
+/// just their file, so they are validated by ETag alone.
+fn page_response(
+    headers: &HeaderMap,
+    (status, body): (StatusCode, PageBody),
+) -> axum::response::Response {
+    if status != StatusCode::OK {
+        return (status, body).into_response();
+    }
+    let etag = body.etag();
+    let mut response = if is_etag_match(headers, &etag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        body.into_response()
+    };
+    set_validators(response.headers_mut(), &etag, None);
+    response
+}
+
+impl IntoResponse for PageBody {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            PageBody::Whole(html) => Html(html).into_response(),
+            PageBody::Streamed {
+                head,
+                content,
+                tail,
+            } => {
+                let chunks = (0..content.len())
+                    .step_by(STREAM_CHUNK_SIZE)
+                    .map(move |start| {
+                        content.slice(start..(start + STREAM_CHUNK_SIZE).min(content.len()))
+                    });
+                let parts = std::iter::once(Bytes::from(head))
+                    .chain(chunks)
+                    .chain(std::iter::once(Bytes::from(tail)))
+                    .map(Ok::<_, std::convert::Infallible>);
+                Html(Body::from_stream(futures_util::stream::iter(parts))).into_response()
+            }
+        }
+    }
+}
+
+/// Render the template around a placeholder and split the page there, so the
+/// head goes out first and the document itself is sent in chunks without
+/// being copied into one large page string. Returns `None` when the template
+/// doesn't emit the content exactly once, or fails; the caller then renders
+/// the page normally.
+fn streamed_page(template: &dyn PageTemplate, page: &PageContext<'_>) -> Option<PageBody> {
+    let placeholder: Arc<str> = CONTENT_PLACEHOLDER.into();
+    let shell = template
+        .render(&PageContext {
+            content: &placeholder,
+            ..*page
+        })
+        .ok()?;
+
+    let mut parts = shell.split(CONTENT_PLACEHOLDER);
+    let (head, tail) = (parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some(PageBody::Streamed {
+        head: head.to_string(),
+        content: Bytes::from_owner(SharedHtml(page.content.clone())),
+        tail: tail.to_string(),
+    })
+}
+
+/// Lets a response body borrow cached HTML instead of copying it.
+struct SharedHtml(Arc<str>);
+
+impl AsRef<[u8]> for SharedHtml {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+/// Serve a shared document, or an asset it references, after verifying the
+/// share token in the path.
+pub(super) async fn serve_shared(
+    AxumPath((token, path)): AxumPath<(String, String)>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let Some(filename) = share::verify_token(&state.settings.share_secret, &token) else {
+        return plain_text_error(StatusCode::FORBIDDEN);
+    };
+
+    if path == filename {
+        match render_markdown(&state.markdown, &filename, true).await {
+            (StatusCode::NOT_FOUND, _) => plain_text_error(StatusCode::NOT_FOUND),
+            page => page_response(&headers, page),
+        }
+    } else if !is_markdown_file(Path::new(&path))
+        && state.settings.static_options.allows_referenced(&path)
+        // Only what the shared document uses, whatever else is servable
+        && state.markdown.read().await.references(Some(&filename), &path)
+    {
+        serve_static_file_inner(path, &state.settings, &headers).await
+    } else {
+        plain_text_error(StatusCode::NOT_FOUND)
+    }
+}
+
+#[derive(Deserialize)]
+pub(super) struct SyncRequest {
+    file: String,
+    line: usize,
+}
+
+/// Scroll the pages showing a document to a source line.
+pub(super) async fn sync_scroll(
+    State(state): State<AppState>,
+    Json(request): Json<SyncRequest>,
+) -> axum::response::Response {
+    if !state
+        .markdown
+        .read()
+        .await
+        .tracked_files
+        .contains_key(&request.file)
+    {
+        return plain_text_error(StatusCode::NOT_FOUND);
+    }
+    let _ = state.change_tx.send(ServerMessage::ScrollTo {
+        file: request.file,
+        line: request.line,
+    });
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[derive(Deserialize)]
+pub(super) struct ShareRequest {
+    /// Name the document is tracked under, as the preview knows it.
+    file: Option<String>,
+    /// Or its path on disk, as `mdserve share` knows it.
+    path: Option<PathBuf>,
+    /// How long the link stays valid; a day if not given.
+    expires_in_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub(super) struct ShareResponse {
+    path: String,
+    expires_in_secs: u64,
+}
+
+pub(super) async fn create_share_link(
+    State(state): State<AppState>,
+    Json(request): Json<ShareRequest>,
+) -> axum::response::Response {
+    let filename = match (request.file, request.path) {
+        (Some(file), _) => state
+            .markdown
+            .read()
+            .await
+            .tracked_files
+            .contains_key(&file)
+            .then_some(file),
+        (None, Some(path)) => tracked_name_of(&state, &path).await,
+        (None, None) => None,
+    };
+    let Some(filename) = filename else {
+        return plain_text_error(StatusCode::NOT_FOUND);
+    };
+
+    let settings = &state.settings;
+    let ttl = request
+        .expires_in_secs
+        .map_or(share::DEFAULT_SHARE_TTL, Duration::from_secs);
+    let token = share::mint_token(&settings.share_secret, &filename, ttl);
+    Json(ShareResponse {
+        path: format!(
+            "{}{}",
+            settings.base_path,
+            share::share_path(&token, &filename)
+        ),
+        expires_in_secs: ttl.as_secs(),
+    })
+    .into_response()
+}
+
+/// Name the document at `path` is tracked under, if it is tracked: the
+/// name the server derives for it must be tracked for that very file, not
+/// another one that happens to share the name.
+async fn tracked_name_of(state: &AppState, path: &Path) -> Option<String> {
+    let path = paths::canonicalize_async(path).await.ok()?;
+    let name = state.settings.tracked_name(&path)?;
+    let tracked_path = state
+        .markdown
+        .read()
+        .await
+        .tracked_files
+        .get(&name)?
+        .path
+        .clone();
+    let tracked_path = paths::canonicalize_async(&tracked_path).await.ok()?;
+    (tracked_path == path).then_some(name)
+}
+
+#[derive(Deserialize)]
+pub(super) struct RenderQuery {
+    /// File the source belongs to, passed on to `map_html` callbacks.
+    file: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(super) struct RenderResponse {
+    html: String,
+    features: BTreeSet<String>,
+}
+
+/// What this server serves, so another invocation can find it.
+pub(super) async fn instance_info(State(state): State<AppState>) -> Json<Arc<InstanceInfo>> {
+    Json(state.settings.instance.clone())
+}
+
+/// The rendered HTML of a tracked file, e.g. for link previews.
+pub(super) async fn render_tracked(
+    AxumPath(filename): AxumPath<String>,
+    State(state): State<AppState>,
+) -> axum::response::Response {
+    let response = with_document(&state.markdown, &filename, |_, document| RenderResponse {
+        html: document.html.to_string(),
+        features: document.features.clone(),
+    })
+    .await;
+    match response {
+        Some(response) => Json(response).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Render markdown sent in the request body, such as unsaved editor
+/// contents for the live preview or text from an editor plugin. Nothing is
+/// cached.
+pub(super) async fn render_preview(
+    State(state): State<AppState>,
+    Query(query): Query<RenderQuery>,
+    source: String,
+) -> Json<RenderResponse> {
+    let document = state
+        .settings
+        .renderer
+        .render(query.file.as_deref().unwrap_or(""), &source);
+    Json(RenderResponse {
+        html: document.html,
+        features: document.features,
+    })
+}
+
+pub(super) async fn get_draft(
+    AxumPath(filename): AxumPath<String>,
+    State(state): State<AppState>,
+) -> axum::response::Response {
+    match state.drafts.lock().await.get(&filename) {
+        Some(draft) => Json(draft.clone()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Autosave editor contents for a tracked file.
+pub(super) async fn put_draft(
+    AxumPath(filename): AxumPath<String>,
+    State(state): State<AppState>,
+    source: String,
+) -> StatusCode {
+    if !state
+        .markdown
+        .read()
+        .await
+        .tracked_files
+        .contains_key(&filename)
+    {
+        return StatusCode::NOT_FOUND;
+    }
+
+    let saved_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    state
+        .drafts
+        .lock()
+        .await
+        .insert(filename, Draft { source, saved_at });
+    StatusCode::NO_CONTENT
+}
+
+pub(super) async fn delete_draft(
+    AxumPath(filename): AxumPath<String>,
+    State(state): State<AppState>,
+) -> StatusCode {
+    state.drafts.lock().await.remove(&filename);
+    StatusCode::NO_CONTENT
+}
+
+/// Write a markdown file under the base directory: a tracked file, or in
+/// directory mode a new one. The write is atomic and goes through the same
+/// refresh and reload as a change picked up by the watcher.
+pub(super) async fn save_file(
+    AxumPath(filename): AxumPath<String>,
+    State(state): State<AppState>,
+    source: String,
+) -> axum::response::Response {
+    let name = Path::new(&filename);
+    if !is_markdown_file(name) {
+        return plain_text_error(StatusCode::FORBIDDEN);
+    }
+
+    let tracked_path = state
+        .markdown
+        .read()
+        .await
+        .tracked_files
+        .get(&filename)
+        .map(|t| t.path.clone());
+    // Tracked files may be nested in recursive mode; new ones are only
+    // created directly in the base directory
+    let is_plain_name = matches!(
+        name.components().collect::<Vec<_>>().as_slice(),
+        [Component::Normal(_)]
+    );
+    let path = match tracked_path {
+        Some(path) => path,
+        None if !is_plain_name => return plain_text_error(StatusCode::FORBIDDEN),
+        None if state.settings.is_directory_mode => state.settings.base_dir.join(name),
+        None => return plain_text_error(StatusCode::NOT_FOUND),
+    };
+
+    // Write through a symlinked file rather than replacing the link
+    let target = paths::canonicalize_async(&path)
+        .await
+        .unwrap_or_else(|_| path.clone());
+    let created = !tokio::fs::try_exists(&target).await.unwrap_or(false);
+
+    if let Err(e) = write_atomically(&target, source.as_bytes()).await {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to save {filename}: {e}"),
+        )
+            .into_response();
+    }
+
+    state.drafts.lock().await.remove(&filename);
+    // Don't wait for (or depend on) the change source
+    handle_markdown_file_change(&path, &state.markdown, None).await;
+
+    if created {
+        StatusCode::CREATED.into_response()
+    } else {
+        StatusCode::NO_CONTENT.into_response()
+    }
+}
+
+#[derive(Deserialize)]
+pub(super) struct UploadQuery {
+    /// Tracked document the file is added to.
+    file: String,
+    /// Original name of the uploaded file. Pasted images have none and are
+    /// named after the time instead.
+    name: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(super) struct UploadResponse {
+    /// Where the file was saved, relative to the document.
+    path: String,
+}
+
+/// Save a file dropped or pasted into the editor in the upload folder next
+/// to its document, under a name no other file has.
+pub(super) async fn upload_file(
+    State(state): State<AppState>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    contents: Bytes,
+) -> axum::response::Response {
+    let settings = &state.settings;
+    let (stem, extension) = match query.name.as_deref() {
+        Some(name) => match sanitize_upload_name(name) {
+            Some(parts) => parts,
+            None => return (StatusCode::BAD_REQUEST, "Invalid file name").into_response(),
+        },
+        None => {
+            let Some(extension) = headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(pasted_image_extension)
+            else {
+                return (
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "Only PNG, JPEG, GIF and WebP images can be pasted",
+                )
+                    .into_response();
+            };
+            let stamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis())
+                .unwrap_or_default();
+            (format!("image-{stamp}"), extension.to_string())
+        }
+    };
+
+    if !settings
+        .upload_options
+        .accepts(&extension, &settings.static_options)
+    {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!(".{extension} files are not accepted"),
+        )
+            .into_response();
+    }
+    if !has_image_signature(&extension, &contents) {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("The upload is not a .{extension} image"),
+        )
+            .into_response();
+    }
+
+    let document = state
+        .markdown
+        .read()
+        .await
+        .tracked_files
+        .get(&query.file)
+        .map(|t| t.path.clone());
+    let Some(document) = document else {
+        return plain_text_error(StatusCode::NOT_FOUND);
+    };
+
+    let upload_dir = &settings.upload_options.dir;
+    let dir = document
+        .parent()
+        .unwrap_or(&settings.base_dir)
+        .join(upload_dir);
+
+    let saved = async {
+        tokio::fs::create_dir_all(&dir).await?;
+        let (name, mut file) = create_unique_file(&dir, &stem, &extension).await?;
+        file.write_all(&contents).await?;
+        file.flush().await?;
+        Ok::<_, std::io::Error>(name)
+    }
+    .await;
+
+    match saved {
+        Ok(name) if upload_dir.is_empty() => Json(UploadResponse { path: name }).into_response(),
+        Ok(name) => Json(UploadResponse {
+            path: format!("{upload_dir}/{name}"),
+        })
+        .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to save upload: {e}"),
+        )
+            .into_response(),
+    }
+}
+
+/// Extension for a pasted image's MIME type.
+fn pasted_image_extension(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    match mime.to_ascii_lowercase().as_str() {
+        "image/png" => Some("png"),
+        "image/jpeg" => Some("jpg"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        _ => None,
+    }
+}
+
+/// Whether `contents` start like an image of the format `extension` names.
+/// Other extensions have no signature to check and always pass.
+fn has_image_signature(extension: &str, contents: &[u8]) -> bool {
+    match extension.to_ascii_lowercase().as_str() {
+        "png" => contents.starts_with(b"\x89PNG"),
+        "jpg" | "jpeg" => contents.starts_with(b"\xFF\xD8\xFF"),
+        "gif" => contents.starts_with(b"GIF87a") || contents.starts_with(b"GIF89a"),
+        "webp" => contents.starts_with(b"RIFF") && contents.get(8..12) == Some(b"WEBP"),
+        _ => true,
+    }
+}
+
+/// Split an uploaded file's name into a stem and extension that are safe to
+/// create and link: only its last component is kept, characters outside
+/// `[A-Za-z0-9._-]` become `-`, and leading dots are dropped so the file
+/// is not hidden.
+fn sanitize_upload_name(name: &str) -> Option<(String, String)> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    let cleaned: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let (stem, extension) = cleaned.trim_start_matches('.').rsplit_once('.')?;
+    if stem.is_empty() || extension.is_empty() {
+        return None;
+    }
+    Some((stem.to_string(), extension.to_string()))
+}
+
+/// Create `<stem>.<extension>` in `dir`, or `<stem>-1.<extension>` and so on
+/// if taken, without ever replacing an existing file.
+async fn create_unique_file(
+    dir: &Path,
+    stem: &str,
+    extension: &str,
+) -> std::io::Result<(String, tokio::fs::File)> {
+    for attempt in 0u32.. {
+        let name = match attempt {
+            0 => format!("{stem}.{extension}"),
+            n => format!("{stem}-{n}.{extension}"),
+        };
+        match tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dir.join(&name))
+            .await
+        {
+            Ok(file) => return Ok((name, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("ran out of file names")
+}
+
+/// Replace `path` with `contents` through a temporary file in the same
+/// directory and a rename, so readers never see a half-written file. The
+/// temporary name is hidden, so it is never served or tracked.
+async fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let temp = dir.join(format!(
+        ".{name}.mdserve-{}-{}.tmp",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = async {
+        let mut file = tokio::fs::File::create(&temp).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        if let Ok(metadata) = tokio::fs::metadata(path).await {
+            tokio::fs::set_permissions(&temp, metadata.permissions()).await?;
+        }
+        tokio::fs::rename(&temp, path).await
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp).await;
+    }
+    result
+}
+
+pub(super) async fn serve_mermaid_js(headers: HeaderMap) -> axum::response::Response {
+    let coding = ContentCoding::negotiate(&headers);
+    let etag = match coding {
+        ContentCoding::Brotli => MERMAID_BROTLI_ETAG,
+        ContentCoding::Gzip => MERMAID_GZIP_ETAG,
+        ContentCoding::Identity => MERMAID_ETAG,
+    };
+
+    if is_etag_match(&headers, etag) {
+        return mermaid_response(StatusCode::NOT_MODIFIED, etag, coding, false);
+    }
+
+    mermaid_response(StatusCode::OK, etag, coding, true)
+}
+
+/// Pages load Mermaid from this URL. Its content never changes, since a new
+/// bundle gets a new URL, so browsers can skip revalidation entirely.
+pub(super) async fn serve_versioned_mermaid_js(headers: HeaderMap) -> axum::response::Response {
+    versioned_asset_response(&headers, &MERMAID_ASSET)
+}
+
+/// Pages with client-side math load KaTeX from this URL, cached like
+/// Mermaid's.
+pub(super) async fn serve_versioned_katex_js(headers: HeaderMap) -> axum::response::Response {
+    versioned_asset_response(&headers, &KATEX_ASSET)
+}
+
+fn versioned_asset_response(
+    headers: &HeaderMap,
+    asset: &EmbeddedAsset,
+) -> axum::response::Response {
+    let mut response = embedded_asset_response(
+        StatusCode::OK,
+        "application/javascript",
+        asset,
+        ContentCoding::negotiate(headers),
+    );
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=31536000, immutable"),
+    );
+    response
+}
+
+/// Whether `If-None-Match` is `*` or lists `etag`. The header compares
+/// weakly, so a `W/` prefix is ignored.
+fn is_etag_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|etags| {
+            etags
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+        })
+}
+
+fn mermaid_response(
+    status: StatusCode,
+    etag: &'static str,
+    coding: ContentCoding,
+    with_body: bool,
+) -> axum::response::Response {
+    let mut response = if with_body {
+        embedded_asset_response(status, "application/javascript", &MERMAID_ASSET, coding)
+    } else {
+        let mut response = status.into_response();
+        response
+            .headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+        response
+    };
+
+    // Use no-cache to force revalidation on each request. This ensures clients
+    // get updated content when mdserve is rebuilt with a new Mermaid version,
+    // while still benefiting from 304 responses via ETag matching.
+    let headers = response.headers_mut();
+    headers.insert(header::ETAG, HeaderValue::from_static(etag));
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("public, no-cache"),
+    );
+    response
+}
+
+/// A bundled asset and its copies compressed at build time.
+struct EmbeddedAsset {
+    plain: &'static [u8],
+    gzip: &'static [u8],
+    brotli: &'static [u8],
+}
+
+const MERMAID_ASSET: EmbeddedAsset = EmbeddedAsset {
+    plain: MERMAID_JS.as_bytes(),
+    gzip: MERMAID_JS_GZIP,
+    brotli: MERMAID_JS_BROTLI,
+};
+
+const KATEX_ASSET: EmbeddedAsset = EmbeddedAsset {
+    plain: KATEX_JS.as_bytes(),
+    gzip: KATEX_JS_GZIP,
+    brotli: KATEX_JS_BROTLI,
+};
+
+/// Encodings bundled assets are precompressed in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ContentCoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl ContentCoding {
+    /// The smallest encoding `Accept-Encoding` allows: brotli, then gzip.
+    fn negotiate(headers: &HeaderMap) -> Self {
+        if accepts_encoding(headers, "br") {
+            ContentCoding::Brotli
+        } else if accepts_encoding(headers, "gzip") {
+            ContentCoding::Gzip
+        } else {
+            ContentCoding::Identity
+        }
+    }
+}
+
+/// Respond with a bundled asset, picking the precompressed copy for
+/// `coding`.
+fn embedded_asset_response(
+    status: StatusCode,
+    content_type: &'static str,
+    asset: &EmbeddedAsset,
+    coding: ContentCoding,
+) -> axum::response::Response {
+    let (body, encoding) = match coding {
+        ContentCoding::Brotli => (asset.brotli, Some("br")),
+        ContentCoding::Gzip => (asset.gzip, Some("gzip")),
+        ContentCoding::Identity => (asset.plain, None),
+    };
+    let mut response = (status, [(header::CONTENT_TYPE, content_type)], body).into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    if let Some(encoding) = encoding {
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+    }
+    response
+}
+
+/// Whether `Accept-Encoding` allows `coding` (explicitly or via `*`) with a
+/// non-zero quality.
+fn accepts_encoding(headers: &HeaderMap, coding: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|accepted| {
+            let mut params = accepted.split(';');
+            let name = params.next().unwrap_or("").trim();
+            let rejected = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case(coding) || name == "*") && !rejected
+        })
+}
+
+async fn serve_static_file_inner(
+    filename: String,
+    settings: &RouterSettings,
+    headers: &HeaderMap,
+) -> axum::response::Response {
+    let (root, relative) = settings.static_root(&filename);
+    let canonical_path = match resolve_static_path(root, &settings.static_options, relative).await {
+        Ok(path) => path,
+        Err(status) => return plain_text_error(status),
+    };
+
+    let Ok(metadata) = tokio::fs::metadata(&canonical_path).await else {
+        return plain_text_error(StatusCode::NOT_FOUND);
+    };
+    let etag = file_etag(&metadata);
+    let modified = metadata.modified().ok();
+    let mut response = if is_not_modified(headers, &etag, modified) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        match open_file_stream(&canonical_path).await {
+            Ok((len, body)) => {
+                let content_type = guess_content_type(&filename);
+                (
+                    StatusCode::OK,
+                    [
+                        (header::CONTENT_TYPE, content_type),
+                        (header::CONTENT_LENGTH, len.to_string()),
+                    ],
+                    body,
+                )
+                    .into_response()
+            }
+            Err(_) => return plain_text_error(StatusCode::NOT_FOUND),
+        }
+    };
+    set_validators(response.headers_mut(), &etag, modified);
+    response
+}
+
+/// ETag for a file on disk, from its size and modification time, so
+/// checking it never reads the file.
+fn file_etag(metadata: &std::fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", metadata.len(), modified.as_nanos())
+}
+
+/// Whether the browser's copy is current: its `If-None-Match` lists `etag`,
+/// or, without that header, `If-Modified-Since` is no older than `modified`.
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    if headers.contains_key(header::IF_NONE_MATCH) {
+        return is_etag_match(headers, etag);
+    }
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok());
+    match (since, modified) {
+        // HTTP dates have whole seconds
+        (Some(since), Some(modified)) => modified
+            .duration_since(since)
+            .map_or(true, |newer| newer < Duration::from_secs(1)),
+        _ => false,
+    }
+}
+
+/// Add validators and `Cache-Control: no-cache` to a response: files and
+/// pages can change at any moment, so browsers keep them but check back
+/// before each use, and get a `304` while they are unchanged.
+fn set_validators(headers: &mut HeaderMap, etag: &str, modified: Option<SystemTime>) {
+    if let Ok(etag) = HeaderValue::from_str(etag) {
+        headers.insert(header::ETAG, etag);
+    }
+    if let Some(modified) = modified {
+        if let Ok(date) = HeaderValue::from_str(&httpdate::fmt_http_date(modified)) {
+            headers.insert(header::LAST_MODIFIED, date);
+        }
+    }
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+}
+
+/// Open a file as a streaming body along with its length, so large assets
+/// are sent from disk in chunks instead of being read into memory first.
+/// Seeking the file before wrapping it is all a `Range` request would need.
+async fn open_file_stream(path: &Path) -> std::io::Result<(u64, Body)> {
+    let file = tokio::fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    Ok((len, Body::from_stream(ReaderStream::new(file))))
+}
+
+/// Serve a tracked markdown file's source or a servable asset as an
+/// attachment, so browsers save it instead of displaying it.
+pub(super) async fn serve_download(
+    AxumPath(filename): AxumPath<String>,
+    State(state): State<AppState>,
+) -> axum::response::Response {
+    let tracked_path = state
+        .markdown
+        .read()
+        .await
+        .tracked_files
+        .get(&filename)
+        .map(|t| t.path.clone());
+    let settings = &*state.settings;
+    let static_options = &settings.static_options;
+
+    let (path, content_type) = if let Some(path) = tracked_path {
+        (path, "text/markdown; charset=utf-8".to_string())
+    } else if servable(&state, None, &filename).await {
+        let (root, relative) = settings.static_root(&filename);
+        match resolve_static_path(root, static_options, relative).await {
+            Ok(path) => (path, guess_content_type(&filename)),
+            Err(status) => return plain_text_error(status),
+        }
+    } else {
+        return plain_text_error(StatusCode::NOT_FOUND);
+    };
+
+    match open_file_stream(&path).await {
+        Ok((len, body)) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, content_type),
+                (header::CONTENT_LENGTH, len.to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    attachment_disposition(&filename),
+                ),
+            ],
+            body,
+        )
+            .into_response(),
+        Err(_) => plain_text_error(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Serve a tracked markdown file's source as it is on disk, for tools and
+/// for comparing it with the rendered page.
+pub(super) async fn serve_raw(
+    AxumPath(filename): AxumPath<String>,
+    State(state): State<AppState>,
+) -> axum::response::Response {
+    let tracked_path = state
+        .markdown
+        .read()
+        .await
+        .tracked_files
+        .get(&filename)
+        .map(|t| t.path.clone());
+    let Some(path) = tracked_path else {
+        return plain_text_error(StatusCode::NOT_FOUND);
+    };
+
+    match open_file_stream(&path).await {
+        Ok((len, body)) => (
+            StatusCode::OK,
+            [
+                (
+                    header::CONTENT_TYPE,
+                    "text/markdown; charset=utf-8".to_string(),
+                ),
+                (header::CONTENT_LENGTH, len.to_string()),
+                (header::CACHE_CONTROL, "no-cache".to_string()),
+            ],
+            body,
+        )
+            .into_response(),
+        Err(_) => plain_text_error(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Build a `Content-Disposition: attachment` value for the last component
+/// of `filename`, with an RFC 5987 encoded variant for non-ASCII names.
+fn attachment_disposition(filename: &str) -> String {
+    let name = Path::new(filename)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("download");
+
+    let fallback: String = name
+        .chars()
+        .map(|c| {
+            if (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    format!(
+        "attachment; filename=\"{fallback}\"; filename*=UTF-8''{}",
+        percent_encode(name)
+    )
+}
+
+/// Resolve a request path to a file under the base directory, applying the
+/// symlink policy. Errors carry the status code to respond with.
+///
+/// The request path is checked lexically and each of its components is
+/// `lstat`ed, which is much cheaper than canonicalizing the whole path on
+/// network filesystems. Canonicalization is only needed when a component
+/// turns out to be a symlink and symlinks are not followed.
+pub(super) async fn resolve_static_path(
+    base_dir: &Path,
+    static_options: &StaticFileOptions,
+    filename: &str,
+) -> Result<PathBuf, StatusCode> {
+    // Browsers resolve `..` before sending, so anything left is hostile
+    if !is_contained_relative_path(Path::new(filename)) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let mut path = base_dir.to_path_buf();
+    let mut through_symlink = false;
+    for component in Path::new(filename).components() {
+        path.push(component);
+        let metadata = tokio::fs::symlink_metadata(&path).await.map_err(|_| {
+            tracing::debug!(file = %path.display(), "not served: no such file");
+            StatusCode::NOT_FOUND
+        })?;
+        through_symlink |= metadata.file_type().is_symlink();
+    }
+
+    // Symlinks may legitimately resolve outside base_dir when followed
+    if !through_symlink || static_options.follow_symlinks {
+        return Ok(path);
+    }
+
+    let canonical_path = paths::canonicalize_async(&path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    if canonical_path.starts_with(base_dir) {
+        Ok(canonical_path)
+    } else {
+        tracing::debug!(
+            file = %canonical_path.display(),
+            "not served: symlink leads outside the directory (see --follow-symlinks)"
+        );
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+fn plain_text_error(status: StatusCode) -> axum::response::Response {
+    let message = if status == StatusCode::FORBIDDEN {
+        "Access denied"
+    } else {
+        "File not found"
+    };
+
+    (
+        status,
+        [(header::CONTENT_TYPE, "text/plain")],
+        message.to_string(),
+    )
+        .into_response()
+}
+
+/// Content type for a static file, from its extension. Text and JavaScript
+/// are declared as UTF-8.
+fn guess_content_type(file_path: &str) -> String {
+    let mime = mime_guess::from_path(file_path).first_or_octet_stream();
+    if mime.type_() == mime_guess::mime::TEXT || mime.subtype() == mime_guess::mime::JAVASCRIPT {
+        format!("{mime}; charset=utf-8")
+    } else {
+        mime.to_string()
+    }
+}
+
+pub(super) async fn websocket_handler(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> axum::response::Response {
+    // Browsers don't apply CORS to WebSockets, and a socket can move every
+    // open preview with `ScrollTo`
+    if !auth::is_same_origin(&headers) {
+        return (
+            StatusCode::FORBIDDEN,
+            "Forbidden: pages from other sites can't connect",
+        )
+            .into_response();
+    }
+    // Subscribe before completing the handshake so messages sent right
+    // after the client connects are not lost.
+    let change_rx = state.change_tx.subscribe();
+    // Sockets may only pass scroll positions on when syncing is on
+    let scroll_tx = state.settings.scroll_sync.then(|| state.change_tx.clone());
+    ws.on_upgrade(move |socket| handle_websocket(socket, change_rx, scroll_tx))
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub(super) struct EventsQuery {
+    /// The tracked document the page shows, as in [`ClientMessage::Viewing`].
+    file: Option<String>,
+}
+
+/// Server-Sent Events stream of the messages `/ws` sends, for browsers
+/// whose WebSocket is blocked by a proxy or extension.
+pub(super) async fn events_handler(
+    State(state): State<AppState>,
+    Query(query): Query<EventsQuery>,
+) -> Sse<impl Stream<Item = Result<sse::Event, std::convert::Infallible>>> {
+    let change_rx = state.change_tx.subscribe();
+    let events = stream::unfold(Some(change_rx), |change_rx| async move {
+        let mut change_rx = change_rx?;
+        let message = next_message(&mut change_rx).await?;
+        // The stream ends once the server says it is stopping
+        let change_rx = (message != ServerMessage::Shutdown).then_some(change_rx);
+        Some((message, change_rx))
+    })
+    .filter_map(move |message| {
+        let event = reaches(&message, query.file.as_deref())
+            .then(|| sse::Event::default().json_data(&message).ok())
+            .flatten();
+        std::future::ready(event.map(Ok))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Next message for a WebSocket client, or `None` once the server is gone.
+/// A client that lagged behind the buffer missed messages it can't get back,
+/// so it is told to reload, which catches it up on any document change.
+async fn next_message(change_rx: &mut broadcast::Receiver<ServerMessage>) -> Option<ServerMessage> {
+    match change_rx.recv().await {
+        Ok(message) => Some(message),
+        Err(broadcast::error::RecvError::Lagged(_)) => Some(ServerMessage::Reload { file: None }),
+        Err(broadcast::error::RecvError::Closed) => None,
+    }
+}
+
+/// Whether a client viewing `viewing` should get `message`. Clients that
+/// haven't said what they show get every reload.
+fn reaches(message: &ServerMessage, viewing: Option<&str>) -> bool {
+    match (message, viewing) {
+        (ServerMessage::Reload { file: Some(file) }, Some(viewing)) => file == viewing,
+        (ServerMessage::ScrollTo { file, .. }, viewing) => viewing == Some(file.as_str()),
+        _ => true,
+    }
+}
+
+async fn handle_websocket(
+    socket: WebSocket,
+    mut change_rx: broadcast::Receiver<ServerMessage>,
+    scroll_tx: Option<broadcast::Sender<ServerMessage>>,
+) {
+    let (mut sender, mut receiver) = socket.split();
+    let (viewing_tx, viewing_rx) = watch::channel(None::<String>);
+
+    let recv_task = tokio::spawn(async move {
+        while let Some(msg) = receiver.next().await {
+            match msg {
+                Ok(Message::Text(text)) => match serde_json::from_str(&text) {
+                    Ok(ClientMessage::Viewing { file }) => {
+                        viewing_tx.send_replace(Some(file));
+                    }
+                    Ok(ClientMessage::ScrollTo { file, line }) => {
+                        if let Some(scroll_tx) = &scroll_tx {
+                            let _ = scroll_tx.send(ServerMessage::ScrollTo { file, line });
+                        }
+                    }
+                    Err(_) => {}
+                },
+                Ok(Message::Close(_)) => break,
+                _ => {}
+            }
+        }
+    });
+
+    let send_task = tokio::spawn(async move {
+        while let Some(reload_msg) = next_message(&mut change_rx).await {
+            if !reaches(&reload_msg, viewing_rx.borrow().as_deref()) {
+                continue;
+            }
+            if let Ok(json) = serde_json::to_string(&reload_msg) {
+                if sender.send(Message::Text(json)).await.is_err() {
+                    break;
+                }
+            }
+            if reload_msg == ServerMessage::Shutdown {
+                let _ = sender.send(Message::Close(None)).await;
+                break;
+            }
+        }
+    });
+
+    tokio::select! {
+        _ = recv_task => {},
+        _ = send_task => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_reloads_reach_only_clients_viewing_the_file() {
+        let edit = ServerMessage::Reload {
+            file: Some("a.md".to_string()),
+        };
+        assert!(reaches(&edit, Some("a.md")));
+        assert!(!reaches(&edit, Some("b.md")));
+        assert!(reaches(&edit, None));
+        assert!(reaches(&ServerMessage::Reload { file: None }, Some("b.md")));
+
+        let viewing: ClientMessage =
+            serde_json::from_str(r#"{"type":"Viewing","file":"guides/setup.md"}"#).unwrap();
+        assert_eq!(
+            viewing,
+            ClientMessage::Viewing {
+                file: "guides/setup.md".to_string()
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&ServerMessage::Reload { file: None }).unwrap(),
+            r#"{"type":"Reload"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lagged_clients_get_a_catch_up_reload() {
+        let (tx, mut rx) = broadcast::channel(1);
+        for kind in ["a", "b", "c"] {
+            tx.send(ServerMessage::Custom {
+                kind: kind.to_string(),
+                payload: serde_json::Value::Null,
+            })
+            .unwrap();
+        }
+
+        assert_eq!(
+            next_message(&mut rx).await,
+            Some(ServerMessage::Reload { file: None })
+        );
+        assert!(matches!(
+            next_message(&mut rx).await,
+            Some(ServerMessage::Custom { kind, .. }) if kind == "c"
+        ));
+
+        drop(tx);
+        assert_eq!(next_message(&mut rx).await, None);
+    }
+
+    #[test]
+    fn test_guess_image_content_types() {
+        assert_eq!(guess_content_type("test.png"), "image/png");
+        assert_eq!(guess_content_type("test.jpg"), "image/jpeg");
+        assert_eq!(guess_content_type("test.jpeg"), "image/jpeg");
+        assert_eq!(guess_content_type("test.gif"), "image/gif");
+        assert_eq!(guess_content_type("test.svg"), "image/svg+xml");
+        assert_eq!(guess_content_type("test.webp"), "image/webp");
+        assert_eq!(guess_content_type("test.bmp"), "image/bmp");
+        assert_eq!(guess_content_type("test.ico"), "image/x-icon");
+
+        assert_eq!(guess_content_type("test.PNG"), "image/png");
+        assert_eq!(guess_content_type("test.JPG"), "image/jpeg");
+
+        assert_eq!(
+            guess_content_type("test.unknown"),
+            "application/octet-stream"
+        );
+        assert_eq!(guess_content_type("test"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_sanitize_upload_name() {
+        assert_eq!(
+            sanitize_upload_name("C:\\Users\\me\\plot (1).PNG"),
+            Some(("plot--1-".to_string(), "PNG".to_string()))
+        );
+        assert_eq!(
+            sanitize_upload_name("..hidden.txt"),
+            Some(("hidden".to_string(), "txt".to_string()))
+        );
+        assert_eq!(sanitize_upload_name(".bashrc"), None);
+        assert_eq!(sanitize_upload_name("archive."), None);
+    }
+
+    #[tokio::test]
+    async fn test_write_atomically_keeps_permissions() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let file = temp_dir.path().join("a.md");
+        fs::write(&file, "old").expect("Failed to write");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        }
+
+        write_atomically(&file, b"new").await.unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_resolve_static_path_symlinks_inside_base_dir() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let base_dir = temp_dir.path().canonicalize().unwrap();
+        fs::create_dir(base_dir.join("assets")).unwrap();
+        fs::write(base_dir.join("assets/logo.png"), b"png").unwrap();
+        std::os::unix::fs::symlink(base_dir.join("assets"), base_dir.join("img")).unwrap();
+
+        let options = StaticFileOptions::default();
+        assert_eq!(
+            resolve_static_path(&base_dir, &options, "assets/logo.png").await,
+            Ok(base_dir.join("assets/logo.png"))
+        );
+        // Not followed, but it stays inside the base directory
+        assert_eq!(
+            resolve_static_path(&base_dir, &options, "img/logo.png").await,
+            Ok(base_dir.join("assets/logo.png"))
+        );
+        assert_eq!(
+            resolve_static_path(&base_dir, &options, "assets/../assets/logo.png").await,
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            resolve_static_path(&base_dir, &options, "missing.png").await,
+            Err(StatusCode::NOT_FOUND)
+        );
+    }
+
+    #[test]
+    fn test_guess_content_type() {
+        assert_eq!(guess_content_type("spec.pdf"), "application/pdf");
+        assert_eq!(guess_content_type("data.JSON"), "application/json");
+        assert_eq!(guess_content_type("style.css"), "text/css; charset=utf-8");
+        assert_eq!(guess_content_type("test.png"), "image/png");
+        assert_eq!(guess_content_type("notes.txt"), "text/plain; charset=utf-8");
+        assert_eq!(
+            guess_content_type("app.js"),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(guess_content_type("font.woff2"), "font/woff2");
+        assert_eq!(guess_content_type("clip.mp4"), "video/mp4");
+    }
+
+    #[test]
+    fn test_attachment_disposition() {
+        assert_eq!(
+            attachment_disposition("notes.md"),
+            "attachment; filename=\"notes.md\"; filename*=UTF-8''notes.md"
+        );
+        assert_eq!(
+            attachment_disposition("images/my \"pic\".png"),
+            "attachment; filename=\"my _pic_.png\"; filename*=UTF-8''my%20%22pic%22.png"
+        );
+        assert_eq!(
+            attachment_disposition("café.md"),
+            "attachment; filename=\"caf_.md\"; filename*=UTF-8''caf%C3%A9.md"
+        );
+    }
+
+    #[test]
+    fn test_content_coding_negotiation() {
+        let negotiate = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+            ContentCoding::negotiate(&headers)
+        };
+
+        assert_eq!(negotiate("gzip, deflate, br"), ContentCoding::Brotli);
+        assert_eq!(negotiate("br;q=0, GZIP;q=0.5"), ContentCoding::Gzip);
+        assert_eq!(negotiate("*"), ContentCoding::Brotli);
+        assert_eq!(negotiate("gzip;q=0"), ContentCoding::Identity);
+        assert_eq!(negotiate("identity"), ContentCoding::Identity);
+        assert_eq!(
+            ContentCoding::negotiate(&HeaderMap::new()),
+            ContentCoding::Identity
+        );
+    }
+}
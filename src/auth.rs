@@ -0,0 +1,246 @@
+//! Access control for servers others can reach: HTTP Basic credentials or
+//! an access token, checked before any route runs, including the WebSocket
+//! upgrade. Routes that change state also turn away cross-site requests.
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    app::{percent_decode, percent_encode},
+    share::{self, constant_time_eq},
+};
+
+/// Cookie remembering an access token given in the URL, so the page's
+/// assets, API calls and WebSocket need not carry it.
+const TOKEN_COOKIE: &str = "mdserve_token";
+
+/// The legacy URL of the bundled Mermaid, which like the versioned one
+/// needs no credentials.
+const MERMAID_PATH: &str = "/mermaid.min.js";
+
+/// Credentials a server requires. A request is let in if it matches any
+/// of them.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AccessControl {
+    pub basic: Option<(String, String)>,
+    pub token: Option<String>,
+    /// Secret share links are signed with; a link with a valid token
+    /// carries its own access.
+    pub share_secret: Vec<u8>,
+    /// Paths of the versioned Mermaid and KaTeX bundles pages load.
+    pub asset_paths: Vec<String>,
+}
+
+/// A fresh access token for servers that must not run without one.
+pub(crate) fn random_token() -> String {
+    share::random_secret()
+        .iter()
+        .take(16)
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+impl AccessControl {
+    pub fn is_enabled(&self) -> bool {
+        self.basic.is_some() || self.token.is_some()
+    }
+
+    /// Whether `path` is reachable without credentials: exactly the bundled
+    /// scripts' URLs, and share links whose token verifies. Everything
+    /// else, `/api/instance` included, could reveal what is served.
+    fn is_open(&self, path: &str) -> bool {
+        if path == MERMAID_PATH || self.asset_paths.iter().any(|asset| asset == path) {
+            return true;
+        }
+        path.strip_prefix("/share/")
+            .and_then(|rest| rest.split_once('/'))
+            .is_some_and(|(token, _)| share::verify_token(&self.share_secret, token).is_some())
+    }
+
+    /// Whether `headers` carry valid Basic credentials, a bearer token or
+    /// the token cookie.
+    fn admits(&self, headers: &HeaderMap) -> bool {
+        let authorization = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        if let Some(authorization) = authorization {
+            if let Some(encoded) = authorization.strip_prefix("Basic ") {
+                if self.admits_basic(encoded) {
+                    return true;
+                }
+            } else if let Some(token) = authorization.strip_prefix("Bearer ") {
+                if self.admits_token(token) {
+                    return true;
+                }
+            }
+        }
+
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|cookies| cookies.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .filter(|(name, _)| *name == TOKEN_COOKIE)
+            .filter_map(|(_, value)| percent_decode(value))
+            .any(|token| self.admits_token(&token))
+    }
+
+    fn admits_basic(&self, encoded: &str) -> bool {
+        let Some((username, password)) = &self.basic else {
+            return false;
+        };
+        let Some(decoded) = STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+        else {
+            return false;
+        };
+        let Some((given_username, given_password)) = decoded.split_once(':') else {
+            return false;
+        };
+        // Both compared, so timing doesn't reveal which one was wrong
+        let username_ok = constant_time_eq(given_username.as_bytes(), username.as_bytes());
+        let password_ok = constant_time_eq(given_password.as_bytes(), password.as_bytes());
+        username_ok && password_ok
+    }
+
+    fn admits_token(&self, given: &str) -> bool {
+        self.token
+            .as_ref()
+            .is_some_and(|token| constant_time_eq(given.as_bytes(), token.as_bytes()))
+    }
+
+    /// The `401` asking for credentials.
+    fn challenge(&self) -> Response {
+        let mut response = (
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized: this preview needs credentials",
+        )
+            .into_response();
+        if self.basic.is_some() {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"mdserve\", charset=\"UTF-8\""),
+            );
+        }
+        response
+    }
+}
+
+/// Middleware letting through requests that satisfy `access`. A valid
+/// `?token=` query parameter is accepted too, and remembered in a cookie.
+pub(crate) async fn require_access(
+    State(access): State<Arc<AccessControl>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    if access.is_open(path) || access.admits(request.headers()) {
+        return next.run(request).await;
+    }
+
+    let query_token = Query::<HashMap<String, String>>::try_from_uri(request.uri())
+        .ok()
+        .and_then(|Query(mut query)| query.remove("token"));
+    match query_token {
+        Some(token) if access.admits_token(&token) => {
+            let cookie = format!(
+                "{TOKEN_COOKIE}={}; Path=/; HttpOnly; SameSite=Strict",
+                percent_encode(&token)
+            );
+            let mut response = next.run(request).await;
+            if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+                response.headers_mut().append(header::SET_COOKIE, cookie);
+            }
+            response
+        }
+        _ => access.challenge(),
+    }
+}
+
+/// Middleware turning away cross-site requests to routes that change files
+/// or other state. Browsers send a form-like POST to another origin without
+/// asking CORS first, so a missing `Access-Control-Allow-Origin` only hides
+/// the response from the page that sent it; the write has already happened.
+/// Requests with neither `Sec-Fetch-Site` nor `Origin` come from tools, not
+/// pages, and are let through.
+pub(crate) async fn require_same_origin(request: Request, next: Next) -> Response {
+    if is_same_origin(request.headers()) {
+        next.run(request).await
+    } else {
+        (
+            StatusCode::FORBIDDEN,
+            "Forbidden: requests from other sites can't change files",
+        )
+            .into_response()
+    }
+}
+
+/// Whether a request comes from one of this server's own pages, a tool, or
+/// the user (an address typed into the browser).
+pub(crate) fn is_same_origin(headers: &HeaderMap) -> bool {
+    if let Some(site) = headers.get("sec-fetch-site") {
+        return matches!(site.to_str(), Ok("same-origin" | "none"));
+    }
+    let Some(origin) = headers.get(header::ORIGIN) else {
+        return true;
+    };
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok());
+    origin
+        .to_str()
+        .ok()
+        .and_then(|origin| origin.split_once("://"))
+        .is_some_and(|(_, authority)| host == Some(authority))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_own_pages_and_tools_are_same_origin() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, HeaderValue::from_static(value));
+            }
+            headers
+        };
+
+        assert!(is_same_origin(&headers(&[])));
+        assert!(is_same_origin(&headers(&[(
+            "sec-fetch-site",
+            "same-origin"
+        )])));
+        assert!(is_same_origin(&headers(&[
+            ("origin", "http://127.0.0.1:3000"),
+            ("host", "127.0.0.1:3000"),
+        ])));
+        assert!(!is_same_origin(&headers(&[(
+            "sec-fetch-site",
+            "cross-site"
+        )])));
+        assert!(!is_same_origin(&headers(&[(
+            "sec-fetch-site",
+            "same-site"
+        )])));
+        assert!(!is_same_origin(&headers(&[
+            ("origin", "http://evil.example"),
+            ("host", "127.0.0.1:3000"),
+        ])));
+        assert!(!is_same_origin(&headers(&[
+            ("origin", "null"),
+            ("host", "127.0.0.1:3000"),
+        ])));
+    }
+}
@@ -0,0 +1,148 @@
+//! The HTTP client the CLI talks to a running mdserve with: to find one
+//! already serving a path, or to have one mint a share link.
+//!
+//! Over HTTPS, servers on a loopback address are trusted whatever their
+//! certificate, since `--tls` generates a new self-signed one every run.
+//! Other hosts need a certificate from a public certificate authority.
+
+use anyhow::{Context, Result};
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderValue, Request, Response},
+};
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use std::{net::IpAddr, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{self, ring, CryptoProvider},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    },
+    TlsConnector,
+};
+
+use crate::app::format_host;
+
+/// Send `request` to `host:port` over a new HTTP/1.1 connection, over TLS
+/// when `tls` is set, and return the response with its body, which may be
+/// at most `limit` bytes. The `Host` header is filled in unless the request
+/// has one.
+pub(crate) async fn send(
+    host: &str,
+    port: u16,
+    tls: bool,
+    mut request: Request<Body>,
+    limit: usize,
+) -> Result<Response<Bytes>> {
+    if !request.headers().contains_key(header::HOST) {
+        let value = HeaderValue::from_str(&format_host(host, port))?;
+        request.headers_mut().insert(header::HOST, value);
+    }
+
+    let stream = TcpStream::connect((host, port)).await?;
+    let response = if tls {
+        let name = ServerName::try_from(host.to_string())?;
+        let stream = TlsConnector::from(Arc::new(client_config(host)?))
+            .connect(name, stream)
+            .await?;
+        exchange(stream, request).await?
+    } else {
+        exchange(stream, request).await?
+    };
+
+    let (parts, body) = response.into_parts();
+    let body = axum::body::to_bytes(Body::new(body), limit)
+        .await
+        .context("response body too large")?;
+    Ok(Response::from_parts(parts, body))
+}
+
+async fn exchange<I>(io: I, request: Request<Body>) -> Result<Response<Incoming>>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(io)).await?;
+    // Drives the connection until the response body has been read
+    tokio::spawn(connection);
+    Ok(sender.send_request(request).await?)
+}
+
+fn client_config(host: &str) -> Result<ClientConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("no TLS protocol versions available")?;
+    let config = if is_loopback(host) {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+            .with_no_client_auth()
+    } else {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    Ok(config)
+}
+
+fn is_loopback(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Accepts whatever certificate a loopback server presents, still checking
+/// that the handshake is signed by its key.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
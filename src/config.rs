@@ -0,0 +1,358 @@
+//! Settings files, so flags used every time needn't be typed every time.
+//!
+//! `~/.config/mdserve/config.toml` applies everywhere and `./mdserve.toml`
+//! to the directory mdserve is started in; flags on the command line win
+//! over both, and the project file over the global one. Keys are the
+//! server's long flag names (`port`, `highlight-theme`, `static_ext`, …)
+//! and values what the flag takes:
+//!
+//! ```toml
+//! port = 4000
+//! highlight-theme = "monokai"
+//! static-ext = ["pdf", "csv"]
+//! toc = true
+//! ```
+//!
+//! Settings are applied after the command line is parsed, each only if the
+//! command line gave neither its flag nor one conflicting with it, so a
+//! list or `verbose` count from a file is replaced rather than added to.
+
+use anyhow::{Context, Result};
+use clap::{
+    builder::Resettable, parser::ValueSource, value_parser, Arg, ArgAction, ArgMatches, Command,
+    FromArgMatches,
+};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use toml::{Spanned, Value};
+
+/// File in the working directory read after the global one.
+const PROJECT_CONFIG: &str = "mdserve.toml";
+
+/// Settings files to apply, lowest precedence first: `explicit` if given,
+/// otherwise whichever of the global and project files exist.
+pub fn config_files(explicit: Option<&Path>) -> Vec<PathBuf> {
+    if let Some(path) = explicit {
+        return vec![path.to_path_buf()];
+    }
+    global_config()
+        .into_iter()
+        .chain([PathBuf::from(PROJECT_CONFIG)])
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// `$XDG_CONFIG_HOME/mdserve/config.toml`, falling back to `~/.config`
+/// (`%APPDATA%` on Windows).
+fn global_config() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        PathBuf::from(std::env::var_os("APPDATA")?)
+    } else {
+        match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        }
+    };
+    Some(dir.join("mdserve").join("config.toml"))
+}
+
+/// Apply the settings in `files` (lowest precedence first) to `args`, which
+/// were parsed from `matches` for `command`. A setting is skipped when the
+/// command line, or a file of higher precedence, gave its flag or one that
+/// conflicts with it. Fails, naming the file and line, on invalid TOML, a
+/// key that isn't a flag, a value the flag doesn't take, or two settings in
+/// one file that conflict.
+pub fn apply_config<T: FromArgMatches>(
+    args: &mut T,
+    command: &Command,
+    matches: &ArgMatches,
+    files: &[PathBuf],
+) -> Result<()> {
+    let settings_command = settings_command(command);
+    // Flags given so far, and the file and line that gave them
+    let mut given: Vec<(&Arg, Option<Location>)> = command
+        .get_arguments()
+        .filter(|arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine))
+        .map(|arg| (arg, None))
+        .collect();
+
+    for file in files.iter().rev() {
+        for setting in read_settings(file)? {
+            let location = &setting.location;
+            let name = setting.key.replace('_', "-");
+            let arg = command
+                .get_arguments()
+                .filter(|arg| arg.get_id() != "config")
+                .find(|arg| arg.get_long() == Some(name.as_str()))
+                .with_context(|| format!("{location}: unknown setting `{}`", setting.key))?;
+
+            if given
+                .iter()
+                .any(|(other, _)| other.get_id() == arg.get_id())
+            {
+                continue;
+            }
+            let conflict = given
+                .iter()
+                .find(|(other, _)| conflicts(command, arg, other));
+            match conflict {
+                Some((other, Some(other_location))) if other_location.file == location.file => {
+                    anyhow::bail!(
+                        "{location}: `{}` conflicts with `{}` on line {}",
+                        setting.key,
+                        other.get_long().unwrap_or_default(),
+                        other_location.line
+                    );
+                }
+                Some(_) => continue,
+                None => {}
+            }
+
+            let values = setting_args(&name, arg, &setting.value)
+                .with_context(|| format!("{location}: invalid setting `{}`", setting.key))?;
+            let setting_matches = settings_command
+                .clone()
+                .try_get_matches_from(values)
+                .map_err(|err| {
+                    let message = err.to_string();
+                    let message = message.lines().next().unwrap_or_default();
+                    anyhow::anyhow!("{location}: {}", message.trim_start_matches("error: "))
+                })?;
+            args.update_from_arg_matches(&setting_matches)?;
+            given.push((arg, Some(setting.location)));
+        }
+    }
+    Ok(())
+}
+
+/// Where a setting was read from.
+#[derive(Clone, Debug)]
+struct Location {
+    file: PathBuf,
+    line: usize,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+struct Setting {
+    key: String,
+    value: Value,
+    location: Location,
+}
+
+/// The settings in `path`, in the order they are written.
+fn read_settings(path: &Path) -> Result<Vec<Setting>> {
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read config file {}", path.display()))?;
+    let table: BTreeMap<Spanned<String>, Value> = toml::from_str(&source)
+        .with_context(|| format!("invalid config file {}", path.display()))?;
+
+    let mut settings: Vec<_> = table
+        .into_iter()
+        .map(|(key, value)| {
+            let start = key.span().start;
+            Setting {
+                key: key.into_inner(),
+                value,
+                location: Location {
+                    file: path.to_path_buf(),
+                    line: source[..start].matches('\n').count() + 1,
+                },
+            }
+        })
+        .collect();
+    settings.sort_by_key(|setting| setting.location.line);
+    Ok(settings)
+}
+
+/// Whether `a` and `b` can't be given together. Conflicts are declared on
+/// either side.
+fn conflicts(command: &Command, a: &Arg, b: &Arg) -> bool {
+    let declared = |arg: &Arg, other: &Arg| {
+        command
+            .get_arg_conflicts_with(arg)
+            .iter()
+            .any(|conflict| conflict.get_id() == other.get_id())
+    };
+    declared(a, b) || declared(b, a)
+}
+
+/// `command` taking one setting at a time as `--flag=value`: nothing is
+/// required or defaulted, so the matches hold just that flag, and switches
+/// and counted flags take `true`/`false` and a number as values.
+fn settings_command(command: &Command) -> Command {
+    command.clone().no_binary_name(true).mut_args(|arg| {
+        // Requirements span the command line and every file
+        let arg = arg
+            .required(false)
+            .requires(Resettable::Reset)
+            .default_value(None);
+        match arg.get_action() {
+            ArgAction::SetTrue => arg.action(ArgAction::Set).value_parser(value_parser!(bool)),
+            ArgAction::Count => arg.action(ArgAction::Set).value_parser(value_parser!(u8)),
+            _ => arg,
+        }
+    })
+}
+
+/// `value` as flags for `settings_command`, e.g. `port = 4000` as
+/// `--port=4000`. A list repeats the flag for each item, if it takes
+/// several, and `true` counts once for a counted flag like `verbose`.
+fn setting_args(name: &str, arg: &Arg, value: &Value) -> Result<Vec<String>> {
+    let values = match value {
+        Value::Array(items) => {
+            if !matches!(arg.get_action(), ArgAction::Append) {
+                anyhow::bail!("takes a single value, not a list");
+            }
+            items.iter().collect()
+        }
+        value => vec![value],
+    };
+    values
+        .into_iter()
+        .map(|value| {
+            let text = match value {
+                Value::String(text) => text.clone(),
+                Value::Integer(number) => number.to_string(),
+                Value::Float(number) => number.to_string(),
+                Value::Boolean(enabled) if matches!(arg.get_action(), ArgAction::Count) => {
+                    u8::from(*enabled).to_string()
+                }
+                Value::Boolean(enabled) => enabled.to_string(),
+                _ => anyhow::bail!("must be a string, number, boolean or list of them"),
+            };
+            Ok(format!("--{name}={text}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, Parser};
+
+    /// The shapes of flag the server takes.
+    #[derive(Parser, Debug)]
+    struct TestArgs {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[arg(short, long, default_value = "3000")]
+        port: u16,
+        #[arg(long)]
+        toc: bool,
+        #[arg(long, value_delimiter = ',')]
+        static_ext: Vec<String>,
+        #[arg(long, conflicts_with = "template_dir")]
+        template_file: Option<PathBuf>,
+        #[arg(long)]
+        template_dir: Option<PathBuf>,
+        #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+        verbose: u8,
+        #[arg(short, long)]
+        quiet: bool,
+    }
+
+    /// `cli` parsed, then `files` (lowest precedence first) applied.
+    fn parse(cli: &[&str], files: &[&str]) -> Result<TestArgs> {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let files: Vec<PathBuf> = files
+            .iter()
+            .enumerate()
+            .map(|(i, source)| {
+                let path = temp_dir.path().join(format!("{i}.toml"));
+                std::fs::write(&path, source).unwrap();
+                path
+            })
+            .collect();
+
+        let command = TestArgs::command();
+        let matches = command
+            .clone()
+            .try_get_matches_from(["mdserve", "docs/"].iter().chain(cli))
+            .unwrap();
+        let mut args = TestArgs::from_arg_matches(&matches).unwrap();
+        apply_config(&mut args, &command, &matches, &files)?;
+        Ok(args)
+    }
+
+    #[test]
+    fn test_settings_apply_where_the_command_line_is_silent() {
+        let config =
+            "# Preview settings\nport = 4000\nstatic_ext = [\"pdf\", \"csv\"]\ntoc = true\n";
+
+        let args = parse(&[], &[config]).unwrap();
+        assert_eq!(args.port, 4000);
+        assert_eq!(args.static_ext, ["pdf", "csv"]);
+        assert!(args.toc);
+
+        let args = parse(&["--port", "5000"], &[config]).unwrap();
+        assert_eq!(args.port, 5000);
+        assert!(args.toc);
+
+        // The project file wins over the global one
+        let args = parse(&[], &["port = 4000\ntoc = true\n", "port = 4100\n"]).unwrap();
+        assert_eq!(args.port, 4100);
+        assert!(args.toc);
+    }
+
+    #[test]
+    fn test_command_line_replaces_lists_and_counts() {
+        let config = "static-ext = [\"pdf\", \"csv\"]\nverbose = 2\n";
+
+        let args = parse(&[], &[config]).unwrap();
+        assert_eq!(args.static_ext, ["pdf", "csv"]);
+        assert_eq!(args.verbose, 2);
+
+        let args = parse(&["--static-ext", "txt", "-v"], &[config]).unwrap();
+        assert_eq!(args.static_ext, ["txt"]);
+        assert_eq!(args.verbose, 1);
+
+        assert_eq!(parse(&[], &["verbose = true\n"]).unwrap().verbose, 1);
+        assert_eq!(parse(&[], &["verbose = 0\n"]).unwrap().verbose, 0);
+    }
+
+    #[test]
+    fn test_command_line_wins_over_conflicting_settings() {
+        let args = parse(&["-v"], &["quiet = true\n"]).unwrap();
+        assert_eq!(args.verbose, 1);
+        assert!(!args.quiet);
+
+        let args = parse(
+            &["--template-dir", "theme/"],
+            &["template-file = \"page.html\"\n"],
+        )
+        .unwrap();
+        assert_eq!(args.template_dir, Some(PathBuf::from("theme/")));
+        assert_eq!(args.template_file, None);
+
+        // And the project file over the global one
+        let args = parse(&[], &["quiet = true\n", "verbose = 1\n"]).unwrap();
+        assert_eq!(args.verbose, 1);
+        assert!(!args.quiet);
+    }
+
+    #[test]
+    fn test_invalid_settings_name_file_and_line() {
+        let error = |source: &str| format!("{:#}", parse(&[], &[source]).unwrap_err());
+
+        assert!(error("port = 4000\nport =\n").contains("line 2"));
+        assert!(error("port = 4000\n\nsidebar = true\n")
+            .contains("0.toml:3: unknown setting `sidebar`"));
+        assert!(error("toc = true\nport = \"high\"\n").contains("0.toml:2: invalid value 'high'"));
+        assert!(error("port = [4000, 4001]\n").contains("0.toml:1: invalid setting `port`"));
+        assert!(error("[server]\nport = 4000\n").contains("0.toml:1: unknown setting `server`"));
+        assert!(error("verbose = -1\n").contains("0.toml:1"));
+        assert!(error("quiet = true\nverbose = 2\n")
+            .contains("0.toml:2: `verbose` conflicts with `quiet` on line 1"));
+        // The command line never reads its own config setting
+        assert!(error("config = \"other.toml\"\n").contains("unknown setting `config`"));
+    }
+}
@@ -0,0 +1,297 @@
+//! Fenced containers, opened by `:::<kind>` and closed by `:::`:
+//!
+//! ````text
+//! :::details Optional *title*
+//! Markdown that is rendered as usual.
+//! :::
+//!
+//! :::tabs
+//! ```bash title="npm"
+//! npm install mdserve
+//! ```
+//! ```bash title="cargo"
+//! cargo install mdserve
+//! ```
+//! :::
+//! ````
+//!
+//! `details` renders a collapsible section; unlike a raw `<details>`
+//! element its body is parsed as markdown. `tabs` shows each fenced code
+//! block as a tab, labelled by its `title="…"` or its language. Containers
+//! nest, and work with raw HTML disabled.
+
+use crate::render::{blank_line_after, RenderContext, RenderStage};
+
+const PREFIX: &str = ":::";
+/// Wraps the placeholders standing in for the containers' tags while the
+/// document is parsed. A private use character, so it never occurs in text.
+const MARKER: char = '\u{E001}';
+const DEFAULT_TITLE: &str = "Details";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Container {
+    Details,
+    Tabs,
+}
+
+impl Container {
+    fn name(self) -> &'static str {
+        match self {
+            Container::Details => "details",
+            Container::Tabs => "tabs",
+        }
+    }
+}
+
+pub(crate) struct ContainerStage;
+
+impl RenderStage for ContainerStage {
+    /// Replace openers and closers with placeholder paragraphs, and put one
+    /// holding the label before each code block directly inside `tabs`.
+    fn pre_parse(&self, source: String, _ctx: &mut RenderContext) -> String {
+        if !source.contains(PREFIX) {
+            return source;
+        }
+
+        let mut out = String::with_capacity(source.len());
+        let mut fence: Option<&str> = None;
+        let mut open: Vec<Container> = Vec::new();
+
+        for line in source.split_inclusive('\n') {
+            let trimmed = line.trim_end();
+            if let Some(marker) = fence {
+                if trimmed.trim_start().starts_with(marker) {
+                    fence = None;
+                }
+                out.push_str(line);
+                continue;
+            }
+
+            if let Some(marker) = ["```", "~~~"]
+                .into_iter()
+                .find(|marker| trimmed.trim_start().starts_with(marker))
+            {
+                fence = Some(marker);
+                if open.last() == Some(&Container::Tabs) {
+                    let info = trimmed.trim_start().trim_start_matches(marker);
+                    push_placeholder(
+                        &mut out,
+                        &format!("{MARKER}tab{MARKER} {}", tab_label(info)),
+                    );
+                }
+                out.push_str(line);
+            } else if let Some((container, title)) = opener(trimmed) {
+                open.push(container);
+                let text = match container {
+                    Container::Details if title.is_empty() => {
+                        format!("{MARKER}details{MARKER} {DEFAULT_TITLE}")
+                    }
+                    Container::Details => format!("{MARKER}details{MARKER} {title}"),
+                    Container::Tabs => format!("{MARKER}tabs{MARKER}"),
+                };
+                push_placeholder(&mut out, &text);
+            } else if trimmed == PREFIX && !open.is_empty() {
+                let container = open.pop().expect("checked non-empty");
+                push_placeholder(&mut out, &format!("{MARKER}/{}{MARKER}", container.name()));
+            } else {
+                out.push_str(line);
+            }
+        }
+
+        while let Some(container) = open.pop() {
+            push_placeholder(&mut out, &format!("{MARKER}/{}{MARKER}", container.name()));
+        }
+        out
+    }
+
+    fn post_html(&self, html: String, _ctx: &mut RenderContext) -> String {
+        if !html.contains(MARKER) {
+            return html;
+        }
+
+        let html = render_details(&html);
+        render_tabs(html)
+    }
+}
+
+/// Append a paragraph holding `text`, set off by blank lines.
+fn push_placeholder(out: &mut String, text: &str) {
+    let separator = blank_line_after(out);
+    out.push_str(&format!("{separator}{text}\n\n"));
+}
+
+/// The container a line opens, with the rest of the line as its title.
+fn opener(line: &str) -> Option<(Container, &str)> {
+    let rest = line.strip_prefix(PREFIX)?;
+    [Container::Details, Container::Tabs]
+        .into_iter()
+        .find_map(|container| {
+            let title = rest.strip_prefix(container.name())?;
+            (title.is_empty() || title.starts_with(' ')).then_some((container, title.trim()))
+        })
+}
+
+/// A tab's label from its code block's info string: the `title="…"`
+/// attribute, else the language.
+fn tab_label(info: &str) -> &str {
+    let info = info.trim();
+    if let Some(start) = info.find("title=\"") {
+        let title = &info[start + "title=\"".len()..];
+        if let Some(end) = title.find('"') {
+            return &title[..end];
+        }
+    }
+    match info.split_whitespace().next() {
+        Some(language) => language,
+        None => "Code",
+    }
+}
+
+fn render_details(html: &str) -> String {
+    let open = format!("<p>{MARKER}details{MARKER} ");
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(&open) {
+        let Some(len) = rest[start..].find("</p>") else {
+            break;
+        };
+        let title = &rest[start + open.len()..start + len];
+        out.push_str(&rest[..start]);
+        out.push_str(&format!(
+            "<details class=\"collapsible\">\n<summary>{title}</summary>"
+        ));
+        rest = &rest[start + len + "</p>".len()..];
+    }
+    out.push_str(rest);
+
+    out.replace(&format!("<p>{MARKER}/details{MARKER}</p>"), "</details>")
+}
+
+/// Build the tab widgets, innermost first so nested groups stay intact.
+fn render_tabs(mut html: String) -> String {
+    let open = format!("<p>{MARKER}tabs{MARKER}</p>");
+    let close = format!("<p>{MARKER}/tabs{MARKER}</p>");
+    let tab = format!("<p>{MARKER}tab{MARKER} ");
+
+    while let Some(start) = html.rfind(&open) {
+        let body_start = start + open.len();
+        let Some(len) = html[body_start..].find(&close) else {
+            break;
+        };
+        let body = &html[body_start..body_start + len];
+
+        let mut labels = Vec::new();
+        let mut panels = Vec::new();
+        let mut parts = body.split(&tab);
+        let before = parts.next().unwrap_or_default();
+        for part in parts {
+            let Some((label, panel)) = part.split_once("</p>") else {
+                continue;
+            };
+            labels.push(label.trim().to_string());
+            panels.push(panel.trim().to_string());
+        }
+
+        let mut widget = String::from(before.trim());
+        widget
+            .push_str("<div class=\"code-tabs\">\n<div class=\"code-tabs-bar\" role=\"tablist\">");
+        for (i, label) in labels.iter().enumerate() {
+            widget.push_str(&format!(
+                "<button type=\"button\" role=\"tab\" aria-selected=\"{}\">{label}</button>",
+                i == 0
+            ));
+        }
+        widget.push_str("</div>\n");
+        for (i, panel) in panels.iter().enumerate() {
+            let hidden = if i == 0 { "" } else { " hidden" };
+            widget.push_str(&format!(
+                "<div class=\"code-tab\" role=\"tabpanel\"{hidden}>\n{panel}\n</div>\n"
+            ));
+        }
+        widget.push_str("</div>");
+
+        html.replace_range(start..body_start + len + close.len(), &widget);
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{RenderOptions, RenderPipeline};
+
+    fn render(content: &str, options: &RenderOptions) -> String {
+        let mut pipeline = RenderPipeline::empty();
+        pipeline.push(ContainerStage);
+        pipeline.render(content, options).html
+    }
+
+    #[test]
+    fn test_details_sections_render_markdown_inside() {
+        let content = "Intro\n:::details Show *more*\n- one\n- **two**\n\n:::details\nNested\n:::\n:::\n\nAfter\n";
+        let html = render(content, &RenderOptions::default());
+
+        assert_eq!(
+            html,
+            "<p>Intro</p>\n\
+             <details class=\"collapsible\">\n<summary>Show <em>more</em></summary>\n\
+             <ul>\n<li>one</li>\n<li><strong>two</strong></li>\n</ul>\n\
+             <details class=\"collapsible\">\n<summary>Details</summary>\n\
+             <p>Nested</p>\n</details>\n</details>\n\
+             <p>After</p>\n"
+        );
+
+        // Raw HTML being escaped doesn't matter
+        let options = RenderOptions {
+            allow_dangerous_html: false,
+            ..Default::default()
+        };
+        assert_eq!(render(content, &options), html);
+    }
+
+    #[test]
+    fn test_details_syntax_left_alone_in_code_and_unclosed_sections_end() {
+        let content = "```\n:::details Not a section\n:::\n```\n";
+        let html = render(content, &RenderOptions::default());
+        assert_eq!(
+            html,
+            "<pre><code>:::details Not a section\n:::\n</code></pre>\n"
+        );
+
+        let html = render(":::details Open\nBody\n", &RenderOptions::default());
+        assert!(html.ends_with("<p>Body</p>\n</details>\n"));
+
+        let html = render(":::detailsx\n", &RenderOptions::default());
+        assert_eq!(html, "<p>:::detailsx</p>\n");
+    }
+
+    #[test]
+    fn test_tabs_group_code_blocks() {
+        let content = "\
+:::details Install
+:::tabs
+```bash title=\"npm\"
+npm i
+```
+```toml
+[deps]
+```
+:::
+:::
+";
+        let html = render(content, &RenderOptions::default());
+
+        assert_eq!(
+            html,
+            "<details class=\"collapsible\">\n<summary>Install</summary>\n\
+             <div class=\"code-tabs\">\n<div class=\"code-tabs-bar\" role=\"tablist\">\
+             <button type=\"button\" role=\"tab\" aria-selected=\"true\">npm</button>\
+             <button type=\"button\" role=\"tab\" aria-selected=\"false\">toml</button></div>\n\
+             <div class=\"code-tab\" role=\"tabpanel\">\n\
+             <pre><code class=\"language-bash\">npm i\n</code></pre>\n</div>\n\
+             <div class=\"code-tab\" role=\"tabpanel\" hidden>\n\
+             <pre><code class=\"language-toml\">[deps]\n</code></pre>\n</div>\n</div>\n\
+             </details>\n"
+        );
+    }
+}
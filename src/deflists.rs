@@ -0,0 +1,105 @@
+//! Definition lists, in the syntax of PHP Markdown Extra and Pandoc: a
+//! paragraph whose first lines are terms and whose following lines start
+//! with `: ` becomes a `<dl>`.
+//!
+//! ```markdown
+//! Apple
+//! : A fruit.
+//! : A company.
+//! ```
+//!
+//! Terms separated by a blank line join the same list. A line after a
+//! definition that doesn't start with `:` continues it.
+
+const OPEN: &str = "<p>";
+const CLOSE: &str = "</p>";
+
+/// Turn definition paragraphs of parser output into `<dl>` lists. Other
+/// paragraphs are left alone.
+pub(crate) fn render_definition_lists(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(OPEN) {
+        let body_start = start + OPEN.len();
+        let Some(len) = rest[body_start..].find(CLOSE) else {
+            break;
+        };
+        let body = &rest[body_start..body_start + len];
+        out.push_str(&rest[..start]);
+        match definition_list(body) {
+            Some(list) => {
+                // Join a list that directly follows the previous one
+                match out.strip_suffix("</dl>\n") {
+                    Some(before) => {
+                        out.truncate(before.len());
+                        out.push_str(list.strip_prefix("<dl>\n").unwrap_or(&list));
+                    }
+                    None => out.push_str(&list),
+                }
+            }
+            None => out.push_str(&rest[start..body_start + len + CLOSE.len()]),
+        }
+        rest = &rest[body_start + len + CLOSE.len()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The `<dl>` for a paragraph's HTML, if its first line is a term and a
+/// later line a definition.
+fn definition_list(body: &str) -> Option<String> {
+    let lines: Vec<&str> = body.split('\n').collect();
+    let first_definition = lines.iter().position(|line| definition(line).is_some())?;
+    if first_definition == 0 {
+        return None;
+    }
+
+    let mut list = String::from("<dl>\n");
+    for term in &lines[..first_definition] {
+        list.push_str(&format!("<dt>{}</dt>\n", term.trim()));
+    }
+    let mut open = false;
+    for line in &lines[first_definition..] {
+        match definition(line) {
+            Some(text) => {
+                if open {
+                    list.push_str("</dd>\n");
+                }
+                list.push_str("<dd>");
+                list.push_str(text);
+                open = true;
+            }
+            None => {
+                list.push('\n');
+                list.push_str(line);
+            }
+        }
+    }
+    list.push_str("</dd>\n</dl>");
+    Some(list)
+}
+
+/// The text of a definition line (`: text`).
+fn definition(line: &str) -> Option<&str> {
+    let text = line.strip_prefix(':')?;
+    text.starts_with([' ', '\t']).then(|| text.trim_start())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_definition_paragraphs_become_lists() {
+        let html = "<p>Apple\n: A <em>fruit</em>.\n: A company\nin California.</p>\n\
+                    <p>Orange\nCitrus\n: Another fruit.</p>\n\
+                    <p>Plain text</p>\n<p>: not a definition</p>\n<p>Time: 10:00</p>";
+
+        assert_eq!(
+            render_definition_lists(html),
+            "<dl>\n<dt>Apple</dt>\n<dd>A <em>fruit</em>.</dd>\n<dd>A company\nin California.</dd>\n\
+             <dt>Orange</dt>\n<dt>Citrus</dt>\n<dd>Another fruit.</dd>\n</dl>\n\
+             <p>Plain text</p>\n<p>: not a definition</p>\n<p>Time: 10:00</p>"
+        );
+    }
+}
@@ -0,0 +1,417 @@
+//! GitHub emoji shortcodes: `:tada:` in text becomes 🎉. Covers the
+//! commonly used part of GitHub's list; unknown codes stay as written.
+
+use crate::render::map_text;
+
+/// Shortcodes and their emoji, sorted by shortcode for binary search.
+const EMOJI: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("1234", "🔢"),
+    ("airplane", "✈️"),
+    ("alarm_clock", "⏰"),
+    ("alien", "👽"),
+    ("ambulance", "🚑"),
+    ("anchor", "⚓"),
+    ("angry", "😠"),
+    ("ant", "🐜"),
+    ("apple", "🍎"),
+    ("arrow_down", "⬇️"),
+    ("arrow_left", "⬅️"),
+    ("arrow_right", "➡️"),
+    ("arrow_up", "⬆️"),
+    ("art", "🎨"),
+    ("astonished", "😲"),
+    ("atom_symbol", "⚛️"),
+    ("baby", "👶"),
+    ("balloon", "🎈"),
+    ("bangbang", "‼️"),
+    ("bar_chart", "📊"),
+    ("battery", "🔋"),
+    ("beer", "🍺"),
+    ("beers", "🍻"),
+    ("bell", "🔔"),
+    ("bike", "🚲"),
+    ("bird", "🐦"),
+    ("birthday", "🎂"),
+    ("blush", "😊"),
+    ("bomb", "💣"),
+    ("book", "📖"),
+    ("bookmark", "🔖"),
+    ("books", "📚"),
+    ("boom", "💥"),
+    ("bowtie", "👔"),
+    ("brain", "🧠"),
+    ("bread", "🍞"),
+    ("broken_heart", "💔"),
+    ("bug", "🐛"),
+    ("bulb", "💡"),
+    ("bus", "🚌"),
+    ("cake", "🍰"),
+    ("calendar", "📆"),
+    ("camera", "📷"),
+    ("car", "🚗"),
+    ("card_index", "📇"),
+    ("cat", "🐱"),
+    ("chart_with_downwards_trend", "📉"),
+    ("chart_with_upwards_trend", "📈"),
+    ("checkered_flag", "🏁"),
+    ("cherries", "🍒"),
+    ("clap", "👏"),
+    ("clipboard", "📋"),
+    ("clock3", "🕒"),
+    ("closed_lock_with_key", "🔐"),
+    ("cloud", "☁️"),
+    ("clown_face", "🤡"),
+    ("coffee", "☕"),
+    ("cold_sweat", "😰"),
+    ("computer", "💻"),
+    ("confetti_ball", "🎊"),
+    ("confused", "😕"),
+    ("construction", "🚧"),
+    ("cookie", "🍪"),
+    ("cool", "🆒"),
+    ("cow", "🐮"),
+    ("crab", "🦀"),
+    ("credit_card", "💳"),
+    ("crossed_fingers", "🤞"),
+    ("crown", "👑"),
+    ("cry", "😢"),
+    ("crystal_ball", "🔮"),
+    ("dart", "🎯"),
+    ("dash", "💨"),
+    ("date", "📅"),
+    ("disappointed", "😞"),
+    ("dizzy", "💫"),
+    ("dog", "🐶"),
+    ("dollar", "💵"),
+    ("door", "🚪"),
+    ("dove", "🕊️"),
+    ("dragon", "🐉"),
+    ("droplet", "💧"),
+    ("ear", "👂"),
+    ("earth_africa", "🌍"),
+    ("earth_americas", "🌎"),
+    ("earth_asia", "🌏"),
+    ("egg", "🥚"),
+    ("eight", "8️⃣"),
+    ("electric_plug", "🔌"),
+    ("elephant", "🐘"),
+    ("email", "📧"),
+    ("envelope", "✉️"),
+    ("exclamation", "❗"),
+    ("expressionless", "😑"),
+    ("eyes", "👀"),
+    ("facepunch", "👊"),
+    ("fearful", "😨"),
+    ("file_folder", "📁"),
+    ("fire", "🔥"),
+    ("fireworks", "🎆"),
+    ("fish", "🐟"),
+    ("fist", "✊"),
+    ("five", "5️⃣"),
+    ("flashlight", "🔦"),
+    ("floppy_disk", "💾"),
+    ("flushed", "😳"),
+    ("fork_and_knife", "🍴"),
+    ("four", "4️⃣"),
+    ("four_leaf_clover", "🍀"),
+    ("fox_face", "🦊"),
+    ("frog", "🐸"),
+    ("frowning", "😦"),
+    ("gear", "⚙️"),
+    ("gem", "💎"),
+    ("ghost", "👻"),
+    ("gift", "🎁"),
+    ("globe_with_meridians", "🌐"),
+    ("grey_exclamation", "❕"),
+    ("grey_question", "❔"),
+    ("grimacing", "😬"),
+    ("grin", "😁"),
+    ("grinning", "😀"),
+    ("guitar", "🎸"),
+    ("hammer", "🔨"),
+    ("hammer_and_wrench", "🛠️"),
+    ("hand", "✋"),
+    ("handshake", "🤝"),
+    ("hankey", "💩"),
+    ("hash", "#️⃣"),
+    ("headphones", "🎧"),
+    ("heart", "❤️"),
+    ("heart_eyes", "😍"),
+    ("heavy_check_mark", "✔️"),
+    ("heavy_minus_sign", "➖"),
+    ("heavy_plus_sign", "➕"),
+    ("hibiscus", "🌺"),
+    ("hourglass", "⌛"),
+    ("house", "🏠"),
+    ("hugs", "🤗"),
+    ("hushed", "😯"),
+    ("ice_cream", "🍨"),
+    ("id", "🆔"),
+    ("inbox_tray", "📥"),
+    ("information_source", "ℹ️"),
+    ("innocent", "😇"),
+    ("jack_o_lantern", "🎃"),
+    ("joy", "😂"),
+    ("key", "🔑"),
+    ("keyboard", "⌨️"),
+    ("kiss", "💋"),
+    ("kissing_heart", "😘"),
+    ("koala", "🐨"),
+    ("label", "🏷️"),
+    ("ladybug", "🐞"),
+    ("laughing", "😆"),
+    ("leaves", "🍃"),
+    ("lemon", "🍋"),
+    ("link", "🔗"),
+    ("lipstick", "💄"),
+    ("lock", "🔒"),
+    ("lollipop", "🍭"),
+    ("loudspeaker", "📢"),
+    ("love_letter", "💌"),
+    ("mag", "🔍"),
+    ("mag_right", "🔎"),
+    ("mailbox", "📫"),
+    ("mask", "😷"),
+    ("medal_sports", "🏅"),
+    ("mega", "📣"),
+    ("memo", "📝"),
+    ("metal", "🤘"),
+    ("microphone", "🎤"),
+    ("microscope", "🔬"),
+    ("money_with_wings", "💸"),
+    ("monkey", "🐒"),
+    ("moon", "🌔"),
+    ("mortar_board", "🎓"),
+    ("mountain", "⛰️"),
+    ("mouse", "🐭"),
+    ("muscle", "💪"),
+    ("mushroom", "🍄"),
+    ("musical_note", "🎵"),
+    ("nail_care", "💅"),
+    ("necktie", "👔"),
+    ("negative_squared_cross_mark", "❎"),
+    ("nerd_face", "🤓"),
+    ("neutral_face", "😐"),
+    ("new", "🆕"),
+    ("newspaper", "📰"),
+    ("nine", "9️⃣"),
+    ("no_entry", "⛔"),
+    ("no_entry_sign", "🚫"),
+    ("no_good", "🙅"),
+    ("no_mouth", "😶"),
+    ("nose", "👃"),
+    ("notebook", "📓"),
+    ("notes", "🎶"),
+    ("ok", "🆗"),
+    ("ok_hand", "👌"),
+    ("one", "1️⃣"),
+    ("open_book", "📖"),
+    ("open_mouth", "😮"),
+    ("outbox_tray", "📤"),
+    ("owl", "🦉"),
+    ("package", "📦"),
+    ("page_facing_up", "📄"),
+    ("page_with_curl", "📃"),
+    ("palm_tree", "🌴"),
+    ("paperclip", "📎"),
+    ("partying_face", "🥳"),
+    ("pencil", "📝"),
+    ("pencil2", "✏️"),
+    ("penguin", "🐧"),
+    ("pensive", "😔"),
+    ("persevere", "😣"),
+    ("phone", "☎️"),
+    ("pig", "🐷"),
+    ("pill", "💊"),
+    ("pizza", "🍕"),
+    ("point_down", "👇"),
+    ("point_left", "👈"),
+    ("point_right", "👉"),
+    ("point_up", "☝️"),
+    ("poop", "💩"),
+    ("popcorn", "🍿"),
+    ("pray", "🙏"),
+    ("pushpin", "📌"),
+    ("question", "❓"),
+    ("rabbit", "🐰"),
+    ("racehorse", "🐎"),
+    ("rage", "😡"),
+    ("rainbow", "🌈"),
+    ("raised_hand", "✋"),
+    ("raised_hands", "🙌"),
+    ("recycle", "♻️"),
+    ("red_circle", "🔴"),
+    ("relaxed", "☺️"),
+    ("relieved", "😌"),
+    ("repeat", "🔁"),
+    ("rocket", "🚀"),
+    ("rofl", "🤣"),
+    ("rose", "🌹"),
+    ("rotating_light", "🚨"),
+    ("round_pushpin", "📍"),
+    ("ruler", "📏"),
+    ("runner", "🏃"),
+    ("running", "🏃"),
+    ("sake", "🍶"),
+    ("santa", "🎅"),
+    ("satellite", "📡"),
+    ("scissors", "✂️"),
+    ("scream", "😱"),
+    ("see_no_evil", "🙈"),
+    ("seedling", "🌱"),
+    ("seven", "7️⃣"),
+    ("shield", "🛡️"),
+    ("ship", "🚢"),
+    ("shipit", "🐿️"),
+    ("shrug", "🤷"),
+    ("six", "6️⃣"),
+    ("skull", "💀"),
+    ("sleeping", "😴"),
+    ("sleepy", "😪"),
+    ("slightly_frowning_face", "🙁"),
+    ("slightly_smiling_face", "🙂"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("smiling_imp", "😈"),
+    ("smirk", "😏"),
+    ("snail", "🐌"),
+    ("snake", "🐍"),
+    ("snowflake", "❄️"),
+    ("snowman", "⛄"),
+    ("sob", "😭"),
+    ("soccer", "⚽"),
+    ("sparkles", "✨"),
+    ("sparkling_heart", "💖"),
+    ("speech_balloon", "💬"),
+    ("star", "⭐"),
+    ("star2", "🌟"),
+    ("stars", "🌠"),
+    ("stop_sign", "🛑"),
+    ("stopwatch", "⏱️"),
+    ("strawberry", "🍓"),
+    ("stuck_out_tongue", "😛"),
+    ("stuck_out_tongue_winking_eye", "😜"),
+    ("sun_with_face", "🌞"),
+    ("sunflower", "🌻"),
+    ("sunglasses", "😎"),
+    ("sunny", "☀️"),
+    ("sweat", "😓"),
+    ("sweat_smile", "😅"),
+    ("tada", "🎉"),
+    ("taxi", "🚕"),
+    ("tea", "🍵"),
+    ("telescope", "🔭"),
+    ("tent", "⛺"),
+    ("test_tube", "🧪"),
+    ("thinking", "🤔"),
+    ("thought_balloon", "💭"),
+    ("three", "3️⃣"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("ticket", "🎫"),
+    ("tiger", "🐯"),
+    ("timer_clock", "⏲️"),
+    ("tired_face", "😫"),
+    ("toolbox", "🧰"),
+    ("tophat", "🎩"),
+    ("tractor", "🚜"),
+    ("traffic_light", "🚥"),
+    ("train", "🚋"),
+    ("trophy", "🏆"),
+    ("truck", "🚚"),
+    ("tulip", "🌷"),
+    ("turtle", "🐢"),
+    ("tv", "📺"),
+    ("two", "2️⃣"),
+    ("umbrella", "☔"),
+    ("unamused", "😒"),
+    ("unicorn", "🦄"),
+    ("unlock", "🔓"),
+    ("upside_down_face", "🙃"),
+    ("v", "✌️"),
+    ("vertical_traffic_light", "🚦"),
+    ("video_camera", "📹"),
+    ("volcano", "🌋"),
+    ("warning", "⚠️"),
+    ("watch", "⌚"),
+    ("wave", "👋"),
+    ("weary", "😩"),
+    ("whale", "🐳"),
+    ("wheelchair", "♿"),
+    ("white_check_mark", "✅"),
+    ("wine_glass", "🍷"),
+    ("wink", "😉"),
+    ("wolf", "🐺"),
+    ("worried", "😟"),
+    ("wrench", "🔧"),
+    ("x", "❌"),
+    ("yum", "😋"),
+    ("zap", "⚡"),
+    ("zero", "0️⃣"),
+    ("zipper_mouth_face", "🤐"),
+    ("zzz", "💤"),
+];
+
+/// Replace known shortcodes in the text of `html`, leaving tags, attributes
+/// and code untouched.
+pub(crate) fn render_emoji(html: &str) -> String {
+    map_text(html, replace_shortcodes)
+}
+
+/// Append `text` to `out` with known `:shortcode:`s replaced.
+fn replace_shortcodes(text: &str, out: &mut String) {
+    let mut rest = text;
+    while let Some(colon) = rest.find(':') {
+        let after = &rest[colon + 1..];
+        let len = after
+            .bytes()
+            .take_while(|b| b.is_ascii_alphanumeric() || b"_+-".contains(b))
+            .count();
+        let emoji = after[len..]
+            .starts_with(':')
+            .then(|| lookup(&after[..len]))
+            .flatten();
+        match emoji {
+            Some(emoji) => {
+                out.push_str(&rest[..colon]);
+                out.push_str(emoji);
+                rest = &after[len + 1..];
+            }
+            None => {
+                out.push_str(&rest[..=colon]);
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+}
+
+fn lookup(shortcode: &str) -> Option<&'static str> {
+    EMOJI
+        .binary_search_by(|(code, _)| code.cmp(&shortcode))
+        .ok()
+        .map(|i| EMOJI[i].1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shortcodes_in_text_become_emoji() {
+        assert!(EMOJI.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        let html = "<p>Shipped :rocket: :tada: :+1: at 10:30:00, :not_an_emoji:</p>\n\
+                    <p><a href=\"/x:tada:\" title=\":tada:\">:fire:</a></p>\n\
+                    <pre><code>:tada:</code></pre>\n<p><code>:tada:</code> :zap:</p>";
+        assert_eq!(
+            render_emoji(html),
+            "<p>Shipped 🚀 🎉 👍 at 10:30:00, :not_an_emoji:</p>\n\
+             <p><a href=\"/x:tada:\" title=\":tada:\">🔥</a></p>\n\
+             <pre><code>:tada:</code></pre>\n<p><code>:tada:</code> ⚡</p>"
+        );
+    }
+}
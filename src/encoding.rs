@@ -0,0 +1,179 @@
+//! Decoding markdown files that aren't plain UTF-8.
+//!
+//! Byte order marks are stripped, UTF-16 (with a BOM, or recognisable by
+//! its zero bytes) is decoded, and anything else that isn't valid UTF-8 is
+//! read as Windows-1252, the usual encoding of files saved by older Windows
+//! editors and a superset of Latin-1's printable characters.
+
+use std::{borrow::Cow, path::Path};
+
+/// A file's text and, when it wasn't UTF-8, the encoding it was decoded
+/// from.
+pub(crate) struct Decoded<'a> {
+    pub text: Cow<'a, str>,
+    pub encoding: Option<&'static str>,
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Decoded<'_> {
+    if let Some(rest) = bytes.strip_prefix(b"\xEF\xBB\xBF") {
+        return decode(rest);
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xFF\xFE") {
+        return Utf16::Le.decode(rest);
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xFE\xFF") {
+        return Utf16::Be.decode(rest);
+    }
+
+    // Valid UTF-8 as far as NULs go, but no text file contains them
+    if let Some(utf16) = Utf16::detect(bytes) {
+        return utf16.decode(bytes);
+    }
+
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Decoded {
+            text: Cow::Borrowed(text),
+            encoding: None,
+        },
+        Err(_) => Decoded {
+            text: Cow::Owned(bytes.iter().map(|&b| windows_1252(b)).collect()),
+            encoding: Some("Windows-1252"),
+        },
+    }
+}
+
+/// Read the markdown file at `path` as the preview server does, decoding
+/// it from UTF-16 or Windows-1252 if it isn't UTF-8.
+pub fn read_markdown(path: &Path) -> std::io::Result<String> {
+    Ok(decode(&std::fs::read(path)?).text.into_owned())
+}
+
+/// `bytes` without the UTF-8 sequence cut short at its end, if any, so a
+/// prefix of a UTF-8 file isn't mistaken for another encoding.
+pub(crate) fn complete_prefix(bytes: &[u8]) -> &[u8] {
+    match std::str::from_utf8(bytes) {
+        // Only an incomplete sequence at the very end, not an invalid byte
+        Err(e) if e.error_len().is_none() => &bytes[..e.valid_up_to()],
+        _ => bytes,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Utf16 {
+    Le,
+    Be,
+}
+
+impl Utf16 {
+    /// Mostly-ASCII text saved as UTF-16 has a zero in every other byte.
+    fn detect(bytes: &[u8]) -> Option<Self> {
+        let sample = &bytes[..bytes.len().min(1024) & !1];
+        if sample.is_empty() {
+            return None;
+        }
+        let zeros = |offset: usize| {
+            sample
+                .iter()
+                .skip(offset)
+                .step_by(2)
+                .filter(|&&b| b == 0)
+                .count()
+        };
+        let pairs = sample.len() / 2;
+        let (even, odd) = (zeros(0), zeros(1));
+        if odd * 10 >= pairs * 9 && even * 10 < pairs {
+            Some(Utf16::Le)
+        } else if even * 10 >= pairs * 9 && odd * 10 < pairs {
+            Some(Utf16::Be)
+        } else {
+            None
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> Decoded<'static> {
+        let units = bytes.chunks_exact(2).map(|pair| match self {
+            Utf16::Le => u16::from_le_bytes([pair[0], pair[1]]),
+            Utf16::Be => u16::from_be_bytes([pair[0], pair[1]]),
+        });
+        Decoded {
+            text: Cow::Owned(
+                char::decode_utf16(units)
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect(),
+            ),
+            encoding: Some(match self {
+                Utf16::Le => "UTF-16LE",
+                Utf16::Be => "UTF-16BE",
+            }),
+        }
+    }
+}
+
+/// Windows-1252 agrees with Latin-1 except for 0x80–0x9F, which hold
+/// typographic punctuation instead of control characters.
+fn windows_1252(byte: u8) -> char {
+    const HIGH_CONTROLS: [char; 32] = [
+        '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž',
+        '\u{8F}', '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}',
+        'ž', 'Ÿ',
+    ];
+    match byte {
+        0x80..=0x9F => HIGH_CONTROLS[usize::from(byte - 0x80)],
+        _ => char::from(byte),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoded(bytes: &[u8]) -> (String, Option<&'static str>) {
+        let decoded = decode(bytes);
+        (decoded.text.into_owned(), decoded.encoding)
+    }
+
+    #[test]
+    fn test_decode_utf8_and_boms() {
+        assert_eq!(decoded(b"caf\xc3\xa9"), ("café".into(), None));
+        assert_eq!(decoded(b"\xEF\xBB\xBF# Title"), ("# Title".into(), None));
+
+        assert_eq!(
+            decoded(b"\xFF\xFE#\x00 \x00\xe9\x00"),
+            ("# é".into(), Some("UTF-16LE"))
+        );
+        assert_eq!(
+            decoded(b"\xFE\xFF\x00#\x00 \x00\xe9"),
+            ("# é".into(), Some("UTF-16BE"))
+        );
+        assert_eq!(
+            decoded(b"#\x00 \x00T\x00i\x00t\x00l\x00e\x00"),
+            ("# Title".into(), Some("UTF-16LE"))
+        );
+    }
+
+    #[test]
+    fn test_read_markdown_decodes_like_the_server() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file = temp_dir.path().join("latin1.md");
+        std::fs::write(&file, b"# Caf\xe9\n").unwrap();
+
+        assert_eq!(read_markdown(&file).unwrap(), "# Café\n");
+    }
+
+    #[test]
+    fn test_complete_prefix_drops_a_cut_character() {
+        assert_eq!(complete_prefix(b"caf\xc3"), b"caf");
+        assert_eq!(complete_prefix(b"\xe2\x82"), b"");
+        assert_eq!(complete_prefix(b"caf\xc3\xa9"), b"caf\xc3\xa9");
+        // Not UTF-8 at all; left for the fallback
+        assert_eq!(complete_prefix(b"Caf\xe9 \xc3"), b"Caf\xe9 \xc3");
+    }
+
+    #[test]
+    fn test_decode_falls_back_to_windows_1252() {
+        assert_eq!(
+            decoded(b"\x93Caf\xe9\x94 \x80 5\n"),
+            ("“Café” € 5\n".into(), Some("Windows-1252"))
+        );
+    }
+}
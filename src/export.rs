@@ -0,0 +1,176 @@
+//! Static site export: tracked documents written as HTML pages, so the
+//! renderer used for previewing also produces what gets published.
+//!
+//! Pages refer to each other and to their assets with relative URLs, so the
+//! output works from any folder of any web server, or straight from disk.
+
+use crate::app::{percent_decode, split_folder};
+
+/// Where a tracked document is written: `guides/setup.md` becomes
+/// `guides/setup.html`.
+pub(crate) fn html_name(name: &str) -> String {
+    match markdown_extension(name) {
+        Some(ext) => format!("{}.html", &name[..name.len() - ext.len() - 1]),
+        None => format!("{name}.html"),
+    }
+}
+
+/// Relative URL of the site root from the page for `name`: `.` at the top
+/// level, `..` one folder down.
+pub(crate) fn root_prefix(name: &str) -> String {
+    let depth = split_folder(name)
+        .0
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .count();
+    if depth == 0 {
+        ".".to_string()
+    } else {
+        vec![".."; depth].join("/")
+    }
+}
+
+fn markdown_extension(path: &str) -> Option<&str> {
+    let (_, ext) = path.rsplit_once('.')?;
+    (ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("markdown")).then_some(ext)
+}
+
+/// Whether an attribute value is a URL on this site rather than an
+/// absolute URL (`https:`, `mailto:`, `data:`, …) or a bare fragment.
+fn is_relative_url(url: &str) -> bool {
+    let has_scheme = url
+        .find([':', '/', '?', '#'])
+        .is_some_and(|i| url[i..].starts_with(':'));
+    !url.is_empty() && !url.starts_with('#') && !has_scheme
+}
+
+/// Point relative links to markdown files at the exported pages, keeping
+/// any query or fragment: `setup.md#install` becomes `setup.html#install`.
+pub(crate) fn rewrite_markdown_links(html: &str) -> String {
+    const HREF: &str = "href=\"";
+
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(HREF) {
+        let value_start = start + HREF.len();
+        let Some(len) = rest[value_start..].find('"') else {
+            break;
+        };
+        // The template escapes slashes, whose `#` would read as a fragment
+        let url = &rest[value_start..value_start + len].replace("&#x2f;", "/");
+        out.push_str(&rest[..value_start]);
+
+        let path_len = url.find(['?', '#']).unwrap_or(url.len());
+        match markdown_extension(&url[..path_len]) {
+            Some(_) if is_relative_url(url) => {
+                out.push_str(&html_name(&url[..path_len]));
+                out.push_str(&url[path_len..]);
+            }
+            _ => out.push_str(url),
+        }
+        rest = &rest[value_start + len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Files a document's HTML refers to through relative `src` and `href`
+/// attributes, as paths from the site root. `folder` is the document's
+/// folder; links to other documents and URLs leaving the site are skipped.
+pub(crate) fn local_references(html: &str, folder: &str) -> Vec<String> {
+    site_paths(html, folder, &["src=\"", "href=\""], |path| {
+        markdown_extension(path).is_none()
+    })
+}
+
+/// Markdown documents a document's HTML links to, as paths from the site
+/// root. `folder` is the document's folder.
+pub(crate) fn markdown_references(html: &str, folder: &str) -> Vec<String> {
+    site_paths(html, folder, &["href=\""], |path| {
+        markdown_extension(path).is_some()
+    })
+}
+
+/// Targets of relative URLs in `attributes` whose path passes `keep`,
+/// resolved from `folder` to paths from the site root, without duplicates.
+fn site_paths(
+    html: &str,
+    folder: &str,
+    attributes: &[&str],
+    keep: impl Fn(&str) -> bool,
+) -> Vec<String> {
+    let mut references = Vec::new();
+    for attribute in attributes {
+        let mut rest = html;
+        while let Some(start) = rest.find(attribute) {
+            rest = &rest[start + attribute.len()..];
+            let Some(len) = rest.find('"') else {
+                break;
+            };
+            let url = &rest[..len].replace("&#x2f;", "/");
+            rest = &rest[len..];
+
+            if !is_relative_url(url) || url.starts_with('/') {
+                continue;
+            }
+            let path = &url[..url.find(['?', '#']).unwrap_or(url.len())];
+            if !keep(path) {
+                continue;
+            }
+            let Some(path) = percent_decode(&path.replace("&amp;", "&")) else {
+                continue;
+            };
+            if let Some(reference) = join_url_path(folder, &path) {
+                if !references.contains(&reference) {
+                    references.push(reference);
+                }
+            }
+        }
+    }
+    references
+}
+
+/// `path` resolved against `folder`, both `/`-separated and relative to the
+/// site root. `None` if it climbs out of the root.
+fn join_url_path(folder: &str, path: &str) -> Option<String> {
+    let mut segments: Vec<&str> = folder.split('/').filter(|s| !s.is_empty()).collect();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    (!segments.is_empty()).then(|| segments.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markdown_links_point_at_exported_pages() {
+        assert_eq!(html_name("guides/setup.md"), "guides/setup.html");
+        assert_eq!(html_name("notes.MARKDOWN"), "notes.html");
+        assert_eq!(root_prefix("README.md"), ".");
+        assert_eq!(root_prefix("guides/deep/x.md"), "../..");
+
+        let html = r#"<a href="setup.md#install">a</a> <a href="../README.md">b</a> <a href="https://example.com/x.md">c</a> <a href="guide.pdf">d</a> <a href="./guides&#x2f;setup.md">e</a>"#;
+        assert_eq!(
+            rewrite_markdown_links(html),
+            r#"<a href="setup.html#install">a</a> <a href="../README.html">b</a> <a href="https://example.com/x.md">c</a> <a href="guide.pdf">d</a> <a href="./guides/setup.html">e</a>"#
+        );
+    }
+
+    #[test]
+    fn test_local_references_are_resolved_from_the_document_folder() {
+        let html = r##"<img src="img/a%20b.png"><img src="../logo.svg?v=2"><img src="https://example.com/x.png"><a href="other.md">o</a><a href="#top">t</a><img src="../../escape.png"><a href="files/report.pdf">r</a>"##;
+        assert_eq!(
+            local_references(html, "guides"),
+            vec!["guides/img/a b.png", "logo.svg", "guides/files/report.pdf"]
+        );
+        assert_eq!(markdown_references(html, "guides"), vec!["guides/other.md"]);
+    }
+}
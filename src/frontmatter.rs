@@ -0,0 +1,119 @@
+//! Document metadata from YAML (`---`) and TOML (`+++`) frontmatter, read
+//! with `serde_yaml` and `toml`. TOML dates are kept as the strings they
+//! were written as. Frontmatter that doesn't parse, or isn't a mapping,
+//! gives no metadata rather than failing the document, since the preview
+//! should still render while frontmatter is being typed.
+
+use serde_json::{Map, Number, Value};
+
+use crate::render::split_frontmatter;
+
+/// Frontmatter keys and their values.
+pub type Metadata = Map<String, Value>;
+
+/// Metadata from the frontmatter `source` starts with, empty if there is
+/// none.
+pub(crate) fn parse(source: &str) -> Metadata {
+    let metadata = match split_frontmatter(source) {
+        Some(frontmatter) if frontmatter.language == "toml" => toml::from_str(frontmatter.body)
+            .ok()
+            .map(|table| toml_to_json(toml::Value::Table(table))),
+        Some(frontmatter) => serde_yaml::from_str::<serde_yaml::Value>(frontmatter.body)
+            .ok()
+            .and_then(|value| serde_json::to_value(value).ok()),
+        None => None,
+    };
+    match metadata {
+        Some(Value::Object(map)) => map,
+        _ => Metadata::new(),
+    }
+}
+
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(string) => Value::String(string),
+        toml::Value::Integer(integer) => Value::Number(integer.into()),
+        toml::Value::Float(float) => Number::from_f64(float).map_or(Value::Null, Value::Number),
+        toml::Value::Boolean(boolean) => Value::Bool(boolean),
+        toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        toml::Value::Array(items) => Value::Array(items.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_yaml_frontmatter() {
+        let source = "---\n\
+            title: \"Hello: World\"\n\
+            author: Ada  # comment\n\
+            date: 2024-05-01\n\
+            draft: false\n\
+            weight: 3\n\
+            tags: [rust, 'mark, down']\n\
+            authors:\n  - name: Ada\n    role: lead\n  - Grace\n\
+            site:\n  url: https://example.com\n\
+            summary: |\n  Line one\n  Line two\n\
+            ---\n# Body";
+
+        assert_eq!(
+            Value::Object(parse(source)),
+            json!({
+                "title": "Hello: World",
+                "author": "Ada",
+                "date": "2024-05-01",
+                "draft": false,
+                "weight": 3,
+                "tags": ["rust", "mark, down"],
+                "authors": [{"name": "Ada", "role": "lead"}, "Grace"],
+                "site": {"url": "https://example.com"},
+                "summary": "Line one\nLine two\n",
+            })
+        );
+        assert!(parse("# No frontmatter").is_empty());
+        // Half-typed or not a mapping
+        assert!(parse("---\ntitle: [Hello\n---\n").is_empty());
+        assert!(parse("---\n- a\n- b\n---\n").is_empty());
+        assert!(parse("+++\ntitle = \n+++\n").is_empty());
+    }
+
+    #[test]
+    fn test_toml_frontmatter() {
+        let source = "+++\n\
+            title = \"Post\" # comment\n\
+            date = 2024-05-01T10:00:00Z\n\
+            ratio = 1_000.5\n\
+            tags = [\n  \"a\",\n  \"b\",\n]\n\
+            extra.\"og:image\" = 'cover.png'\n\
+            [author]\n\
+            name = \"Ada\"\n\
+            links = { home = \"https://ada.dev\" }\n\
+            [[releases]]\n\
+            version = 1\n\
+            [[releases]]\n\
+            version = 2\n\
+            +++\n";
+
+        assert_eq!(
+            Value::Object(parse(source)),
+            json!({
+                "title": "Post",
+                "date": "2024-05-01T10:00:00Z",
+                "ratio": 1000.5,
+                "tags": ["a", "b"],
+                "extra": {"og:image": "cover.png"},
+                "author": {"name": "Ada", "links": {"home": "https://ada.dev"}},
+                "releases": [{"version": 1}, {"version": 2}],
+            })
+        );
+    }
+}
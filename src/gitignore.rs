@@ -0,0 +1,167 @@
+//! The parts of `.gitignore` that keep build output and dependencies out of
+//! recursive scans: `*`, `?` and `**` globs, `!` negation, a trailing `/`
+//! for folders only and a leading or inner `/` anchoring a pattern to the
+//! folder of its `.gitignore`. Character classes are matched literally.
+//!
+//! Only `.gitignore` files inside the scanned tree are read, so a tree
+//! scanned from below a repository's root skips less than `git` would.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Folders never worth entering, whether or not anything ignores them.
+pub(crate) const EXCLUDED_DIRS: [&str; 2] = ["node_modules", "target"];
+
+/// Rules in effect in a folder: its own `.gitignore`'s after its parents'.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IgnoreRules {
+    rules: Arc<Vec<Rule>>,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    /// Folder of the `.gitignore` the rule came from.
+    base: PathBuf,
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    /// Matched against the whole path below `base` rather than any name.
+    anchored: bool,
+}
+
+impl IgnoreRules {
+    /// These rules plus those of `dir`'s `.gitignore`, if it has one.
+    pub fn enter(&self, dir: &Path) -> Self {
+        let Ok(contents) = fs::read_to_string(dir.join(".gitignore")) else {
+            return self.clone();
+        };
+        let mut rules = self.rules.as_ref().clone();
+        rules.extend(contents.lines().filter_map(|line| Rule::parse(dir, line)));
+        Self {
+            rules: Arc::new(rules),
+        }
+    }
+
+    /// Whether `path` is ignored. The last rule matching it decides.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(path, is_dir))
+            .is_some_and(|rule| !rule.negated)
+    }
+}
+
+impl Rule {
+    fn parse(base: &Path, line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let pattern = line.trim_start_matches('/');
+        if pattern.is_empty() {
+            return None;
+        }
+        Some(Self {
+            base: base.to_path_buf(),
+            pattern: pattern.to_string(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let Ok(relative) = path.strip_prefix(&self.base) else {
+            return false;
+        };
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        if self.anchored {
+            glob_matches(&self.pattern, &relative)
+        } else {
+            let name = relative.rsplit('/').next().unwrap_or(&relative);
+            glob_matches(&self.pattern, name)
+        }
+    }
+}
+
+/// Whether `text` matches the glob `pattern`: `*` and `?` stay within a
+/// path segment, `**` crosses them.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    matches_from(pattern.as_bytes(), text.as_bytes())
+}
+
+fn matches_from(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `a/**/b` also matches `a/b`
+            let rest = rest.strip_prefix(b"/").unwrap_or(rest);
+            (0..=text.len()).any(|i| matches_from(rest, &text[i..]))
+        }
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| matches_from(rest, &text[i..])),
+        [b'?', rest @ ..] => {
+            matches!(text, [c, tail @ ..] if *c != b'/' && matches_from(rest, tail))
+        }
+        [c, rest @ ..] => matches!(text, [t, tail @ ..] if t == c && matches_from(rest, tail)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("*.log", "debug.log"));
+        assert!(!glob_matches("*.log", "logs/debug.log"));
+        assert!(glob_matches("build?", "build1"));
+        assert!(glob_matches("docs/**/draft.md", "docs/draft.md"));
+        assert!(glob_matches("docs/**/draft.md", "docs/a/b/draft.md"));
+        assert!(glob_matches("**/out", "a/out"));
+        assert!(!glob_matches("dist", "dist2"));
+    }
+
+    #[test]
+    fn test_rules_follow_gitignore_semantics() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir(root.join("docs")).unwrap();
+        fs::write(
+            root.join(".gitignore"),
+            "# build output\ndist/\n*.tmp.md\n!keep.tmp.md\n/generated.md\n",
+        )
+        .unwrap();
+        fs::write(root.join("docs").join(".gitignore"), "drafts/\n").unwrap();
+
+        let rules = IgnoreRules::default().enter(root);
+        assert!(rules.is_ignored(&root.join("dist"), true));
+        assert!(!rules.is_ignored(&root.join("dist"), false));
+        assert!(rules.is_ignored(&root.join("docs").join("a.tmp.md"), false));
+        assert!(!rules.is_ignored(&root.join("keep.tmp.md"), false));
+        assert!(rules.is_ignored(&root.join("generated.md"), false));
+        assert!(!rules.is_ignored(&root.join("docs").join("generated.md"), false));
+
+        let docs = rules.enter(&root.join("docs"));
+        assert!(docs.is_ignored(&root.join("docs").join("drafts"), true));
+        assert!(!rules.is_ignored(&root.join("docs").join("drafts"), true));
+        assert!(docs.is_ignored(&root.join("docs").join("dist"), true));
+    }
+}
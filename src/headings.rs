@@ -0,0 +1,255 @@
+//! Heading IDs generated the way GitHub does, so `#fragment` links copied
+//! from a rendered README resolve to the same heading here.
+
+use crate::{
+    frontmatter,
+    render::{split_frontmatter, RenderContext, RenderStage},
+};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// A heading of a rendered document, for its table of contents.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Heading {
+    /// 1 for `<h1>` through 6 for `<h6>`.
+    pub level: u8,
+    /// The heading's `id`, for `#fragment` links.
+    pub id: String,
+    /// The heading's text, without markup.
+    pub text: String,
+}
+
+/// Hands out GitHub-style slugs for the headings of one document, adding
+/// `-1`, `-2`, … to repeats.
+#[derive(Debug, Default)]
+pub(crate) struct Slugger {
+    occurrences: HashMap<String, usize>,
+}
+
+impl Slugger {
+    pub fn slug(&mut self, text: &str) -> String {
+        let original = github_slug(text);
+        let mut slug = original.clone();
+        // A suffixed slug can collide with a heading that reads the same,
+        // e.g. "a" twice and then "a-1"
+        while self.occurrences.contains_key(&slug) {
+            let count = self.occurrences.entry(original.clone()).or_default();
+            *count += 1;
+            slug = format!("{original}-{count}");
+        }
+        self.occurrences.insert(slug.clone(), 0);
+        slug
+    }
+}
+
+/// Lowercase `text`, drop everything but letters, digits, `-`, `_` and
+/// spaces, then turn spaces into hyphens (github-slugger's rules).
+pub(crate) fn github_slug(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | ' '))
+        .map(|c| if c == ' ' { '-' } else { c })
+        .collect()
+}
+
+/// Gives every `<h1>`–`<h6>` produced by the parser an `id` from its text
+/// and records it for the table of contents. Headings written as raw HTML
+/// keep their own attributes and are left out.
+pub(crate) struct HeadingIdStage;
+
+impl RenderStage for HeadingIdStage {
+    fn post_html(&self, html: String, ctx: &mut RenderContext) -> String {
+        let mut slugger = Slugger::default();
+        let mut out = String::with_capacity(html.len());
+        let mut rest = html.as_str();
+
+        while let Some((start, level)) = next_heading(rest) {
+            let inner_start = start + "<h1>".len();
+            let close = format!("</h{level}>");
+            let Some(len) = rest[inner_start..].find(&close) else {
+                break;
+            };
+            let inner = &rest[inner_start..inner_start + len];
+            let text = text_content(inner);
+            let id = slugger.slug(&text);
+            out.push_str(&rest[..start]);
+            out.push_str(&format!("<h{level} id=\"{id}\">"));
+            ctx.add_heading(Heading {
+                level: level as u8 - b'0',
+                id,
+                text: text.trim().to_string(),
+            });
+            out.push_str(inner);
+            out.push_str(&close);
+            rest = &rest[inner_start + len + close.len()..];
+        }
+
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Offset and level of the next attribute-less heading tag.
+fn next_heading(html: &str) -> Option<(usize, char)> {
+    let mut offset = 0;
+    while let Some(found) = html[offset..].find("<h") {
+        let start = offset + found;
+        let tag = &html.as_bytes()[start + 2..];
+        if let [level @ b'1'..=b'6', b'>', ..] = tag {
+            return Some((start, *level as char));
+        }
+        offset = start + 2;
+    }
+    None
+}
+
+/// The text of an HTML fragment: tags dropped, the parser's escapes
+/// decoded.
+fn text_content(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+/// A document's title for labelling it: its frontmatter `title`, else the
+/// text of its first top-level `# ` heading outside code blocks.
+pub(crate) fn document_title(source: &str) -> Option<String> {
+    if let Some(serde_json::Value::String(title)) = frontmatter::parse(source).get("title") {
+        if !title.trim().is_empty() {
+            return Some(title.trim().to_string());
+        }
+    }
+
+    // Frontmatter lines starting with `#` are comments, not headings
+    let body = match split_frontmatter(source) {
+        Some(frontmatter) => {
+            let end = frontmatter.body.as_ptr() as usize - source.as_ptr() as usize
+                + frontmatter.body.len();
+            source[end..].split_once('\n').map_or("", |(_, rest)| rest)
+        }
+        None => source,
+    };
+
+    let mut fence: Option<&str> = None;
+    for line in body.lines() {
+        let trimmed = line.trim_start();
+        if let Some(open) = fence {
+            if trimmed.starts_with(open) {
+                fence = None;
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fence = Some(&trimmed[..3]);
+            continue;
+        }
+        let Some(heading) = line.strip_prefix("# ") else {
+            continue;
+        };
+        let heading = heading.trim().trim_end_matches('#').trim_end();
+        // Rendered on its own, so emphasis and code lose their markup
+        let text = text_content(&markdown::to_html(heading));
+        let text = text.trim();
+        if !text.is_empty() {
+            return Some(text.to_string());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{RenderOptions, RenderPipeline};
+
+    #[test]
+    fn test_github_slugs() {
+        let mut slugger = Slugger::default();
+        let slugs: Vec<_> = [
+            "Getting Started",
+            "What's new in v2.0?",
+            "C++ & Rust: `unsafe` code",
+            "Ünïcödé Überschrift",
+            "Emoji 🎉 party",
+            "Getting Started",
+            "getting-started-1",
+            "Getting Started",
+            "snake_case-and-kebab",
+        ]
+        .iter()
+        .map(|text| slugger.slug(text))
+        .collect();
+
+        assert_eq!(
+            slugs,
+            vec![
+                "getting-started",
+                "whats-new-in-v20",
+                "c--rust-unsafe-code",
+                "ünïcödé-überschrift",
+                "emoji--party",
+                "getting-started-1",
+                "getting-started-1-1",
+                "getting-started-2",
+                "snake_case-and-kebab",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_document_titles() {
+        assert_eq!(
+            document_title("---\n# comment\ntitle: Release notes\n---\n# Heading\n").as_deref(),
+            Some("Release notes")
+        );
+        assert_eq!(
+            document_title(
+                "---\n# comment\n---\nIntro\n\n```sh\n# not a title\n```\n# Setup & *Usage* #\n"
+            )
+            .as_deref(),
+            Some("Setup & Usage")
+        );
+        assert_eq!(document_title("## Only a subheading\n"), None);
+    }
+
+    #[test]
+    fn test_headings_get_ids() {
+        let content = "# Intro\n\n## Setup & *Usage*\n\n## Setup & Usage\n\n<h2 class=\"raw\">Raw</h2>\n\n###### [Link](x.md) `code`\n";
+        let doc = RenderPipeline::default().render(content, &RenderOptions::default());
+
+        assert_eq!(
+            doc.html,
+            "<h1 id=\"intro\">Intro</h1>\n\
+             <h2 id=\"setup--usage\">Setup &amp; <em>Usage</em></h2>\n\
+             <h2 id=\"setup--usage-1\">Setup &amp; Usage</h2>\n\
+             <h2 class=\"raw\">Raw</h2>\n\
+             <h6 id=\"link-code\"><a href=\"x.md\">Link</a> <code>code</code></h6>\n"
+        );
+
+        let outline: Vec<_> = doc
+            .headings
+            .iter()
+            .map(|h| (h.level, h.id.as_str(), h.text.as_str()))
+            .collect();
+        assert_eq!(
+            outline,
+            vec![
+                (1, "intro", "Intro"),
+                (2, "setup--usage", "Setup & Usage"),
+                (2, "setup--usage-1", "Setup & Usage"),
+                (6, "link-code", "Link code"),
+            ]
+        );
+    }
+}
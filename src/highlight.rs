@@ -0,0 +1,641 @@
+//! Server-side syntax highlighting for fenced code blocks.
+//!
+//! A small lexer per language family marks comments, strings, numbers,
+//! keywords and literals with `hl-*` classes; [`HighlightTheme`] supplies
+//! the colours. Code in languages without a definition is left as is.
+
+/// Colours for highlighted code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HighlightTheme {
+    /// Follow the page theme chosen in the browser, with Catppuccin's
+    /// colours for the Catppuccin page themes and GitHub's for the rest.
+    #[default]
+    Auto,
+    /// GitHub's light or dark colours, whichever suits the page theme.
+    Github,
+    /// Solarized light or dark, whichever suits the page theme.
+    Solarized,
+    /// GitHub's light colours.
+    GithubLight,
+    /// GitHub's dark colours.
+    GithubDark,
+    Monokai,
+    SolarizedLight,
+    SolarizedDark,
+}
+
+/// Page themes with a dark background, where light/dark pairs switch to
+/// their dark palette.
+const DARK_PAGE_THEMES: [&str; 3] = ["dark", "catppuccin-macchiato", "catppuccin-mocha"];
+
+impl HighlightTheme {
+    /// Stylesheet for the `hl-*` classes. Fixed themes and light/dark pairs
+    /// also set the code block background, so the colours stay readable.
+    pub fn css(self) -> String {
+        let palette = match self {
+            HighlightTheme::Auto => return format!("{AUTO_VARIABLES}{VARIABLE_RULES}"),
+            HighlightTheme::Github => return pair_css(&GITHUB, &GITHUB_DARK),
+            HighlightTheme::Solarized => return pair_css(&SOLARIZED_LIGHT, &SOLARIZED_DARK),
+            HighlightTheme::GithubLight => &GITHUB,
+            HighlightTheme::GithubDark => &GITHUB_DARK,
+            HighlightTheme::Monokai => &MONOKAI,
+            HighlightTheme::SolarizedLight => &SOLARIZED_LIGHT,
+            HighlightTheme::SolarizedDark => &SOLARIZED_DARK,
+        };
+        format!(
+            "pre:has(> code[class*=\"language-\"]) {{ background: {}; color: {}; }}\n{}",
+            palette.background,
+            palette.text,
+            palette.rules()
+        )
+    }
+}
+
+struct Palette {
+    background: &'static str,
+    text: &'static str,
+    comment: &'static str,
+    string: &'static str,
+    number: &'static str,
+    keyword: &'static str,
+    literal: &'static str,
+}
+
+impl Palette {
+    fn variables(&self) -> String {
+        format!(
+            "--hl-background: {}; --hl-text: {}; --hl-comment: {}; --hl-string: {}; \
+             --hl-number: {}; --hl-keyword: {}; --hl-literal: {};",
+            self.background,
+            self.text,
+            self.comment,
+            self.string,
+            self.number,
+            self.keyword,
+            self.literal
+        )
+    }
+
+    fn rules(&self) -> String {
+        format!(
+            ".hl-comment {{ color: {}; font-style: italic; }}\n\
+             .hl-string {{ color: {}; }}\n\
+             .hl-number {{ color: {}; }}\n\
+             .hl-keyword {{ color: {}; }}\n\
+             .hl-literal {{ color: {}; }}\n",
+            self.comment, self.string, self.number, self.keyword, self.literal
+        )
+    }
+}
+
+const GITHUB: Palette = Palette {
+    background: "#f6f8fa",
+    text: "#24292f",
+    comment: "#6e7781",
+    string: "#0a3069",
+    number: "#0550ae",
+    keyword: "#cf222e",
+    literal: "#0550ae",
+};
+
+const GITHUB_DARK: Palette = Palette {
+    background: "#161b22",
+    text: "#e6edf3",
+    comment: "#8b949e",
+    string: "#a5d6ff",
+    number: "#79c0ff",
+    keyword: "#ff7b72",
+    literal: "#79c0ff",
+};
+
+const MONOKAI: Palette = Palette {
+    background: "#272822",
+    text: "#f8f8f2",
+    comment: "#75715e",
+    string: "#e6db74",
+    number: "#ae81ff",
+    keyword: "#f92672",
+    literal: "#ae81ff",
+};
+
+const SOLARIZED_LIGHT: Palette = Palette {
+    background: "#fdf6e3",
+    text: "#657b83",
+    comment: "#93a1a1",
+    string: "#2aa198",
+    number: "#d33682",
+    keyword: "#859900",
+    literal: "#b58900",
+};
+
+const SOLARIZED_DARK: Palette = Palette {
+    background: "#002b36",
+    text: "#839496",
+    comment: "#586e75",
+    string: "#2aa198",
+    number: "#d33682",
+    keyword: "#859900",
+    literal: "#b58900",
+};
+
+/// `light`'s colours, switched to `dark`'s under the dark page themes.
+fn pair_css(light: &Palette, dark: &Palette) -> String {
+    let dark_selector = DARK_PAGE_THEMES
+        .map(|theme| format!("[data-theme=\"{theme}\"]"))
+        .join(", ");
+    format!(
+        ":root {{ {} }}\n\
+         {dark_selector} {{ {} }}\n\
+         pre:has(> code[class*=\"language-\"]) {{ background: var(--hl-background); color: var(--hl-text); }}\n\
+         {VARIABLE_RULES}",
+        light.variables(),
+        dark.variables()
+    )
+}
+
+/// GitHub colours for the light and dark page themes, Catppuccin's for
+/// the Catppuccin ones.
+const AUTO_VARIABLES: &str = "\
+:root { --hl-comment: #6e7781; --hl-string: #0a3069; --hl-number: #0550ae; --hl-keyword: #cf222e; --hl-literal: #0550ae; }
+[data-theme=\"dark\"] { --hl-comment: #8b949e; --hl-string: #a5d6ff; --hl-number: #79c0ff; --hl-keyword: #ff7b72; --hl-literal: #79c0ff; }
+[data-theme=\"catppuccin-latte\"] { --hl-comment: #9ca0b0; --hl-string: #40a02b; --hl-number: #fe640b; --hl-keyword: #8839ef; --hl-literal: #fe640b; }
+[data-theme=\"catppuccin-macchiato\"] { --hl-comment: #6e738d; --hl-string: #a6da95; --hl-number: #f5a97f; --hl-keyword: #c6a0f6; --hl-literal: #f5a97f; }
+[data-theme=\"catppuccin-mocha\"] { --hl-comment: #6c7086; --hl-string: #a6e3a1; --hl-number: #fab387; --hl-keyword: #cba6f7; --hl-literal: #fab387; }
+";
+
+/// The `hl-*` classes coloured from the `--hl-*` variables.
+const VARIABLE_RULES: &str = "\
+.hl-comment { color: var(--hl-comment); font-style: italic; }
+.hl-string { color: var(--hl-string); }
+.hl-number { color: var(--hl-number); }
+.hl-keyword { color: var(--hl-keyword); }
+.hl-literal { color: var(--hl-literal); }
+";
+
+/// What the lexer needs to know about a language.
+struct Language {
+    /// Names used after the opening fence.
+    names: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    /// `"""` and `'''` strings, which may span lines.
+    triple_quotes: bool,
+    keywords: &'static [&'static str],
+    literals: &'static [&'static str],
+}
+
+const LANGUAGES: &[Language] = &[
+    Language {
+        names: &["rust", "rs"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"'],
+        triple_quotes: false,
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+            "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait",
+            "type", "unsafe", "use", "where", "while",
+        ],
+        literals: &["true", "false", "None", "Some", "Ok", "Err"],
+    },
+    Language {
+        names: &["javascript", "js", "jsx", "mjs", "typescript", "ts", "tsx"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\'', '`'],
+        triple_quotes: false,
+        keywords: &[
+            "async",
+            "await",
+            "break",
+            "case",
+            "catch",
+            "class",
+            "const",
+            "continue",
+            "default",
+            "delete",
+            "do",
+            "else",
+            "enum",
+            "export",
+            "extends",
+            "finally",
+            "for",
+            "from",
+            "function",
+            "if",
+            "implements",
+            "import",
+            "in",
+            "instanceof",
+            "interface",
+            "let",
+            "new",
+            "of",
+            "return",
+            "static",
+            "switch",
+            "this",
+            "throw",
+            "try",
+            "type",
+            "typeof",
+            "var",
+            "void",
+            "while",
+            "yield",
+        ],
+        literals: &["true", "false", "null", "undefined", "NaN"],
+    },
+    Language {
+        names: &["python", "py"],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        triple_quotes: true,
+        keywords: &[
+            "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+            "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in",
+            "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+            "with", "yield",
+        ],
+        literals: &["True", "False", "None"],
+    },
+    Language {
+        names: &["bash", "sh", "shell", "zsh", "console"],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        triple_quotes: false,
+        keywords: &[
+            "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+            "in", "local", "return", "then", "until", "while",
+        ],
+        literals: &["true", "false"],
+    },
+    Language {
+        names: &["go", "golang"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '`'],
+        triple_quotes: false,
+        keywords: &[
+            "break",
+            "case",
+            "chan",
+            "const",
+            "continue",
+            "default",
+            "defer",
+            "else",
+            "fallthrough",
+            "for",
+            "func",
+            "go",
+            "goto",
+            "if",
+            "import",
+            "interface",
+            "map",
+            "package",
+            "range",
+            "return",
+            "select",
+            "struct",
+            "switch",
+            "type",
+            "var",
+        ],
+        literals: &["true", "false", "nil", "iota"],
+    },
+    Language {
+        names: &[
+            "c", "h", "cpp", "c++", "cc", "hpp", "java", "kotlin", "kt", "csharp", "cs", "swift",
+        ],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"'],
+        triple_quotes: false,
+        keywords: &[
+            "abstract",
+            "auto",
+            "break",
+            "case",
+            "catch",
+            "class",
+            "const",
+            "continue",
+            "default",
+            "do",
+            "else",
+            "enum",
+            "extends",
+            "extern",
+            "final",
+            "for",
+            "fun",
+            "func",
+            "goto",
+            "if",
+            "implements",
+            "import",
+            "interface",
+            "let",
+            "namespace",
+            "new",
+            "override",
+            "package",
+            "private",
+            "protected",
+            "public",
+            "return",
+            "sizeof",
+            "static",
+            "struct",
+            "switch",
+            "template",
+            "this",
+            "throw",
+            "try",
+            "typedef",
+            "typename",
+            "union",
+            "using",
+            "val",
+            "var",
+            "virtual",
+            "void",
+            "volatile",
+            "while",
+        ],
+        literals: &["true", "false", "null", "nullptr", "NULL", "nil"],
+    },
+    Language {
+        names: &["ruby", "rb"],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        triple_quotes: false,
+        keywords: &[
+            "begin", "break", "case", "class", "def", "do", "else", "elsif", "end", "ensure",
+            "for", "if", "in", "module", "next", "raise", "require", "rescue", "return", "self",
+            "then", "unless", "until", "when", "while", "yield",
+        ],
+        literals: &["true", "false", "nil"],
+    },
+    Language {
+        names: &["sql"],
+        line_comments: &["--"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['\''],
+        triple_quotes: false,
+        keywords: &[
+            "and", "as", "by", "create", "delete", "from", "group", "having", "insert", "into",
+            "join", "left", "limit", "not", "on", "or", "order", "select", "set", "table",
+            "update", "values", "where", "AND", "AS", "BY", "CREATE", "DELETE", "FROM", "GROUP",
+            "HAVING", "INSERT", "INTO", "JOIN", "LEFT", "LIMIT", "NOT", "ON", "OR", "ORDER",
+            "SELECT", "SET", "TABLE", "UPDATE", "VALUES", "WHERE",
+        ],
+        literals: &["null", "true", "false", "NULL", "TRUE", "FALSE"],
+    },
+    Language {
+        names: &["json", "jsonc"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"'],
+        triple_quotes: false,
+        keywords: &[],
+        literals: &["true", "false", "null"],
+    },
+    Language {
+        names: &["toml", "yaml", "yml", "ini", "dockerfile", "makefile"],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        triple_quotes: false,
+        keywords: &[],
+        literals: &["true", "false", "null", "yes", "no"],
+    },
+    Language {
+        names: &["css", "scss"],
+        line_comments: &[],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\''],
+        triple_quotes: false,
+        keywords: &["important", "media", "import", "keyframes"],
+        literals: &[],
+    },
+];
+
+fn language(name: &str) -> Option<&'static Language> {
+    let name = name.to_ascii_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|language| language.names.contains(&name.as_str()))
+}
+
+/// Highlight the `<pre><code class="language-…">` blocks of parser output
+/// whose language is known.
+pub(crate) fn highlight_code_blocks(html: &str) -> String {
+    const OPEN: &str = "<pre><code class=\"language-";
+    const CLOSE: &str = "</code></pre>";
+
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(OPEN) {
+        let name_start = start + OPEN.len();
+        let Some(name_len) = rest[name_start..].find('"') else {
+            break;
+        };
+        let Some(tag_len) = rest[name_start..].find('>') else {
+            break;
+        };
+        let code_start = name_start + tag_len + 1;
+        let Some(code_len) = rest[code_start..].find(CLOSE) else {
+            break;
+        };
+
+        out.push_str(&rest[..code_start]);
+        let code = &rest[code_start..code_start + code_len];
+        match language(&rest[name_start..name_start + name_len]) {
+            Some(language) => out.push_str(&highlight(&unescape_html(code), language)),
+            None => out.push_str(code),
+        }
+        out.push_str(CLOSE);
+        rest = &rest[code_start + code_len + CLOSE.len()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// `code` as HTML with its tokens wrapped in `hl-*` spans.
+fn highlight(code: &str, language: &Language) -> String {
+    let mut out = String::with_capacity(code.len() * 2);
+    let mut plain_start = 0;
+    let mut i = 0;
+
+    while i < code.len() {
+        let rest = &code[i..];
+        let c = rest.chars().next().unwrap_or_default();
+        let after_word = code[..i]
+            .chars()
+            .next_back()
+            .is_some_and(|p| p.is_alphanumeric() || p == '_');
+
+        let token = if let Some(len) = comment_len(rest, language) {
+            Some((len, "hl-comment"))
+        } else if language.quotes.contains(&c) {
+            Some((string_len(rest, c, language.triple_quotes), "hl-string"))
+        } else if c.is_ascii_digit() && !after_word {
+            let len = rest
+                .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '_' || ch == '.'))
+                .unwrap_or(rest.len());
+            Some((len, "hl-number"))
+        } else if (c.is_alphabetic() || c == '_') && !after_word {
+            let len = rest
+                .find(|ch: char| !(ch.is_alphanumeric() || ch == '_'))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            if language.keywords.contains(&word) {
+                Some((len, "hl-keyword"))
+            } else if language.literals.contains(&word) {
+                Some((len, "hl-literal"))
+            } else {
+                // Skip the whole word so keywords inside it aren't matched
+                i += len;
+                continue;
+            }
+        } else {
+            None
+        };
+
+        match token {
+            Some((len, class)) => {
+                out.push_str(&escape(&code[plain_start..i]));
+                out.push_str("<span class=\"");
+                out.push_str(class);
+                out.push_str("\">");
+                out.push_str(&escape(&rest[..len]));
+                out.push_str("</span>");
+                i += len;
+                plain_start = i;
+            }
+            None => i += c.len_utf8(),
+        }
+    }
+    out.push_str(&escape(&code[plain_start..]));
+    out
+}
+
+/// Length of the comment starting `text`, if one does.
+fn comment_len(text: &str, language: &Language) -> Option<usize> {
+    if language
+        .line_comments
+        .iter()
+        .any(|marker| text.starts_with(marker))
+    {
+        return Some(text.find('\n').unwrap_or(text.len()));
+    }
+    let (open, close) = language.block_comment?;
+    text.starts_with(open).then(|| {
+        text[open.len()..]
+            .find(close)
+            .map_or(text.len(), |end| open.len() + end + close.len())
+    })
+}
+
+/// Length of the string opened by `quote` at the start of `text`. Strings
+/// that aren't closed end at the line break.
+fn string_len(text: &str, quote: char, triple_quotes: bool) -> usize {
+    let triple: String = [quote; 3].iter().collect();
+    if triple_quotes && text.starts_with(&triple) {
+        return text[3..]
+            .find(&triple)
+            .map_or(text.len(), |end| 3 + end + 3);
+    }
+
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '\n' if quote != '`' => return i,
+            c if c == quote => return i + c.len_utf8(),
+            _ => {}
+        }
+    }
+    text.len()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_languages_are_highlighted() {
+        let html = "<pre><code class=\"language-rust\">// add\nfn add(a: u8) -&gt; u8 { let s = &quot;fn&quot;; a + 1 }\n</code></pre>";
+        assert_eq!(
+            highlight_code_blocks(html),
+            "<pre><code class=\"language-rust\"><span class=\"hl-comment\">// add</span>\n\
+             <span class=\"hl-keyword\">fn</span> add(a: u8) -&gt; u8 { \
+             <span class=\"hl-keyword\">let</span> s = <span class=\"hl-string\">&quot;fn&quot;</span>; \
+             a + <span class=\"hl-number\">1</span> }\n</code></pre>"
+        );
+
+        let python = "<pre><code class=\"language-python\">x = &quot;&quot;&quot;a\n# not a comment&quot;&quot;&quot;  # note\n</code></pre>";
+        assert_eq!(
+            highlight_code_blocks(python),
+            "<pre><code class=\"language-python\">x = <span class=\"hl-string\">&quot;&quot;&quot;a\n# not a comment&quot;&quot;&quot;</span>  <span class=\"hl-comment\"># note</span>\n</code></pre>"
+        );
+    }
+
+    #[test]
+    fn test_unknown_languages_and_identifiers_are_left_alone() {
+        let html = "<pre><code class=\"language-mermaid\">graph LR\n  if --&gt; fn\n</code></pre>\n<pre><code>let x\n</code></pre>";
+        assert_eq!(highlight_code_blocks(html), html);
+
+        let words = "<pre><code class=\"language-js\">letter = iffy + x1</code></pre>";
+        assert_eq!(highlight_code_blocks(words), words);
+    }
+
+    #[test]
+    fn test_theme_pairs_follow_the_page_theme() {
+        let css = HighlightTheme::Solarized.css();
+        let (light, dark) = css.split_once("\n[data-theme=\"dark\"]").unwrap();
+        assert!(light.starts_with(":root {"));
+        assert!(light.contains("--hl-background: #fdf6e3;"));
+        let dark = dark.lines().next().unwrap();
+        assert!(dark.contains("[data-theme=\"catppuccin-mocha\"]"));
+        assert!(dark.contains("--hl-background: #002b36;"));
+        assert!(css.contains("background: var(--hl-background)"));
+        assert!(css.contains(".hl-keyword { color: var(--hl-keyword); }"));
+
+        let github = HighlightTheme::Github.css();
+        assert!(github.contains("--hl-keyword: #cf222e;"));
+        assert!(github.contains("--hl-keyword: #ff7b72;"));
+
+        // Fixed themes look the same under every page theme
+        let fixed = HighlightTheme::GithubLight.css();
+        assert!(!fixed.contains("data-theme"));
+        assert!(fixed.contains(".hl-keyword { color: #cf222e; }"));
+    }
+}
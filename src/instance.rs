@@ -0,0 +1,191 @@
+//! Finding an mdserve that is already serving the same path, so starting
+//! it twice opens the running server instead of a second one on the next
+//! free port.
+//!
+//! Servers answer `GET /api/instance` with their version and a fingerprint
+//! of what they serve: the SHA-1 of the canonical directory (or file, in
+//! single-file mode) and any further paths served next to it, so the paths
+//! themselves aren't exposed. A new invocation probes the ports the server
+//! would have fallen back through.
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{path::PathBuf, time::Duration};
+
+use crate::{
+    app::{browsable_host, format_host, open_browser, MAX_PORT_ATTEMPTS},
+    client, paths, terminal,
+};
+
+/// How long a port gets to answer the probe.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+/// Responses are tiny; anything bigger isn't an mdserve.
+const MAX_RESPONSE: usize = 4096;
+
+/// What `GET /api/instance` returns.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct InstanceInfo {
+    pub name: String,
+    pub version: String,
+    pub root: String,
+}
+
+impl InstanceInfo {
+    /// Info for a server serving the canonical `roots`, in any order.
+    pub fn new(roots: &[PathBuf]) -> Self {
+        Self {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            root: fingerprint(roots),
+        }
+    }
+}
+
+fn fingerprint(roots: &[PathBuf]) -> String {
+    let mut roots: Vec<_> = roots.iter().map(|root| root.to_string_lossy()).collect();
+    roots.sort();
+    roots.dedup();
+    Sha1::digest(roots.join("\n").as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// If an mdserve on `hostname` at `port` (or one of the ports after it)
+/// already serves exactly `paths`, print its URL, open it when `open` is
+/// set, copy it to the clipboard when `copy` is set and return `true`.
+pub async fn reuse_running_instance(
+    paths: &[PathBuf],
+    hostname: &str,
+    port: u16,
+    open: bool,
+    copy: bool,
+) -> anyhow::Result<bool> {
+    let host = browsable_host(hostname);
+    let Some(port) = find_instance(paths, &host, port).await else {
+        return Ok(false);
+    };
+
+    let url = format!("http://{}", format_host(&host, port));
+    let served: Vec<_> = paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    println!(
+        "✓ mdserve is already serving {} at {}",
+        served.join(", "),
+        terminal::hyperlink(&url, &url)
+    );
+    if copy {
+        terminal::copy_url(&url);
+    }
+    if open {
+        open_browser(&url)?;
+    }
+    Ok(true)
+}
+
+/// The port of a running mdserve serving exactly the paths in `served`.
+async fn find_instance(served: &[PathBuf], host: &str, port: u16) -> Option<u16> {
+    let roots: Vec<_> = served
+        .iter()
+        .map(|path| paths::canonicalize(path).unwrap_or_else(|_| path.clone()))
+        .collect();
+    let expected = InstanceInfo::new(&roots);
+
+    for offset in 0..MAX_PORT_ATTEMPTS {
+        let port = port.checked_add(offset)?;
+        if let Ok(Some(info)) = tokio::time::timeout(PROBE_TIMEOUT, probe(host, port)).await {
+            if info == expected {
+                return Some(port);
+            }
+        }
+    }
+    None
+}
+
+/// Ask `host:port` for its instance info. `None` if nothing listens there
+/// or it isn't an mdserve.
+async fn probe(host: &str, port: u16) -> Option<InstanceInfo> {
+    let request = Request::get("/api/instance").body(Body::empty()).ok()?;
+    let response = client::send(host, port, false, request, MAX_RESPONSE)
+        .await
+        .ok()?;
+    if response.status() != StatusCode::OK {
+        return None;
+    }
+    serde_json::from_slice(response.body()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Mdserve;
+
+    #[tokio::test]
+    async fn test_running_instances_are_found_by_path() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let docs = temp_dir.path().join("docs");
+        std::fs::create_dir(&docs).unwrap();
+        std::fs::write(docs.join("a.md"), "# A").unwrap();
+
+        let server = Mdserve::builder()
+            .base_dir(&docs)
+            .watch(false)
+            .serve("127.0.0.1", 0)
+            .await
+            .unwrap();
+        let port = server.local_addr().port();
+
+        assert_eq!(
+            find_instance(std::slice::from_ref(&docs), "127.0.0.1", port).await,
+            Some(port)
+        );
+        // The same directory through another spelling
+        let relative = docs.join("..").join("docs");
+        assert_eq!(
+            find_instance(&[relative], "127.0.0.1", port).await,
+            Some(port)
+        );
+        assert_eq!(
+            find_instance(&[temp_dir.path().to_path_buf()], "127.0.0.1", port).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_running_instances_match_every_served_path() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let a = temp_dir.path().join("a");
+        let b = temp_dir.path().join("b");
+        for dir in [&a, &b] {
+            std::fs::create_dir(dir).unwrap();
+            std::fs::write(dir.join("doc.md"), "# Doc").unwrap();
+        }
+
+        let server = Mdserve::builder()
+            .base_dir(&a)
+            .extra_paths([b.clone()])
+            .watch(false)
+            .serve("127.0.0.1", 0)
+            .await
+            .unwrap();
+        let port = server.local_addr().port();
+
+        assert_eq!(
+            find_instance(&[a.clone(), b.clone()], "127.0.0.1", port).await,
+            Some(port)
+        );
+        assert_eq!(
+            find_instance(&[b.clone(), a.clone()], "127.0.0.1", port).await,
+            Some(port)
+        );
+        // Serves a/, but not only a/
+        assert_eq!(find_instance(&[a], "127.0.0.1", port).await, None);
+        assert_eq!(find_instance(&[b], "127.0.0.1", port).await, None);
+    }
+}
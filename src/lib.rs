@@ -0,0 +1,62 @@
+//! Markdown preview server with live reload.
+//!
+//! The `mdserve` binary is a thin CLI over this crate. Embedders can build
+//! the same router with [`Mdserve::builder`] and mount it in their own axum
+//! application.
+
+mod alerts;
+mod app;
+mod auth;
+mod client;
+mod config;
+mod containers;
+mod deflists;
+mod emoji;
+mod encoding;
+mod export;
+mod frontmatter;
+mod gitignore;
+mod headings;
+mod highlight;
+mod instance;
+mod lint;
+mod logging;
+mod math;
+mod mdns;
+mod paths;
+#[cfg(feature = "pulldown-cmark")]
+mod pulldown;
+mod render;
+mod server;
+mod share;
+mod signal;
+mod sourcepos;
+mod template;
+mod terminal;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod tls;
+mod tunnel;
+pub mod watch;
+mod wikilinks;
+
+pub use app::{
+    scan_markdown_files, serve_markdown, ClientMessage, ExportSummary, Mdserve, MdserveBuilder,
+    NoticeLevel, ServerHandle, ServerMessage, StateHandle, StaticFileOptions, UploadOptions,
+};
+pub use config::{apply_config, config_files};
+pub use encoding::read_markdown;
+pub use frontmatter::Metadata;
+pub use headings::Heading;
+pub use highlight::HighlightTheme;
+pub use instance::reuse_running_instance;
+pub use lint::{LintRules, LintStage};
+pub use logging::init_logging;
+pub use render::{
+    markdown_to_html, Diagnostic, MarkdownBackend, MathMode, RenderContext, RenderOptions,
+    RenderPipeline, RenderStage, RenderedDocument, MATH_FEATURE, MERMAID_FEATURE,
+};
+pub use share::{parse_duration, share_url};
+pub use template::{FileTemplate, MiniJinjaTemplate, PageContext, PageTemplate};
+pub use tls::TlsCertificate;
+pub use tunnel::TunnelProvider;
@@ -0,0 +1,555 @@
+//! Optional checks on a document's source. Findings are reported as
+//! [`Diagnostic`]s and shown in the page: a collapsible panel above the
+//! content lists them, and a marker in the margin flags each block that has
+//! any.
+
+use crate::render::{blank_line_after, Diagnostic, RenderContext, RenderOptions, RenderStage};
+use markdown::mdast::Node;
+
+/// Sentences with more words than this are reported.
+const MAX_SENTENCE_WORDS: usize = 35;
+/// Wraps a block's line range in the placeholder paragraph inserted before
+/// it. Private use characters don't occur in prose and pass through the
+/// parser unchanged, whether or not raw HTML is allowed.
+const MARKER: char = '\u{E000}';
+
+/// Which sets of checks a [`LintStage`] runs. All are off by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LintRules {
+    /// Long sentences, repeated words and images without alt text.
+    pub prose: bool,
+    /// Markdown style: heading levels that skip a step, trailing spaces,
+    /// bare URLs and duplicate headings.
+    pub markdown: bool,
+}
+
+impl LintRules {
+    pub fn any(&self) -> bool {
+        self.prose || self.markdown
+    }
+}
+
+/// Runs the enabled checks before parsing and turns their findings into
+/// markers and a panel afterwards. Add it with
+/// [`RenderPipeline::prepend`](crate::RenderPipeline::prepend), so line
+/// numbers match the file.
+pub struct LintStage {
+    rules: LintRules,
+    options: RenderOptions,
+}
+
+impl LintStage {
+    pub fn new(rules: LintRules, options: RenderOptions) -> Self {
+        Self { rules, options }
+    }
+}
+
+impl RenderStage for LintStage {
+    fn pre_parse(&self, source: String, ctx: &mut RenderContext) -> String {
+        let parse_options = self.options.to_markdown_options().parse;
+        let Ok(tree) = markdown::to_mdast(&source, &parse_options) else {
+            return source;
+        };
+
+        let mut findings = Vec::new();
+        if self.rules.prose {
+            check_prose(&tree, &mut findings);
+        }
+        if self.rules.markdown {
+            check_markdown(&tree, &source, &mut findings);
+        }
+        findings.sort_by_key(|finding| finding.line);
+
+        let source = insert_markers(source, &tree, &findings);
+        for finding in findings {
+            ctx.report(finding);
+        }
+        source
+    }
+
+    fn post_html(&self, html: String, ctx: &mut RenderContext) -> String {
+        let diagnostics = ctx.diagnostics();
+        if diagnostics.is_empty() {
+            return html;
+        }
+
+        let (html, blocks) = replace_markers(&html, diagnostics);
+        let mut panel = format!(
+            "<details class=\"lint-panel\"><summary>{} {}</summary><ul>",
+            diagnostics.len(),
+            if diagnostics.len() == 1 {
+                "suggestion"
+            } else {
+                "suggestions"
+            }
+        );
+        for diagnostic in diagnostics {
+            let line = match blocks.iter().find(|block| block.contains(&diagnostic.line)) {
+                Some(block) => format!(
+                    "<a href=\"#lint-line-{}\">Line {}</a>",
+                    block.start(),
+                    diagnostic.line
+                ),
+                None => format!("Line {}", diagnostic.line),
+            };
+            panel.push_str(&format!(
+                "<li>{line}: {} <span class=\"lint-rule\">{}</span></li>",
+                escape_html(&diagnostic.message),
+                escape_html(&diagnostic.rule)
+            ));
+        }
+        panel.push_str("</ul></details>\n");
+        panel + &html
+    }
+}
+
+/// Put a placeholder paragraph before every top-level block with findings,
+/// holding the block's line range. Blank lines around it keep it from
+/// joining the neighbouring blocks.
+fn insert_markers(mut source: String, tree: &Node, findings: &[Diagnostic]) -> String {
+    let Some(blocks) = tree.children() else {
+        return source;
+    };
+
+    let mut markers = Vec::new();
+    for block in blocks {
+        let Some(position) = block.position() else {
+            continue;
+        };
+        let lines = position.start.line..=position.end.line;
+        if findings.iter().any(|finding| lines.contains(&finding.line)) {
+            let line_start = source[..position.start.offset]
+                .rfind('\n')
+                .map_or(0, |i| i + 1);
+            let separator = blank_line_after(&source[..line_start]);
+            markers.push((
+                line_start,
+                format!(
+                    "{separator}{MARKER}lint:{}-{}{MARKER}\n\n",
+                    position.start.line, position.end.line
+                ),
+            ));
+        }
+    }
+
+    for (offset, marker) in markers.into_iter().rev() {
+        source.insert_str(offset, &marker);
+    }
+    source
+}
+
+/// Swap the rendered placeholders for margin markers titled with their
+/// block's findings. Returns the line ranges of the marked blocks.
+fn replace_markers(
+    html: &str,
+    diagnostics: &[Diagnostic],
+) -> (String, Vec<std::ops::RangeInclusive<usize>>) {
+    let open = format!("<p>{MARKER}lint:");
+    let close = format!("{MARKER}</p>");
+    let mut out = String::with_capacity(html.len());
+    let mut blocks = Vec::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find(&open) {
+        let Some(len) = rest[start + open.len()..].find(&close) else {
+            break;
+        };
+        let range = &rest[start + open.len()..start + open.len() + len];
+        out.push_str(&rest[..start]);
+        rest = &rest[start + open.len() + len + close.len()..];
+
+        let Some((first, last)) = range
+            .split_once('-')
+            .and_then(|(first, last)| Some((first.parse().ok()?, last.parse().ok()?)))
+        else {
+            continue;
+        };
+        let lines = first..=last;
+        let title = diagnostics
+            .iter()
+            .filter(|diagnostic| lines.contains(&diagnostic.line))
+            .map(|diagnostic| format!("Line {}: {}", diagnostic.line, diagnostic.message))
+            .collect::<Vec<_>>()
+            .join("\n");
+        out.push_str(&format!(
+            "<div class=\"lint-marker\" id=\"lint-line-{first}\" title=\"{}\"></div>",
+            escape_html(&title)
+        ));
+        blocks.push(lines);
+    }
+
+    out.push_str(rest);
+    (out, blocks)
+}
+
+fn check_prose(tree: &Node, findings: &mut Vec<Diagnostic>) {
+    walk(tree, &mut |node| match node {
+        Node::Paragraph(paragraph) => {
+            let Some(position) = &paragraph.position else {
+                return;
+            };
+            let mut text = String::new();
+            for child in &paragraph.children {
+                plain_text(child, &mut text);
+            }
+            for (line, words) in long_sentences(&text) {
+                findings.push(Diagnostic::new(
+                    position.start.line + line,
+                    "long-sentence",
+                    format!("Sentence has {words} words; consider splitting it"),
+                ));
+            }
+        }
+        Node::Text(text) => {
+            let Some(position) = &text.position else {
+                return;
+            };
+            for (line, word) in repeated_words(&text.value) {
+                findings.push(Diagnostic::new(
+                    position.start.line + line,
+                    "repeated-word",
+                    format!("Repeated word \"{word}\""),
+                ));
+            }
+        }
+        Node::Image(markdown::mdast::Image { alt, position, .. })
+        | Node::ImageReference(markdown::mdast::ImageReference { alt, position, .. })
+            if alt.trim().is_empty() =>
+        {
+            if let Some(position) = position {
+                findings.push(Diagnostic::new(
+                    position.start.line,
+                    "missing-alt-text",
+                    "Image has no alt text",
+                ));
+            }
+        }
+        _ => {}
+    });
+}
+
+fn check_markdown(tree: &Node, source: &str, findings: &mut Vec<Diagnostic>) {
+    let mut code_lines = Vec::new();
+    let mut links = Vec::new();
+    walk(tree, &mut |node| match node {
+        Node::Code(_) | Node::Html(_) | Node::Yaml(_) | Node::Toml(_) => {
+            if let Some(position) = node.position() {
+                code_lines.push(position.start.line..=position.end.line);
+            }
+        }
+        Node::Link(_) | Node::LinkReference(_) => {
+            if let Some(position) = node.position() {
+                links.push(position.start.offset..position.end.offset);
+            }
+        }
+        _ => {}
+    });
+
+    let mut previous_depth = None;
+    let mut headings: Vec<(String, usize)> = Vec::new();
+    walk(tree, &mut |node| match node {
+        Node::Heading(heading) => {
+            let Some(position) = &heading.position else {
+                return;
+            };
+            let line = position.start.line;
+            if let Some(previous) = previous_depth {
+                if heading.depth > previous + 1 {
+                    findings.push(Diagnostic::new(
+                        line,
+                        "heading-increment",
+                        format!("Heading level jumps from h{previous} to h{}", heading.depth),
+                    ));
+                }
+            }
+            previous_depth = Some(heading.depth);
+
+            let mut text = String::new();
+            for child in &heading.children {
+                plain_text(child, &mut text);
+            }
+            let text = text.trim().to_string();
+            match headings.iter().find(|(seen, _)| *seen == text) {
+                Some((_, first)) => findings.push(Diagnostic::new(
+                    line,
+                    "duplicate-heading",
+                    format!("Duplicate heading \"{text}\" (first on line {first})"),
+                )),
+                None => headings.push((text, line)),
+            }
+        }
+        // Literal autolinks are links whose source is just the URL
+        Node::Link(link) => {
+            let Some(position) = &link.position else {
+                return;
+            };
+            if !source[position.start.offset..].starts_with(['[', '<']) {
+                findings.push(Diagnostic::new(
+                    position.start.line,
+                    "bare-url",
+                    format!("Bare URL {}; use <{}> or a link", link.url, link.url),
+                ));
+            }
+        }
+        // Without autolink parsing, bare URLs stay text
+        Node::Text(text) => {
+            let Some(position) = &text.position else {
+                return;
+            };
+            if links
+                .iter()
+                .any(|link| link.contains(&position.start.offset))
+            {
+                return;
+            }
+            for (line, content) in text.value.split('\n').enumerate() {
+                if let Some(url) = content
+                    .split_whitespace()
+                    .find(|word| word.starts_with("http://") || word.starts_with("https://"))
+                {
+                    findings.push(Diagnostic::new(
+                        position.start.line + line,
+                        "bare-url",
+                        format!("Bare URL {url}; use <{url}> or a link"),
+                    ));
+                }
+            }
+        }
+        _ => {}
+    });
+
+    for (index, line) in source.lines().enumerate() {
+        let number = index + 1;
+        let trailing = line.len() - line.trim_end_matches([' ', '\t']).len();
+        // Exactly two spaces is a hard line break
+        let hard_break = trailing == 2 && line.ends_with("  ") && !line.trim().is_empty();
+        if trailing > 0 && !hard_break && !code_lines.iter().any(|lines| lines.contains(&number)) {
+            findings.push(Diagnostic::new(
+                number,
+                "trailing-spaces",
+                "Line ends with trailing whitespace",
+            ));
+        }
+    }
+}
+
+fn walk<'a>(node: &'a Node, visit: &mut dyn FnMut(&'a Node)) {
+    visit(node);
+    for child in node.children().into_iter().flatten() {
+        walk(child, visit);
+    }
+}
+
+/// The text a reader sees in inline content. Soft line breaks are kept, so
+/// lines can be counted.
+fn plain_text(node: &Node, out: &mut String) {
+    match node {
+        Node::Text(text) => out.push_str(&text.value),
+        Node::InlineCode(code) => out.push_str(&code.value),
+        Node::Break(_) => out.push('\n'),
+        _ => {
+            for child in node.children().into_iter().flatten() {
+                plain_text(child, out);
+            }
+        }
+    }
+}
+
+/// Sentences longer than [`MAX_SENTENCE_WORDS`], as (line offset of the
+/// sentence's start, word count).
+fn long_sentences(text: &str) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    let mut words = 0;
+    let mut start_line = 0;
+
+    for (line, content) in text.split('\n').enumerate() {
+        for word in content.split_whitespace() {
+            if words == 0 {
+                start_line = line;
+            }
+            words += 1;
+            let ends_sentence = word
+                .trim_end_matches(['"', '\'', ')', '*', '_', '\u{201d}', '\u{2019}'])
+                .ends_with(['.', '!', '?']);
+            if ends_sentence {
+                if words > MAX_SENTENCE_WORDS {
+                    found.push((start_line, words));
+                }
+                words = 0;
+            }
+        }
+    }
+    if words > MAX_SENTENCE_WORDS {
+        found.push((start_line, words));
+    }
+    found
+}
+
+/// Words written twice in a row ("the the"), as (line offset, word).
+/// Punctuation between the two breaks the pair, as in "Yes. Yes".
+fn repeated_words(text: &str) -> Vec<(usize, &str)> {
+    let mut found = Vec::new();
+    let mut previous: Option<&str> = None;
+
+    for (line, content) in text.split('\n').enumerate() {
+        for token in content.split_whitespace() {
+            let word = token.trim_matches(|c: char| !c.is_alphanumeric());
+            if word.is_empty() || !word.chars().any(char::is_alphabetic) {
+                previous = None;
+                continue;
+            }
+            if previous.is_some_and(|previous| previous.eq_ignore_ascii_case(word))
+                && token.starts_with(word)
+            {
+                found.push((line, word));
+            }
+            previous = token.ends_with(word).then_some(word);
+        }
+    }
+    found
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\n', "&#10;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::RenderPipeline;
+
+    fn prose_pipeline() -> RenderPipeline {
+        let mut pipeline = RenderPipeline::default();
+        pipeline.prepend(LintStage::new(
+            LintRules {
+                prose: true,
+                ..Default::default()
+            },
+            RenderOptions::default(),
+        ));
+        pipeline
+    }
+
+    #[test]
+    fn test_prose_checks_report_source_lines() {
+        let long: String = (0..40).map(|i| format!("w{i} ")).collect();
+        let content = format!(
+            "# Title\n\nFine here.\nThen the the\nsame.\n\n![](a.png)\n\n{long}end.\n\n- Yes. Yes, had had.\n"
+        );
+        let doc = prose_pipeline().render(&content, &RenderOptions::default());
+
+        let found: Vec<_> = doc
+            .diagnostics
+            .iter()
+            .map(|d| (d.line, d.rule.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (4, "repeated-word"),
+                (7, "missing-alt-text"),
+                (9, "long-sentence"),
+                (11, "repeated-word"),
+            ]
+        );
+        assert_eq!(doc.diagnostics[0].message, "Repeated word \"the\"");
+        assert!(doc.diagnostics[3].message.contains("\"had\""));
+    }
+
+    #[test]
+    fn test_findings_shown_as_panel_and_markers() {
+        let content = "# Title\n\nIntro.\nIt is is fine.\n\n![](a.png)\n";
+        let doc = prose_pipeline().render(content, &RenderOptions::default());
+
+        assert!(doc.html.starts_with("<details class=\"lint-panel\">"));
+        assert!(doc.html.contains("<summary>2 suggestions</summary>"));
+        assert!(doc
+            .html
+            .contains("<a href=\"#lint-line-3\">Line 4</a>: Repeated word &quot;is&quot;"));
+        assert!(doc.html.contains(
+            "<div class=\"lint-marker\" id=\"lint-line-3\" title=\"Line 4: Repeated word &quot;is&quot;\"></div>\n<p>Intro.\nIt is is fine.</p>"
+        ));
+        assert!(doc.html.contains("id=\"lint-line-6\""));
+
+        // A marker after a list leaves it tight
+        let doc = prose_pipeline().render("- a\n- b\n\nSo so.\n", &RenderOptions::default());
+        assert!(doc.html.starts_with("<details"));
+        assert!(doc.html.contains("<li>a</li>"));
+        assert!(!doc.html.contains(MARKER));
+
+        // Markers survive escaped HTML, and clean documents are untouched
+        let options = RenderOptions {
+            allow_dangerous_html: false,
+            ..Default::default()
+        };
+        let mut pipeline = RenderPipeline::default();
+        pipeline.prepend(LintStage::new(
+            LintRules {
+                prose: true,
+                ..Default::default()
+            },
+            options.clone(),
+        ));
+        let doc = pipeline.render(content, &options);
+        assert!(doc.html.contains("<div class=\"lint-marker\""));
+
+        let doc = prose_pipeline().render("# Title\n\nAll good.\n", &RenderOptions::default());
+        assert!(doc.diagnostics.is_empty());
+        assert_eq!(doc.html, "<h1 id=\"title\">Title</h1>\n<p>All good.</p>\n");
+    }
+
+    #[test]
+    fn test_markdown_rules() {
+        let content = "# Guide\n\n### Setup \n\nSee https://example.com and <https://ok.example>.  \nNext [link](https://x.example).\n\n```\ncode   \n```\n\n## Setup\n";
+        let mut pipeline = RenderPipeline::default();
+        pipeline.prepend(LintStage::new(
+            LintRules {
+                markdown: true,
+                ..Default::default()
+            },
+            RenderOptions::default(),
+        ));
+        let doc = pipeline.render(content, &RenderOptions::default());
+
+        let found: Vec<_> = doc
+            .diagnostics
+            .iter()
+            .map(|d| (d.line, d.rule.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (3, "heading-increment"),
+                (3, "trailing-spaces"),
+                (5, "bare-url"),
+                (12, "duplicate-heading"),
+            ]
+        );
+        assert_eq!(
+            doc.diagnostics[3].message,
+            "Duplicate heading \"Setup\" (first on line 3)"
+        );
+
+        // Bare URLs are still found when autolinks are off
+        let options = RenderOptions {
+            autolinks: false,
+            ..Default::default()
+        };
+        let mut pipeline = RenderPipeline::empty();
+        pipeline.prepend(LintStage::new(
+            LintRules {
+                markdown: true,
+                ..Default::default()
+            },
+            options.clone(),
+        ));
+        let doc = pipeline.render("Go to https://example.com now.\n", &options);
+        assert_eq!(doc.diagnostics.len(), 1);
+        assert_eq!(doc.diagnostics[0].rule, "bare-url");
+    }
+}
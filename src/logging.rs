@@ -0,0 +1,217 @@
+//! Log lines on stderr, for `-v` and `-q`.
+//!
+//! A minimal [`tracing`] subscriber: events at or above the chosen level are
+//! printed one per line, followed by the fields of the spans they happened
+//! in (for requests, the method and URI).
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{self, Write as _},
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span, Event, Level, Metadata, Subscriber,
+};
+
+/// Print log events at `max_level` and above to stderr. Only the first call
+/// takes effect.
+pub fn init_logging(max_level: LevelFilter) {
+    let _ =
+        tracing::subscriber::set_global_default(LineSubscriber::new(max_level, std::io::stderr()));
+}
+
+struct LineSubscriber {
+    max_level: LevelFilter,
+    out: Mutex<Box<dyn Write + Send>>,
+    spans: Mutex<HashMap<u64, SpanFields>>,
+    next_id: AtomicU64,
+}
+
+impl LineSubscriber {
+    fn new(max_level: LevelFilter, out: impl Write + Send + 'static) -> Self {
+        Self {
+            max_level,
+            out: Mutex::new(Box::new(out)),
+            spans: Mutex::default(),
+            next_id: AtomicU64::new(1),
+        }
+    }
+}
+
+/// A span's fields, formatted, and how many handles to it are open.
+struct SpanFields {
+    fields: String,
+    refs: usize,
+}
+
+thread_local! {
+    /// Spans entered on this thread, innermost last.
+    static CURRENT: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Subscriber for LineSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.max_level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.max_level)
+    }
+
+    fn new_span(&self, attributes: &span::Attributes<'_>) -> span::Id {
+        let mut fields = FieldWriter::default();
+        attributes.record(&mut fields);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.spans.lock().unwrap().insert(
+            id,
+            SpanFields {
+                fields: fields.fields,
+                refs: 1,
+            },
+        );
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            let mut fields = FieldWriter {
+                fields: std::mem::take(&mut span.fields),
+                ..Default::default()
+            };
+            values.record(&mut fields);
+            span.fields = fields.fields;
+        }
+    }
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut line = FieldWriter::default();
+        event.record(&mut line);
+
+        let spans = self.spans.lock().unwrap();
+        CURRENT.with(|current| {
+            for id in current.borrow().iter().rev() {
+                if let Some(span) = spans.get(id).filter(|span| !span.fields.is_empty()) {
+                    line.fields.push_str(&span.fields);
+                }
+            }
+        });
+        drop(spans);
+
+        let level = match *event.metadata().level() {
+            Level::ERROR => "ERROR",
+            Level::WARN => " WARN",
+            Level::INFO => " INFO",
+            Level::DEBUG => "DEBUG",
+            Level::TRACE => "TRACE",
+        };
+        let _ = writeln!(
+            self.out.lock().unwrap(),
+            "{level} {}{}",
+            line.message,
+            line.fields
+        );
+    }
+
+    fn enter(&self, span: &span::Id) {
+        CURRENT.with(|current| current.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &span::Id) {
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            if let Some(position) = current.iter().rposition(|id| *id == span.into_u64()) {
+                current.remove(position);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &span::Id) -> span::Id {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            span.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: span::Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let Some(fields) = spans.get_mut(&span.into_u64()) else {
+            return false;
+        };
+        fields.refs -= 1;
+        if fields.refs > 0 {
+            return false;
+        }
+        spans.remove(&span.into_u64());
+        true
+    }
+}
+
+/// Collects an event's message and ` key=value` pairs for its other fields.
+#[derive(Default)]
+struct FieldWriter {
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldWriter {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={value}", field.name());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            let _ = write!(self.fields, " {}={value:?}", field.name());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Output shared with the test after the subscriber took it.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_lines_carry_fields_of_the_enclosing_span() {
+        let buffer = Buffer::default();
+        let subscriber = LineSubscriber::new(LevelFilter::INFO, buffer.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", method = "GET", uri = "/a.md");
+            let _entered = span.enter();
+            tracing::info!(status = 404, "response");
+            tracing::debug!("too detailed");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output, " INFO response status=404 method=GET uri=/a.md\n");
+    }
+}
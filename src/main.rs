@@ -1,61 +1,781 @@
 use anyhow::Result;
-use clap::Parser;
-use std::path::PathBuf;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use std::{path::PathBuf, time::Duration};
+use tracing::level_filters::LevelFilter;
 
-mod app;
-
-use app::{scan_markdown_files, serve_markdown};
+use mdserve::{
+    apply_config, config_files, init_logging, parse_duration, read_markdown,
+    reuse_running_instance, serve_markdown, share_url, HighlightTheme, LintRules, LintStage,
+    MarkdownBackend, MathMode, Mdserve, RenderOptions, RenderPipeline, StaticFileOptions,
+    TlsCertificate, TunnelProvider, UploadOptions,
+};
 
 #[derive(Parser)]
 #[command(name = "mdserve")]
 #[command(about = "A simple HTTP server for markdown preview")]
 #[command(version)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+/// Options for serving, which settings files can give too.
+#[derive(clap::Args)]
 struct Args {
-    /// Path to markdown file or directory to serve
-    path: PathBuf,
+    /// Markdown files or directories to serve; paths after the first are served under a prefix named after their directory
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+
+    /// Read settings from FILE instead of ~/.config/mdserve/config.toml and ./mdserve.toml
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
 
     /// Hostname (domain or IP address) to listen on
     #[arg(short = 'H', long, default_value = "127.0.0.1")]
     hostname: String,
 
-    /// Port to serve on
+    /// Port to serve on (0 picks a free one)
     #[arg(short, long, default_value = "3000")]
     port: u16,
 
+    /// Ports after --port to try when it is taken (0 fails instead)
+    #[arg(long, value_name = "N", default_value = "9")]
+    auto_port: u16,
+
     /// Open the preview in the default browser
     #[arg(short, long)]
     open: bool,
+
+    /// Show a source editor next to the preview and allow saving edits to disk
+    #[arg(long)]
+    edit: bool,
+
+    /// Accept PUT /api/files/<name> to write markdown files (implied by --edit)
+    #[arg(long)]
+    allow_write: bool,
+
+    /// Folder, relative to the document, that editor uploads are saved in
+    #[arg(long, value_name = "DIR", default_value = "assets")]
+    upload_dir: String,
+
+    /// Largest file accepted as an editor upload, in megabytes
+    #[arg(long, value_name = "MB", default_value = "16")]
+    max_upload_mb: usize,
+
+    /// Flag long sentences, repeated words and images without alt text
+    #[arg(long)]
+    prose_lint: bool,
+
+    /// Flag skipped heading levels, trailing spaces, bare URLs and duplicate headings
+    #[arg(long)]
+    lint: bool,
+
+    /// Serve images through symlinks that point outside the served directory
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Additional file extensions to serve besides images and files documents reference (e.g. pdf,json,css)
+    #[arg(long, value_delimiter = ',', value_name = "EXT")]
+    static_ext: Vec<String>,
+
+    /// File extensions never to serve, even images or files documents reference (e.g. svg,zip)
+    #[arg(long, value_delimiter = ',', value_name = "EXT")]
+    deny_static_ext: Vec<String>,
+
+    /// Also serve markdown files in subdirectories, grouped by folder in the sidebar (directory mode only)
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Serve every non-hidden file in the directory (directory mode only)
+    #[arg(long)]
+    serve_all_static: bool,
+
+    /// Also serve static files from DIR, under /MOUNT/ (defaults to the folder's name); repeatable
+    #[arg(long, value_name = "DIR[:MOUNT]", value_parser = parse_assets_dir)]
+    assets_dir: Vec<(PathBuf, String)>,
+
+    /// Render pages with a MiniJinja template file instead of the built-in one (reloaded on change)
+    #[arg(long, value_name = "PATH", conflicts_with = "template_dir")]
+    template_file: Option<PathBuf>,
+
+    /// Render pages with the templates in DIR, starting from its main.html (reloaded on change)
+    #[arg(long, value_name = "DIR")]
+    template_dir: Option<PathBuf>,
+
+    /// Resolve images referenced by absolute paths under PREFIX (a leading `~` is your home directory); repeatable
+    #[arg(long, value_name = "PREFIX")]
+    allow_abs_images: Vec<PathBuf>,
+
+    /// Render `$…$` and `$$…$$` math; `server` converts it to MathML, which needs no JavaScript, `client` typesets it with the bundled KaTeX
+    #[arg(long, value_name = "MODE")]
+    math: Option<Math>,
+
+    /// Parser for markdown (`pulldown-cmark` needs the cargo feature of that name)
+    #[arg(long, value_name = "PARSER", default_value = "markdown")]
+    markdown_backend: Backend,
+
+    /// Colours for highlighted code blocks (`auto` follows the page theme)
+    #[arg(long, value_name = "THEME", default_value = "auto")]
+    highlight_theme: Theme,
+
+    /// Mermaid theme for diagrams (`auto` follows the page theme); a document's `mermaid` frontmatter overrides it
+    #[arg(long, value_name = "THEME", default_value = "auto")]
+    mermaid_theme: MermaidTheme,
+
+    /// Show an outline of the current document's headings in the sidebar
+    #[arg(long)]
+    toc: bool,
+
+    /// Label sidebar entries by file name or by document title (frontmatter `title`, else the first `#` heading)
+    #[arg(long, value_name = "LABEL", default_value = "filename")]
+    sidebar_labels: SidebarLabels,
+
+    /// Link `[[Page Name]]` and `[[Page Name|label]]` to the served document with a matching name
+    #[arg(long)]
+    wikilinks: bool,
+
+    /// Let editors scroll the preview to a source line (POST /api/sync or a ScrollTo message on /ws)
+    #[arg(long)]
+    scroll_sync: bool,
+
+    /// Copy the preview URL to the clipboard (the share link with --public)
+    #[arg(long)]
+    copy_url: bool,
+
+    /// Start a server even if one is already serving this path (by default its URL is printed, and opened with --open)
+    #[arg(long)]
+    new_instance: bool,
+
+    /// Also serve at a public HTTPS URL through a tunnel and print a share link for it (a random --token is required unless --auth or --token is given)
+    #[arg(
+        long,
+        value_name = "PROVIDER",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "cloudflared"
+    )]
+    public: Option<Public>,
+
+    /// Advertise the preview on the local network over mDNS/Bonjour (needs e.g. -H 0.0.0.0)
+    #[arg(long)]
+    mdns: bool,
+
+    /// Serve HTTPS, with a self-signed certificate unless --tls-cert and --tls-key are given
+    #[arg(long, conflicts_with_all = ["public", "mdns"])]
+    tls: bool,
+
+    /// PEM certificate chain to serve HTTPS with (implies --tls)
+    #[arg(long, value_name = "PATH", requires = "tls_key", conflicts_with_all = ["public", "mdns"])]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Ask for HTTP Basic credentials before serving anything but share links
+    #[arg(long, value_name = "USER:PASSWORD", value_parser = parse_credentials)]
+    auth: Option<(String, String)>,
+
+    /// Require SECRET, as ?token=SECRET or an `Authorization: Bearer` header, before serving anything but share links
+    #[arg(long, value_name = "SECRET")]
+    token: Option<String>,
+
+    /// Document served at / in directory mode (defaults to README.md, readme.md or index.md, then the alphabetically first)
+    #[arg(long, value_name = "FILE")]
+    index: Option<String>,
+
+    /// Redirect / to the index document's own URL instead of rendering it there
+    #[arg(long)]
+    index_redirect: bool,
+
+    /// Escape raw HTML in documents (same as --disable html)
+    #[arg(long)]
+    no_html: bool,
+
+    /// Plain CommonMark without GitHub extensions (same as --disable gfm)
+    #[arg(long)]
+    no_gfm: bool,
+
+    /// Show YAML/TOML frontmatter as a code block, or hide it (the default)
+    #[arg(long, value_name = "MODE")]
+    frontmatter: Option<FrontmatterMode>,
+
+    /// Markdown constructs to turn on (comma-separated)
+    #[arg(long, value_delimiter = ',', value_name = "CONSTRUCT")]
+    enable: Vec<Construct>,
+
+    /// Markdown constructs to turn off (comma-separated); wins over --enable
+    #[arg(long, value_delimiter = ',', value_name = "CONSTRUCT")]
+    disable: Vec<Construct>,
+
+    /// Keep at most N rendered documents in memory (least recently viewed are evicted)
+    #[arg(long, value_name = "N")]
+    max_cached_docs: Option<usize>,
+
+    /// Cap the memory used by rendered documents, in megabytes
+    #[arg(long, value_name = "MB")]
+    max_cache_mb: Option<usize>,
+
+    /// Render only the first MB megabytes of larger markdown files
+    #[arg(long, value_name = "MB", default_value = "16")]
+    max_file_mb: usize,
+
+    /// Serve at most N connections at once; further clients wait
+    #[arg(long, value_name = "N")]
+    max_connections: Option<usize>,
+
+    /// Close connections idle longer than this (e.g. 30s, 5m; 0 disables)
+    #[arg(long, value_name = "DURATION", default_value = "60s", value_parser = parse_duration)]
+    idle_timeout: Duration,
+
+    /// Send responses uncompressed even to browsers that accept gzip or brotli
+    #[arg(long)]
+    no_compression: bool,
+
+    /// Live-reload messages buffered per browser before it is sent a catch-up reload
+    #[arg(long, value_name = "N")]
+    ws_buffer: Option<usize>,
+
+    /// Log requests and file changes on stderr; -vv also raw watcher events and why files 404
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Log only errors (by default warnings are logged too)
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+/// Parser and compiler features that can be toggled from the command line.
+#[derive(Clone, Copy, ValueEnum)]
+enum Construct {
+    /// GitHub Flavored Markdown as a whole
+    Gfm,
+    /// GFM tables
+    Tables,
+    /// GFM strikethrough
+    Strikethrough,
+    /// GFM task list items
+    TaskLists,
+    /// GFM literal autolinks
+    Autolinks,
+    /// GFM footnotes
+    Footnotes,
+    /// Raw HTML passthrough
+    Html,
+    /// YAML/TOML frontmatter
+    Frontmatter,
+    /// Open http(s) links in a new tab, with an external link icon
+    ExternalLinks,
+    /// Syntax highlighting of fenced code blocks
+    Highlight,
+    /// GitHub alerts (> [!NOTE], > [!WARNING], ...) as callouts
+    Alerts,
+    /// Emoji shortcodes (:tada:, :rocket:, ...)
+    Emoji,
+    /// Definition lists (a term, then `: definition` lines)
+    DefinitionLists,
+}
+
+impl Construct {
+    fn apply(self, options: &mut RenderOptions, enabled: bool) {
+        let flag = match self {
+            Construct::Gfm => &mut options.gfm,
+            Construct::Tables => &mut options.tables,
+            Construct::Strikethrough => &mut options.strikethrough,
+            Construct::TaskLists => &mut options.task_lists,
+            Construct::Autolinks => &mut options.autolinks,
+            Construct::Footnotes => &mut options.footnotes,
+            Construct::Html => &mut options.allow_dangerous_html,
+            Construct::Frontmatter => &mut options.frontmatter,
+            Construct::ExternalLinks => &mut options.external_links,
+            Construct::Highlight => &mut options.highlight,
+            Construct::Alerts => &mut options.alerts,
+            Construct::Emoji => &mut options.emoji,
+            Construct::DefinitionLists => &mut options.definition_lists,
+        };
+        *flag = enabled;
+    }
+}
+
+/// What `--frontmatter` does with a document's frontmatter.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum FrontmatterMode {
+    /// As a code block at the top of the document
+    Show,
+    /// Not at all
+    Hide,
+}
+
+/// Where math is rendered.
+#[derive(Clone, Copy, ValueEnum)]
+enum Math {
+    /// As MathML, while rendering the page
+    Server,
+    /// In the browser, with the bundled KaTeX
+    Client,
+}
+
+impl Math {
+    fn mode(math: Option<Math>) -> MathMode {
+        match math {
+            Some(Math::Server) => MathMode::Server,
+            Some(Math::Client) => MathMode::Client,
+            None => MathMode::Off,
+        }
+    }
+}
+
+/// Markdown parsers for `--markdown-backend`.
+#[derive(Clone, Copy, ValueEnum)]
+enum Backend {
+    /// markdown-rs
+    Markdown,
+    /// pulldown-cmark
+    #[cfg(feature = "pulldown-cmark")]
+    PulldownCmark,
+}
+
+impl From<Backend> for MarkdownBackend {
+    fn from(backend: Backend) -> Self {
+        match backend {
+            Backend::Markdown => MarkdownBackend::Markdown,
+            #[cfg(feature = "pulldown-cmark")]
+            Backend::PulldownCmark => MarkdownBackend::PulldownCmark,
+        }
+    }
+}
+
+/// Code colour schemes for `--highlight-theme`.
+#[derive(Clone, Copy, ValueEnum)]
+enum Theme {
+    /// Match the page theme picked in the browser
+    Auto,
+    /// GitHub light or dark, following the page theme
+    Github,
+    /// Solarized light or dark, following the page theme
+    Solarized,
+    GithubLight,
+    GithubDark,
+    Monokai,
+    SolarizedLight,
+    SolarizedDark,
+}
+
+impl From<Theme> for HighlightTheme {
+    fn from(theme: Theme) -> Self {
+        match theme {
+            Theme::Auto => HighlightTheme::Auto,
+            Theme::Github => HighlightTheme::Github,
+            Theme::Solarized => HighlightTheme::Solarized,
+            Theme::GithubLight => HighlightTheme::GithubLight,
+            Theme::GithubDark => HighlightTheme::GithubDark,
+            Theme::Monokai => HighlightTheme::Monokai,
+            Theme::SolarizedLight => HighlightTheme::SolarizedLight,
+            Theme::SolarizedDark => HighlightTheme::SolarizedDark,
+        }
+    }
+}
+
+/// Mermaid's themes, for `--mermaid-theme`.
+#[derive(Clone, Copy, ValueEnum)]
+enum MermaidTheme {
+    /// `dark` with a dark page theme, `default` otherwise
+    Auto,
+    Default,
+    Neutral,
+    Dark,
+    Forest,
+    Base,
+}
+
+impl MermaidTheme {
+    /// The theme's name in Mermaid, `None` for `auto`.
+    fn name(self) -> Option<&'static str> {
+        match self {
+            MermaidTheme::Auto => None,
+            MermaidTheme::Default => Some("default"),
+            MermaidTheme::Neutral => Some("neutral"),
+            MermaidTheme::Dark => Some("dark"),
+            MermaidTheme::Forest => Some("forest"),
+            MermaidTheme::Base => Some("base"),
+        }
+    }
+}
+
+/// What sidebar entries are labelled with, for `--sidebar-labels`.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SidebarLabels {
+    Filename,
+    /// The document's title, or its file name if it has none
+    Title,
+}
+
+/// Tunnel services for `--public`.
+#[derive(Clone, Copy, ValueEnum)]
+enum Public {
+    /// Cloudflare quick tunnel; needs `cloudflared` installed
+    Cloudflared,
+    /// localhost.run over `ssh`
+    LocalhostRun,
+}
+
+impl From<Public> for TunnelProvider {
+    fn from(public: Public) -> Self {
+        match public {
+            Public::Cloudflared => TunnelProvider::Cloudflared,
+            Public::LocalhostRun => TunnelProvider::LocalhostRun,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print a time-limited link to one document on a running server
+    Share {
+        /// Markdown file to share
+        file: PathBuf,
+
+        /// How long the link stays valid (e.g. 30m, 24h, 7d)
+        #[arg(short, long, default_value = "24h", value_parser = parse_duration)]
+        expires: Duration,
+
+        /// Hostname the server is reachable at
+        #[arg(short = 'H', long, default_value = "127.0.0.1")]
+        hostname: String,
+
+        /// Port the server is running on
+        #[arg(short, long, default_value = "3000")]
+        port: u16,
+
+        /// The server serves HTTPS (started with --tls or --tls-cert)
+        #[arg(long)]
+        tls: bool,
+
+        /// The server's access token, if it was started with one
+        #[arg(long, value_name = "TOKEN")]
+        token: Option<String>,
+    },
+    /// Print a document's HTML; exits non-zero if linting finds problems
+    Render {
+        /// Markdown file to render
+        file: PathBuf,
+
+        /// Check markdown style (see the server's --lint)
+        #[arg(long)]
+        lint: bool,
+
+        /// Check prose (see the server's --prose-lint)
+        #[arg(long)]
+        prose_lint: bool,
+
+        /// Render math (see the server's --math)
+        #[arg(long, value_name = "MODE")]
+        math: Option<Math>,
+
+        /// Parser for markdown (see the server's --markdown-backend)
+        #[arg(long, value_name = "PARSER", default_value = "markdown")]
+        markdown_backend: Backend,
+    },
+    /// Write a file or directory as a static HTML site; exits non-zero if linting finds problems
+    Export {
+        /// Markdown file or directory to export
+        path: PathBuf,
+
+        /// Directory the site is written to
+        #[arg(short, long, value_name = "DIR", default_value = "site")]
+        out: PathBuf,
+
+        /// Include markdown files in subdirectories (see the server's --recursive)
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Render math (see the server's --math)
+        #[arg(long, value_name = "MODE")]
+        math: Option<Math>,
+
+        /// Colours for highlighted code blocks (see the server's --highlight-theme)
+        #[arg(long, value_name = "THEME", default_value = "auto")]
+        highlight_theme: Theme,
+
+        /// Mermaid theme for diagrams (see the server's --mermaid-theme)
+        #[arg(long, value_name = "THEME", default_value = "auto")]
+        mermaid_theme: MermaidTheme,
+
+        /// Outline each page's headings (see the server's --toc)
+        #[arg(long)]
+        toc: bool,
+
+        /// Label sidebar entries by file name or title (see the server's --sidebar-labels)
+        #[arg(long, value_name = "LABEL", default_value = "filename")]
+        sidebar_labels: SidebarLabels,
+
+        /// Link `[[Page Name]]` to matching documents (see the server's --wikilinks)
+        #[arg(long)]
+        wikilinks: bool,
+
+        /// Render pages with a MiniJinja template file or directory (see the server's --template-dir)
+        #[arg(long, value_name = "PATH")]
+        template: Option<PathBuf>,
+
+        /// Check markdown style (see the server's --lint)
+        #[arg(long)]
+        lint: bool,
+
+        /// Check prose (see the server's --prose-lint)
+        #[arg(long)]
+        prose_lint: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
-    let absolute_path = args.path.canonicalize().unwrap_or(args.path);
-
-    let (base_dir, tracked_files, is_directory_mode) = if absolute_path.is_file() {
-        // Single-file mode: derive parent directory
-        let base_dir = absolute_path
-            .parent()
-            .unwrap_or_else(|| std::path::Path::new("."))
-            .to_path_buf();
-        let tracked_files = vec![absolute_path];
-        (base_dir, tracked_files, false)
-    } else if absolute_path.is_dir() {
-        // Directory mode: scan directory for markdown files
-        let tracked_files = scan_markdown_files(&absolute_path)?;
-        if tracked_files.is_empty() {
-            anyhow::bail!("No markdown files found in directory");
+    let Cli { command, args } = parse_args()?;
+
+    match command {
+        Some(Command::Share {
+            file,
+            expires,
+            hostname,
+            port,
+            tls,
+            token,
+        }) => {
+            println!(
+                "{}",
+                share_url(&file, expires, &hostname, port, tls, token.as_deref()).await?
+            );
+            return Ok(());
+        }
+        Some(Command::Render {
+            file,
+            lint,
+            prose_lint,
+            math,
+            markdown_backend,
+        }) => {
+            let mut rules = LintRules::default();
+            rules.markdown = lint;
+            rules.prose = prose_lint;
+            let mut options = RenderOptions::default();
+            options.math = Math::mode(math);
+            options.backend = markdown_backend.into();
+            if !render(&file, rules, options)? {
+                std::process::exit(1);
+            }
+            return Ok(());
         }
-        (absolute_path, tracked_files, true)
+        Some(Command::Export {
+            path,
+            out,
+            recursive,
+            math,
+            highlight_theme,
+            mermaid_theme,
+            toc,
+            sidebar_labels,
+            wikilinks,
+            template,
+            lint,
+            prose_lint,
+        }) => {
+            let mut builder = if path.is_dir() {
+                Mdserve::builder().base_dir(path).recursive(recursive)
+            } else {
+                Mdserve::builder().file(path)
+            };
+            if let Some(template) = template {
+                builder = builder.template_path(template);
+            }
+            if let Some(theme) = mermaid_theme.name() {
+                builder = builder.mermaid_theme(theme);
+            }
+            let mut options = RenderOptions::default();
+            options.math = Math::mode(math);
+            let summary = builder
+                .render_options(options)
+                .highlight_theme(highlight_theme.into())
+                .toc(toc)
+                .sidebar_titles(sidebar_labels == SidebarLabels::Title)
+                .wikilinks(wikilinks)
+                .lint(lint)
+                .prose_lint(prose_lint)
+                .export(&out)
+                .await?;
+            println!(
+                "📦 Exported {} pages and {} files to {}",
+                summary.pages,
+                summary.files,
+                out.display()
+            );
+            for (name, diagnostic) in &summary.diagnostics {
+                eprintln!(
+                    "{name}:{}: {} [{}]",
+                    diagnostic.line, diagnostic.message, diagnostic.rule
+                );
+            }
+            if !summary.diagnostics.is_empty() {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+
+    init_logging(match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::WARN,
+        (false, 1) => LevelFilter::INFO,
+        (false, 2) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    });
+
+    let mut paths = args
+        .paths
+        .into_iter()
+        .map(|path| path.canonicalize().unwrap_or(path));
+    let absolute_path = paths
+        .next()
+        .expect("clap requires a path without a subcommand");
+    let extra_paths: Vec<PathBuf> = paths.collect();
+
+    let tls = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsCertificate::Files { cert, key }),
+        _ => args.tls.then_some(TlsCertificate::SelfSigned),
+    };
+
+    let served: Vec<PathBuf> = std::iter::once(absolute_path.clone())
+        .chain(extra_paths.iter().cloned())
+        .collect();
+    // Port 0 is never one a running instance was given, and a running
+    // instance speaks plain HTTP to us
+    if !args.new_instance
+        && args.port != 0
+        && tls.is_none()
+        && reuse_running_instance(&served, &args.hostname, args.port, args.open, args.copy_url)
+            .await?
+    {
+        return Ok(());
+    }
+
+    let is_directory_mode = absolute_path.is_dir();
+    if args.serve_all_static && !is_directory_mode {
+        anyhow::bail!("--serve-all-static is only supported in directory mode");
+    }
+    if args.recursive && !is_directory_mode && !extra_paths.iter().any(|path| path.is_dir()) {
+        anyhow::bail!("--recursive is only supported in directory mode");
+    }
+    if (args.index.is_some() || args.index_redirect) && !is_directory_mode && extra_paths.is_empty()
+    {
+        anyhow::bail!("--index and --index-redirect are only supported in directory mode");
+    }
+    if args.public.is_some() && (args.edit || args.allow_write) {
+        anyhow::bail!("--public cannot be combined with --edit or --allow-write");
+    }
+
+    let static_options = StaticFileOptions {
+        follow_symlinks: args.follow_symlinks,
+        extensions: normalize_extensions(&args.static_ext),
+        serve_all: args.serve_all_static,
+        deny_extensions: normalize_extensions(&args.deny_static_ext),
+    };
+
+    let mut render_options = RenderOptions::default();
+    render_options.math = Math::mode(args.math);
+    render_options.backend = args.markdown_backend.into();
+    for construct in &args.enable {
+        construct.apply(&mut render_options, true);
+    }
+    for construct in &args.disable {
+        construct.apply(&mut render_options, false);
+    }
+    if args.no_html {
+        render_options.allow_dangerous_html = false;
+    }
+    if args.no_gfm {
+        render_options.gfm = false;
+    }
+    render_options.show_frontmatter = args.frontmatter == Some(FrontmatterMode::Show);
+
+    let mut builder = if absolute_path.is_file() {
+        // Single-file mode: base directory is the file's parent
+        Mdserve::builder().file(absolute_path)
+    } else if is_directory_mode {
+        // Directory mode: track every markdown file in the directory
+        Mdserve::builder().base_dir(absolute_path)
     } else {
         anyhow::bail!("Path must be a file or directory");
     };
+    // Further paths are mounted next to the first, each watched on its own
+    builder = builder.recursive(args.recursive).extra_paths(extra_paths);
+
+    if let Some(max) = args.max_cached_docs {
+        builder = builder.max_cached_documents(max);
+    }
+    if let Some(mb) = args.max_cache_mb {
+        builder = builder.max_cache_bytes(mb.saturating_mul(1024 * 1024));
+    }
+    builder = builder.max_file_size(args.max_file_mb.saturating_mul(1024 * 1024));
+    if let Some(max) = args.max_connections {
+        builder = builder.max_connections(max);
+    }
+    builder = builder.idle_timeout((!args.idle_timeout.is_zero()).then_some(args.idle_timeout));
+    builder = builder.compression(!args.no_compression);
+    if let Some(capacity) = args.ws_buffer {
+        builder = builder.message_buffer(capacity);
+    }
+    if let Some(index) = args.index {
+        builder = builder.index(index);
+    }
+    for (dir, mount) in args.assets_dir {
+        builder = builder.asset_dir(dir, mount);
+    }
+    if let Some(theme) = args.mermaid_theme.name() {
+        builder = builder.mermaid_theme(theme);
+    }
+    if let Some(template) = args.template_file.or(args.template_dir) {
+        builder = builder.template_path(template);
+    }
+    for prefix in args.allow_abs_images {
+        builder = builder.allow_absolute_images(expand_home(prefix)?);
+    }
+    if let Some(provider) = args.public {
+        builder = builder.public_tunnel(provider.into());
+    }
+    if let Some(certificate) = tls {
+        builder = builder.tls(certificate);
+    }
+    if let Some((username, password)) = args.auth {
+        builder = builder.basic_auth(username, password);
+    }
+    if let Some(token) = args.token {
+        builder = builder.access_token(token);
+    }
 
-    // Single unified serve function
     serve_markdown(
-        base_dir,
-        tracked_files,
-        is_directory_mode,
+        builder
+            .static_files(static_options)
+            .render_options(render_options)
+            .highlight_theme(args.highlight_theme.into())
+            .toc(args.toc)
+            .sidebar_titles(args.sidebar_labels == SidebarLabels::Title)
+            .wikilinks(args.wikilinks)
+            .scroll_sync(args.scroll_sync)
+            .prose_lint(args.prose_lint)
+            .lint(args.lint)
+            .redirect_index(args.index_redirect)
+            .auto_port(args.auto_port)
+            .mdns(args.mdns)
+            .copy_url(args.copy_url)
+            .edit(args.edit)
+            .allow_write(args.allow_write)
+            .uploads(UploadOptions {
+                dir: args.upload_dir,
+                max_size: args.max_upload_mb.saturating_mul(1024 * 1024),
+            }),
         args.hostname,
         args.port,
         args.open,
@@ -64,3 +784,93 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Command-line arguments, after the flags from config files. Subcommands
+/// don't read config files.
+fn parse_args() -> Result<Cli> {
+    let command = Cli::command();
+    let matches = command.clone().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    if cli.command.is_some() {
+        return Ok(cli);
+    }
+
+    let files = config_files(cli.args.config.as_deref());
+    apply_config(&mut cli.args, &command, &matches, &files)?;
+    // Requirements between flags aren't checked across settings files
+    if cli.args.tls_cert.is_some() != cli.args.tls_key.is_some() {
+        anyhow::bail!("--tls-cert and --tls-key must be given together");
+    }
+    Ok(cli)
+}
+
+/// Extensions from `--static-ext`-style lists, without dots or blanks.
+fn normalize_extensions(extensions: &[String]) -> Vec<String> {
+    extensions
+        .iter()
+        .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect()
+}
+
+/// Split `--assets-dir` into the directory and its mount. A colon followed
+/// by a path separator is part of a Windows path, not a mount.
+fn parse_assets_dir(value: &str) -> Result<(PathBuf, String), String> {
+    let (dir, mount) = match value.rsplit_once(':') {
+        Some((dir, mount)) if !dir.is_empty() && !mount.contains(['/', '\\']) => {
+            (PathBuf::from(dir), Some(mount.to_string()))
+        }
+        _ => (PathBuf::from(value), None),
+    };
+    let mount = match mount {
+        Some(mount) => mount,
+        None => dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| format!("{value} has no folder name to mount it under"))?,
+    };
+    Ok((dir, mount))
+}
+
+/// Split `--auth` input into user name and password at the first `:`.
+fn parse_credentials(value: &str) -> Result<(String, String), String> {
+    match value.split_once(':') {
+        Some((username, password)) if !username.is_empty() && !password.is_empty() => {
+            Ok((username.to_string(), password.to_string()))
+        }
+        _ => Err("expected USER:PASSWORD".to_string()),
+    }
+}
+
+/// `path` with a leading `~` replaced by the home directory.
+fn expand_home(path: PathBuf) -> Result<PathBuf> {
+    let Ok(rest) = path.strip_prefix("~") else {
+        return Ok(path);
+    };
+    let home = std::env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" })
+        .ok_or_else(|| anyhow::anyhow!("could not determine the home directory"))?;
+    Ok(PathBuf::from(home).join(rest))
+}
+
+/// Print `file` as HTML and its lint findings to stderr. Returns whether
+/// the document is free of findings.
+fn render(file: &std::path::Path, rules: LintRules, options: RenderOptions) -> Result<bool> {
+    let content = read_markdown(file)?;
+    let mut pipeline = RenderPipeline::default();
+    if rules.any() {
+        pipeline.prepend(LintStage::new(rules, options.clone()));
+    }
+
+    let document = pipeline.render(&content, &options);
+    println!("{}", document.html);
+    for diagnostic in &document.diagnostics {
+        eprintln!(
+            "{}:{}: {} [{}]",
+            file.display(),
+            diagnostic.line,
+            diagnostic.message,
+            diagnostic.rule
+        );
+    }
+    Ok(document.diagnostics.is_empty())
+}
@@ -0,0 +1,757 @@
+//! Server-side math: TeX from `$…$` and `$$…$$` turned into MathML, which
+//! browsers lay out natively, so math prints and works without JavaScript.
+//!
+//! This covers the notation common in notes and READMEs (scripts,
+//! fractions, roots, Greek letters, operators and relations, accents, font
+//! commands, `\left`/`\right` and matrix-like environments). Anything else
+//! is shown as an error node holding the unknown command.
+
+/// Turn the math elements of parser output into MathML: inline
+/// `<code class="language-math math-inline">` and display
+/// `<pre><code class="language-math …">` blocks.
+pub(crate) fn render_math(html: &str) -> String {
+    const INLINE: &str = "<code class=\"language-math math-inline\">";
+    const DISPLAY: &str = "<pre><code class=\"language-math";
+
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    loop {
+        let inline = rest.find(INLINE);
+        let display = rest.find(DISPLAY);
+        let (start, display) = match (inline, display) {
+            (Some(i), Some(d)) if d < i => (d, true),
+            (Some(i), _) => (i, false),
+            (None, Some(d)) => (d, true),
+            (None, None) => break,
+        };
+
+        let (open_end, close) = if display {
+            let Some(tag_end) = rest[start + DISPLAY.len()..].find('>') else {
+                break;
+            };
+            (start + DISPLAY.len() + tag_end + 1, "</code></pre>")
+        } else {
+            (start + INLINE.len(), "</code>")
+        };
+        let Some(len) = rest[open_end..].find(close) else {
+            break;
+        };
+
+        out.push_str(&rest[..start]);
+        out.push_str(&tex_to_mathml(
+            &unescape_html(&rest[open_end..open_end + len]),
+            display,
+        ));
+        rest = &rest[open_end + len + close.len()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Convert one formula to a `<math>` element, keeping the TeX as an
+/// annotation so it can still be copied.
+pub(crate) fn tex_to_mathml(tex: &str, display: bool) -> String {
+    let mut parser = Parser {
+        tokens: tokenize(tex),
+        pos: 0,
+        display,
+    };
+    let mut body = String::new();
+    // Stray closing braces would otherwise end the formula early
+    loop {
+        body.push_str(&parser.row(|_| false));
+        if parser.next().is_none() {
+            break;
+        }
+    }
+
+    format!(
+        "<math xmlns=\"http://www.w3.org/1998/Math/MathML\" display=\"{}\"><semantics><mrow>{body}</mrow><annotation encoding=\"application/x-tex\">{}</annotation></semantics></math>",
+        if display { "block" } else { "inline" },
+        escape(tex.trim())
+    )
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Command(String),
+    Char(char),
+    /// Raw text argument of `\text{…}` and friends.
+    Text(String),
+    Open,
+    Close,
+    Sup,
+    Sub,
+    Align,
+    NewRow,
+}
+
+fn tokenize(tex: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = tex.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\\') => tokens.push(Token::NewRow),
+                Some(c) if c.is_ascii_alphabetic() => {
+                    let mut name = c.to_string();
+                    while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphabetic()) {
+                        name.push(c);
+                        chars.next();
+                    }
+                    if matches!(
+                        name.as_str(),
+                        "text" | "textrm" | "mbox" | "textit" | "textbf"
+                    ) {
+                        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                        if chars.next_if_eq(&'{').is_some() {
+                            let mut text = String::new();
+                            let mut depth = 0;
+                            for c in chars.by_ref() {
+                                match c {
+                                    '{' => depth += 1,
+                                    '}' if depth == 0 => break,
+                                    '}' => depth -= 1,
+                                    _ => {}
+                                }
+                                text.push(c);
+                            }
+                            tokens.push(Token::Text(text));
+                            continue;
+                        }
+                    }
+                    tokens.push(Token::Command(name));
+                }
+                Some(c) => tokens.push(Token::Command(c.to_string())),
+                None => {}
+            },
+            '{' => tokens.push(Token::Open),
+            '}' => tokens.push(Token::Close),
+            '^' => tokens.push(Token::Sup),
+            '_' => tokens.push(Token::Sub),
+            '&' => tokens.push(Token::Align),
+            '%' => while chars.next_if(|&c| c != '\n').is_some() {},
+            c if c.is_whitespace() => {}
+            c => tokens.push(Token::Char(c)),
+        }
+    }
+    tokens
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    display: bool,
+}
+
+/// A parsed node and whether it takes limits above and below in display
+/// style (`\sum`, `\lim`, …).
+struct Atom {
+    mathml: String,
+    limits: bool,
+}
+
+impl Atom {
+    fn new(mathml: impl Into<String>) -> Self {
+        Self {
+            mathml: mathml.into(),
+            limits: false,
+        }
+    }
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Parse atoms until `}` (left unconsumed), the end, or a token for
+    /// which `stop` holds.
+    fn row(&mut self, stop: fn(&Token) -> bool) -> String {
+        let mut out = String::new();
+        while let Some(token) = self.peek() {
+            if *token == Token::Close || stop(token) {
+                break;
+            }
+            out.push_str(&self.scripted());
+        }
+        out
+    }
+
+    /// An atom with any sub- and superscripts.
+    fn scripted(&mut self) -> String {
+        let base = match self.peek() {
+            Some(Token::Sup | Token::Sub) => Atom::new("<mrow></mrow>"),
+            _ => self.atom(),
+        };
+
+        let mut sub = None;
+        let mut sup = None;
+        loop {
+            match self.peek() {
+                Some(Token::Sub) if sub.is_none() => {
+                    self.pos += 1;
+                    sub = Some(self.argument());
+                }
+                Some(Token::Sup) if sup.is_none() => {
+                    self.pos += 1;
+                    sup = Some(self.argument());
+                }
+                Some(Token::Char('\'')) if sup.is_none() => {
+                    let mut primes = String::new();
+                    while self.peek() == Some(&Token::Char('\'')) {
+                        self.pos += 1;
+                        primes.push('′');
+                    }
+                    sup = Some(format!("<mo>{primes}</mo>"));
+                }
+                _ => break,
+            }
+        }
+
+        let under = base.limits && self.display;
+        let base = base.mathml;
+        match (sub, sup) {
+            (None, None) => base,
+            (Some(sub), None) if under => format!("<munder>{base}{sub}</munder>"),
+            (Some(sub), None) => format!("<msub>{base}{sub}</msub>"),
+            (None, Some(sup)) if under => format!("<mover>{base}{sup}</mover>"),
+            (None, Some(sup)) => format!("<msup>{base}{sup}</msup>"),
+            (Some(sub), Some(sup)) if under => {
+                format!("<munderover>{base}{sub}{sup}</munderover>")
+            }
+            (Some(sub), Some(sup)) => format!("<msubsup>{base}{sub}{sup}</msubsup>"),
+        }
+    }
+
+    /// A command's argument: a braced group or a single atom.
+    fn argument(&mut self) -> String {
+        match self.peek() {
+            Some(Token::Open) => self.atom().mathml,
+            Some(Token::Close) | None => "<mrow></mrow>".to_string(),
+            _ => self.atom().mathml,
+        }
+    }
+
+    /// The plain characters of a braced argument, if it holds nothing else.
+    fn plain_argument(&mut self) -> Option<String> {
+        let start = self.pos;
+        let mut text = String::new();
+        match self.next() {
+            Some(Token::Open) => loop {
+                match self.next() {
+                    Some(Token::Char(c)) if c.is_alphanumeric() => text.push(c),
+                    Some(Token::Close) => return Some(text),
+                    _ => break,
+                }
+            },
+            Some(Token::Char(c)) if c.is_alphanumeric() => return Some(c.to_string()),
+            _ => {}
+        }
+        self.pos = start;
+        None
+    }
+
+    fn atom(&mut self) -> Atom {
+        match self.next() {
+            None => Atom::new("<mrow></mrow>"),
+            Some(Token::Open) => {
+                let inner = self.row(|_| false);
+                self.pos += 1;
+                Atom::new(format!("<mrow>{inner}</mrow>"))
+            }
+            Some(Token::Char(c)) if c.is_ascii_digit() || c == '.' => {
+                let mut number = c.to_string();
+                while let Some(Token::Char(c)) = self.peek() {
+                    if !(c.is_ascii_digit() || *c == '.') {
+                        break;
+                    }
+                    number.push(*c);
+                    self.pos += 1;
+                }
+                Atom::new(format!("<mn>{number}</mn>"))
+            }
+            Some(Token::Char(c)) if c.is_alphabetic() => Atom::new(format!("<mi>{c}</mi>")),
+            Some(Token::Char(c)) => {
+                let op = match c {
+                    '-' => "−".to_string(),
+                    '*' => "∗".to_string(),
+                    c => escape(&c.to_string()),
+                };
+                Atom::new(format!("<mo>{op}</mo>"))
+            }
+            Some(Token::Text(text)) => Atom::new(format!("<mtext>{}</mtext>", escape(&text))),
+            Some(Token::Command(name)) => self.command(&name),
+            Some(Token::Sup | Token::Sub | Token::Align | Token::NewRow | Token::Close) => {
+                Atom::new("<mrow></mrow>")
+            }
+        }
+    }
+
+    fn command(&mut self, name: &str) -> Atom {
+        if let Some(letter) = greek(name) {
+            let variant = if letter.chars().all(char::is_uppercase) {
+                " mathvariant=\"normal\""
+            } else {
+                ""
+            };
+            return Atom::new(format!("<mi{variant}>{letter}</mi>"));
+        }
+        if let Some(symbol) = identifier_symbol(name) {
+            return Atom::new(format!("<mi>{symbol}</mi>"));
+        }
+        if let Some(op) = operator(name) {
+            return Atom::new(format!("<mo>{}</mo>", escape(op)));
+        }
+        if let Some(op) = large_operator(name) {
+            return Atom {
+                mathml: format!("<mo largeop=\"true\" movablelimits=\"true\">{op}</mo>"),
+                limits: !op.starts_with('∫') && !op.starts_with('∮') && !op.starts_with('∬'),
+            };
+        }
+        if FUNCTIONS.contains(&name) {
+            return Atom {
+                mathml: format!("<mi>{name}</mi>"),
+                limits: LIMIT_FUNCTIONS.contains(&name),
+            };
+        }
+        if let Some(width) = space(name) {
+            return Atom::new(format!("<mspace width=\"{width}\"/>"));
+        }
+        if let Some(accent) = accent(name) {
+            let base = self.argument();
+            return Atom::new(format!(
+                "<mover accent=\"true\">{base}<mo>{accent}</mo></mover>"
+            ));
+        }
+
+        match name {
+            "frac" | "dfrac" | "tfrac" => {
+                let numerator = self.argument();
+                let denominator = self.argument();
+                Atom::new(format!("<mfrac>{numerator}{denominator}</mfrac>"))
+            }
+            "binom" => {
+                let top = self.argument();
+                let bottom = self.argument();
+                Atom::new(format!(
+                    "<mrow><mo>(</mo><mfrac linethickness=\"0\">{top}{bottom}</mfrac><mo>)</mo></mrow>"
+                ))
+            }
+            "sqrt" => {
+                if self.peek() == Some(&Token::Char('[')) {
+                    self.pos += 1;
+                    let index = self.row(|token| *token == Token::Char(']'));
+                    self.pos += 1;
+                    let radicand = self.argument();
+                    Atom::new(format!("<mroot>{radicand}<mrow>{index}</mrow></mroot>"))
+                } else {
+                    Atom::new(format!("<msqrt>{}</msqrt>", self.argument()))
+                }
+            }
+            "underline" => Atom::new(format!(
+                "<munder accentunder=\"true\">{}<mo>_</mo></munder>",
+                self.argument()
+            )),
+            "mathrm" | "operatorname" | "mathup" => match self.plain_argument() {
+                Some(text) => Atom::new(format!("<mi mathvariant=\"normal\">{text}</mi>")),
+                None => Atom::new(self.argument()),
+            },
+            "mathbb" | "mathbf" | "boldsymbol" | "mathcal" => match self.plain_argument() {
+                Some(text) => {
+                    let styled: String = text.chars().map(|c| styled_letter(name, c)).collect();
+                    Atom::new(format!("<mi>{styled}</mi>"))
+                }
+                None => Atom::new(self.argument()),
+            },
+            "mathit" | "displaystyle" | "textstyle" | "limits" | "nolimits" => {
+                Atom::new("<mrow></mrow>")
+            }
+            "left" => {
+                let open = self.delimiter();
+                let inner = self.row(|token| *token == Token::Command("right".into()));
+                let close = if self.next().is_some() {
+                    self.delimiter()
+                } else {
+                    String::new()
+                };
+                Atom::new(format!("<mrow>{open}{inner}{close}</mrow>"))
+            }
+            "begin" => self.environment(),
+            _ => Atom::new(format!(
+                "<merror><mtext>\\{}</mtext></merror>",
+                escape(name)
+            )),
+        }
+    }
+
+    /// The delimiter after `\left` or `\right`; `.` is none.
+    fn delimiter(&mut self) -> String {
+        let symbol = match self.next() {
+            Some(Token::Char('.')) | None => return String::new(),
+            Some(Token::Char(c)) => escape(&c.to_string()),
+            Some(Token::Command(name)) => match operator(&name) {
+                Some(op) => escape(op),
+                None => return String::new(),
+            },
+            _ => return String::new(),
+        };
+        format!("<mo fence=\"true\" stretchy=\"true\">{symbol}</mo>")
+    }
+
+    /// `\begin{name} … \end{name}` as a table, with the environment's
+    /// fences.
+    fn environment(&mut self) -> Atom {
+        let name = self.plain_argument().unwrap_or_default();
+        let is_end = |token: &Token| *token == Token::Command("end".into());
+
+        let mut rows = Vec::new();
+        let mut cells = Vec::new();
+        loop {
+            cells.push(self.row(|token| {
+                matches!(token, Token::Align | Token::NewRow)
+                    || *token == Token::Command("end".into())
+            }));
+            match self.next() {
+                Some(Token::Align) => {}
+                Some(Token::NewRow) => rows.push(std::mem::take(&mut cells)),
+                Some(token) if is_end(&token) => {
+                    self.plain_argument();
+                    break;
+                }
+                // A stray closing brace, or the end of the formula
+                Some(_) => {}
+                None => break,
+            }
+        }
+        if !(cells.len() == 1 && cells[0].is_empty()) {
+            rows.push(cells);
+        }
+
+        let (open, close, align) = match name.as_str() {
+            "pmatrix" => ("(", ")", None),
+            "bmatrix" => ("[", "]", None),
+            "Bmatrix" => ("{", "}", None),
+            "vmatrix" => ("|", "|", None),
+            "Vmatrix" => ("‖", "‖", None),
+            "cases" => ("{", "", Some("left left")),
+            "aligned" | "align" | "split" => ("", "", Some("right left")),
+            _ => ("", "", None),
+        };
+
+        let mut table = match align {
+            Some(align) => format!("<mtable columnalign=\"{align}\">"),
+            None => "<mtable>".to_string(),
+        };
+        for row in rows {
+            table.push_str("<mtr>");
+            for cell in row {
+                table.push_str(&format!("<mtd>{cell}</mtd>"));
+            }
+            table.push_str("</mtr>");
+        }
+        table.push_str("</mtable>");
+
+        let fence = |symbol: &str| {
+            if symbol.is_empty() {
+                String::new()
+            } else {
+                format!("<mo fence=\"true\" stretchy=\"true\">{symbol}</mo>")
+            }
+        };
+        Atom::new(format!(
+            "<mrow>{}{table}{}</mrow>",
+            fence(open),
+            fence(close)
+        ))
+    }
+}
+
+const FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh", "tanh",
+    "log", "ln", "lg", "exp", "det", "dim", "gcd", "deg", "arg", "ker", "hom", "Pr", "lim",
+    "liminf", "limsup", "max", "min", "sup", "inf",
+];
+
+/// Functions that take limits below in display style.
+const LIMIT_FUNCTIONS: &[&str] = &[
+    "lim", "liminf", "limsup", "max", "min", "sup", "inf", "det", "gcd", "Pr",
+];
+
+fn greek(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "alpha" => "α",
+        "beta" => "β",
+        "gamma" => "γ",
+        "delta" => "δ",
+        "epsilon" => "ϵ",
+        "varepsilon" => "ε",
+        "zeta" => "ζ",
+        "eta" => "η",
+        "theta" => "θ",
+        "vartheta" => "ϑ",
+        "iota" => "ι",
+        "kappa" => "κ",
+        "lambda" => "λ",
+        "mu" => "μ",
+        "nu" => "ν",
+        "xi" => "ξ",
+        "pi" => "π",
+        "varpi" => "ϖ",
+        "rho" => "ρ",
+        "varrho" => "ϱ",
+        "sigma" => "σ",
+        "varsigma" => "ς",
+        "tau" => "τ",
+        "upsilon" => "υ",
+        "phi" => "ϕ",
+        "varphi" => "φ",
+        "chi" => "χ",
+        "psi" => "ψ",
+        "omega" => "ω",
+        "Gamma" => "Γ",
+        "Delta" => "Δ",
+        "Theta" => "Θ",
+        "Lambda" => "Λ",
+        "Xi" => "Ξ",
+        "Pi" => "Π",
+        "Sigma" => "Σ",
+        "Upsilon" => "Υ",
+        "Phi" => "Φ",
+        "Psi" => "Ψ",
+        "Omega" => "Ω",
+        _ => return None,
+    })
+}
+
+fn identifier_symbol(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "infty" => "∞",
+        "partial" => "∂",
+        "nabla" => "∇",
+        "emptyset" | "varnothing" => "∅",
+        "ell" => "ℓ",
+        "hbar" => "ℏ",
+        "Re" => "ℜ",
+        "Im" => "ℑ",
+        "aleph" => "ℵ",
+        _ => return None,
+    })
+}
+
+fn operator(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "times" => "×",
+        "cdot" => "⋅",
+        "pm" => "±",
+        "mp" => "∓",
+        "div" => "÷",
+        "ast" => "∗",
+        "star" => "⋆",
+        "circ" => "∘",
+        "bullet" => "∙",
+        "oplus" => "⊕",
+        "otimes" => "⊗",
+        "leq" | "le" => "≤",
+        "geq" | "ge" => "≥",
+        "neq" | "ne" => "≠",
+        "ll" => "≪",
+        "gg" => "≫",
+        "approx" => "≈",
+        "equiv" => "≡",
+        "sim" => "∼",
+        "simeq" => "≃",
+        "cong" => "≅",
+        "propto" => "∝",
+        "to" | "rightarrow" => "→",
+        "leftarrow" | "gets" => "←",
+        "Rightarrow" | "implies" => "⇒",
+        "Leftarrow" => "⇐",
+        "leftrightarrow" => "↔",
+        "Leftrightarrow" | "iff" => "⇔",
+        "mapsto" => "↦",
+        "in" => "∈",
+        "notin" => "∉",
+        "ni" => "∋",
+        "subset" => "⊂",
+        "subseteq" => "⊆",
+        "supset" => "⊃",
+        "supseteq" => "⊇",
+        "cup" => "∪",
+        "cap" => "∩",
+        "setminus" => "∖",
+        "forall" => "∀",
+        "exists" => "∃",
+        "neg" | "lnot" => "¬",
+        "land" | "wedge" => "∧",
+        "lor" | "vee" => "∨",
+        "cdots" => "⋯",
+        "ldots" | "dots" => "…",
+        "vdots" => "⋮",
+        "ddots" => "⋱",
+        "langle" => "⟨",
+        "rangle" => "⟩",
+        "lfloor" => "⌊",
+        "rfloor" => "⌋",
+        "lceil" => "⌈",
+        "rceil" => "⌉",
+        "mid" => "∣",
+        "parallel" => "∥",
+        "perp" => "⊥",
+        "{" | "lbrace" => "{",
+        "}" | "rbrace" => "}",
+        "|" | "Vert" => "‖",
+        "vert" => "|",
+        "%" => "%",
+        "$" => "$",
+        "#" => "#",
+        "&" => "&",
+        "_" => "_",
+        _ => return None,
+    })
+}
+
+fn large_operator(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "sum" => "∑",
+        "prod" => "∏",
+        "coprod" => "∐",
+        "int" => "∫",
+        "iint" => "∬",
+        "oint" => "∮",
+        "bigcup" => "⋃",
+        "bigcap" => "⋂",
+        "bigoplus" => "⨁",
+        "bigotimes" => "⨂",
+        _ => return None,
+    })
+}
+
+fn space(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "," => "0.1667em",
+        ":" | ">" => "0.2222em",
+        ";" => "0.2778em",
+        " " => "0.25em",
+        "quad" => "1em",
+        "qquad" => "2em",
+        "!" => "0",
+        _ => return None,
+    })
+}
+
+fn accent(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "hat" | "widehat" => "^",
+        "bar" | "overline" => "¯",
+        "vec" | "overrightarrow" => "→",
+        "dot" => "˙",
+        "ddot" => "¨",
+        "tilde" | "widetilde" => "~",
+        _ => return None,
+    })
+}
+
+/// `c` in the Unicode mathematical alphabet a font command selects.
+fn styled_letter(command: &str, c: char) -> char {
+    let offset = |base: u32, from: char| char::from_u32(base + (c as u32 - from as u32));
+    let styled = match (command, c) {
+        ("mathbb", 'C') => Some('ℂ'),
+        ("mathbb", 'H') => Some('ℍ'),
+        ("mathbb", 'N') => Some('ℕ'),
+        ("mathbb", 'P') => Some('ℙ'),
+        ("mathbb", 'Q') => Some('ℚ'),
+        ("mathbb", 'R') => Some('ℝ'),
+        ("mathbb", 'Z') => Some('ℤ'),
+        ("mathbb", 'A'..='Z') => offset(0x1D538, 'A'),
+        ("mathbb", 'a'..='z') => offset(0x1D552, 'a'),
+        ("mathbb", '0'..='9') => offset(0x1D7D8, '0'),
+        ("mathcal", 'B') => Some('ℬ'),
+        ("mathcal", 'E') => Some('ℰ'),
+        ("mathcal", 'F') => Some('ℱ'),
+        ("mathcal", 'H') => Some('ℋ'),
+        ("mathcal", 'I') => Some('ℐ'),
+        ("mathcal", 'L') => Some('ℒ'),
+        ("mathcal", 'M') => Some('ℳ'),
+        ("mathcal", 'R') => Some('ℛ'),
+        ("mathcal", 'A'..='Z') => offset(0x1D49C, 'A'),
+        ("mathbf" | "boldsymbol", 'A'..='Z') => offset(0x1D400, 'A'),
+        ("mathbf" | "boldsymbol", 'a'..='z') => offset(0x1D41A, 'a'),
+        ("mathbf" | "boldsymbol", '0'..='9') => offset(0x1D7CE, '0'),
+        _ => None,
+    };
+    styled.unwrap_or(c)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(tex: &str, display: bool) -> String {
+        let math = tex_to_mathml(tex, display);
+        let start = math.find("<semantics><mrow>").unwrap() + "<semantics><mrow>".len();
+        let end = math.rfind("</mrow><annotation").unwrap();
+        math[start..end].to_string()
+    }
+
+    #[test]
+    fn test_tex_to_mathml() {
+        assert_eq!(
+            body("x^2 + y_{i,j} - 3.5", false),
+            "<msup><mi>x</mi><mn>2</mn></msup><mo>+</mo><msub><mi>y</mi><mrow><mi>i</mi><mo>,</mo><mi>j</mi></mrow></msub><mo>−</mo><mn>3.5</mn>"
+        );
+        assert_eq!(
+            body(r"\frac{a}{b} \leq \sqrt[3]{\alpha}", false),
+            "<mfrac><mrow><mi>a</mi></mrow><mrow><mi>b</mi></mrow></mfrac><mo>≤</mo><mroot><mrow><mi>α</mi></mrow><mrow><mn>3</mn></mrow></mroot>"
+        );
+        assert_eq!(
+            body(r"\sum_{i=1}^n i", true),
+            "<munderover><mo largeop=\"true\" movablelimits=\"true\">∑</mo><mrow><mi>i</mi><mo>=</mo><mn>1</mn></mrow><mi>n</mi></munderover><mi>i</mi>"
+        );
+        assert_eq!(
+            body(r"\int_0^1 \mathbb{R} \text{ if } x < y", false),
+            "<msubsup><mo largeop=\"true\" movablelimits=\"true\">∫</mo><mn>0</mn><mn>1</mn></msubsup><mi>ℝ</mi><mtext> if </mtext><mi>x</mi><mo>&lt;</mo><mi>y</mi>"
+        );
+        assert_eq!(
+            body(r"\left( \begin{pmatrix} 1 & 0 \\ 0 & 1 \end{pmatrix} \right.", false),
+            "<mrow><mo fence=\"true\" stretchy=\"true\">(</mo><mrow><mo fence=\"true\" stretchy=\"true\">(</mo><mtable><mtr><mtd><mn>1</mn></mtd><mtd><mn>0</mn></mtd></mtr><mtr><mtd><mn>0</mn></mtd><mtd><mn>1</mn></mtd></mtr></mtable><mo fence=\"true\" stretchy=\"true\">)</mo></mrow></mrow>"
+        );
+        assert_eq!(
+            body(r"\foo} f'", false),
+            "<merror><mtext>\\foo</mtext></merror><msup><mi>f</mi><mo>′</mo></msup>"
+        );
+    }
+
+    #[test]
+    fn test_render_math_replaces_parser_output() {
+        let html = "<p>Area <code class=\"language-math math-inline\">a&lt;b</code>.</p>\n<pre><code class=\"language-math math-display\">x\n</code></pre>\n<pre><code class=\"language-rust\">x</code></pre>";
+        let rendered = render_math(html);
+
+        assert!(rendered.starts_with(
+            "<p>Area <math xmlns=\"http://www.w3.org/1998/Math/MathML\" display=\"inline\"><semantics><mrow><mi>a</mi><mo>&lt;</mo><mi>b</mi></mrow><annotation encoding=\"application/x-tex\">a&lt;b</annotation></semantics></math>.</p>"
+        ));
+        assert!(rendered.contains("display=\"block\"><semantics><mrow><mi>x</mi></mrow>"));
+        assert!(rendered.ends_with("<pre><code class=\"language-rust\">x</code></pre>"));
+    }
+}
@@ -0,0 +1,424 @@
+//! Advertising the preview on the local network over multicast DNS
+//! (Bonjour/Zeroconf), so a tablet or another machine can open it from its
+//! list of services instead of someone typing an IP address.
+//!
+//! This is just enough of a responder for one `_http._tcp` service: it
+//! announces the service at startup, answers queries for the service type,
+//! the instance and its host name, and says goodbye when dropped. The
+//! socket shares port 5353 with the system's own responder.
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+const SERVICE_TYPE: [&str; 3] = ["_http", "_tcp", "local"];
+const SERVICE_TYPES: [&str; 4] = ["_services", "_dns-sd", "_udp", "local"];
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// Tells caches to replace what they hold for a name rather than add to it.
+const CACHE_FLUSH: u16 = 0x8000;
+/// RFC 6762's recommended TTLs for host records and everything else.
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+/// DNS labels are at most 63 bytes.
+const MAX_LABEL: usize = 63;
+
+/// The preview as seen by other devices.
+struct Service {
+    instance: String,
+    host: String,
+    ip: Ipv4Addr,
+    port: u16,
+}
+
+impl Service {
+    fn new(hostname: &str, ip: Ipv4Addr, port: u16) -> Self {
+        let mut instance = format!("markdown preview on {hostname}");
+        if port != 3000 {
+            instance.push_str(&format!(" ({port})"));
+        }
+        while instance.len() > MAX_LABEL {
+            instance.pop();
+        }
+        // A name of our own, so the system responder's records for the
+        // machine's name are left alone
+        let mut host = format!("{hostname}-mdserve");
+        host.truncate(MAX_LABEL);
+        Self {
+            instance,
+            host,
+            ip,
+            port,
+        }
+    }
+
+    fn instance_name(&self) -> [&str; 4] {
+        [&self.instance, "_http", "_tcp", "local"]
+    }
+
+    fn host_name(&self) -> [&str; 2] {
+        [&self.host, "local"]
+    }
+
+    /// Whether `packet` is a query asking about any of our names.
+    fn answers(&self, packet: &[u8]) -> bool {
+        let Some(questions) = questions(packet) else {
+            return false;
+        };
+        questions.iter().any(|name| {
+            same_name(name, &SERVICE_TYPE)
+                || same_name(name, &SERVICE_TYPES)
+                || same_name(name, &self.instance_name())
+                || same_name(name, &self.host_name())
+        })
+    }
+
+    /// Every record for the service; with `goodbye`, TTLs of zero so caches
+    /// drop them.
+    fn response(&self, goodbye: bool) -> Vec<u8> {
+        let ttl = |ttl: u32| if goodbye { 0 } else { ttl };
+        let mut packet = Vec::with_capacity(512);
+        // ID 0, authoritative answer, no questions, five answers
+        for field in [0u16, 0x8400, 0, 5, 0, 0] {
+            packet.extend_from_slice(&field.to_be_bytes());
+        }
+
+        let mut instance = Vec::new();
+        write_name(&mut instance, &self.instance_name());
+        let mut host = Vec::new();
+        write_name(&mut host, &self.host_name());
+        let mut service_type = Vec::new();
+        write_name(&mut service_type, &SERVICE_TYPE);
+        let mut srv = Vec::new();
+        for field in [0u16, 0, self.port] {
+            srv.extend_from_slice(&field.to_be_bytes());
+        }
+        srv.extend_from_slice(&host);
+        let txt = b"\x06path=/";
+
+        write_record(
+            &mut packet,
+            &SERVICE_TYPES,
+            TYPE_PTR,
+            CLASS_IN,
+            ttl(OTHER_TTL),
+            &service_type,
+        );
+        write_record(
+            &mut packet,
+            &SERVICE_TYPE,
+            TYPE_PTR,
+            CLASS_IN,
+            ttl(OTHER_TTL),
+            &instance,
+        );
+        write_record(
+            &mut packet,
+            &self.instance_name(),
+            TYPE_SRV,
+            CLASS_IN | CACHE_FLUSH,
+            ttl(HOST_TTL),
+            &srv,
+        );
+        write_record(
+            &mut packet,
+            &self.instance_name(),
+            TYPE_TXT,
+            CLASS_IN | CACHE_FLUSH,
+            ttl(OTHER_TTL),
+            txt,
+        );
+        write_record(
+            &mut packet,
+            &self.host_name(),
+            TYPE_A,
+            CLASS_IN | CACHE_FLUSH,
+            ttl(HOST_TTL),
+            &self.ip.octets(),
+        );
+        packet
+    }
+}
+
+fn write_name(buf: &mut Vec<u8>, labels: &[&str]) {
+    for label in labels {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+fn write_record(buf: &mut Vec<u8>, name: &[&str], kind: u16, class: u16, ttl: u32, data: &[u8]) {
+    write_name(buf, name);
+    buf.extend_from_slice(&kind.to_be_bytes());
+    buf.extend_from_slice(&class.to_be_bytes());
+    buf.extend_from_slice(&ttl.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn same_name(name: &[String], labels: &[&str]) -> bool {
+    name.len() == labels.len()
+        && name
+            .iter()
+            .zip(labels)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+/// The names asked about in a query. `None` for responses and malformed
+/// packets.
+fn questions(packet: &[u8]) -> Option<Vec<Vec<String>>> {
+    let field = |at: usize| Some(u16::from_be_bytes([*packet.get(at)?, *packet.get(at + 1)?]));
+    if field(2)? & 0x8000 != 0 {
+        return None;
+    }
+
+    let mut pos = 12;
+    let mut names = Vec::new();
+    for _ in 0..field(4)? {
+        names.push(read_name(packet, &mut pos)?);
+        // Type and class
+        pos += 4;
+    }
+    (pos <= packet.len()).then_some(names)
+}
+
+/// Read a possibly compressed name at `pos`, leaving `pos` after it.
+fn read_name(packet: &[u8], pos: &mut usize) -> Option<Vec<String>> {
+    let mut labels = Vec::new();
+    let mut at = *pos;
+    let mut jumped = false;
+    // Pointers could loop; no real name needs this many
+    for _ in 0..128 {
+        let len = *packet.get(at)? as usize;
+        if len == 0 {
+            if !jumped {
+                *pos = at + 1;
+            }
+            return Some(labels);
+        }
+        if len & 0xC0 == 0xC0 {
+            let target = (len & 0x3F) << 8 | *packet.get(at + 1)? as usize;
+            if !jumped {
+                *pos = at + 2;
+                jumped = true;
+            }
+            at = target;
+            continue;
+        }
+        if len > MAX_LABEL {
+            return None;
+        }
+        let label = packet.get(at + 1..at + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        at += 1 + len;
+    }
+    None
+}
+
+/// A service being advertised. Dropping it stops answering and tells the
+/// network the service is gone.
+pub(crate) struct Advertisement {
+    socket: Arc<UdpSocket>,
+    service: Arc<Service>,
+    task: JoinHandle<()>,
+}
+
+impl Advertisement {
+    /// Name the service is listed under.
+    pub fn instance(&self) -> &str {
+        &self.service.instance
+    }
+
+    /// URL other devices resolving the advertised host name can open.
+    pub fn url(&self) -> String {
+        format!("http://{}.local:{}", self.service.host, self.service.port)
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = self
+            .socket
+            .try_send_to(&self.service.response(true), (GROUP, PORT).into());
+    }
+}
+
+/// Advertise the server listening on `port` at `ip`, from [`lan_address`].
+pub(crate) fn advertise(ip: Ipv4Addr, port: u16) -> Result<Advertisement> {
+    let service = Arc::new(Service::new(&machine_name(), ip, port));
+    let socket = Arc::new(
+        UdpSocket::from_std(bind(ip).context("failed to open the mDNS port")?)
+            .context("failed to open the mDNS port")?,
+    );
+
+    let task = tokio::spawn(respond(socket.clone(), service.clone()));
+    Ok(Advertisement {
+        socket,
+        service,
+        task,
+    })
+}
+
+async fn respond(socket: Arc<UdpSocket>, service: Arc<Service>) {
+    let group = SocketAddr::from((GROUP, PORT));
+    let response = service.response(false);
+
+    // Announce twice, a second apart, so listening browsers pick it up
+    // without asking
+    let announce = async {
+        for _ in 0..2 {
+            let _ = socket.send_to(&response, group).await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    };
+    let answer = async {
+        let mut buf = [0u8; 9000];
+        loop {
+            let Ok((len, _)) = socket.recv_from(&mut buf).await else {
+                continue;
+            };
+            if service.answers(&buf[..len]) {
+                let _ = socket.send_to(&response, group).await;
+            }
+        }
+    };
+    tokio::join!(announce, answer);
+}
+
+fn bind(ip: Ipv4Addr) -> io::Result<std::net::UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(
+        unix,
+        not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+    ))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT).into())?;
+    socket.join_multicast_v4(&GROUP, &ip)?;
+    socket.set_multicast_if_v4(&ip)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// The address other devices reach a server bound to `hostname` at: the
+/// bound address, or for a wildcard bind the one multicast traffic leaves
+/// from. Fails when `hostname` is only reachable from this machine.
+pub(crate) fn lan_address(hostname: &str) -> Result<Ipv4Addr> {
+    let loopback = anyhow::anyhow!(
+        "{hostname} is only reachable from this machine; listen on the network with -H 0.0.0.0 to advertise it"
+    );
+    match hostname.parse::<IpAddr>() {
+        Ok(ip) if ip.is_loopback() => return Err(loopback),
+        Ok(IpAddr::V4(ip)) if !ip.is_unspecified() => return Ok(ip),
+        Err(_) if hostname.eq_ignore_ascii_case("localhost") => return Err(loopback),
+        _ => {}
+    }
+
+    // Connecting a UDP socket sends nothing; it only picks a route
+    let probe = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    probe.connect((GROUP, PORT))?;
+    match probe.local_addr()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() && !ip.is_loopback() => Ok(ip),
+        _ => anyhow::bail!("no network interface to advertise on"),
+    }
+}
+
+/// This machine's name as a DNS label, e.g. `dev-laptop`.
+fn machine_name() -> String {
+    let name = ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok())
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .or_else(|| {
+            let output = std::process::Command::new("hostname").output().ok()?;
+            String::from_utf8(output.stdout).ok()
+        })
+        .unwrap_or_default();
+
+    let label: String = name
+        .trim()
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    match label.trim_matches('-') {
+        "" => "mdserve".to_string(),
+        label => label.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &[&str]) -> Vec<u8> {
+        let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        write_name(&mut packet, name);
+        packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_service_answers_queries_for_its_names() {
+        let service = Service::new("dev-laptop", Ipv4Addr::new(192, 168, 1, 20), 3000);
+        assert_eq!(service.instance, "markdown preview on dev-laptop");
+
+        assert!(service.answers(&query(&["_http", "_tcp", "local"])));
+        assert!(service.answers(&query(&["_HTTP", "_tcp", "local"])));
+        assert!(service.answers(&query(&[
+            "markdown preview on dev-laptop",
+            "_http",
+            "_tcp",
+            "local"
+        ])));
+        assert!(service.answers(&query(&["dev-laptop-mdserve", "local"])));
+        assert!(!service.answers(&query(&["_ipp", "_tcp", "local"])));
+        assert!(!service.answers(&query(&["dev-laptop", "local"])));
+
+        // Our own announcement, looped back, is not a query
+        assert!(!service.answers(&service.response(false)));
+    }
+
+    #[test]
+    fn test_compressed_names_are_read() {
+        // "local" at offset 12, then "_tcp" pointing back at it
+        let mut packet = vec![0; 12];
+        write_name(&mut packet, &["local"]);
+        packet.extend_from_slice(&[4, b'_', b't', b'c', b'p', 0xC0, 12]);
+
+        let mut pos = 19;
+        assert_eq!(
+            read_name(&packet, &mut pos),
+            Some(vec!["_tcp".to_string(), "local".to_string()])
+        );
+        assert_eq!(pos, packet.len());
+
+        // A pointer to itself
+        let mut pos = 0;
+        assert_eq!(read_name(&[0xC0, 0], &mut pos), None);
+    }
+}
@@ -0,0 +1,103 @@
+//! Canonical paths that behave the same on every platform.
+//!
+//! On Windows `fs::canonicalize` returns extended-length paths
+//! (`\\?\C:\docs`, `\\?\UNC\server\share\docs`). Those don't treat `/` as a
+//! separator, so joining a request path like `assets/logo.png` onto one
+//! names a single odd component, and comparing one with a plain path never
+//! matches. Some network filesystems can't be canonicalized at all. The
+//! standard library adds the prefix itself when a path is too long, so
+//! stripping it loses nothing.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// The absolute, symlink-free form of `path`, without an extended-length
+/// prefix where the plain form names the same file. Falls back to the
+/// absolute path when an existing path can't be canonicalized, as happens
+/// on some network shares.
+pub(crate) fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+    match path.canonicalize() {
+        Ok(canonical) => Ok(simplify(canonical)),
+        Err(e) => match path.try_exists() {
+            Ok(true) => std::path::absolute(path),
+            _ => Err(e),
+        },
+    }
+}
+
+/// Async [`canonicalize`], for request handlers.
+pub(crate) async fn canonicalize_async(path: &Path) -> io::Result<PathBuf> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || canonicalize(&path))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// `path` without its extended-length prefix, if it has one that can be
+/// dropped.
+pub(crate) fn simplify(path: PathBuf) -> PathBuf {
+    if cfg!(windows) {
+        strip_verbatim(&path).unwrap_or(path)
+    } else {
+        path
+    }
+}
+
+/// `\\?\C:\…` as `C:\…` and `\\?\UNC\server\share\…` as
+/// `\\server\share\…`. `None` for other paths, and for names only an
+/// extended-length path can express: components ending in a dot or space,
+/// or `/` inside a component.
+fn strip_verbatim(path: &Path) -> Option<PathBuf> {
+    let text = path.to_str()?;
+    let plain = if let Some(rest) = text.strip_prefix(r"\\?\UNC\") {
+        format!(r"\\{rest}")
+    } else {
+        let rest = text.strip_prefix(r"\\?\")?;
+        let bytes = rest.as_bytes();
+        if !(bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && &bytes[1..3] == br":\") {
+            return None;
+        }
+        rest.to_string()
+    };
+
+    let representable = plain
+        .trim_start_matches('\\')
+        .split('\\')
+        .all(|component| !component.ends_with(['.', ' ']) && !component.contains('/'));
+    representable.then(|| PathBuf::from(plain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_verbatim() {
+        let strip = |path: &str| strip_verbatim(Path::new(path)).map(|p| p.display().to_string());
+
+        assert_eq!(strip(r"\\?\C:\docs\notes"), Some(r"C:\docs\notes".into()));
+        assert_eq!(
+            strip(r"\\?\UNC\server\share\docs"),
+            Some(r"\\server\share\docs".into())
+        );
+        assert_eq!(strip(r"C:\docs"), None);
+        assert_eq!(strip(r"\\?\Volume{1234}\docs"), None);
+        assert_eq!(strip(r"\\?\C:\docs\trailing."), None);
+        assert_eq!(strip(r"\\?\C:\a/b"), None);
+    }
+
+    #[test]
+    fn test_canonicalize_resolves_relative_paths() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().join("docs");
+        std::fs::create_dir(&dir).unwrap();
+
+        let canonical = canonicalize(&dir.join("..").join("docs")).unwrap();
+        assert!(canonical.is_absolute());
+        assert!(canonical.ends_with("docs"));
+        assert_eq!(canonical, canonicalize(&canonical).unwrap());
+        assert!(canonicalize(&dir.join("missing")).is_err());
+    }
+}
@@ -0,0 +1,64 @@
+//! The pulldown-cmark backend. Math and task list checkboxes are written
+//! the way markdown-rs writes them, since the stages after parsing look for
+//! that markup.
+
+use pulldown_cmark::{html, Event, Options, Parser};
+
+use crate::render::{escape_html, MarkdownParser, MathMode, RenderOptions};
+
+pub(crate) struct PulldownCmark;
+
+impl MarkdownParser for PulldownCmark {
+    fn to_html(&self, content: &str, options: &RenderOptions) -> Option<String> {
+        let events = Parser::new_ext(content, parser_options(options)).map(|event| match event {
+            Event::InlineMath(tex) => Event::InlineHtml(
+                format!(
+                    "<code class=\"language-math math-inline\">{}</code>",
+                    escape_html(&tex)
+                )
+                .into(),
+            ),
+            Event::DisplayMath(tex) => Event::InlineHtml(
+                format!(
+                    "<pre><code class=\"language-math math-display\">{}</code></pre>",
+                    escape_html(&tex)
+                )
+                .into(),
+            ),
+            Event::TaskListMarker(checked) => Event::InlineHtml(
+                if checked {
+                    "<input type=\"checkbox\" disabled=\"\" checked=\"\" /> "
+                } else {
+                    "<input type=\"checkbox\" disabled=\"\" /> "
+                }
+                .into(),
+            ),
+            Event::Html(raw) | Event::InlineHtml(raw) if !options.allow_dangerous_html => {
+                Event::Text(raw)
+            }
+            event => event,
+        });
+
+        let mut out = String::with_capacity(content.len() * 3 / 2);
+        html::push_html(&mut out, events);
+        Some(out)
+    }
+}
+
+fn parser_options(options: &RenderOptions) -> Options {
+    let mut parse = Options::empty();
+    if options.gfm {
+        parse.set(Options::ENABLE_TABLES, options.tables);
+        parse.set(Options::ENABLE_STRIKETHROUGH, options.strikethrough);
+        parse.set(Options::ENABLE_TASKLISTS, options.task_lists);
+        parse.set(Options::ENABLE_FOOTNOTES, options.footnotes);
+    }
+    if options.frontmatter {
+        parse.insert(
+            Options::ENABLE_YAML_STYLE_METADATA_BLOCKS
+                | Options::ENABLE_PLUSES_DELIMITED_METADATA_BLOCKS,
+        );
+    }
+    parse.set(Options::ENABLE_MATH, options.math != MathMode::Off);
+    parse
+}
@@ -0,0 +1,968 @@
+use crate::{
+    alerts,
+    containers::ContainerStage,
+    deflists, emoji,
+    frontmatter::{self, Metadata},
+    headings::{Heading, HeadingIdStage},
+    highlight, math, sourcepos,
+};
+use std::{collections::BTreeSet, sync::Arc};
+
+/// Feature flag set by the built-in stage when a document contains a
+/// ```` ```mermaid ```` block, so the page loads Mermaid.
+pub const MERMAID_FEATURE: &str = "mermaid";
+
+/// Feature flag set by the built-in stage when a document contains math.
+pub const MATH_FEATURE: &str = "math";
+
+/// Options controlling how markdown is turned into HTML.
+///
+/// The defaults match what the mdserve CLI renders. New options are added
+/// as fields, so construct this with [`RenderOptions::default`] and adjust
+/// the fields you care about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RenderOptions {
+    /// Enable GitHub Flavored Markdown (tables, task lists, strikethrough,
+    /// autolinks, footnotes). When disabled, plain CommonMark is used and the
+    /// individual GFM toggles below have no effect.
+    pub gfm: bool,
+    /// GFM tables.
+    pub tables: bool,
+    /// GFM `~~strikethrough~~`.
+    pub strikethrough: bool,
+    /// GFM `- [ ]` task list items.
+    pub task_lists: bool,
+    /// GFM bare URL and email autolinks.
+    pub autolinks: bool,
+    /// GFM footnotes (`[^1]`).
+    pub footnotes: bool,
+    /// Pass raw HTML in the document through instead of escaping it.
+    pub allow_dangerous_html: bool,
+    /// Recognize YAML (`---`) and TOML (`+++`) frontmatter so it is not
+    /// rendered as content.
+    pub frontmatter: bool,
+    /// Show recognized frontmatter as a code block at the top of the
+    /// document instead of dropping it.
+    pub show_frontmatter: bool,
+    /// Open absolute `http(s)` links in a new tab, so following one doesn't
+    /// replace the live preview, and mark them for an external link icon.
+    pub external_links: bool,
+    /// Whether `$…$` and `$$…$$` are parsed as math, and how it is shown.
+    pub math: MathMode,
+    /// Syntax highlight fenced code blocks in common languages.
+    pub highlight: bool,
+    /// Show GitHub alerts (blockquotes starting with `[!NOTE]`,
+    /// `[!WARNING]`, …) as callouts.
+    pub alerts: bool,
+    /// Replace GitHub emoji shortcodes like `:tada:` with the emoji.
+    pub emoji: bool,
+    /// Definition lists: a term on one line, `: definition` on the next.
+    pub definition_lists: bool,
+    /// Give top-level blocks a `data-sourcepos` attribute with the lines
+    /// and columns they come from, for scrolling the preview to a line.
+    pub source_positions: bool,
+    /// The parser markdown goes through before the stages run.
+    pub backend: MarkdownBackend,
+}
+
+/// Which parser turns markdown into HTML.
+///
+/// The two disagree on some edge cases (emphasis next to punctuation, HTML
+/// blocks, list nesting); everything after parsing is shared.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MarkdownBackend {
+    /// The `markdown` crate (markdown-rs).
+    #[default]
+    Markdown,
+    /// pulldown-cmark, with the `pulldown-cmark` cargo feature. It has no
+    /// GFM autolinks for bare URLs, so [`RenderOptions::autolinks`] has no
+    /// effect.
+    #[cfg(feature = "pulldown-cmark")]
+    PulldownCmark,
+}
+
+impl MarkdownBackend {
+    fn parser(self) -> &'static dyn MarkdownParser {
+        match self {
+            Self::Markdown => &MarkdownRs,
+            #[cfg(feature = "pulldown-cmark")]
+            Self::PulldownCmark => &crate::pulldown::PulldownCmark,
+        }
+    }
+}
+
+/// A markdown parser and HTML compiler behind a [`MarkdownBackend`].
+///
+/// Stages expect markdown-rs output, so other parsers emit the same markup
+/// for math and task list checkboxes.
+pub(crate) trait MarkdownParser: Sync {
+    fn to_html(&self, content: &str, options: &RenderOptions) -> Option<String>;
+}
+
+struct MarkdownRs;
+
+impl MarkdownParser for MarkdownRs {
+    fn to_html(&self, content: &str, options: &RenderOptions) -> Option<String> {
+        markdown::to_html_with_options(content, &options.to_markdown_options()).ok()
+    }
+}
+
+/// How math in a document is rendered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MathMode {
+    /// Dollar signs are plain text.
+    #[default]
+    Off,
+    /// Convert TeX to MathML while rendering, so math needs no JavaScript
+    /// and prints as shown.
+    Server,
+    /// Leave the TeX in `language-math` code elements for the bundled
+    /// KaTeX to typeset in the browser. Pages only load it when they
+    /// contain math.
+    Client,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            gfm: true,
+            tables: true,
+            strikethrough: true,
+            task_lists: true,
+            autolinks: true,
+            footnotes: true,
+            allow_dangerous_html: true,
+            frontmatter: true,
+            show_frontmatter: false,
+            external_links: true,
+            math: MathMode::Off,
+            highlight: true,
+            alerts: true,
+            emoji: true,
+            definition_lists: true,
+            source_positions: false,
+            backend: MarkdownBackend::default(),
+        }
+    }
+}
+
+impl RenderOptions {
+    pub(crate) fn to_markdown_options(&self) -> markdown::Options {
+        let mut options = if self.gfm {
+            markdown::Options::gfm()
+        } else {
+            markdown::Options::default()
+        };
+        if self.gfm {
+            let constructs = &mut options.parse.constructs;
+            constructs.gfm_table = self.tables;
+            constructs.gfm_strikethrough = self.strikethrough;
+            constructs.gfm_task_list_item = self.task_lists;
+            constructs.gfm_autolink_literal = self.autolinks;
+            constructs.gfm_footnote_definition = self.footnotes;
+            constructs.gfm_label_start_footnote = self.footnotes;
+        }
+        options.compile.allow_dangerous_html = self.allow_dangerous_html;
+        options.parse.constructs.frontmatter = self.frontmatter;
+        if self.math != MathMode::Off {
+            options.parse.constructs.math_flow = true;
+            options.parse.constructs.math_text = true;
+        }
+        options
+    }
+}
+
+/// Render markdown to an HTML fragment exactly as the preview server does,
+/// built-in [`RenderStage`]s included.
+pub fn markdown_to_html(content: &str, options: &RenderOptions) -> String {
+    RenderPipeline::default().render(content, options).html
+}
+
+/// Markdown parsed to HTML without any stages, leaving source position
+/// marks for the caller to apply after its own passes. Positions refer to
+/// `original`, what `content` was before stages rewrote it.
+fn render_marked(content: &str, original: &str, options: &RenderOptions) -> String {
+    let Some(mut html) = options.backend.parser().to_html(content, options) else {
+        return "Error parsing markdown".to_string();
+    };
+    if options.source_positions {
+        html = sourcepos::mark_blocks(&html, content, original, options);
+    }
+    if options.frontmatter && options.show_frontmatter {
+        if let Some(frontmatter) = split_frontmatter(content) {
+            html.insert_str(
+                0,
+                &format!(
+                    "<pre class=\"frontmatter\"><code class=\"language-{}\">{}</code></pre>\n",
+                    frontmatter.language,
+                    escape_html(frontmatter.body)
+                ),
+            );
+        }
+    }
+    if options.gfm && options.task_lists {
+        html = mark_task_list_items(&html);
+    }
+    html
+}
+
+/// Frontmatter at the start of a document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Frontmatter<'a> {
+    /// `yaml` for `---` fences, `toml` for `+++`.
+    pub language: &'static str,
+    /// The text between the fences, ending in a line break.
+    pub body: &'a str,
+}
+
+/// The frontmatter `source` starts with, found by the parser's rules: a
+/// `---` or `+++` fence on the first line, closed by the same fence.
+pub(crate) fn split_frontmatter(source: &str) -> Option<Frontmatter<'_>> {
+    let (first, rest) = source.split_once('\n')?;
+    let (fence, language) = match first.trim_end() {
+        "---" => ("---", "yaml"),
+        "+++" => ("+++", "toml"),
+        _ => return None,
+    };
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == fence {
+            return Some(Frontmatter {
+                language,
+                body: &rest[..offset],
+            });
+        }
+        offset += line.len();
+    }
+    None
+}
+
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Elements whose text is shown literally.
+const LITERAL_ELEMENTS: [&str; 7] = ["code", "kbd", "math", "pre", "samp", "script", "style"];
+
+/// Rebuild `html` with `replace` appending each run of text outside tags to
+/// the output, rewritten as it likes. Tags, attributes and the text of code
+/// and other literal elements are copied unchanged.
+pub(crate) fn map_text(html: &str, mut replace: impl FnMut(&str, &mut String)) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    // Open elements whose text is left alone
+    let mut literal_depth = 0usize;
+
+    while let Some(start) = rest.find('<') {
+        let (text, tag) = rest.split_at(start);
+        if literal_depth == 0 {
+            replace(text, &mut out);
+        } else {
+            out.push_str(text);
+        }
+        let end = tag.find('>').map_or(tag.len(), |end| end + 1);
+        let (tag, after) = tag.split_at(end);
+        let closing = tag.starts_with("</");
+        let name: String = tag
+            .trim_start_matches(['<', '/'])
+            .chars()
+            .take_while(char::is_ascii_alphanumeric)
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        if LITERAL_ELEMENTS.contains(&name.as_str()) && !tag.ends_with("/>") {
+            if closing {
+                literal_depth = literal_depth.saturating_sub(1);
+            } else {
+                literal_depth += 1;
+            }
+        }
+        out.push_str(tag);
+        rest = after;
+    }
+    if literal_depth == 0 {
+        replace(rest, &mut out);
+    } else {
+        out.push_str(rest);
+    }
+    out
+}
+
+/// Give task list items and their checkboxes GitHub's classes, so they can
+/// be styled without bullets.
+fn mark_task_list_items(html: &str) -> String {
+    const CHECKBOX: &str = "<input type=\"checkbox\" disabled=\"\"";
+    const MARKED: &str = "<input type=\"checkbox\" class=\"task-list-item-checkbox\" disabled=\"\"";
+
+    html.replace(
+        &format!("<li>{CHECKBOX}"),
+        &format!("<li class=\"task-list-item\">{MARKED}"),
+    )
+    .replace(
+        &format!("<li>\n<p>{CHECKBOX}"),
+        &format!("<li class=\"task-list-item\">\n<p>{MARKED}"),
+    )
+}
+
+/// Add `target="_blank"`, `rel="noopener"` and the `external-link` class to
+/// every link whose href is an absolute `http(s)` URL. Links written as raw
+/// HTML keep their own classes, target and rel.
+fn mark_external_links(html: &str) -> String {
+    const LINK: &str = "<a ";
+
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(LINK) {
+        let Some(len) = rest[start..].find('>') else {
+            break;
+        };
+        let tag = &rest[start..start + len];
+        out.push_str(&rest[..start]);
+        let external = attribute_value(tag, "href").is_some_and(|href| {
+            let href = &tag[href];
+            href.starts_with("http://") || href.starts_with("https://")
+        });
+        if external {
+            mark_external_link(tag, &mut out);
+        } else {
+            out.push_str(tag);
+        }
+        out.push('>');
+        rest = &rest[start + len + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Append the start tag `tag` (without its `>`) of an external link to
+/// `out`, with `external-link` merged into any `class` it already has.
+fn mark_external_link(tag: &str, out: &mut String) {
+    match attribute_value(tag, "class") {
+        Some(class)
+            if tag[class.clone()]
+                .split_whitespace()
+                .any(|c| c == "external-link") =>
+        {
+            out.push_str(tag)
+        }
+        Some(class) => {
+            out.push_str(&tag[..class.end]);
+            if !tag[class.clone()].trim().is_empty() {
+                out.push(' ');
+            }
+            out.push_str("external-link");
+            out.push_str(&tag[class.end..]);
+        }
+        None => {
+            out.push_str(tag);
+            out.push_str(r#" class="external-link""#);
+        }
+    }
+    if attribute_value(tag, "target").is_none() {
+        out.push_str(r#" target="_blank""#);
+    }
+    if attribute_value(tag, "rel").is_none() {
+        out.push_str(r#" rel="noopener""#);
+    }
+}
+
+/// Byte range of the quoted value of attribute `name` in the start tag
+/// `tag`, matched case-insensitively.
+fn attribute_value(tag: &str, name: &str) -> Option<std::ops::Range<usize>> {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(found) = lower[from..].find(name) {
+        let at = from + found;
+        from = at + name.len();
+        if !lower[..at].ends_with(char::is_whitespace) {
+            continue;
+        }
+        let after = lower[from..].trim_start();
+        let Some(value) = after.strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let value_start = tag.len() - value.len() + 1;
+        let value_len = tag[value_start..].find(quote)?;
+        return Some(value_start..value_start + value_len);
+    }
+    None
+}
+
+/// A problem a stage found in a document, e.g. a lint warning.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// 1-based line in the markdown source.
+    pub line: usize,
+    /// Short identifier of the check that reported it, e.g. `long-sentence`.
+    pub rule: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(line: usize, rule: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            rule: rule.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// What to add to `text` so it ends in a blank line. Never more than one:
+/// the parser makes a list loose when two blank lines follow it.
+pub(crate) fn blank_line_after(text: &str) -> &'static str {
+    let mut lines = text.rsplit('\n');
+    match (lines.next(), lines.next()) {
+        (None | Some(""), None) => "",
+        (Some(last), Some(previous)) if last.trim().is_empty() && previous.trim().is_empty() => "",
+        (Some(last), _) if last.trim().is_empty() => "\n",
+        _ => "\n\n",
+    }
+}
+
+/// Per-document state shared by the stages of a [`RenderPipeline`].
+#[derive(Debug, Default)]
+pub struct RenderContext {
+    options: RenderOptions,
+    features: BTreeSet<String>,
+    diagnostics: Vec<Diagnostic>,
+    headings: Vec<Heading>,
+}
+
+impl RenderContext {
+    /// The options the document is being rendered with.
+    pub fn options(&self) -> &RenderOptions {
+        &self.options
+    }
+
+    /// Mark the document as needing a page-level feature (e.g. a client
+    /// script). Features are exposed to the template.
+    pub fn enable_feature(&mut self, name: impl Into<String>) {
+        self.features.insert(name.into());
+    }
+
+    pub fn has_feature(&self, name: &str) -> bool {
+        self.features.contains(name)
+    }
+
+    /// Record a problem found in the document. Diagnostics are returned
+    /// with the rendered document.
+    pub fn report(&mut self, diagnostic: Diagnostic) {
+        self.diagnostics.push(diagnostic);
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Add a heading to the document's table of contents, in document
+    /// order.
+    pub fn add_heading(&mut self, heading: Heading) {
+        self.headings.push(heading);
+    }
+
+    pub fn headings(&self) -> &[Heading] {
+        &self.headings
+    }
+}
+
+/// A transform registered into the render pipeline.
+///
+/// Both hooks default to passing their input through, so a stage only
+/// implements the side it cares about.
+pub trait RenderStage: Send + Sync {
+    /// Rewrite the markdown source before it is parsed.
+    fn pre_parse(&self, source: String, _ctx: &mut RenderContext) -> String {
+        source
+    }
+
+    /// Rewrite the HTML fragment produced by the parser.
+    fn post_html(&self, html: String, _ctx: &mut RenderContext) -> String {
+        html
+    }
+}
+
+/// Output of [`RenderPipeline::render`].
+#[derive(Clone, Debug, Default)]
+pub struct RenderedDocument {
+    pub html: String,
+    pub features: BTreeSet<String>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Table of contents: the headings given an `id`, in document order.
+    pub headings: Vec<Heading>,
+    /// Keys from the document's frontmatter. Empty when frontmatter is off.
+    pub metadata: Metadata,
+}
+
+impl RenderedDocument {
+    pub fn has_feature(&self, name: &str) -> bool {
+        self.features.contains(name)
+    }
+}
+
+/// Ordered list of [`RenderStage`]s wrapped around the markdown parser.
+///
+/// The default pipeline contains mdserve's built-in stages; stages pushed
+/// later run after them, in registration order.
+#[derive(Clone)]
+pub struct RenderPipeline {
+    stages: Vec<Arc<dyn RenderStage>>,
+}
+
+impl Default for RenderPipeline {
+    fn default() -> Self {
+        Self {
+            stages: vec![
+                Arc::new(AlertStage),
+                Arc::new(DefinitionListStage),
+                Arc::new(EmojiStage),
+                Arc::new(ServerMathStage),
+                Arc::new(HighlightStage),
+                Arc::new(ExternalLinkStage),
+                Arc::new(MermaidStage),
+                Arc::new(MathStage),
+                Arc::new(HeadingIdStage),
+                Arc::new(ContainerStage),
+            ],
+        }
+    }
+}
+
+impl RenderPipeline {
+    /// A pipeline without the built-in stages: the parser's HTML, with no
+    /// alerts, definition lists, emoji, server-side math, highlighting or
+    /// external link marks, whatever the options say.
+    pub fn empty() -> Self {
+        Self { stages: Vec::new() }
+    }
+
+    pub fn push(&mut self, stage: impl RenderStage + 'static) {
+        self.stages.push(Arc::new(stage));
+    }
+
+    /// Run `stage` before every other stage, so it sees the source as
+    /// written and the HTML as parsed.
+    pub fn prepend(&mut self, stage: impl RenderStage + 'static) {
+        self.stages.insert(0, Arc::new(stage));
+    }
+
+    pub fn render(&self, content: &str, options: &RenderOptions) -> RenderedDocument {
+        let mut ctx = RenderContext {
+            options: options.clone(),
+            ..Default::default()
+        };
+
+        let mut source = content.to_string();
+        for stage in &self.stages {
+            source = stage.pre_parse(source, &mut ctx);
+        }
+
+        let mut html = render_marked(&source, content, options);
+        for stage in &self.stages {
+            html = stage.post_html(html, &mut ctx);
+        }
+        if options.source_positions {
+            html = sourcepos::apply_marks(&html);
+        }
+
+        RenderedDocument {
+            html,
+            features: ctx.features,
+            diagnostics: ctx.diagnostics,
+            headings: ctx.headings,
+            metadata: if options.frontmatter {
+                frontmatter::parse(content)
+            } else {
+                Metadata::new()
+            },
+        }
+    }
+}
+
+struct AlertStage;
+
+impl RenderStage for AlertStage {
+    fn post_html(&self, html: String, ctx: &mut RenderContext) -> String {
+        if ctx.options().alerts {
+            alerts::render_alerts(&html)
+        } else {
+            html
+        }
+    }
+}
+
+struct DefinitionListStage;
+
+impl RenderStage for DefinitionListStage {
+    fn post_html(&self, html: String, ctx: &mut RenderContext) -> String {
+        if ctx.options().definition_lists {
+            deflists::render_definition_lists(&html)
+        } else {
+            html
+        }
+    }
+}
+
+struct EmojiStage;
+
+impl RenderStage for EmojiStage {
+    fn post_html(&self, html: String, ctx: &mut RenderContext) -> String {
+        if ctx.options().emoji {
+            emoji::render_emoji(&html)
+        } else {
+            html
+        }
+    }
+}
+
+/// Turns math into MathML with [`MathMode::Server`]; runs before
+/// [`MathStage`] so the page is still flagged.
+struct ServerMathStage;
+
+impl RenderStage for ServerMathStage {
+    fn post_html(&self, html: String, ctx: &mut RenderContext) -> String {
+        if ctx.options().math == MathMode::Server {
+            math::render_math(&html)
+        } else {
+            html
+        }
+    }
+}
+
+struct HighlightStage;
+
+impl RenderStage for HighlightStage {
+    fn post_html(&self, html: String, ctx: &mut RenderContext) -> String {
+        if ctx.options().highlight {
+            highlight::highlight_code_blocks(&html)
+        } else {
+            html
+        }
+    }
+}
+
+struct ExternalLinkStage;
+
+impl RenderStage for ExternalLinkStage {
+    fn post_html(&self, html: String, ctx: &mut RenderContext) -> String {
+        if ctx.options().external_links {
+            mark_external_links(&html)
+        } else {
+            html
+        }
+    }
+}
+
+struct MermaidStage;
+
+impl RenderStage for MermaidStage {
+    fn post_html(&self, html: String, ctx: &mut RenderContext) -> String {
+        if html.contains(r#"class="language-mermaid""#) {
+            ctx.enable_feature(MERMAID_FEATURE);
+        }
+        html
+    }
+}
+
+struct MathStage;
+
+impl RenderStage for MathStage {
+    fn post_html(&self, html: String, ctx: &mut RenderContext) -> String {
+        if html.contains("<math") || html.contains("class=\"language-math") {
+            ctx.enable_feature(MATH_FEATURE);
+        }
+        html
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_options_render_gfm_and_html() {
+        let html = markdown_to_html(
+            "| a |\n|---|\n| b |\n\n<span>raw</span>",
+            &RenderOptions::default(),
+        );
+
+        assert!(html.contains("<table>"));
+        assert!(html.contains("<span>raw</span>"));
+    }
+
+    #[test]
+    fn test_disabling_gfm_renders_commonmark() {
+        let options = RenderOptions {
+            gfm: false,
+            ..Default::default()
+        };
+        let html = markdown_to_html("~~gone~~", &options);
+
+        assert!(!html.contains("<del>"));
+    }
+
+    #[test]
+    fn test_disabling_dangerous_html_escapes_tags() {
+        let options = RenderOptions {
+            allow_dangerous_html: false,
+            ..Default::default()
+        };
+        let html = markdown_to_html("<script>alert(1)</script>", &options);
+
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;"));
+    }
+
+    #[test]
+    fn test_individual_gfm_toggles() {
+        let options = RenderOptions {
+            tables: false,
+            strikethrough: false,
+            ..Default::default()
+        };
+        let html = markdown_to_html("| a |\n|---|\n| b |\n\n~~x~~ - [ ] y", &options);
+
+        assert!(!html.contains("<table>"));
+        assert!(!html.contains("<del>"));
+
+        let html = markdown_to_html("- [x] done\n\nhttps://example.com", &options);
+        assert!(html.contains(r#"type="checkbox""#));
+        assert!(html.contains(r#"<a href="https://example.com""#));
+    }
+
+    #[test]
+    fn test_task_lists_footnotes_and_definitions_render_like_github() {
+        let html = markdown_to_html(
+            "- [x] done\n- [ ] todo\n\n1. [ ] loose\n\n2. item\n\nNote[^1]\n\nTerm\n: Definition\n\n[^1]: Footnote.\n",
+            &RenderOptions::default(),
+        );
+
+        assert!(html.contains(
+            "<li class=\"task-list-item\"><input type=\"checkbox\" class=\"task-list-item-checkbox\" disabled=\"\" checked=\"\" /> done</li>"
+        ));
+        assert!(html.contains(
+            "<li class=\"task-list-item\">\n<p><input type=\"checkbox\" class=\"task-list-item-checkbox\" disabled=\"\" /> loose</p>"
+        ));
+        assert!(html.contains("<li>\n<p>item</p>"));
+        assert!(html.contains("<dl>\n<dt>Term</dt>\n<dd>Definition</dd>\n</dl>"));
+        assert!(html.contains(r#"<section data-footnotes="" class="footnotes">"#));
+        assert!(html.contains(r##"<a href="#user-content-fnref-1" data-footnote-backref="""##));
+
+        let options = RenderOptions {
+            definition_lists: false,
+            ..Default::default()
+        };
+        assert!(!markdown_to_html("Term\n: Definition", &options).contains("<dl>"));
+    }
+
+    #[test]
+    fn test_frontmatter_toggle() {
+        let content = "---\ntitle: Post\n---\n\n# Post\n";
+
+        let hidden = markdown_to_html(content, &RenderOptions::default());
+        assert!(!hidden.contains("title: Post"));
+
+        let options = RenderOptions {
+            frontmatter: false,
+            ..Default::default()
+        };
+        let shown = markdown_to_html(content, &options);
+        assert!(shown.contains("title: Post"));
+
+        let options = RenderOptions {
+            show_frontmatter: true,
+            highlight: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            markdown_to_html(content, &options),
+            "<pre class=\"frontmatter\"><code class=\"language-yaml\">title: Post\n</code></pre>\n\
+             <h1 id=\"post\">Post</h1>\n"
+        );
+        assert_eq!(
+            split_frontmatter("+++\na = 1\n+++"),
+            Some(Frontmatter {
+                language: "toml",
+                body: "a = 1\n"
+            })
+        );
+        assert_eq!(split_frontmatter("---\nnot closed\n"), None);
+    }
+
+    #[test]
+    fn test_external_links_open_in_new_tab() {
+        let content =
+            "[Docs](https://example.com \"Docs\") and [local](other.md) and <http://a.example>";
+
+        let html = markdown_to_html(content, &RenderOptions::default());
+        assert_eq!(
+            html,
+            "<p><a href=\"https://example.com\" title=\"Docs\" class=\"external-link\" target=\"_blank\" rel=\"noopener\">Docs</a> and <a href=\"other.md\">local</a> and <a href=\"http://a.example\" class=\"external-link\" target=\"_blank\" rel=\"noopener\">http://a.example</a></p>"
+        );
+
+        let options = RenderOptions {
+            external_links: false,
+            ..Default::default()
+        };
+        let html = markdown_to_html(content, &options);
+        assert!(!html.contains("target="));
+
+        // Raw HTML links keep their attributes, with the class merged in
+        assert_eq!(
+            mark_external_links(
+                "<a class=\"x\" href=\"https://a.example\">a</a> \
+                 <a href='https://b.example' CLASS='' target=\"_self\">b</a>"
+            ),
+            "<a class=\"x external-link\" href=\"https://a.example\" target=\"_blank\" rel=\"noopener\">a</a> \
+             <a href='https://b.example' CLASS='external-link' target=\"_self\" rel=\"noopener\">b</a>"
+        );
+    }
+
+    #[test]
+    fn test_server_math_renders_mathml() {
+        let content = "Area grows as $x^2$.\n\n$$\n\\frac{1}{2}\n$$\n";
+
+        let html = markdown_to_html(content, &RenderOptions::default());
+        assert_eq!(
+            html,
+            "<p>Area grows as $x^2$.</p>\n<p>$$\n\\frac{1}{2}\n$$</p>\n"
+        );
+
+        let options = RenderOptions {
+            math: MathMode::Server,
+            ..Default::default()
+        };
+        let html = markdown_to_html(content, &options);
+        assert!(html.contains("<msup><mi>x</mi><mn>2</mn></msup>"));
+        assert!(html.contains("display=\"block\"><semantics><mrow><mfrac>"));
+        assert!(!html.contains("<code"));
+    }
+
+    #[test]
+    fn test_client_math_is_left_for_katex() {
+        let options = RenderOptions {
+            math: MathMode::Client,
+            ..Default::default()
+        };
+        let doc = RenderPipeline::default().render("Area grows as $x^2$.", &options);
+        assert_eq!(
+            doc.html,
+            "<p>Area grows as <code class=\"language-math math-inline\">x^2</code>.</p>"
+        );
+        assert!(doc.has_feature(MATH_FEATURE));
+
+        let doc = RenderPipeline::default().render("No math here.", &options);
+        assert!(!doc.has_feature(MATH_FEATURE));
+    }
+
+    struct Shout;
+
+    impl RenderStage for Shout {
+        fn pre_parse(&self, source: String, _ctx: &mut RenderContext) -> String {
+            source.replace("hello", "HELLO")
+        }
+
+        fn post_html(&self, html: String, ctx: &mut RenderContext) -> String {
+            ctx.enable_feature("shout");
+            html.replace("<p>", "<p class=\"shout\">")
+        }
+    }
+
+    #[test]
+    fn test_pipeline_runs_registered_stages() {
+        let mut pipeline = RenderPipeline::default();
+        pipeline.push(Shout);
+        let doc = pipeline.render("hello", &RenderOptions::default());
+
+        assert_eq!(doc.html, "<p class=\"shout\">HELLO</p>");
+        assert!(doc.has_feature("shout"));
+        assert!(!doc.has_feature(MERMAID_FEATURE));
+    }
+
+    #[test]
+    fn test_builtin_stage_detects_mermaid() {
+        let content = "```mermaid\ngraph TD\n```\n";
+
+        let doc = RenderPipeline::default().render(content, &RenderOptions::default());
+        assert!(doc.has_feature(MERMAID_FEATURE));
+
+        let doc = RenderPipeline::empty().render(content, &RenderOptions::default());
+        assert!(!doc.has_feature(MERMAID_FEATURE));
+    }
+
+    #[test]
+    fn test_empty_pipeline_skips_builtin_passes() {
+        let content = "> [!NOTE]\n> Note\n\nTerm\n: Definition\n\n:tada: $a$ [out](https://example.com)\n\n```rust\nfn main() {}\n```\n";
+        let options = RenderOptions {
+            math: MathMode::Server,
+            ..Default::default()
+        };
+
+        let html = RenderPipeline::default().render(content, &options).html;
+        assert!(html.contains("markdown-alert"));
+        assert!(html.contains("<dl>"));
+        assert!(html.contains('🎉'));
+        assert!(html.contains("<math"));
+        assert!(html.contains("hl-keyword"));
+        assert!(html.contains("external-link"));
+
+        let html = RenderPipeline::empty().render(content, &options).html;
+        assert!(!html.contains("markdown-alert"));
+        assert!(!html.contains("<dl>"));
+        assert!(html.contains(":tada:"));
+        assert!(!html.contains("<math"));
+        assert!(!html.contains("hl-"));
+        assert!(!html.contains("external-link"));
+    }
+
+    #[cfg(feature = "pulldown-cmark")]
+    const BACKEND_FIXTURE: &str = "---\ntitle: Fixture\n---\n\n# Title\n\nSome *emphasis*, `code` and [a link](https://example.com).\n\n- [x] done\n- [ ] todo\n\n| a | b |\n|---|---|\n| 1 | ~~2~~ |\n\n```rust\nfn main() {}\n```\n\n> [!NOTE]\n> An alert.\n\nTerm\n: Definition\n\nInline $a^2$ math.\n";
+
+    #[cfg(feature = "pulldown-cmark")]
+    #[test]
+    fn test_backends_render_the_same_fixture() {
+        let render = |backend| {
+            let options = RenderOptions {
+                backend,
+                math: MathMode::Server,
+                ..Default::default()
+            };
+            // pulldown-cmark puts table rows on one line
+            markdown_to_html(BACKEND_FIXTURE, &options).replace('\n', "")
+        };
+
+        let markdown = render(MarkdownBackend::Markdown);
+        assert_eq!(markdown, render(MarkdownBackend::PulldownCmark));
+        assert!(markdown.contains("<li class=\"task-list-item\"><input type=\"checkbox\""));
+        assert!(markdown.contains("<del>2</del>"));
+        assert!(markdown.contains("<annotation encoding=\"application/x-tex\">a^2</annotation>"));
+        assert!(!markdown.contains("Fixture"));
+    }
+
+    #[cfg(feature = "pulldown-cmark")]
+    #[test]
+    fn test_pulldown_cmark_escapes_html_unless_allowed() {
+        let options = RenderOptions {
+            backend: MarkdownBackend::PulldownCmark,
+            allow_dangerous_html: false,
+            ..Default::default()
+        };
+        let html = markdown_to_html("<div>raw</div>\n\nText <b>bold</b>", &options);
+        assert!(html.contains("&lt;div&gt;raw&lt;/div&gt;"));
+        assert!(html.contains("Text &lt;b&gt;bold&lt;/b&gt;"));
+
+        let options = RenderOptions {
+            allow_dangerous_html: true,
+            ..options
+        };
+        assert!(markdown_to_html("Text <b>bold</b>", &options).contains("<b>bold</b>"));
+    }
+}
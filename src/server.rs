@@ -0,0 +1,268 @@
+//! HTTP/1 and HTTP/2 connection handling, plain or over TLS, with a cap on
+//! concurrent connections and an idle timeout for keep-alive connections.
+
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader},
+    net::TcpListener,
+    sync::{watch, Semaphore},
+};
+use tokio_rustls::TlsAcceptor;
+
+/// How long to back off when accepting fails, e.g. when out of file
+/// descriptors, instead of spinning on the error.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(50);
+
+/// Limits on the connections a server keeps open.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ConnectionLimits {
+    /// Most connections served at once. Further clients wait in the listen
+    /// backlog until a connection closes.
+    pub max_connections: Option<usize>,
+    /// How long a connection may sit idle between requests (or take to send
+    /// request headers) before it is closed. WebSockets are not affected
+    /// once upgraded.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            idle_timeout: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+/// Serve `router` on `listener` until `shutdown` turns true, then let open
+/// connections finish their in-flight requests. With `tls`, connections are
+/// served over TLS once the handshake completes.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    router: Router,
+    limits: ConnectionLimits,
+    tls: Option<TlsAcceptor>,
+    mut shutdown: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    let permits = limits
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max.max(1))));
+    // Every connection task holds a receiver; all are gone once they finish
+    let (done_tx, done_rx) = watch::channel(());
+
+    loop {
+        let permit = match &permits {
+            Some(permits) => tokio::select! {
+                permit = permits.clone().acquire_owned() => {
+                    Some(permit.expect("connection semaphore is never closed"))
+                }
+                _ = stopped(&mut shutdown) => break,
+            },
+            None => None,
+        };
+
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(_) => {
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            },
+            _ = stopped(&mut shutdown) => break,
+        };
+
+        let service = TowerToHyperService::new(router.clone());
+        let mut shutdown = shutdown.clone();
+        let done_rx = done_rx.clone();
+        let tls = tls.clone();
+
+        tokio::spawn(async move {
+            match tls {
+                Some(acceptor) => {
+                    // A client stalling the handshake counts as idle
+                    let handshake = async {
+                        match limits.idle_timeout {
+                            Some(timeout) => tokio::time::timeout(timeout, acceptor.accept(stream))
+                                .await
+                                .ok()?
+                                .ok(),
+                            None => acceptor.accept(stream).await.ok(),
+                        }
+                    };
+                    let stream = tokio::select! {
+                        stream = handshake => stream,
+                        _ = stopped(&mut shutdown) => None,
+                    };
+                    if let Some(stream) = stream {
+                        serve_connection(stream, service, limits, &mut shutdown).await;
+                    }
+                }
+                None => serve_connection(stream, service, limits, &mut shutdown).await,
+            }
+
+            drop(permit);
+            drop(done_rx);
+        });
+    }
+
+    drop(listener);
+    drop(done_rx);
+    done_tx.closed().await;
+    Ok(())
+}
+
+/// Serve HTTP/1 or HTTP/2 requests on one connection, whichever the client
+/// speaks, until the client is done or shutdown is requested.
+async fn serve_connection<I>(
+    io: I,
+    service: TowerToHyperService<Router>,
+    limits: ConnectionLimits,
+    shutdown: &mut watch::Receiver<bool>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // The protocol is told apart by the first bytes, which hyper waits for
+    // without a timeout, so a client that never sends any is idle here
+    let mut io = BufReader::new(io);
+    let first_bytes = async {
+        match limits.idle_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, io.fill_buf())
+                .await
+                .is_ok_and(|read| read.is_ok_and(|bytes| !bytes.is_empty())),
+            None => io.fill_buf().await.is_ok_and(|bytes| !bytes.is_empty()),
+        }
+    };
+    let sent = tokio::select! {
+        sent = first_bytes => sent,
+        _ = stopped(shutdown) => false,
+    };
+    if !sent {
+        return;
+    }
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(limits.idle_timeout);
+    builder.http2().timer(TokioTimer::new());
+    let conn = builder.serve_connection_with_upgrades(TokioIo::new(io), service);
+    tokio::pin!(conn);
+
+    tokio::select! {
+        _ = conn.as_mut() => {}
+        _ = stopped(shutdown) => {
+            conn.as_mut().graceful_shutdown();
+            let _ = conn.await;
+        }
+    }
+}
+
+/// Resolves once shutdown is requested. A dropped sender never requests
+/// it, so servers whose handle was dropped keep running.
+async fn stopped(shutdown: &mut watch::Receiver<bool>) {
+    if shutdown.wait_for(|stop| *stop).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    #[tokio::test]
+    async fn test_connection_limit_and_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        let limits = ConnectionLimits {
+            max_connections: Some(1),
+            idle_timeout: Some(Duration::from_millis(300)),
+        };
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(serve(listener, router, limits, None, shutdown_rx));
+
+        // An idle client takes the only slot
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        let mut waiting = TcpStream::connect(addr).await.unwrap();
+        waiting
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        let early = tokio::time::timeout(
+            Duration::from_millis(100),
+            waiting.read_to_string(&mut response),
+        )
+        .await;
+        assert!(early.is_err(), "second connection served over the limit");
+
+        // The idle connection times out, freeing the slot
+        let mut buf = [0u8; 1];
+        let closed = tokio::time::timeout(Duration::from_secs(5), idle.read(&mut buf))
+            .await
+            .expect("idle connection was not closed");
+        assert_eq!(closed.unwrap(), 0);
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            waiting.read_to_string(&mut response),
+        )
+        .await
+        .expect("waiting connection was not served")
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("ok"));
+
+        shutdown_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server did not shut down")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serves_http2() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        tokio::spawn(serve(
+            listener,
+            router,
+            ConnectionLimits::default(),
+            None,
+            shutdown_rx,
+        ));
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+        let request = axum::http::Request::get("http://localhost/")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.version(), axum::http::Version::HTTP_2);
+        let body = axum::body::to_bytes(axum::body::Body::new(response.into_body()), 16)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"ok");
+    }
+}
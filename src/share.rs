@@ -0,0 +1,361 @@
+//! Share links: signed, expiring URLs that open one document, and the
+//! assets it references, without the server's credentials. Links are
+//! minted by the server that serves the document, from the browser's
+//! download menu or from `mdserve share`.
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    http::{header, Request},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{app::format_host, client, paths};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SECRET_FILE_NAME: &str = "share-secret";
+const SECRET_LEN: usize = 32;
+/// Responses to a share request are tiny; anything bigger isn't an mdserve.
+const MAX_RESPONSE: usize = 4096;
+
+/// How long share links minted from the browser stay valid.
+pub(crate) const DEFAULT_SHARE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Directory holding mdserve's per-user files (`$XDG_CONFIG_HOME/mdserve`,
+/// `~/.config/mdserve` or `%APPDATA%\mdserve`).
+pub(crate) fn config_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            if cfg!(windows) {
+                std::env::var_os("APPDATA").map(PathBuf::from)
+            } else {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+            }
+        })?;
+    Some(base.join("mdserve"))
+}
+
+/// Load the share-link signing secret, creating it on first use.
+///
+/// The secret lives in the user's config directory so links outlive the
+/// server that minted them.
+pub(crate) fn load_or_create_secret() -> Result<Vec<u8>> {
+    let dir = config_dir().context("could not determine config directory")?;
+    let path = dir.join(SECRET_FILE_NAME);
+
+    if let Ok(secret) = fs::read(&path) {
+        if secret.len() >= SECRET_LEN {
+            return Ok(secret);
+        }
+    }
+
+    fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let secret = random_secret();
+    write_private(&path, &secret).with_context(|| format!("failed to write {}", path.display()))?;
+
+    Ok(secret)
+}
+
+/// The secret for links to files under `root`, derived from the user's
+/// `secret`, so a link minted for one served tree is rejected by servers
+/// for any other.
+pub(crate) fn root_secret(secret: &[u8], root: &Path) -> Vec<u8> {
+    mac(secret, root.to_string_lossy().as_bytes())
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+/// A fresh secret that is never persisted, for when the config directory
+/// is unavailable. Links minted with it die with the server.
+pub(crate) fn random_secret() -> Vec<u8> {
+    (0..SECRET_LEN).map(|_| rand::random::<u8>()).collect()
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    fs::write(path, contents)
+}
+
+/// Mint a token granting access to `filename` until `ttl` from now.
+///
+/// Tokens have the form `<base64url(filename)>.<expiry>.<signature>` so a
+/// request for an asset under the share prefix can still be tied back to
+/// the shared document.
+pub(crate) fn mint_token(secret: &[u8], filename: &str, ttl: Duration) -> String {
+    let expires = unix_now().saturating_add(ttl.as_secs());
+    let signature = sign(secret, filename, expires);
+
+    format!(
+        "{}.{expires}.{signature}",
+        URL_SAFE_NO_PAD.encode(filename.as_bytes())
+    )
+}
+
+/// Verify a token and return the document name it grants access to.
+pub(crate) fn verify_token(secret: &[u8], token: &str) -> Option<String> {
+    let mut parts = token.split('.');
+    let encoded_name = parts.next()?;
+    let expires: u64 = parts.next()?.parse().ok()?;
+    let signature = parts.next()?;
+    if parts.next().is_some() || expires < unix_now() {
+        return None;
+    }
+
+    let filename = String::from_utf8(URL_SAFE_NO_PAD.decode(encoded_name).ok()?).ok()?;
+    let signature = decode_hex(signature)?;
+    mac(secret, signed_message(&filename, expires).as_bytes())
+        .verify_slice(&signature)
+        .ok()
+        .map(|()| filename)
+}
+
+/// Ask the server at `hostname:port` for a share link to `file`, valid for
+/// `ttl`. Fails unless that server serves `file`. Links are signed with a
+/// secret scoped to what that server serves, so only it can mint them.
+/// `tls` is set for servers serving HTTPS, and `token` is the server's
+/// access token, if any.
+pub async fn share_url(
+    file: &Path,
+    ttl: Duration,
+    hostname: &str,
+    port: u16,
+    tls: bool,
+    token: Option<&str>,
+) -> Result<String> {
+    // The server names the document the way it tracks it
+    let path =
+        paths::canonicalize(file).with_context(|| format!("not found: {}", file.display()))?;
+
+    let scheme = if tls { "https" } else { "http" };
+    let origin = format!("{scheme}://{}", format_host(hostname, port));
+    let body = serde_json::json!({
+        "path": path,
+        "expires_in_secs": ttl.as_secs(),
+    })
+    .to_string();
+    let mut request = Request::post("/api/share").header(header::CONTENT_TYPE, "application/json");
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+    }
+    let request = request.body(Body::from(body))?;
+
+    let response = client::send(hostname, port, tls, request, MAX_RESPONSE)
+        .await
+        .with_context(|| format!("no mdserve answered at {origin}"))?;
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!("{origin} could not share {}: {status}", file.display());
+    }
+    let path = serde_json::from_slice::<serde_json::Value>(response.body())
+        .ok()
+        .and_then(|response| response["path"].as_str().map(str::to_string))
+        .context("unexpected response to the share request")?;
+    Ok(format!("{origin}{path}"))
+}
+
+/// Path under which a shared document is served.
+pub(crate) fn share_path(token: &str, filename: &str) -> String {
+    format!("/share/{token}/{filename}")
+}
+
+/// Parse durations like `90s`, `30m`, `24h` or `7d`. Bare numbers are seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => value.split_at(idx),
+        None => (value, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {value}"))?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid duration unit in {value} (use s, m, h or d)"
+            ))
+        }
+    };
+
+    Ok(Duration::from_secs(number.saturating_mul(multiplier)))
+}
+
+fn sign(secret: &[u8], filename: &str, expires: u64) -> String {
+    mac(secret, signed_message(filename, expires).as_bytes())
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn signed_message(filename: &str, expires: u64) -> String {
+    format!("{filename}\n{expires}")
+}
+
+fn mac(key: &[u8], message: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac
+}
+
+/// Bytes of a lowercase or uppercase hex string.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_roundtrip() {
+        let secret = random_secret();
+        let token = mint_token(&secret, "notes.md", DEFAULT_SHARE_TTL);
+
+        assert_eq!(verify_token(&secret, &token), Some("notes.md".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_share_url_is_minted_by_the_server() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let docs = temp_dir.path().join("docs");
+        fs::create_dir_all(docs.join("notes")).unwrap();
+        let file = docs.join("notes").join("plan.md");
+        fs::write(&file, "# Plan").unwrap();
+        // Same name, not served
+        let stray = temp_dir.path().join("plan.md");
+        fs::write(&stray, "# Stray").unwrap();
+
+        let server = crate::Mdserve::builder()
+            .base_dir(&docs)
+            .recursive(true)
+            .access_token("s3cret")
+            .watch(false)
+            .serve("127.0.0.1", 0)
+            .await
+            .unwrap();
+        let port = server.local_addr().port();
+
+        let ttl = Duration::from_secs(60);
+        let url = share_url(&file, ttl, "127.0.0.1", port, false, Some("s3cret"))
+            .await
+            .unwrap();
+        let prefix = format!("http://127.0.0.1:{port}/share/");
+        assert!(url.starts_with(&prefix), "{url}");
+        assert!(url.ends_with("/notes/plan.md"), "{url}");
+
+        let untracked = share_url(&stray, ttl, "127.0.0.1", port, false, Some("s3cret")).await;
+        assert!(untracked.is_err());
+        // Protected servers don't mint links for just anyone
+        assert!(share_url(&file, ttl, "127.0.0.1", port, false, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_share_url_from_a_tls_server() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file = temp_dir.path().join("plan.md");
+        fs::write(&file, "# Plan").unwrap();
+
+        let server = crate::Mdserve::builder()
+            .file(&file)
+            .tls(crate::TlsCertificate::SelfSigned)
+            .watch(false)
+            .serve("127.0.0.1", 0)
+            .await
+            .unwrap();
+        let port = server.local_addr().port();
+
+        let ttl = Duration::from_secs(60);
+        let url = share_url(&file, ttl, "127.0.0.1", port, true, None)
+            .await
+            .unwrap();
+        let prefix = format!("https://127.0.0.1:{port}/share/");
+        assert!(url.starts_with(&prefix), "{url}");
+        assert!(url.ends_with("/plan.md"), "{url}");
+
+        // Plain HTTP gets no answer from it
+        assert!(share_url(&file, ttl, "127.0.0.1", port, false, None)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_token_rejects_other_secret_and_tampering() {
+        let secret = random_secret();
+        let token = mint_token(&secret, "notes.md", DEFAULT_SHARE_TTL);
+
+        assert_eq!(verify_token(&random_secret(), &token), None);
+
+        let other_doc = URL_SAFE_NO_PAD.encode("secret.md");
+        let (_, rest) = token.split_once('.').unwrap();
+        assert_eq!(verify_token(&secret, &format!("{other_doc}.{rest}")), None);
+    }
+
+    #[test]
+    fn test_token_expires() {
+        let secret = random_secret();
+        let encoded = URL_SAFE_NO_PAD.encode("notes.md");
+        let expired = unix_now() - 1;
+        let token = format!("{encoded}.{expired}.{}", sign(&secret, "notes.md", expired));
+
+        assert_eq!(verify_token(&secret, &token), None);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("24h"), Ok(Duration::from_secs(86400)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(604800)));
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("h").is_err());
+    }
+}
@@ -0,0 +1,55 @@
+//! Waiting for Ctrl+C or `SIGTERM`, so the server can tell pages it is
+//! stopping and finish in-flight requests before exiting.
+//!
+//! The handlers only set a flag, which is polled: that is all a signal
+//! handler may safely do. Each restores the default action as it runs, so
+//! a second Ctrl+C stops a shutdown that hangs.
+
+#[cfg(unix)]
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Once,
+};
+#[cfg(unix)]
+use std::time::Duration;
+
+#[cfg(unix)]
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How often the flag is checked.
+#[cfg(unix)]
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[cfg(unix)]
+extern "C" fn request_shutdown(signal: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+    // SAFETY: `signal` is async-signal-safe and the default action is valid
+    // for the signal being handled.
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+    }
+}
+
+/// Resolves once the process is asked to stop with `SIGINT` or `SIGTERM`.
+/// On other platforms it never resolves, and Ctrl+C ends the process as
+/// before.
+pub(crate) async fn shutdown_requested() {
+    #[cfg(unix)]
+    {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // SAFETY: the handler only stores to an atomic and calls
+            // `signal`, both async-signal-safe.
+            unsafe {
+                libc::signal(libc::SIGINT, handler);
+                libc::signal(libc::SIGTERM, handler);
+            }
+        });
+        while !REQUESTED.load(Ordering::SeqCst) {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+    #[cfg(not(unix))]
+    std::future::pending::<()>().await;
+}
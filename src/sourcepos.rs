@@ -0,0 +1,236 @@
+//! Source positions for scroll syncing: each top-level block of a rendered
+//! document gets `data-sourcepos="12:1-15:20"`, the lines and columns it
+//! spans in the file (as cmark's `--sourcepos` writes them), so an editor
+//! can scroll the preview to its cursor.
+//!
+//! Blocks are marked right after parsing, with text that later passes read
+//! past, and the marks become attributes once every stage has run: stages
+//! recognize the parser's plain tags, so attributes can't be added sooner.
+
+use markdown::{mdast::Node, unist::Position};
+
+use crate::render::{escape_html, RenderOptions};
+
+/// Wraps a block's position in the mark put before it. Private use
+/// characters don't occur in documents, and this one isn't lint's.
+const MARKER: char = '\u{E001}';
+
+/// Mark each top-level block of `html`, the parser's output for `source`,
+/// with where it is in `original`, the document before stages rewrote it.
+/// Blocks a stage added are left unmarked, and marking stops at the first
+/// block that can't be lined up with the HTML.
+pub(crate) fn mark_blocks(
+    html: &str,
+    source: &str,
+    original: &str,
+    options: &RenderOptions,
+) -> String {
+    let parse = options.to_markdown_options().parse;
+    let Ok(tree) = markdown::to_mdast(source, &parse) else {
+        return html.to_string();
+    };
+    let blocks = tree.children().map(Vec::as_slice).unwrap_or_default();
+    let positions = if source == original {
+        blocks
+            .iter()
+            .map(|block| block.position().cloned())
+            .collect()
+    } else {
+        original_positions(blocks, source, original, &parse)
+    };
+
+    let mut out = String::with_capacity(html.len());
+    let mut offset = 0;
+    for (block, position) in blocks.iter().zip(positions) {
+        let output = match block {
+            Node::Definition(_) | Node::FootnoteDefinition(_) | Node::Yaml(_) | Node::Toml(_) => {
+                continue
+            }
+            // Raw HTML is copied through, or escaped when it isn't allowed
+            Node::Html(raw) if options.allow_dangerous_html => Some(raw.value.len()),
+            Node::Html(raw) => Some(escape_html(&raw.value).len()),
+            _ => element_len(&html[offset..]),
+        };
+        let start = offset + html[offset..].len() - html[offset..].trim_start().len();
+        let Some(len) = output.filter(|&len| html.len() >= start + len) else {
+            break;
+        };
+        out.push_str(&html[offset..start]);
+        if let (Some(position), false) = (position, matches!(block, Node::Html(_))) {
+            out.push_str(&format!(
+                "{MARKER}{}:{}-{}:{}{MARKER}",
+                position.start.line, position.start.column, position.end.line, position.end.column
+            ));
+        }
+        out.push_str(&html[start..start + len]);
+        offset = start + len;
+    }
+    out.push_str(&html[offset..]);
+    out
+}
+
+/// Positions in `original` of the top-level `blocks` of `source`, found by
+/// matching their text in order. Blocks that aren't in `original` have none.
+fn original_positions(
+    blocks: &[Node],
+    source: &str,
+    original: &str,
+    parse: &markdown::ParseOptions,
+) -> Vec<Option<Position>> {
+    let tree = markdown::to_mdast(original, parse).ok();
+    let originals: Vec<(&str, &Position)> = tree
+        .as_ref()
+        .and_then(Node::children)
+        .into_iter()
+        .flatten()
+        .filter_map(|block| {
+            let position = block.position()?;
+            Some((
+                &original[position.start.offset..position.end.offset],
+                position,
+            ))
+        })
+        .collect();
+
+    let mut next = 0;
+    blocks
+        .iter()
+        .map(|block| {
+            let position = block.position()?;
+            let text = &source[position.start.offset..position.end.offset];
+            let found = originals[next..]
+                .iter()
+                .position(|(original, _)| *original == text)?;
+            next += found + 1;
+            Some(originals[next - 1].1.clone())
+        })
+        .collect()
+}
+
+/// Length of the element `html` starts with, after leading whitespace:
+/// up to its matching closing tag, or its start tag if it has no content.
+fn element_len(html: &str) -> Option<usize> {
+    let trimmed = html.trim_start();
+    let name: String = trimmed
+        .strip_prefix('<')?
+        .chars()
+        .take_while(char::is_ascii_alphanumeric)
+        .collect();
+    if name.is_empty() {
+        return None;
+    }
+    let tag_end = trimmed.find('>')? + 1;
+    if trimmed[..tag_end].ends_with("/>") {
+        return Some(tag_end);
+    }
+
+    let open = format!("<{name}");
+    let close = format!("</{name}>");
+    let mut depth = 1;
+    let mut offset = tag_end;
+    while depth > 0 {
+        let next_close = trimmed[offset..].find(&close)? + offset;
+        let next_open = trimmed[offset..next_close]
+            .match_indices(&open)
+            .find(|(i, _)| trimmed[offset + i + open.len()..].starts_with(['>', ' ', '/']))
+            .map(|(i, _)| offset + i);
+        match next_open {
+            Some(next_open) => {
+                depth += 1;
+                offset = next_open + open.len();
+            }
+            None => {
+                depth -= 1;
+                offset = next_close + close.len();
+            }
+        }
+    }
+    Some(offset)
+}
+
+/// Turn the marks [`mark_blocks`] left into `data-sourcepos` attributes on
+/// the elements they precede. A mark a stage moved away from its element is
+/// dropped.
+pub(crate) fn apply_marks(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(MARKER) {
+        let after = &rest[start + MARKER.len_utf8()..];
+        let Some(len) = after.find(MARKER) else {
+            break;
+        };
+        let position = &after[..len];
+        out.push_str(&rest[..start]);
+        rest = &after[len + MARKER.len_utf8()..];
+
+        let name_len = rest.strip_prefix('<').map_or(0, |tag| {
+            tag.chars().take_while(char::is_ascii_alphanumeric).count()
+        });
+        if name_len > 0 {
+            out.push_str(&rest[..1 + name_len]);
+            out.push_str(&format!(" data-sourcepos=\"{position}\""));
+            rest = &rest[1 + name_len..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{RenderContext, RenderPipeline, RenderStage};
+
+    #[test]
+    fn test_blocks_carry_their_source_lines() {
+        let options = RenderOptions {
+            source_positions: true,
+            ..Default::default()
+        };
+        let html = RenderPipeline::default()
+            .render(
+                "---\ntitle: x\n---\n\n# Title\n\n<div>\nraw\n</div>\n\n- a\n- b\n\n> [!NOTE]\n> Alert\n",
+                &options,
+            )
+            .html;
+
+        assert!(html.contains(r#"<h1 data-sourcepos="5:1-5:8" id="title">Title</h1>"#));
+        assert!(html.contains("<div>\nraw\n</div>"));
+        assert!(html.contains(r#"<ul data-sourcepos="11:1-13:1">"#));
+        assert!(html.contains(r#"<div data-sourcepos="14:1-15:8" class="markdown-alert"#));
+        assert!(!html.contains(MARKER));
+
+        let escaped = RenderOptions {
+            allow_dangerous_html: false,
+            ..options
+        };
+        assert_eq!(
+            crate::render::markdown_to_html("<p title=\"a\">raw</p>\n\ntext", &escaped),
+            "&lt;p title=&quot;a&quot;&gt;raw&lt;/p&gt;\n<p data-sourcepos=\"3:1-3:5\">text</p>"
+        );
+    }
+
+    struct Preamble;
+
+    impl RenderStage for Preamble {
+        fn pre_parse(&self, source: String, _ctx: &mut RenderContext) -> String {
+            format!("Added by a stage\n\n{source}")
+        }
+    }
+
+    #[test]
+    fn test_positions_refer_to_the_file_before_stages() {
+        let options = RenderOptions {
+            source_positions: true,
+            ..Default::default()
+        };
+        let mut pipeline = RenderPipeline::empty();
+        pipeline.push(Preamble);
+
+        assert_eq!(
+            pipeline.render("One\n\nTwo", &options).html,
+            "<p>Added by a stage</p>\n<p data-sourcepos=\"1:1-1:4\">One</p>\n\
+             <p data-sourcepos=\"3:1-3:4\">Two</p>"
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use anyhow::{Context, Result};
+use minijinja::{
+    context, escape_formatter,
+    value::{Object, Value},
+    Environment, Error, Output, State,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use crate::{app::split_folder, frontmatter::Metadata, headings::Heading};
+
+const TEMPLATE_NAME: &str = "main.html";
+static TEMPLATE_ENV: OnceLock<Environment<'static>> = OnceLock::new();
+
+fn builtin_env() -> &'static Environment<'static> {
+    TEMPLATE_ENV.get_or_init(|| {
+        let mut env = Environment::new();
+        env.set_formatter(format_value);
+        minijinja_embed::load_templates!(&mut env);
+        env
+    })
+}
+
+/// Rendered document HTML handed to MiniJinja by reference. Safe strings
+/// can only be made from an owned `String`, which would copy the document
+/// on every request.
+#[derive(Debug)]
+struct SafeHtml(Arc<str>);
+
+impl Object for SafeHtml {
+    fn render(self: &Arc<Self>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The default formatter, except that [`SafeHtml`] is written unescaped.
+fn format_value(out: &mut Output, state: &State, value: &Value) -> Result<(), Error> {
+    match value.downcast_object_ref::<SafeHtml>() {
+        Some(html) => out.write_str(&html.0).map_err(Error::from),
+        None => escape_formatter(out, state, value),
+    }
+}
+
+/// Everything a page template gets to render one document.
+#[derive(Debug, Serialize)]
+#[non_exhaustive]
+pub struct PageContext<'a> {
+    /// Rendered document HTML. Already safe; do not escape it.
+    pub content: &'a Arc<str>,
+    /// Title derived from the file name.
+    pub page_title: &'a str,
+    pub current_file: &'a str,
+    /// Tracked files for the navigation sidebar, sorted. Empty when
+    /// navigation is hidden.
+    pub files: &'a [String],
+    /// Sidebar labels for the files that have a title, by name. Empty
+    /// unless titles are used for labels.
+    pub file_titles: &'a BTreeMap<String, String>,
+    pub show_navigation: bool,
+    /// Whether the document contains Mermaid diagrams.
+    pub mermaid_enabled: bool,
+    /// Version of the bundled Mermaid, served with long-lived caching at
+    /// `{base_path}/assets/mermaid-{mermaid_version}.min.js`.
+    pub mermaid_version: &'a str,
+    /// Whether the page loads the bundled KaTeX to typeset its math.
+    pub katex_enabled: bool,
+    /// Version of the bundled KaTeX, served like Mermaid at
+    /// `{base_path}/assets/katex-{katex_version}.min.js`.
+    pub katex_version: &'a str,
+    /// Feature flags set by render stages.
+    pub features: &'a BTreeSet<String>,
+    /// The page is being viewed through a share link.
+    pub shared: bool,
+    /// The page is written to a static site: there is no server for live
+    /// reload, downloads or link previews.
+    pub exported: bool,
+    /// Headings for the document outline. Empty when the table of contents
+    /// is off.
+    pub toc: &'a [Heading],
+    /// Mount point prefix for generated URLs (empty at the root).
+    pub base_path: &'a str,
+    /// Show the source editor next to the preview. Never set for shared
+    /// views.
+    pub edit: bool,
+    /// Stylesheet colouring highlighted code.
+    pub highlight_css: &'a str,
+    /// Keys from the document's frontmatter (`title`, `author`, `date`, …).
+    pub metadata: &'a Metadata,
+    /// Options passed to `mermaid.initialize`, e.g. `theme`. Empty keeps
+    /// diagrams following the page theme.
+    pub mermaid_config: &'a Metadata,
+}
+
+/// Turns a rendered document into a full HTML page.
+///
+/// The built-in implementation uses MiniJinja and `templates/main.html`.
+/// Embedders can implement this to render pages however they like.
+pub trait PageTemplate: Send + Sync {
+    fn render(&self, page: &PageContext<'_>) -> Result<String>;
+}
+
+/// A MiniJinja template receiving the [`PageContext`] fields as variables.
+pub struct MiniJinjaTemplate {
+    env: Option<Environment<'static>>,
+}
+
+impl MiniJinjaTemplate {
+    /// The page template shipped with mdserve.
+    pub fn builtin() -> Self {
+        Self { env: None }
+    }
+
+    /// Compile a template from source.
+    pub fn from_source(source: impl Into<String>) -> Result<Self> {
+        let env = compile([(TEMPLATE_NAME.to_string(), source.into())])
+            .context("invalid custom template")?;
+        Ok(Self { env: Some(env) })
+    }
+}
+
+impl PageTemplate for MiniJinjaTemplate {
+    fn render(&self, page: &PageContext<'_>) -> Result<String> {
+        render_page(self.env.as_ref().unwrap_or_else(|| builtin_env()), page)
+    }
+}
+
+/// An environment holding `templates`, given as name and source.
+fn compile(templates: impl IntoIterator<Item = (String, String)>) -> Result<Environment<'static>> {
+    let mut env = Environment::new();
+    env.set_formatter(format_value);
+    for (name, source) in templates {
+        // Templates borrow their source for the environment's lifetime.
+        // Templates are compiled once per process, or per edit of a
+        // FileTemplate, so leaking the source is the simplest way to get a
+        // 'static environment without MiniJinja's loader feature.
+        let name: &'static str = Box::leak(name.into_boxed_str());
+        let source: &'static str = Box::leak(source.into_boxed_str());
+        env.add_template(name, source)
+            .with_context(|| format!("invalid template {name}"))?;
+    }
+    Ok(env)
+}
+
+/// MiniJinja templates read from disk, re-read with [`reload`] when they
+/// change.
+///
+/// Either a single template file, or a directory whose `main.html` renders
+/// pages and can `include` or `extend` the other templates in it by their
+/// path within the directory.
+///
+/// [`reload`]: FileTemplate::reload
+pub struct FileTemplate {
+    path: PathBuf,
+    env: RwLock<Arc<Environment<'static>>>,
+}
+
+impl FileTemplate {
+    /// Read the template file or directory at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path
+            .as_ref()
+            .canonicalize()
+            .with_context(|| format!("template not found: {}", path.as_ref().display()))?;
+        let env = load_env(&path)?;
+        Ok(Self {
+            path,
+            env: RwLock::new(Arc::new(env)),
+        })
+    }
+
+    /// The template file or directory, canonicalized.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the templates again. If that fails, the previous ones stay in
+    /// use. Each reload keeps the old sources in memory, which is fine for
+    /// the edits of a preview session.
+    pub fn reload(&self) -> Result<()> {
+        let env = load_env(&self.path)?;
+        *self.env.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(env);
+        Ok(())
+    }
+}
+
+impl PageTemplate for FileTemplate {
+    fn render(&self, page: &PageContext<'_>) -> Result<String> {
+        let env = self.env.read().unwrap_or_else(|e| e.into_inner()).clone();
+        render_page(&env, page)
+    }
+}
+
+fn load_env(path: &Path) -> Result<Environment<'static>> {
+    if !path.is_dir() {
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        return compile([(TEMPLATE_NAME.to_string(), source)]);
+    }
+
+    let mut templates = Vec::new();
+    read_templates(path, "", &mut templates)?;
+    if !templates.iter().any(|(name, _)| name == TEMPLATE_NAME) {
+        anyhow::bail!("no {TEMPLATE_NAME} in {}", path.display());
+    }
+    compile(templates)
+}
+
+/// Collect the templates under `dir` as `(name, source)`, named by their
+/// `/`-separated path after `prefix`. Hidden entries and files that aren't
+/// UTF-8 text are skipped.
+fn read_templates(dir: &Path, prefix: &str, templates: &mut Vec<(String, String)>) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let Some(file_name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if file_name.starts_with('.') {
+            continue;
+        }
+        let name = format!("{prefix}{file_name}");
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            read_templates(&entry.path(), &format!("{name}/"), templates)?;
+        } else if let Ok(source) = fs::read_to_string(entry.path()) {
+            templates.push((name, source));
+        }
+    }
+    Ok(())
+}
+
+fn render_page(env: &Environment<'static>, page: &PageContext<'_>) -> Result<String> {
+    let template = env.get_template(TEMPLATE_NAME).context("Template error")?;
+
+    let files: Vec<Value> = page
+        .files
+        .iter()
+        .map(|name| file_node(name, page.file_titles))
+        .collect();
+
+    template
+        .render(context! {
+            content => Value::from_object(SafeHtml(page.content.clone())),
+            file_tree => file_tree(page.files, page.file_titles, page.current_file),
+            mermaid_enabled => page.mermaid_enabled,
+            mermaid_version => page.mermaid_version,
+            katex_enabled => page.katex_enabled,
+            katex_version => page.katex_version,
+            features => page.features,
+            show_navigation => page.show_navigation,
+            files => files,
+            current_file => page.current_file,
+            page_title => page.page_title,
+            shared => page.shared,
+            exported => page.exported,
+            toc => page.toc,
+            base_path => page.base_path,
+            edit => page.edit,
+            highlight_css => page.highlight_css,
+            metadata => page.metadata,
+            mermaid_config => page.mermaid_config,
+        })
+        .context("Rendering error")
+}
+
+/// A folder of the sidebar tree, before it is handed to the template.
+#[derive(Default)]
+struct Folder<'a> {
+    files: Vec<&'a str>,
+    folders: BTreeMap<&'a str, Folder<'a>>,
+}
+
+/// A sidebar entry for the file `name`: `{name, folder, label}`, the label
+/// being its title if it has one, otherwise its file name.
+fn file_node(name: &str, titles: &BTreeMap<String, String>) -> Value {
+    let (folder, file_name) = split_folder(name);
+    let label = titles.get(name).map_or(file_name, String::as_str);
+    context! { name => name, folder => folder, label => label }
+}
+
+/// `files`, in sidebar order, as nested folders for the `file_tree`
+/// template variable. Each level lists its files (see [`file_node`]), then
+/// its folders as `{folder, path, open, children}`, `open` when it holds
+/// `current_file`.
+fn file_tree(
+    files: &[String],
+    titles: &BTreeMap<String, String>,
+    current_file: &str,
+) -> Vec<Value> {
+    let mut root = Folder::default();
+    for name in files {
+        let (folder, _) = split_folder(name);
+        let mut node = &mut root;
+        for segment in folder.split('/').filter(|segment| !segment.is_empty()) {
+            node = node.folders.entry(segment).or_default();
+        }
+        node.files.push(name);
+    }
+    folder_nodes(&root, "", titles, current_file)
+}
+
+fn folder_nodes(
+    folder: &Folder<'_>,
+    path: &str,
+    titles: &BTreeMap<String, String>,
+    current_file: &str,
+) -> Vec<Value> {
+    let files = folder.files.iter().map(|name| file_node(name, titles));
+    let folders = folder.folders.iter().map(|(segment, child)| {
+        let path = if path.is_empty() {
+            segment.to_string()
+        } else {
+            format!("{path}/{segment}")
+        };
+        let open = current_file
+            .strip_prefix(path.as_str())
+            .is_some_and(|rest| rest.starts_with('/'));
+        context! {
+            folder => segment,
+            children => folder_nodes(child, &path, titles, current_file),
+            path => path,
+            open => open,
+        }
+    });
+    files.chain(folders).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(content: &Arc<str>) -> PageContext<'_> {
+        static NO_FEATURES: BTreeSet<String> = BTreeSet::new();
+        static NO_TITLES: BTreeMap<String, String> = BTreeMap::new();
+        static NO_METADATA: std::sync::LazyLock<Metadata> = std::sync::LazyLock::new(Metadata::new);
+        PageContext {
+            content,
+            page_title: "<notes>",
+            current_file: "notes.md",
+            files: &[],
+            file_titles: &NO_TITLES,
+            show_navigation: false,
+            mermaid_enabled: false,
+            mermaid_version: "0",
+            katex_enabled: false,
+            katex_version: "0",
+            features: &NO_FEATURES,
+            shared: false,
+            exported: false,
+            toc: &[],
+            base_path: "",
+            edit: false,
+            highlight_css: "",
+            metadata: &NO_METADATA,
+            mermaid_config: &NO_METADATA,
+        }
+    }
+
+    #[test]
+    fn test_content_is_shared_unescaped() {
+        let template = MiniJinjaTemplate::from_source("{{ content }}|{{ page_title }}").unwrap();
+        let content: Arc<str> = "<p>a &amp; b</p>".into();
+
+        assert_eq!(
+            template.render(&page(&content)).unwrap(),
+            "<p>a &amp; b</p>|&lt;notes&gt;"
+        );
+    }
+
+    #[test]
+    fn test_files_are_nested_by_folder() {
+        let template = MiniJinjaTemplate::from_source(
+            "{% for node in file_tree recursive %}{% if node.children %}\
+             [{{ node.folder }}{% if node.open %}*{% endif %}:{{ loop(node.children) }}]\
+             {% else %}{{ node.label }},{% endif %}{% endfor %}",
+        )
+        .unwrap();
+        let files = [
+            "README.md",
+            "guides/intro.md",
+            "guides/api/auth.md",
+            "notes/todo.md",
+        ]
+        .map(String::from);
+        let content: Arc<str> = "".into();
+        let mut page = page(&content);
+        page.files = &files;
+        page.current_file = "guides/api/auth.md";
+
+        assert_eq!(
+            template.render(&page).unwrap(),
+            "README.md,[guides*:intro.md,[api*:auth.md,]][notes:todo.md,]"
+        );
+    }
+
+    #[test]
+    fn test_template_directory_is_loaded_and_reloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("partials")).unwrap();
+        fs::write(
+            dir.path().join("main.html"),
+            "{% include 'partials/nav.html' %}{{ content }}",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("partials/nav.html"),
+            "<nav>{{ current_file }}</nav>",
+        )
+        .unwrap();
+        let content: Arc<str> = "<p>x</p>".into();
+
+        let template = FileTemplate::load(dir.path()).unwrap();
+        assert_eq!(
+            template.render(&page(&content)).unwrap(),
+            "<nav>notes.md</nav><p>x</p>"
+        );
+
+        fs::write(dir.path().join("partials/nav.html"), "<nav>edited</nav>").unwrap();
+        template.reload().unwrap();
+        assert_eq!(
+            template.render(&page(&content)).unwrap(),
+            "<nav>edited</nav><p>x</p>"
+        );
+
+        // A broken edit keeps the last good templates
+        fs::write(dir.path().join("main.html"), "{% if %}").unwrap();
+        assert!(template.reload().is_err());
+        assert_eq!(
+            template.render(&page(&content)).unwrap(),
+            "<nav>edited</nav><p>x</p>"
+        );
+
+        fs::remove_file(dir.path().join("main.html")).unwrap();
+        assert!(FileTemplate::load(dir.path()).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+//! Getting URLs from the terminal mdserve runs in to a browser: clickable
+//! links and the clipboard.
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use std::{
+    io::{IsTerminal, Write},
+    process::{Command, Stdio},
+};
+
+/// `text` as an OSC 8 hyperlink to `url`. Terminals without hyperlinks
+/// ignore the escapes and show `text`; when stdout isn't a terminal, plain
+/// `text` is returned so logs stay clean.
+pub(crate) fn hyperlink(url: &str, text: &str) -> String {
+    if escapes_supported() {
+        format!("\x1b]8;;{url}\x1b\\{text}\x1b]8;;\x1b\\")
+    } else {
+        text.to_string()
+    }
+}
+
+fn escapes_supported() -> bool {
+    std::io::stdout().is_terminal() && std::env::var("TERM").map_or(true, |term| term != "dumb")
+}
+
+/// Copy `url` to the clipboard and say whether it worked.
+pub(crate) fn copy_url(url: &str) {
+    match copy_to_clipboard(url) {
+        Ok(()) => println!("📋 Copied {url} to the clipboard"),
+        Err(e) => eprintln!("⚠ Could not copy the URL: {e:#}"),
+    }
+}
+
+/// Put `text` on the system clipboard through the platform's clipboard
+/// program, or failing that, an OSC 52 escape, which many terminals
+/// (including over SSH) turn into a clipboard write.
+fn copy_to_clipboard(text: &str) -> Result<()> {
+    let programs: &[(&str, &[&str])] = if cfg!(target_os = "macos") {
+        &[("pbcopy", &[])]
+    } else if cfg!(windows) {
+        &[("clip", &[])]
+    } else {
+        &[
+            ("wl-copy", &[]),
+            ("xclip", &["-selection", "clipboard"]),
+            ("xsel", &["--clipboard", "--input"]),
+        ]
+    };
+
+    for (program, args) in programs {
+        let Ok(mut child) = Command::new(program)
+            .args(*args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            continue;
+        };
+        // Closing stdin tells the program the text is complete
+        let written = child
+            .stdin
+            .take()
+            .is_some_and(|mut stdin| stdin.write_all(text.as_bytes()).is_ok());
+        if child.wait().is_ok_and(|status| status.success()) && written {
+            return Ok(());
+        }
+    }
+
+    if escapes_supported() {
+        let mut stdout = std::io::stdout();
+        write!(stdout, "\x1b]52;c;{}\x07", STANDARD.encode(text))?;
+        stdout.flush()?;
+        return Ok(());
+    }
+    anyhow::bail!(
+        "no clipboard program found (tried {})",
+        programs
+            .iter()
+            .map(|(program, _)| *program)
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
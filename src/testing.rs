@@ -0,0 +1,182 @@
+//! Helpers for testing code that embeds mdserve.
+//!
+//! [`TestPreview`] serves a temporary directory through an in-memory
+//! [`axum_test::TestServer`] with a manual change source, so file changes
+//! are applied exactly when the test says so and no sleeps are needed.
+//! Enable the `testing` feature to use it.
+
+use anyhow::{Context, Result};
+use axum_test::{TestServer, TestWebSocket};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tempfile::TempDir;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::{
+    app::{Mdserve, MdserveBuilder, ServerMessage, StateHandle},
+    watch::{self, ChangeSender, FileEvent},
+};
+
+/// How long helpers wait for the server before failing.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A preview of a temporary directory, driven by the test.
+pub struct TestPreview {
+    pub server: TestServer,
+    pub state: StateHandle,
+    dir: TempDir,
+    changes: ChangeSender,
+    handled: Mutex<mpsc::UnboundedReceiver<FileEvent>>,
+}
+
+impl TestPreview {
+    /// Serve `files` (name and content pairs) in directory mode.
+    pub fn new(files: &[(&str, &str)]) -> Result<Self> {
+        Self::with_builder(files, |builder| builder)
+    }
+
+    /// Like [`new`](Self::new), with a chance to adjust the builder. The base
+    /// directory and change source are already set.
+    pub fn with_builder(
+        files: &[(&str, &str)],
+        configure: impl FnOnce(MdserveBuilder) -> MdserveBuilder,
+    ) -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        for (name, content) in files {
+            fs::write(dir.path().join(name), content)?;
+        }
+
+        let (changes, source) = watch::channel();
+        let (handled_tx, handled_rx) = mpsc::unbounded_channel();
+        let builder = Mdserve::builder()
+            .base_dir(dir.path())
+            .change_source(source)
+            .on_file_event(move |event| {
+                let handled_tx = handled_tx.clone();
+                async move {
+                    let _ = handled_tx.send(event);
+                }
+            });
+
+        let (router, state) = configure(builder).build()?;
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router)
+            .context("failed to start test server")?;
+
+        Ok(Self {
+            server,
+            state,
+            dir,
+            changes,
+            handled: Mutex::new(handled_rx),
+        })
+    }
+
+    /// Canonical path of the served directory.
+    pub fn dir(&self) -> PathBuf {
+        self.dir
+            .path()
+            .canonicalize()
+            .unwrap_or_else(|_| self.dir.path().to_path_buf())
+    }
+
+    /// Path of `name` inside the served directory.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir().join(name)
+    }
+
+    /// Write `name` and wait until the server has processed the change.
+    pub async fn write(&self, name: &str, content: &str) -> Result<()> {
+        let path = self.path(name);
+        let existed = path.exists();
+        fs::write(&path, content)?;
+
+        if existed {
+            self.changes.modified(&path).await;
+        } else {
+            self.changes.created(&path).await;
+        }
+        self.wait_handled(&path).await
+    }
+
+    /// Delete `name` and wait until the server has processed the change.
+    pub async fn remove(&self, name: &str) -> Result<()> {
+        let path = self.path(name);
+        fs::remove_file(&path)?;
+        self.changes.removed(&path).await;
+        self.wait_handled(&path).await
+    }
+
+    /// Open a live-reload socket. Messages sent after this returns are
+    /// guaranteed to reach it.
+    pub async fn websocket(&self) -> PreviewSocket {
+        PreviewSocket(
+            self.server
+                .get_websocket("/ws")
+                .await
+                .into_websocket()
+                .await,
+        )
+    }
+
+    async fn wait_handled(&self, path: &Path) -> Result<()> {
+        let mut handled = self.handled.lock().await;
+        tokio::time::timeout(TIMEOUT, async {
+            while let Some(event) = handled.recv().await {
+                if event.path == path {
+                    return Ok(());
+                }
+            }
+            anyhow::bail!("change source closed")
+        })
+        .await
+        .context("timed out waiting for file event")?
+    }
+}
+
+/// The live-reload WebSocket of a [`TestPreview`].
+pub struct PreviewSocket(pub TestWebSocket);
+
+impl PreviewSocket {
+    /// Next message from the server, failing after a timeout.
+    pub async fn next_message(&mut self) -> Result<ServerMessage> {
+        tokio::time::timeout(TIMEOUT, self.0.receive_json::<ServerMessage>())
+            .await
+            .context("timed out waiting for server message")
+    }
+
+    /// Wait for the next message other than a notice and check that it is
+    /// a reload.
+    pub async fn expect_reload(&mut self) -> Result<()> {
+        loop {
+            match self.next_message().await? {
+                ServerMessage::Reload { .. } => return Ok(()),
+                ServerMessage::Notice { .. } => {}
+                other => anyhow::bail!("expected Reload, got {other:?}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_preview_applies_writes_deterministically() {
+        let preview = TestPreview::new(&[("a.md", "# Before")]).unwrap();
+        let mut socket = preview.websocket().await;
+
+        preview.write("a.md", "# After").await.unwrap();
+        socket.expect_reload().await.unwrap();
+        assert!(preview.server.get("/a.md").await.text().contains("After"));
+
+        preview.write("b.md", "# New").await.unwrap();
+        socket.expect_reload().await.unwrap();
+        assert_eq!(preview.state.tracked_files().await, vec!["a.md", "b.md"]);
+    }
+}
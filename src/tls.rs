@@ -0,0 +1,118 @@
+//! HTTPS for previews opened over the network, where browser features such
+//! as the clipboard API need a secure context. The certificate comes from
+//! PEM files, or is generated self-signed at startup.
+
+use anyhow::{Context, Result};
+use rcgen::CertifiedKey;
+use std::{
+    fs::File,
+    io::BufReader,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+/// Where the certificate for HTTPS comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TlsCertificate {
+    /// A PEM certificate chain and the PEM private key for it.
+    Files { cert: PathBuf, key: PathBuf },
+    /// A certificate generated at startup for `localhost`, the loopback
+    /// addresses and the hostname served on. Browsers warn about it until
+    /// it is trusted.
+    SelfSigned,
+}
+
+/// Accepts TLS connections for a server listening on `hostname`.
+pub(crate) fn acceptor(certificate: &TlsCertificate, hostname: &str) -> Result<TlsAcceptor> {
+    let (chain, key) = match certificate {
+        TlsCertificate::Files { cert, key } => (read_chain(cert)?, read_key(key)?),
+        TlsCertificate::SelfSigned => self_signed(hostname)?,
+    };
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .context("no TLS protocol versions available")?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .context("the TLS certificate and key don't match")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn read_chain(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let chain = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    if chain.is_empty() {
+        anyhow::bail!("no PEM certificate in {}", path.display());
+    }
+    Ok(chain)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("failed to read {}", path.display()))?
+        .with_context(|| format!("no PEM private key in {}", path.display()))
+}
+
+/// A new self-signed certificate for the names `hostname` is reached at.
+fn self_signed(hostname: &str) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let mut names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    let unspecified = hostname
+        .parse::<IpAddr>()
+        .is_ok_and(|ip| ip.is_unspecified());
+    if !unspecified && !names.iter().any(|name| name == hostname) {
+        names.push(hostname.to_string());
+    }
+
+    let CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(names)
+        .context("failed to generate a self-signed certificate")?;
+    let key = PrivatePkcs8KeyDer::from(key_pair.serialize_der());
+    Ok((vec![cert.der().clone()], key.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_certificate_files_are_read() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = temp_dir.path().join("cert.pem");
+        let key_path = temp_dir.path().join("key.pem");
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+
+        let files = TlsCertificate::Files {
+            cert: cert_path.clone(),
+            key: key_path.clone(),
+        };
+        assert!(acceptor(&files, "127.0.0.1").is_ok());
+        assert!(acceptor(&TlsCertificate::SelfSigned, "0.0.0.0").is_ok());
+
+        // Swapped, neither file holds what is expected
+        let swapped = TlsCertificate::Files {
+            cert: key_path,
+            key: cert_path,
+        };
+        assert!(acceptor(&swapped, "127.0.0.1").is_err());
+    }
+}
@@ -0,0 +1,180 @@
+//! Public HTTPS URLs for a local server through a tunnelling service, so a
+//! draft can be shown to someone outside the network without deploying it.
+//!
+//! The provider's own client runs as a child process pointed at the local
+//! port; the public URL is read from its output.
+
+use anyhow::{Context, Result};
+use std::{
+    io::{BufRead, BufReader, Read},
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+use crate::app::format_host;
+
+/// How long the provider gets to report its URL.
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A service that forwards a public HTTPS URL to the local server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TunnelProvider {
+    /// Cloudflare quick tunnels (`cloudflared`, no account needed).
+    Cloudflared,
+    /// localhost.run, over the system `ssh` client.
+    LocalhostRun,
+}
+
+impl TunnelProvider {
+    fn program(self) -> &'static str {
+        match self {
+            TunnelProvider::Cloudflared => "cloudflared",
+            TunnelProvider::LocalhostRun => "ssh",
+        }
+    }
+
+    fn command(self, host: &str, port: u16) -> Command {
+        let local = format_host(host, port);
+        let mut command = Command::new(self.program());
+        match self {
+            TunnelProvider::Cloudflared => {
+                command.args(["tunnel", "--no-autoupdate", "--url"]);
+                command.arg(format!("http://{local}"));
+            }
+            TunnelProvider::LocalhostRun => {
+                command.args([
+                    "-o",
+                    "StrictHostKeyChecking=accept-new",
+                    "-o",
+                    "ServerAliveInterval=30",
+                    "-R",
+                ]);
+                command.arg(format!("80:{local}"));
+                command.arg("nokey@localhost.run");
+            }
+        }
+        command
+    }
+
+    /// Hosts the provider hands out tunnels under. Its output also links to
+    /// docs and terms, which must not be mistaken for the tunnel.
+    fn tunnel_domains(self) -> &'static [&'static str] {
+        match self {
+            TunnelProvider::Cloudflared => &[".trycloudflare.com"],
+            TunnelProvider::LocalhostRun => &[".lhr.life", ".lhr.rocks"],
+        }
+    }
+
+    /// The tunnel URL in a line of the provider's output.
+    fn public_url(self, line: &str) -> Option<String> {
+        line.match_indices("https://").find_map(|(start, _)| {
+            let url = line[start..]
+                .split(|c: char| c.is_whitespace() || c == '|')
+                .next()?;
+            let host = url["https://".len()..].split('/').next()?;
+            self.tunnel_domains()
+                .iter()
+                .any(|domain| host.ends_with(domain))
+                .then(|| format!("https://{host}"))
+        })
+    }
+}
+
+/// A running tunnel. Dropping it stops the provider.
+pub(crate) struct Tunnel {
+    child: Child,
+    pub url: String,
+}
+
+impl Tunnel {
+    /// Start `provider` for the server at `host:port` and wait for its
+    /// public URL.
+    pub async fn start(provider: TunnelProvider, host: &str, port: u16) -> Result<Self> {
+        let program = provider.program();
+        let mut child = provider
+            .command(host, port)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to run {program}; is it installed?"))?;
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let outputs: [Option<Box<dyn Read + Send>>; 2] = [
+            child.stdout.take().map(|out| Box::new(out) as _),
+            child.stderr.take().map(|err| Box::new(err) as _),
+        ];
+        for output in outputs.into_iter().flatten() {
+            let tx = tx.clone();
+            // Keep reading after the URL so the provider never blocks on a
+            // full pipe
+            std::thread::spawn(move || {
+                for line in BufReader::new(output).lines().map_while(Result::ok) {
+                    if let Some(url) = provider.public_url(&line) {
+                        let _ = tx.send(url);
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        let mut tunnel = Tunnel {
+            child,
+            url: String::new(),
+        };
+        match tokio::time::timeout(START_TIMEOUT, rx.recv()).await {
+            Ok(Some(url)) => {
+                tunnel.url = url;
+                Ok(tunnel)
+            }
+            Ok(None) => anyhow::bail!("{program} exited without reporting a public URL"),
+            Err(_) => anyhow::bail!(
+                "{program} did not report a public URL within {}s",
+                START_TIMEOUT.as_secs()
+            ),
+        }
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_public_urls_are_found_in_provider_output() {
+        let cloudflared = TunnelProvider::Cloudflared;
+        assert_eq!(
+            cloudflared.public_url(
+                "2024-01-01T00:00:00Z INF |  https://calm-river-1234.trycloudflare.com                    |"
+            ),
+            Some("https://calm-river-1234.trycloudflare.com".to_string())
+        );
+        assert_eq!(
+            cloudflared.public_url(
+                "INF Requesting new quick Tunnel on trycloudflare.com... see https://www.cloudflare.com/website-terms/"
+            ),
+            None
+        );
+
+        let localhost_run = TunnelProvider::LocalhostRun;
+        assert_eq!(
+            localhost_run.public_url(
+                "a1b2c3d4e5f6.lhr.life tunneled with tls termination, https://a1b2c3d4e5f6.lhr.life"
+            ),
+            Some("https://a1b2c3d4e5f6.lhr.life".to_string())
+        );
+        assert_eq!(
+            localhost_run.public_url("docs: https://localhost.run/docs/"),
+            None
+        );
+    }
+}
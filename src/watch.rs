@@ -0,0 +1,352 @@
+use anyhow::Result;
+use notify::{
+    event::{CreateKind, DataChange, Flag, ModifyKind, RemoveKind},
+    Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+const EVENT_BUFFER: usize = 100;
+/// First delay before re-creating a failed watcher; doubles up to
+/// `RETRY_MAX_DELAY` while attempts keep failing.
+const RETRY_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+/// `info` of the event a source sends when it stops seeing changes.
+const DEGRADED_INFO: &str = "mdserve:watch-degraded";
+
+/// Supplies the file events that drive live reload.
+///
+/// The router calls [`start`](Self::start) once with its canonical base
+/// directory and consumes events from the returned channel until it is
+/// closed. Events use notify's types whatever the source.
+///
+/// A source that loses track of the directory reports it with
+/// [`degraded_event`] and, once it watches again, sends an event flagged
+/// [`Flag::Rescan`] so the router re-reads everything it may have missed.
+pub trait ChangeSource: Send {
+    fn start(self: Box<Self>, base_dir: &Path) -> Result<mpsc::Receiver<Event>>;
+}
+
+/// The event telling the router that changes are no longer being seen.
+pub fn degraded_event() -> Event {
+    Event::new(EventKind::Other).set_info(DEGRADED_INFO)
+}
+
+/// The event telling the router that changes may have been missed and
+/// every file should be re-read.
+pub fn rescan_event() -> Event {
+    Event::new(EventKind::Other).set_flag(Flag::Rescan)
+}
+
+pub(crate) fn is_degraded_event(event: &Event) -> bool {
+    event.info() == Some(DEGRADED_INFO)
+}
+
+pub(crate) fn is_rescan_event(event: &Event) -> bool {
+    event.flag() == Some(Flag::Rescan)
+}
+
+/// Watches the base directory (non-recursively, unless created with
+/// [`recursive`](Self::recursive)) with the platform's native notify
+/// backend. This is the default source.
+///
+/// When the watcher fails (the inotify watch limit is hit, the directory
+/// is removed or its volume unmounted) it is dropped and re-created with
+/// backoff until watching works again.
+#[derive(Debug, Default)]
+pub struct NotifySource {
+    recursive: bool,
+}
+
+impl NotifySource {
+    /// Also watch every subdirectory, for recursive directory mode.
+    pub fn recursive() -> Self {
+        Self { recursive: true }
+    }
+}
+
+impl ChangeSource for NotifySource {
+    fn start(self: Box<Self>, base_dir: &Path) -> Result<mpsc::Receiver<Event>> {
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+        let (error_tx, error_rx) = mpsc::unbounded_channel();
+
+        let mode = if self.recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        let watcher = watch_dir(base_dir, mode, tx.clone(), error_tx.clone())?;
+
+        // The watcher stops when dropped, so keep it alive for as long as
+        // someone is listening.
+        tokio::spawn(supervise(
+            base_dir.to_path_buf(),
+            mode,
+            watcher,
+            tx,
+            error_tx,
+            error_rx,
+        ));
+
+        Ok(rx)
+    }
+}
+
+fn watch_dir(
+    base_dir: &Path,
+    mode: RecursiveMode,
+    tx: mpsc::Sender<Event>,
+    errors: mpsc::UnboundedSender<notify::Error>,
+) -> notify::Result<RecommendedWatcher> {
+    let dir = base_dir.to_path_buf();
+    let mut watcher = RecommendedWatcher::new(
+        move |res: std::result::Result<Event, notify::Error>| match res {
+            // Removing (or unmounting) the directory ends the watch without
+            // an error, so treat it as one
+            Ok(event)
+                if matches!(event.kind, EventKind::Remove(_)) && event.paths.contains(&dir) =>
+            {
+                let _ = errors.send(notify::Error::path_not_found().add_path(dir.clone()));
+            }
+            Ok(event) => {
+                let _ = tx.blocking_send(event);
+            }
+            Err(error) => {
+                let _ = errors.send(error);
+            }
+        },
+        Config::default(),
+    )?;
+
+    watcher.watch(base_dir, mode)?;
+    Ok(watcher)
+}
+
+/// Hold `watcher` until the router stops listening, replacing it whenever
+/// it reports an error.
+async fn supervise(
+    base_dir: PathBuf,
+    mode: RecursiveMode,
+    mut watcher: RecommendedWatcher,
+    tx: mpsc::Sender<Event>,
+    error_tx: mpsc::UnboundedSender<notify::Error>,
+    mut errors: mpsc::UnboundedReceiver<notify::Error>,
+) {
+    loop {
+        let error = tokio::select! {
+            _ = tx.closed() => return,
+            Some(error) = errors.recv() => error,
+        };
+
+        eprintln!("⚠ File watcher failed ({error}); live reload paused");
+        drop(watcher);
+        if tx.send(degraded_event()).await.is_err() {
+            return;
+        }
+
+        let mut delay = RETRY_INITIAL_DELAY;
+        watcher = loop {
+            tokio::select! {
+                _ = tx.closed() => return,
+                _ = tokio::time::sleep(delay) => {}
+            }
+            match watch_dir(&base_dir, mode, tx.clone(), error_tx.clone()) {
+                Ok(watcher) => break watcher,
+                Err(_) => delay = (delay * 2).min(RETRY_MAX_DELAY),
+            }
+        };
+
+        // Errors queued by the old watcher are stale now
+        while errors.try_recv().is_ok() {}
+        eprintln!("⚡ File watcher restored; live reload resumed");
+        if tx.send(rescan_event()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// A source fed by hand through a [`ChangeSender`], for deterministic tests
+/// and for embedders that already know when files change.
+#[derive(Debug)]
+pub struct ChannelSource {
+    rx: mpsc::Receiver<Event>,
+}
+
+impl ChangeSource for ChannelSource {
+    fn start(self: Box<Self>, _base_dir: &Path) -> Result<mpsc::Receiver<Event>> {
+        Ok(self.rx)
+    }
+}
+
+/// Sending half of a [`ChannelSource`].
+#[derive(Clone, Debug)]
+pub struct ChangeSender {
+    tx: mpsc::Sender<Event>,
+}
+
+impl ChangeSender {
+    /// Deliver a raw event. Returns `false` once the router has gone away.
+    pub async fn send(&self, event: Event) -> bool {
+        self.tx.send(event).await.is_ok()
+    }
+
+    /// Report that the contents of `path` changed.
+    pub async fn modified(&self, path: impl Into<PathBuf>) -> bool {
+        self.send(
+            Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content)))
+                .add_path(path.into()),
+        )
+        .await
+    }
+
+    /// Report that `path` was created.
+    pub async fn created(&self, path: impl Into<PathBuf>) -> bool {
+        self.send(Event::new(EventKind::Create(CreateKind::File)).add_path(path.into()))
+            .await
+    }
+
+    /// Report that `path` was removed.
+    pub async fn removed(&self, path: impl Into<PathBuf>) -> bool {
+        self.send(Event::new(EventKind::Remove(RemoveKind::File)).add_path(path.into()))
+            .await
+    }
+
+    /// Report that changes are no longer being seen.
+    pub async fn degraded(&self) -> bool {
+        self.send(degraded_event()).await
+    }
+
+    /// Report that changes may have been missed, e.g. after watching
+    /// resumed.
+    pub async fn rescan(&self) -> bool {
+        self.send(rescan_event()).await
+    }
+}
+
+/// Create a manually driven change source and its sender.
+pub fn channel() -> (ChangeSender, ChannelSource) {
+    let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+    (ChangeSender { tx }, ChannelSource { rx })
+}
+
+/// What happened to a file, as reported to [`on_file_event`] callbacks.
+///
+/// [`on_file_event`]: crate::MdserveBuilder::on_file_event
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FileEventKind {
+    Created,
+    Modified,
+    /// The file appeared under this path through a rename.
+    Renamed,
+    Removed,
+}
+
+/// A change to one file in the watched directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileEvent {
+    pub path: PathBuf,
+    pub kind: FileEventKind,
+}
+
+/// Flatten a raw notify event into per-file events, dropping the ones that
+/// carry no useful change (metadata updates, the old side of a rename).
+pub(crate) fn file_events(event: &Event) -> Vec<FileEvent> {
+    use notify::event::RenameMode;
+
+    let (kind, paths): (FileEventKind, &[PathBuf]) = match event.kind {
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+            (FileEventKind::Renamed, &event.paths[1..])
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => (
+            FileEventKind::Renamed,
+            &event.paths[..event.paths.len().min(1)],
+        ),
+        EventKind::Modify(ModifyKind::Name(RenameMode::Any)) => {
+            let existing = event.paths.first().filter(|path| path.exists());
+            (
+                FileEventKind::Renamed,
+                existing.map(std::slice::from_ref).unwrap_or(&[]),
+            )
+        }
+        EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any) => {
+            (FileEventKind::Modified, &event.paths[..])
+        }
+        EventKind::Create(_) => (FileEventKind::Created, &event.paths[..]),
+        EventKind::Remove(_) => (FileEventKind::Removed, &event.paths[..]),
+        _ => return Vec::new(),
+    };
+
+    paths
+        .iter()
+        .map(|path| FileEvent {
+            path: path.clone(),
+            kind,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::RenameMode;
+
+    #[test]
+    fn test_file_events_from_notify_events() {
+        let created = Event::new(EventKind::Create(CreateKind::File)).add_path("/d/a.md".into());
+        assert_eq!(
+            file_events(&created),
+            vec![FileEvent {
+                path: "/d/a.md".into(),
+                kind: FileEventKind::Created
+            }]
+        );
+
+        let renamed = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path("/d/a.md~".into())
+            .add_path("/d/a.md".into());
+        assert_eq!(
+            file_events(&renamed),
+            vec![FileEvent {
+                path: "/d/a.md".into(),
+                kind: FileEventKind::Renamed
+            }]
+        );
+
+        let renamed_away = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+            .add_path("/d/a.md".into());
+        assert!(file_events(&renamed_away).is_empty());
+    }
+
+    async fn next_matching(rx: &mut mpsc::Receiver<Event>, wanted: fn(&Event) -> bool) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !wanted(&rx.recv().await.expect("source closed")) {}
+        })
+        .await
+        .expect("Timeout waiting for event");
+    }
+
+    #[tokio::test]
+    async fn test_notify_source_recovers_when_directory_returns() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().canonicalize().unwrap().join("docs");
+        std::fs::create_dir(&dir).unwrap();
+        let mut rx = Box::new(NotifySource::default()).start(&dir).unwrap();
+
+        std::fs::remove_dir(&dir).unwrap();
+        next_matching(&mut rx, is_degraded_event).await;
+
+        std::fs::create_dir(&dir).unwrap();
+        next_matching(&mut rx, is_rescan_event).await;
+
+        // Events flow from the new watcher
+        std::fs::write(dir.join("a.md"), "# A").unwrap();
+        next_matching(&mut rx, |event| {
+            event.paths.iter().any(|p| p.ends_with("a.md"))
+        })
+        .await;
+    }
+}
@@ -0,0 +1,176 @@
+//! Obsidian-style wiki links: `[[Page Name]]`, `[[Page Name|label]]` and
+//! `[[Page Name#Heading]]` link to the tracked document whose name matches,
+//! ignoring case, the `.md` extension and the difference between spaces,
+//! `-` and `_`. `[[guides/Setup]]` narrows the match to a folder.
+//!
+//! Links are resolved when a page is served rather than when it is
+//! rendered, so they follow files being added, removed and renamed.
+
+use std::fmt::Write;
+
+use crate::{
+    app::{percent_encode, split_folder},
+    headings::github_slug,
+    render::map_text,
+};
+
+/// Replace wiki links in the text of `html`, the document `current_file`,
+/// with relative links to the matching `files`. Targets matching none are
+/// kept as text in a `wikilink-missing` span.
+pub(crate) fn render_wikilinks(html: &str, current_file: &str, files: &[String]) -> String {
+    map_text(html, |text, out| {
+        replace_links(text, current_file, files, out)
+    })
+}
+
+/// Append `text` to `out` with its `[[…]]` links replaced. Embeds
+/// (`![[…]]`) are left as written.
+fn replace_links(text: &str, current_file: &str, files: &[String], out: &mut String) {
+    let mut rest = text;
+    while let Some(start) = rest.find("[[") {
+        let after = &rest[start + 2..];
+        let Some(len) = after.find("]]") else {
+            break;
+        };
+        let inner = &after[..len];
+        if inner.trim().is_empty() || inner.contains('[') || rest[..start].ends_with('!') {
+            out.push_str(&rest[..start + 2]);
+            rest = after;
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        write_link(inner, current_file, files, out);
+        rest = &after[len + 2..];
+    }
+    out.push_str(rest);
+}
+
+/// Append the link for `inner`, the escaped text between the brackets.
+fn write_link(inner: &str, current_file: &str, files: &[String], out: &mut String) {
+    let (target, label) = match inner.split_once('|') {
+        Some((target, label)) => (target, label.trim()),
+        None => (inner, inner.trim()),
+    };
+    let target = unescape_html(target.trim());
+    let (page, heading) = target.split_once('#').unwrap_or((&target, ""));
+    let fragment = if heading.is_empty() {
+        String::new()
+    } else {
+        format!("#{}", github_slug(heading))
+    };
+
+    if page.is_empty() {
+        let _ = write!(out, r#"<a href="{fragment}" class="wikilink">{label}</a>"#);
+        return;
+    }
+    match resolve(page, current_file, files) {
+        Some(name) => {
+            let href = relative_url(split_folder(current_file).0, name);
+            let _ = write!(
+                out,
+                r#"<a href="{href}{fragment}" class="wikilink">{label}</a>"#
+            );
+        }
+        None => {
+            let _ = write!(
+                out,
+                r#"<span class="wikilink wikilink-missing" title="No document matches">{label}</span>"#
+            );
+        }
+    }
+}
+
+/// The file `target` names. When several match, one in `current_file`'s
+/// folder wins, then the least nested, then the first in sidebar order.
+fn resolve<'a>(target: &str, current_file: &str, files: &'a [String]) -> Option<&'a str> {
+    let wanted = normalize(target);
+    let suffix = format!("/{wanted}");
+    let folder = split_folder(current_file).0;
+    files
+        .iter()
+        .filter(|name| {
+            let name = normalize(name);
+            name == wanted || name.ends_with(&suffix)
+        })
+        .min_by_key(|name| (split_folder(name).0 != folder, name.matches('/').count()))
+        .map(String::as_str)
+}
+
+/// `name` in the form names are compared in: lowercase, without a markdown
+/// extension, with runs of spaces, `-` and `_` as one space.
+fn normalize(name: &str) -> String {
+    let name = name.trim_start_matches("./");
+    let stem = match name.rsplit_once('.') {
+        Some((stem, ext))
+            if ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("markdown") =>
+        {
+            stem
+        }
+        _ => name,
+    };
+    stem.split('/')
+        .map(|segment| {
+            segment
+                .to_lowercase()
+                .split([' ', '-', '_'])
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// URL of the document `name` from a page in `folder`: `setup.md` from the
+/// same folder, `../notes/todo.md` from a sibling.
+fn relative_url(folder: &str, name: &str) -> String {
+    let from: Vec<&str> = folder.split('/').filter(|s| !s.is_empty()).collect();
+    let to: Vec<&str> = name.split('/').collect();
+    let common = from
+        .iter()
+        .zip(&to[..to.len() - 1])
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut parts = vec!["..".to_string(); from.len() - common];
+    parts.extend(to[common..].iter().map(|segment| percent_encode(segment)));
+    parts.join("/")
+}
+
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_links_resolve_to_matching_documents() {
+        let files = [
+            "Getting Started.md".to_string(),
+            "guides/setup.md".to_string(),
+            "notes/setup.md".to_string(),
+            "notes/todo-list.md".to_string(),
+        ];
+        let html = "<p>See [[getting started]], [[Setup|the setup]] and \
+                    [[Todo List#Next Steps]] or [[Missing Page]]. \
+                    <code>[[Setup]]</code> ![[diagram.png]]</p>";
+
+        assert_eq!(
+            render_wikilinks(html, "notes/index.md", &files),
+            "<p>See <a href=\"../Getting%20Started.md\" class=\"wikilink\">getting started</a>, \
+             <a href=\"setup.md\" class=\"wikilink\">the setup</a> and \
+             <a href=\"todo-list.md#next-steps\" class=\"wikilink\">Todo List#Next Steps</a> or \
+             <span class=\"wikilink wikilink-missing\" title=\"No document matches\">Missing Page</span>. \
+             <code>[[Setup]]</code> ![[diagram.png]]</p>"
+        );
+        assert_eq!(
+            render_wikilinks("[[guides/setup]] [[#Intro]]", "README.md", &files),
+            "<a href=\"guides/setup.md\" class=\"wikilink\">guides/setup</a> \
+             <a href=\"#intro\" class=\"wikilink\">#Intro</a>"
+        );
+    }
+}